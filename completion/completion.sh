@@ -17,17 +17,17 @@ _system76-power ()
     prev="${COMP_WORDS[COMP_CWORD-1]}"
 
     # 1st level options
-    opts="charge-threshold daemon graphics help profile --version --help"
+    opts="bench-mode brightness capabilities charge-threshold config consumers daemon doctor export fan graphics help info profile setup suspend-report throttling --version --help"
 
     # 2nd/3rd level options
     case "${prev}" in
         graphics)
-            local _opts="compute integrated hybrid nvidia power switchable --help"
+            local _opts="compute discrete hybrid info integrated link nvidia power power-states reconcile switchable topology vfio --dry-run --help"
             COMPREPLY=( $(compgen -W "${_opts}" -- ${cur}) )
             return 0
             ;;
 
-        battery|balanced|compute|integrated|hybrid|nvidia|performance|switchable|on|off|auto)
+        battery|balanced|capabilities|consumers|doctor|info|suspend-report|throttling|compute|integrated|hybrid|link|nvidia|performance|switchable|topology|vfio|on|off|auto)
             local _opts="--help"
             COMPREPLY=( $(compgen -W "${_opts}" -- ${cur}) )
             return 0
@@ -51,7 +51,12 @@ _system76-power ()
             ;;
 
 	      power)
-	          local _opts="auto on off --help"
+	          local _opts="auto on off --device --force --help"
+            COMPREPLY=( $(compgen -W "${_opts}" -- ${cur}) )
+            return 0
+            ;;
+        config)
+            local _opts="validate --help"
             COMPREPLY=( $(compgen -W "${_opts}" -- ${cur}) )
             return 0
             ;;
@@ -60,6 +65,41 @@ _system76-power ()
             COMPREPLY=( $(compgen -W "${_opts}" -- ${cur}) )
             return 0
             ;;
+        bench-mode)
+            local _opts="on off --duration --help"
+            COMPREPLY=( $(compgen -W "${_opts}" -- ${cur}) )
+            return 0
+            ;;
+        brightness)
+            local _opts="screen keyboard --help"
+            COMPREPLY=( $(compgen -W "${_opts}" -- ${cur}) )
+            return 0
+            ;;
+        screen|keyboard)
+            local _opts="up down"
+            COMPREPLY=( $(compgen -W "${_opts}" -- ${cur}) )
+            return 0
+            ;;
+        export)
+            local _opts="--shell --toml --help"
+            COMPREPLY=( $(compgen -W "${_opts}" -- ${cur}) )
+            return 0
+            ;;
+        setup)
+            local _opts="--output --help"
+            COMPREPLY=( $(compgen -W "${_opts}" -- ${cur}) )
+            return 0
+            ;;
+        fan)
+            local _opts="--unit --history --help"
+            COMPREPLY=( $(compgen -W "${_opts}" -- ${cur}) )
+            return 0
+            ;;
+        --unit)
+            local _opts="celsius fahrenheit"
+            COMPREPLY=( $(compgen -W "${_opts}" -- ${cur}) )
+            return 0
+            ;;
         --profile)
             local _opts="full_charge balanced max_lifespan --help"
             COMPREPLY=( $(compgen -W "${_opts}" -- ${cur}) )
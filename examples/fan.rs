@@ -1,12 +1,14 @@
 use log::LevelFilter;
-use std::{process, thread, time};
+use std::{process, sync::Arc, thread, time};
 use system76_power::{
     fan::{FanDaemon, FanDaemonError},
     logging,
+    sensors::SensorCache,
 };
 
 fn inner() -> Result<(), FanDaemonError> {
-    let daemon = FanDaemon::new(false);
+    // Every temperature is read anew, as this loop is the only reader.
+    let daemon = FanDaemon::new(false, Arc::new(SensorCache::new(time::Duration::new(0, 0))));
 
     loop {
         if let Some(temp) = daemon.get_temp() {
@@ -8,27 +8,108 @@
 //!  - https://www.kernel.org/doc/html/latest/userspace-api/sysfs-platform_profile.html
 //! - Available Platform Profiles:
 //!  - https://mjmwired.net/kernel/Documentation/ABI/testing/sysfs-platform_profile
+//!
+//! The platform profile may also be changed by firmware hotkeys or other tools, so each platform
+//! profile maps back onto the profile of the daemon which it most resembles.
 
-use std::{fs, path::Path};
+use crate::config::Profile;
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    os::unix::io::AsRawFd,
+    path::Path,
+};
 
 const SYSFS_PATH: &str = "/sys/firmware/acpi/platform_profile";
+const CHOICES_PATH: &str = "/sys/firmware/acpi/platform_profile_choices";
 
 pub fn supported() -> bool { Path::new(SYSFS_PATH).exists() }
 
-pub fn battery() {
-    if let Err(why) = fs::write(SYSFS_PATH, "low-power") {
-        eprintln!("ACPI Platform Profile: could not set to low-power: {}", why);
+/// The current platform profile.
+pub fn get() -> Option<String> {
+    fs::read_to_string(SYSFS_PATH).ok().map(|value| value.trim().to_owned())
+}
+
+/// Calls `changed` each time the platform profile changes, until it returns `false`. The kernel
+/// notifies pollers of the file with `POLLPRI`, so this blocks, and belongs on a thread of its own.
+pub fn watch<F: FnMut() -> bool>(mut changed: F) -> io::Result<()> {
+    let mut file = File::open(SYSFS_PATH)?;
+    let mut buf = [0; 64];
+    loop {
+        // Reading the file from the start rearms the notification.
+        file.seek(SeekFrom::Start(0))?;
+        let _ = file.read(&mut buf)?;
+
+        let mut fd = libc::pollfd {
+            fd:      file.as_raw_fd(),
+            events:  libc::POLLPRI | libc::POLLERR,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut fd, 1, -1) } < 0 {
+            let why = io::Error::last_os_error();
+            if why.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(why);
+        }
+
+        if !changed() {
+            return Ok(());
+        }
     }
 }
 
-pub fn balanced() {
-    if let Err(why) = fs::write(SYSFS_PATH, "balanced-performance") {
-        eprintln!("ACPI Platform Profile: could not set to balanced-performance: {}", why);
+/// The platform profiles which correspond to a profile, in order of preference.
+fn candidates(profile: Profile) -> &'static [&'static str] {
+    match profile {
+        Profile::Battery => &["low-power", "quiet", "cool"],
+        Profile::Balanced => &["balanced-performance", "balanced"],
+        Profile::Performance => &["performance"],
     }
 }
 
-pub fn performance() {
-    if let Err(why) = fs::write(SYSFS_PATH, "performance") {
-        eprintln!("ACPI Platform Profile: could not set to performance: {}", why);
+/// The platform profile to select for a profile. The most preferred is assumed if the platform
+/// does not report its choices.
+fn choice(profile: Profile, choices: &str) -> &'static str {
+    let candidates = candidates(profile);
+    let choices: Vec<&str> = choices.split_whitespace().collect();
+    candidates.iter().find(|candidate| choices.contains(candidate)).unwrap_or(&candidates[0])
+}
+
+/// The profile which a platform profile corresponds to. The `custom` profile corresponds to none.
+pub fn profile_for(value: &str) -> Option<Profile> {
+    [Profile::Battery, Profile::Balanced, Profile::Performance]
+        .iter()
+        .copied()
+        .find(|&profile| candidates(profile).contains(&value))
+}
+
+/// Selects the platform profile of a profile, unless the platform profile which is already
+/// selected corresponds to it.
+pub fn set(profile: Profile) -> io::Result<()> {
+    if get().as_deref().and_then(profile_for) == Some(profile) {
+        return Ok(());
+    }
+
+    let value = choice(profile, &fs::read_to_string(CHOICES_PATH).unwrap_or_default());
+    fs::write(SYSFS_PATH, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn choices() {
+        let choices = "low-power balanced performance\n";
+        assert_eq!(choice(Profile::Battery, choices), "low-power");
+        assert_eq!(choice(Profile::Balanced, choices), "balanced");
+        assert_eq!(choice(Profile::Performance, choices), "performance");
+        assert_eq!(choice(Profile::Battery, "quiet balanced performance"), "quiet");
+        assert_eq!(choice(Profile::Balanced, ""), "balanced-performance");
+
+        assert_eq!(profile_for("cool"), Some(Profile::Battery));
+        assert_eq!(profile_for("balanced-performance"), Some(Profile::Balanced));
+        assert_eq!(profile_for("custom"), None);
     }
 }
@@ -0,0 +1,167 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! The wattage of the AC adapter, where it is exposed, and a lower CPU power limit while the
+//! adapter is too weak to run the system and charge the battery at once.
+//!
+//! USB Power Delivery chargers expose the voltage and current which they negotiated, as do some
+//! barrel adapters through the firmware. Other adapters are only found to be underpowered once the
+//! battery discharges while they are plugged in. As the lower power limit stops the discharge, an
+//! adapter of known wattage remains underpowered until it is unplugged or negotiates another
+//! wattage. The power limit itself is lowered through [`crate::power_limit`].
+
+use crate::config::AdapterConfig;
+use std::{cmp, fs, path::Path};
+
+const POWER_SUPPLY: &str = "/sys/class/power_supply";
+
+// The power limit on an underpowered adapter of unknown wattage, in watts.
+const FALLBACK_LIMIT: u32 = 15;
+
+/// The AC adapters which are plugged in.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Adapter {
+    /// The highest wattage of the adapters, where any exposes it.
+    pub watts:    Option<u32>,
+    /// The system battery is discharging despite the adapter.
+    pub draining: bool,
+}
+
+impl Adapter {
+    /// Reads the adapters in sysfs, returning `None` if none is online.
+    pub fn from_sysfs() -> Option<Self> {
+        let mut online = false;
+        let mut adapter = Adapter::default();
+
+        for entry in fs::read_dir(POWER_SUPPLY).ok()?.filter_map(Result::ok) {
+            let path = entry.path();
+            match read_trimmed(&path, "type").as_deref() {
+                Some("Mains") | Some("USB")
+                    if read_trimmed(&path, "online").as_deref() == Some("1") =>
+                {
+                    online = true;
+                    adapter.watts = cmp::max(adapter.watts, supply_watts(&path));
+                }
+                // Batteries of peripherals such as mice have a scope of `Device`.
+                Some("Battery") if read_trimmed(&path, "scope").as_deref() != Some("Device") => {
+                    adapter.draining |=
+                        read_trimmed(&path, "status").as_deref() == Some("Discharging");
+                }
+                _ => (),
+            }
+        }
+
+        if online {
+            Some(adapter)
+        } else {
+            None
+        }
+    }
+}
+
+/// The wattage which a supply negotiated, from its maximum voltage and current, in microvolts
+/// and microamps.
+fn supply_watts(path: &Path) -> Option<u32> {
+    let voltage: u64 = read_trimmed(path, "voltage_max")?.parse().ok()?;
+    let current: u64 = read_trimmed(path, "current_max")?.parse().ok()?;
+    match voltage.saturating_mul(current) / 1_000_000_000_000 {
+        0 => None,
+        watts => Some(watts as u32),
+    }
+}
+
+fn read_trimmed(path: &Path, attribute: &str) -> Option<String> {
+    fs::read_to_string(path.join(attribute)).ok().map(|value| value.trim().to_owned())
+}
+
+/// Whether the adapter is underpowered, given the adapter which was last found to be.
+fn underpowered(min_watts: u32, previous: Option<Adapter>, adapter: Option<Adapter>) -> bool {
+    let adapter = match adapter {
+        Some(adapter) => adapter,
+        None => return false,
+    };
+
+    adapter.draining
+        || adapter.watts.map_or(false, |watts| watts < min_watts)
+        || (adapter.watts.is_some()
+            && previous.map_or(false, |previous| previous.watts == adapter.watts))
+}
+
+pub struct AdapterLimit {
+    config:       AdapterConfig,
+    adapter:      Option<Adapter>,
+    underpowered: bool,
+}
+
+impl AdapterLimit {
+    pub fn new(config: AdapterConfig) -> Self {
+        AdapterLimit { config, adapter: None, underpowered: false }
+    }
+
+    /// The wattage of the adapter, where it is plugged in and exposes it.
+    pub fn watts(&self) -> Option<u32> { self.adapter.and_then(|adapter| adapter.watts) }
+
+    pub fn is_underpowered(&self) -> bool { self.underpowered }
+
+    /// Takes the adapters which were read. Returns whether the wattage changed, or whether the
+    /// adapter is underpowered.
+    pub fn update(&mut self, adapter: Option<Adapter>) -> bool {
+        let previous = if self.underpowered { self.adapter } else { None };
+        let underpowered =
+            self.config.enabled && underpowered(self.config.min_watts, previous, adapter);
+        let changed = self.watts() != adapter.and_then(|adapter| adapter.watts)
+            || underpowered != self.underpowered;
+
+        self.adapter = adapter;
+        if underpowered != self.underpowered {
+            self.underpowered = underpowered;
+            if underpowered {
+                log::warn!(
+                    "AC adapter{} is underpowered, lowering the power limit",
+                    self.watts().map_or_else(String::new, |watts| format!(" of {} W", watts))
+                );
+            } else {
+                log::info!("AC adapter is no longer underpowered, restoring the power limit");
+            }
+        }
+
+        changed
+    }
+
+    /// The power limit of the CPU while the adapter is underpowered, in microwatts.
+    pub fn power_limit(&self) -> Option<u64> {
+        if !self.underpowered {
+            return None;
+        }
+
+        let watts = match self.config.power_limit {
+            0 => self.watts().map_or(FALLBACK_LIMIT, |watts| watts / 2),
+            watts => watts,
+        };
+        Some(u64::from(watts) * 1_000_000)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn underpowered_adapters() {
+        let adapter = |watts, draining| Some(Adapter { watts, draining });
+
+        assert!(!underpowered(45, None, None));
+        assert!(!underpowered(45, None, adapter(Some(65), false)));
+        assert!(underpowered(45, None, adapter(Some(30), false)));
+        assert!(underpowered(45, None, adapter(Some(65), true)));
+        assert!(underpowered(45, None, adapter(None, true)));
+        assert!(!underpowered(45, None, adapter(None, false)));
+
+        // The lower power limit stops the discharge, which must not restore it.
+        assert!(underpowered(45, adapter(Some(65), true), adapter(Some(65), false)));
+        assert!(!underpowered(45, adapter(None, true), adapter(None, false)));
+        assert!(!underpowered(45, adapter(Some(65), true), adapter(Some(100), false)));
+        assert!(!underpowered(45, adapter(Some(65), true), None));
+    }
+}
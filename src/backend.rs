@@ -0,0 +1,127 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! The system which graphics devices are managed through: the PCI bus, the files which configure
+//! the graphics mode, and the commands which apply it.
+//!
+//! `System` uses the real system. Programs which embed `Graphics` may substitute their own
+//! backends through `GraphicsBuilder`, such as to test against a fake bus whose devices are
+//! directories of sysfs attributes, without writing to `/etc` or running `systemctl`.
+
+use crate::{
+    pci::PciBus,
+    state::{self, StateStore},
+};
+use std::{
+    fmt, fs, io,
+    path::Path,
+    process::{Command, ExitStatus},
+    sync::Arc,
+    time::SystemTime,
+};
+use sysfs_class::{PciDevice, SysClass};
+
+/// Enumerates and rescans the PCI bus.
+pub trait PciBackend: Send + Sync {
+    /// The functions which are on the bus.
+    fn devices(&self) -> io::Result<Vec<PciDevice>>;
+
+    /// Rescans the bus below a bridge, or the whole bus with `None`, bringing back the devices
+    /// which were removed from it.
+    fn rescan(&self, bridge: Option<&Path>) -> io::Result<()>;
+}
+
+/// Reads and writes the files which configure the graphics mode.
+pub trait FileBackend: Send + Sync {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    /// Replaces the contents of a file, creating its directory if needed.
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+
+    /// Removes a file, which is not an error if it does not exist.
+    fn remove(&self, path: &Path) -> io::Result<()>;
+
+    /// When a file was last changed, which fails if it does not exist.
+    fn modified(&self, path: &Path) -> io::Result<SystemTime>;
+
+    /// The names of the entries of a directory.
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>>;
+}
+
+/// Runs commands, such as those which rebuild the initramfs or enable services.
+pub trait CommandBackend: Send + Sync {
+    /// Runs a program to completion. Mocks may build the status with
+    /// `std::os::unix::process::ExitStatusExt::from_raw`.
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<ExitStatus>;
+}
+
+/// The real system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct System;
+
+impl PciBackend for System {
+    fn devices(&self) -> io::Result<Vec<PciDevice>> { PciDevice::all() }
+
+    fn rescan(&self, bridge: Option<&Path>) -> io::Result<()> {
+        match bridge {
+            Some(bridge) => fs::write(bridge.join("rescan"), "1"),
+            None => PciBus::new()?.rescan(),
+        }
+    }
+}
+
+impl FileBackend for System {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> { fs::read(path) }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        path.parent().map_or(Ok(()), fs::create_dir_all)?;
+        state::write_atomic(path, contents)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        match fs::remove_file(path) {
+            Err(why) if why.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        }
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<SystemTime> { fs::metadata(path)?.modified() }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+        fs::read_dir(dir)?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+            .collect()
+    }
+}
+
+impl CommandBackend for System {
+    fn run(&self, program: &str, args: &[&str]) -> io::Result<ExitStatus> {
+        Command::new(program).args(args).status()
+    }
+}
+
+/// The backends which a `Graphics` uses, which are shared with the switches it starts.
+#[derive(Clone)]
+pub struct Backends {
+    pub pci:      Arc<dyn PciBackend>,
+    pub files:    Arc<dyn FileBackend>,
+    pub commands: Arc<dyn CommandBackend>,
+    /// Where the graphics mode, and the devices which were removed from the bus, are saved.
+    pub state:    StateStore,
+}
+
+impl Default for Backends {
+    fn default() -> Self {
+        Backends {
+            pci:      Arc::new(System),
+            files:    Arc::new(System),
+            commands: Arc::new(System),
+            state:    StateStore::default(),
+        }
+    }
+}
+
+impl fmt::Debug for Backends {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("Backends") }
+}
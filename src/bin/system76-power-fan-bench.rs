@@ -0,0 +1,366 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Exercises the fans of a model through the hwmon devices which the daemon controls, to check
+//! that the EC follows the duty cycles it is given before the model is enabled in the quirks.
+//!
+//! The fans are only under manual control for the duration of a run, which is capped, and are
+//! returned to the firmware when it ends, fails, is interrupted, or the temperature gets too
+//! high.
+
+#![deny(clippy::all)]
+
+use clap::{App, AppSettings, Arg, ArgMatches};
+use dbus::blocking::Connection;
+use log::LevelFilter;
+use std::{
+    iter, process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use system76_power::{
+    fan::{self, FanDaemon},
+    logging, safety,
+    sensors::SensorCache,
+    DBUS_NAME,
+};
+
+// The longest that a run may hold the fans, whatever is asked for.
+const MAX_DURATION: u64 = 30 * 60;
+
+// The highest temperature, in degrees Celsius, which a run may be allowed to reach.
+const MAX_TEMP: u64 = 100;
+
+// How often the temperature is checked while waiting for the fans to settle.
+const TICK: Duration = Duration::from_millis(250);
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn interrupt(_signal: libc::c_int) { INTERRUPTED.store(true, Ordering::SeqCst); }
+
+/// The speeds of the fans, in RPM, and the temperature, in thousandths of a Celsius, once the
+/// fans settled at a duty cycle in percent.
+#[derive(Debug)]
+struct Sample {
+    percent: u8,
+    speeds:  Vec<u32>,
+    temp:    Option<u32>,
+}
+
+struct Options {
+    /// The duty cycles to step through, in percent.
+    duties:   Vec<u8>,
+    /// A duty cycle to hold until the run ends, in place of stepping through them.
+    hold:     Option<u8>,
+    settle:   Duration,
+    deadline: Instant,
+    /// Thousandths of a Celsius above which the run is aborted.
+    max_temp: u32,
+}
+
+/// The problems with how the fans followed the duty cycles, where a fan may slow down by up to
+/// `tolerance` percent as the duty cycle rises, to allow for noise in its readings.
+fn evaluate(samples: &[Sample], tolerance: u32) -> Vec<String> {
+    let mut problems = Vec::new();
+    let fans = match samples.first() {
+        Some(first) if !first.speeds.is_empty() => first.speeds.len(),
+        _ => return vec!["no fan reported its speed".to_owned()],
+    };
+
+    for pair in samples.windows(2) {
+        let (low, high) = (&pair[0], &pair[1]);
+        for fan in 0..fans {
+            let (before, after) = match (low.speeds.get(fan), high.speeds.get(fan)) {
+                (Some(&before), Some(&after)) => (before, after),
+                _ => {
+                    problems.push(format!("fan {} stopped reporting its speed", fan + 1));
+                    continue;
+                }
+            };
+
+            if high.percent > low.percent && after * 100 < before * (100 - tolerance) {
+                problems.push(format!(
+                    "fan {} slowed from {} to {} RPM as its duty rose from {}% to {}%",
+                    fan + 1,
+                    before,
+                    after,
+                    low.percent,
+                    high.percent
+                ));
+            }
+        }
+    }
+
+    if let Some(last) = samples.iter().rev().find(|sample| sample.percent > 0) {
+        for (fan, &speed) in last.speeds.iter().enumerate() {
+            if speed == 0 {
+                problems.push(format!("fan {} did not spin at {}%", fan + 1, last.percent));
+            }
+        }
+    }
+
+    problems
+}
+
+/// Waits for the fans to settle, failing if the run must be aborted in the meantime. Returns
+/// `false` if the duration of the run ran out first. Whether to abort is checked at least once.
+fn settle(daemon: &FanDaemon, options: &Options) -> Result<bool, String> {
+    let until = Instant::now() + options.settle;
+    loop {
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            return Err("interrupted".to_owned());
+        }
+
+        if Instant::now() >= options.deadline {
+            return Ok(false);
+        }
+
+        if let Some(temp) = daemon.get_temp().filter(|&temp| temp > options.max_temp) {
+            return Err(format!(
+                "{:.1}°C exceeds the maximum of {}°C",
+                temp as f32 / 1000.0,
+                options.max_temp / 1000
+            ));
+        }
+
+        let now = Instant::now();
+        if now >= until {
+            return Ok(true);
+        }
+
+        thread::sleep(TICK.min(until - now));
+    }
+}
+
+fn bench(daemon: &FanDaemon, options: &Options) -> Result<Vec<Sample>, String> {
+    let duties: Box<dyn Iterator<Item = u8>> = match options.hold {
+        Some(percent) => Box::new(iter::repeat(percent)),
+        None => Box::new(options.duties.iter().copied()),
+    };
+
+    let mut samples = Vec::new();
+    for percent in duties {
+        daemon
+            .try_set_duty(Some(fan::percent_duty(percent)))
+            .map_err(|why| format!("the EC did not accept {}%: {}", percent, why))?;
+        if !settle(daemon, options)? {
+            // A held duty cycle is sampled until the duration runs out.
+            if options.hold.is_some() {
+                break;
+            }
+            return Err("the run took longer than its duration".to_owned());
+        }
+
+        let sample = Sample { percent, speeds: daemon.fan_speeds(), temp: daemon.get_temp() };
+        let speeds: Vec<String> = sample.speeds.iter().map(|rpm| format!("{:>6}", rpm)).collect();
+        let temp = sample.temp.map_or("-".to_owned(), |t| format!("{:.1}°C", t as f32 / 1000.0));
+        println!("{:>4}% {:>8}  {}", percent, temp, speeds.join(" "));
+        samples.push(sample);
+    }
+
+    Ok(samples)
+}
+
+/// Whether the daemon is running, which would fight over the fans.
+fn daemon_running() -> bool {
+    let conn = match Connection::new_system() {
+        Ok(conn) => conn,
+        Err(_) => return false,
+    };
+
+    let proxy =
+        conn.with_proxy("org.freedesktop.DBus", "/org/freedesktop/DBus", Duration::from_secs(5));
+    proxy
+        .method_call("org.freedesktop.DBus", "NameHasOwner", (DBUS_NAME,))
+        .map_or(false, |(owned,): (bool,)| owned)
+}
+
+fn options(matches: &ArgMatches) -> Result<Options, String> {
+    let number = |name: &str| -> Result<u64, String> {
+        let value = matches.value_of(name).unwrap_or_default();
+        value.parse().map_err(|_| format!("invalid {}: {}", name, value))
+    };
+
+    let step = number("step")?.clamp(1, 100) as usize;
+    let hold = match matches.value_of("duty") {
+        Some(_) => Some(number("duty")?.min(100) as u8),
+        None => None,
+    };
+
+    let duration = number("duration")?;
+    if duration > MAX_DURATION {
+        return Err(format!("the duration may be at most {} seconds", MAX_DURATION));
+    }
+
+    // Speeds which are read before the fans settle do not reflect the duty cycle.
+    let settle = number("settle")?;
+    if settle == 0 {
+        return Err("the fans must be given at least a second to settle".to_owned());
+    }
+
+    let max_temp = number("max-temp")?;
+    if max_temp == 0 || max_temp > MAX_TEMP {
+        return Err(format!("the maximum temperature must be from 1 to {}°C", MAX_TEMP));
+    }
+
+    Ok(Options {
+        duties: (0..=100).step_by(step).collect(),
+        hold,
+        settle: Duration::from_secs(settle),
+        deadline: Instant::now() + Duration::from_secs(duration),
+        max_temp: max_temp as u32 * 1000,
+    })
+}
+
+fn run(matches: &ArgMatches) -> Result<(), String> {
+    if unsafe { libc::geteuid() } != 0 {
+        return Err("must be run as root".to_owned());
+    }
+
+    if daemon_running() {
+        return Err("system76-power is running, and must be stopped while fans are tested".into());
+    }
+
+    let options = options(matches)?;
+    let tolerance = matches.value_of("tolerance").unwrap_or_default();
+    let tolerance: u32 =
+        tolerance.parse().map_err(|_| format!("invalid tolerance: {}", tolerance))?;
+
+    // Fatal signals and panics return the fans to the firmware, as does the daemon when dropped.
+    safety::install();
+    for &signal in &[libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+        unsafe {
+            libc::signal(signal, interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        }
+    }
+
+    // Every temperature is read anew, as this is the only reader.
+    let daemon = FanDaemon::new(false, Arc::new(SensorCache::new(Duration::from_secs(0))));
+    if !daemon.is_supported() {
+        return Err("no fans which system76-power controls were found".to_owned());
+    }
+
+    println!("{:>5} {:>8}  Fans (RPM)", "Duty", "Temp");
+    let res = bench(&daemon, &options);
+    drop(daemon);
+    println!("Returned the fans to automatic control");
+
+    let samples = res.map_err(|why| format!("aborted: {}", why))?;
+    if options.hold.is_some() {
+        return Ok(());
+    }
+
+    let problems = evaluate(&samples, tolerance.min(100));
+    if problems.is_empty() {
+        println!("PASS");
+        Ok(())
+    } else {
+        for problem in &problems {
+            println!("  - {}", problem);
+        }
+        Err("FAIL".to_owned())
+    }
+}
+
+fn main() {
+    let matches = App::new("system76-power-fan-bench")
+        .about("Checks that the fans follow the duty cycles which they are given")
+        .long_about(
+            "Steps the fans through duty cycles from 0% to 100%, waiting at each for the fans to \
+             settle, and checks that they speed up as the duty cycle rises and spin at the top. \
+             With --duty, a single duty cycle is held until the duration runs out instead.\n\nThe \
+             fans are returned to automatic control when the run ends, is interrupted, fails, or \
+             the temperature exceeds the maximum. The daemon must be stopped beforehand.",
+        )
+        .version(env!("CARGO_PKG_VERSION"))
+        .global_setting(AppSettings::ColoredHelp)
+        .arg(
+            Arg::with_name("duty")
+                .long("duty")
+                .help("Hold a duty cycle, in percent, rather than stepping through them")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("step")
+                .long("step")
+                .help("Percent between the duty cycles which are stepped through")
+                .takes_value(true)
+                .default_value("10"),
+        )
+        .arg(
+            Arg::with_name("settle")
+                .long("settle")
+                .help("Seconds to wait at each duty cycle before reading the fans")
+                .takes_value(true)
+                .default_value("10"),
+        )
+        .arg(
+            Arg::with_name("duration")
+                .long("duration")
+                .help("Seconds after which the run is aborted, up to 1800")
+                .takes_value(true)
+                .default_value("300"),
+        )
+        .arg(
+            Arg::with_name("max-temp")
+                .long("max-temp")
+                .help("Degrees Celsius above which the run is aborted, up to 100")
+                .takes_value(true)
+                .default_value("85"),
+        )
+        .arg(
+            Arg::with_name("tolerance")
+                .long("tolerance")
+                .help("Percent by which a fan may slow down as its duty cycle rises")
+                .takes_value(true)
+                .default_value("10"),
+        )
+        .get_matches();
+
+    if let Err(why) = logging::setup(LevelFilter::Warn) {
+        eprintln!("failed to set up logging: {}", why);
+        process::exit(1);
+    }
+
+    if let Err(why) = run(&matches) {
+        eprintln!("{}", why);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(percent: u8, speeds: &[u32]) -> Sample {
+        Sample { percent, speeds: speeds.to_vec(), temp: None }
+    }
+
+    #[test]
+    fn evaluation() {
+        let good = [sample(0, &[0, 0]), sample(50, &[2400, 2300]), sample(100, &[4800, 4700])];
+        assert!(evaluate(&good, 10).is_empty());
+
+        // Noise within the tolerance is allowed.
+        let noisy = [sample(50, &[2400]), sample(60, &[2300])];
+        assert!(evaluate(&noisy, 10).is_empty());
+
+        let ignored = [sample(0, &[0, 0]), sample(50, &[2400, 0]), sample(100, &[1200])];
+        assert_eq!(
+            evaluate(&ignored, 10),
+            vec![
+                "fan 1 slowed from 2400 to 1200 RPM as its duty rose from 50% to 100%".to_owned(),
+                "fan 2 stopped reporting its speed".to_owned(),
+            ]
+        );
+
+        let stalled = [sample(0, &[0]), sample(100, &[0])];
+        assert_eq!(evaluate(&stalled, 10), vec!["fan 1 did not spin at 100%".to_owned()]);
+        assert_eq!(evaluate(&[], 10), vec!["no fan reported its speed".to_owned()]);
+    }
+}
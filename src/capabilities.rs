@@ -0,0 +1,42 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Features which depend on hardware or firmware support, such as the `system76_io` and
+//! `system76_acpi` drivers. The daemon runs without any of them, and reports which are
+//! available so that clients may hide the features that are not.
+
+use std::fmt::{self, Display, Formatter};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capability {
+    ChargeThresholds,
+    DisplayPortMux,
+    EnergyAwareScheduling,
+    FanControl,
+    GraphicsSwitching,
+    Hibernation,
+    HotPlugDetect,
+    IntelPstate,
+    KeyboardBacklight,
+}
+
+impl Capability {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Capability::ChargeThresholds => "charge-thresholds",
+            Capability::DisplayPortMux => "displayport-mux",
+            Capability::EnergyAwareScheduling => "energy-aware-scheduling",
+            Capability::FanControl => "fan-control",
+            Capability::GraphicsSwitching => "graphics-switching",
+            Capability::Hibernation => "hibernation",
+            Capability::HotPlugDetect => "hotplug-detect",
+            Capability::IntelPstate => "intel-pstate",
+            Capability::KeyboardBacklight => "keyboard-backlight",
+        }
+    }
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result { f.write_str(self.as_str()) }
+}
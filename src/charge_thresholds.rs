@@ -6,16 +6,24 @@ use dbus::{
     arg::{cast, Append, Arg, ArgType, Get, Iter, IterAppend, RefArg, Variant},
     strings::Signature,
 };
-use std::{collections::HashMap, fs, path::Path};
+use std::collections::{BTreeMap, HashMap};
 
-use crate::err_str;
+use crate::{ec, err_str};
 
-const START_THRESHOLD: &str = "/sys/class/power_supply/BAT0/charge_control_start_threshold";
-const END_THRESHOLD: &str = "/sys/class/power_supply/BAT0/charge_control_end_threshold";
 const UNSUPPORTED_ERROR: &str = "Not running System76 firmware with charge threshold support";
 const OUT_OF_RANGE_ERROR: &str = "Charge threshold out of range: should be 0-100";
 const ORDER_ERROR: &str = "Charge end threshold must be strictly greater than start";
 
+/// Charging presets of the firmware, as named by the kernel's `charge_type` attribute, and the
+/// profiles they correspond to. Other thresholds are shown by the firmware as `Custom`.
+const FIRMWARE_PRESETS: &[(&str, &str)] =
+    &[("Standard", "full_charge"), ("Long Life", "max_lifespan")];
+const CUSTOM_PRESET: &str = "Custom";
+
+/// Thresholds which the configuration gives particular batteries, by name such as `BAT1`, in
+/// place of those which are set for the others.
+pub type BatteryThresholds = BTreeMap<String, (u8, u8)>;
+
 #[derive(Debug)]
 pub struct ChargeProfile {
     pub id:          String,
@@ -67,14 +75,9 @@ impl<'a> Get<'a> for ChargeProfile {
     }
 }
 
-fn is_s76_ec() -> bool {
-    // For now, only support thresholds on System76 hardware
-    Path::new("/sys/bus/acpi/devices/17761776:00").is_dir()
-}
-
-fn supports_thresholds() -> bool {
-    Path::new(START_THRESHOLD).exists() && Path::new(END_THRESHOLD).exists()
-}
+/// Whether charge thresholds can be read and set on this hardware. For now, they are only
+/// supported on System76 hardware.
+pub fn is_supported() -> bool { ec::supports_charge_thresholds() }
 
 pub fn get_charge_profiles() -> Vec<ChargeProfile> {
     vec![
@@ -110,35 +113,312 @@ pub fn get_charge_profiles() -> Vec<ChargeProfile> {
     ]
 }
 
-pub(crate) fn get_charge_thresholds() -> Result<(u8, u8), String> {
-    if !is_s76_ec() || !supports_thresholds() {
+fn validate((start, end): (u8, u8)) -> Result<(), String> {
+    if start > 100 || end > 100 {
+        Err(OUT_OF_RANGE_ERROR.to_string())
+    } else if end <= start {
+        Err(ORDER_ERROR.to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// The battery whose thresholds are reported as those of the system, and which the firmware
+/// preset follows. This is the first battery without thresholds of its own.
+fn primary_battery<'a>(batteries: &'a [String], overrides: &BatteryThresholds) -> Option<&'a str> {
+    batteries
+        .iter()
+        .find(|battery| !overrides.contains_key(*battery))
+        .or_else(|| batteries.first())
+        .map(String::as_str)
+}
+
+/// The thresholds which each battery should have, where the primary battery has `thresholds`.
+fn targets(
+    batteries: &[String],
+    thresholds: (u8, u8),
+    overrides: &BatteryThresholds,
+) -> Vec<(String, (u8, u8))> {
+    batteries
+        .iter()
+        .map(|battery| (battery.clone(), overrides.get(battery).cloned().unwrap_or(thresholds)))
+        .collect()
+}
+
+pub(crate) fn get_charge_thresholds(overrides: &BatteryThresholds) -> Result<(u8, u8), String> {
+    if !is_supported() {
         return Err(UNSUPPORTED_ERROR.to_string());
     }
 
-    let start_str = fs::read_to_string(START_THRESHOLD).map_err(err_str)?;
-    let end_str = fs::read_to_string(END_THRESHOLD).map_err(err_str)?;
+    let batteries = ec::batteries();
+    let battery = primary_battery(&batteries, overrides).ok_or(UNSUPPORTED_ERROR)?;
+    ec::charge_thresholds(battery).map_err(err_str)
+}
 
-    let start = start_str.trim().parse::<u8>().map_err(err_str)?;
-    let end = end_str.trim().parse::<u8>().map_err(err_str)?;
+/// Sets the thresholds of every battery, except those which the configuration gives their own.
+/// Each battery is set even if another fails.
+pub(crate) fn set_charge_thresholds(
+    thresholds: (u8, u8),
+    overrides: &BatteryThresholds,
+) -> Result<(), String> {
+    if !is_supported() {
+        return Err(UNSUPPORTED_ERROR.to_string());
+    }
+
+    validate(thresholds)?;
+
+    let batteries = ec::batteries();
+    let mut failures = Vec::new();
+    for (battery, (start, end)) in targets(&batteries, thresholds, overrides) {
+        let res = validate((start, end))
+            .and_then(|_| ec::set_charge_thresholds(&battery, start, end).map_err(err_str));
+        if let Err(why) = res {
+            failures.push(format!("{}: {}", battery, why));
+        }
+    }
 
-    Ok((start, end))
+    if let Some(battery) = primary_battery(&batteries, overrides) {
+        if ec::supports_charge_type(battery) {
+            let preset = firmware_preset(thresholds);
+            if ec::charge_type(battery).ok().as_deref() != Some(preset) {
+                if let Err(why) = ec::set_charge_type(battery, preset) {
+                    failures.push(format!("{}: {}", battery, why));
+                }
+            }
+        }
+    }
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(failures.join(", "))
+    }
 }
 
-pub(crate) fn set_charge_thresholds((start, end): (u8, u8)) -> Result<(), String> {
-    if !is_s76_ec() || !supports_thresholds() {
+/// Compares the thresholds of each battery with those it should have, which are those of the
+/// primary battery unless the configuration gives it its own.
+fn compliance(
+    actual: &[(String, (u8, u8))],
+    overrides: &BatteryThresholds,
+) -> Vec<(String, u8, u8, bool)> {
+    let batteries: Vec<String> = actual.iter().map(|(battery, _)| battery.clone()).collect();
+    let primary = primary_battery(&batteries, overrides)
+        .and_then(|primary| actual.iter().find(|(battery, _)| battery == primary))
+        .map(|&(_, thresholds)| thresholds);
+
+    actual
+        .iter()
+        .map(|(battery, (start, end))| {
+            let expected = overrides.get(battery).cloned().or(primary);
+            (battery.clone(), *start, *end, expected == Some((*start, *end)))
+        })
+        .collect()
+}
+
+/// Each battery with its charge thresholds, and whether they are those it should have.
+pub(crate) fn battery_thresholds(
+    overrides: &BatteryThresholds,
+) -> Result<Vec<(String, u8, u8, bool)>, String> {
+    if !is_supported() {
         return Err(UNSUPPORTED_ERROR.to_string());
-    } else if start > 100 || end > 100 {
-        return Err(OUT_OF_RANGE_ERROR.to_string());
-    } else if end <= start {
-        return Err(ORDER_ERROR.to_string());
     }
 
-    // Without this, setting start threshold may fail if the previous end
-    // threshold is higher.
-    fs::write(END_THRESHOLD, "100").map_err(err_str)?;
+    let mut actual = Vec::new();
+    for battery in ec::batteries() {
+        let thresholds = ec::charge_thresholds(&battery).map_err(err_str)?;
+        actual.push((battery, thresholds));
+    }
+
+    Ok(compliance(&actual, overrides))
+}
 
-    fs::write(START_THRESHOLD, format!("{}", start)).map_err(err_str)?;
-    fs::write(END_THRESHOLD, format!("{}", end)).map_err(err_str)?;
+/// The profile whose thresholds are those given, if any.
+pub fn profile_for_thresholds(thresholds: (u8, u8)) -> Option<ChargeProfile> {
+    get_charge_profiles().into_iter().find(|profile| (profile.start, profile.end) == thresholds)
+}
 
-    Ok(())
+/// The firmware preset which corresponds to the thresholds.
+fn firmware_preset(thresholds: (u8, u8)) -> &'static str {
+    profile_for_thresholds(thresholds)
+        .and_then(|profile| {
+            FIRMWARE_PRESETS.iter().find(|&&(_, id)| id == profile.id).map(|&(preset, _)| preset)
+        })
+        .unwrap_or(CUSTOM_PRESET)
+}
+
+/// The thresholds of the profile which corresponds to a firmware preset, if any.
+fn preset_thresholds(preset: &str) -> Option<(u8, u8)> {
+    let &(_, id) = FIRMWARE_PRESETS.iter().find(|&&(name, _)| name == preset)?;
+    get_charge_profiles()
+        .into_iter()
+        .find(|profile| profile.id == id)
+        .map(|profile| (profile.start, profile.end))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct ChargeState {
+    thresholds: (u8, u8),
+    preset:     Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Reconcile {
+    Nothing,
+    SetPreset(&'static str),
+    SetThresholds((u8, u8)),
+}
+
+/// Decides how to bring the thresholds and firmware preset back in sync, after either was
+/// changed. A changed preset takes precedence, as it is the newer choice.
+fn reconcile(last: &ChargeState, current: &ChargeState) -> Reconcile {
+    let preset = match current.preset {
+        Some(ref preset) => preset,
+        None => return Reconcile::Nothing,
+    };
+
+    if current.preset != last.preset {
+        if let Some(thresholds) = preset_thresholds(preset) {
+            if thresholds != current.thresholds {
+                return Reconcile::SetThresholds(thresholds);
+            }
+        }
+    }
+
+    if current.thresholds != last.thresholds {
+        let expected = firmware_preset(current.thresholds);
+        if preset != expected {
+            return Reconcile::SetPreset(expected);
+        }
+    }
+
+    Reconcile::Nothing
+}
+
+/// Keeps the charge thresholds and the preset shown by the firmware in sync, when they are
+/// changed by the firmware or by other programs.
+pub struct ChargeSync {
+    last:      ChargeState,
+    overrides: BatteryThresholds,
+}
+
+impl ChargeSync {
+    fn read(overrides: &BatteryThresholds) -> Result<ChargeState, String> {
+        let thresholds = get_charge_thresholds(overrides)?;
+        let batteries = ec::batteries();
+        let preset = match primary_battery(&batteries, overrides) {
+            Some(battery) if ec::supports_charge_type(battery) => {
+                Some(ec::charge_type(battery).map_err(err_str)?)
+            }
+            _ => None,
+        };
+
+        Ok(ChargeState { thresholds, preset })
+    }
+
+    pub fn new(overrides: BatteryThresholds) -> Option<ChargeSync> {
+        if !is_supported() {
+            return None;
+        }
+
+        match Self::read(&overrides) {
+            Ok(last) => Some(ChargeSync { last, overrides }),
+            Err(why) => {
+                log::warn!("failed to read charge thresholds: {}", why);
+                None
+            }
+        }
+    }
+
+    /// Checks for changes, reconciling them. Returns the thresholds if they have changed.
+    pub fn poll(&mut self) -> Option<(u8, u8)> {
+        let mut current = Self::read(&self.overrides).map_err(|why| log::warn!("{}", why)).ok()?;
+
+        match reconcile(&self.last, &current) {
+            Reconcile::Nothing => (),
+            Reconcile::SetPreset(preset) => {
+                log::info!("charge thresholds changed, setting firmware preset to {}", preset);
+                let batteries = ec::batteries();
+                let battery = primary_battery(&batteries, &self.overrides).unwrap_or_default();
+                match ec::set_charge_type(battery, preset) {
+                    Ok(()) => current.preset = Some(preset.to_owned()),
+                    Err(why) => log::warn!("failed to set firmware charge preset: {}", why),
+                }
+            }
+            Reconcile::SetThresholds(thresholds) => {
+                log::info!(
+                    "firmware charge preset changed to {}, setting thresholds to {:?}",
+                    current.preset.as_deref().unwrap_or_default(),
+                    thresholds
+                );
+                match set_charge_thresholds(thresholds, &self.overrides) {
+                    Ok(()) => current.thresholds = thresholds,
+                    Err(why) => log::warn!("failed to set charge thresholds: {}", why),
+                }
+            }
+        }
+
+        let changed = current.thresholds != self.last.thresholds;
+        self.last = current;
+        if changed {
+            Some(self.last.thresholds)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(thresholds: (u8, u8), preset: &str) -> ChargeState {
+        ChargeState { thresholds, preset: Some(preset.to_owned()) }
+    }
+
+    #[test]
+    fn reconcile_presets() {
+        let full = state((96, 100), "Standard");
+
+        // The preset was changed in the firmware.
+        assert_eq!(
+            reconcile(&full, &state((96, 100), "Long Life")),
+            Reconcile::SetThresholds((50, 60))
+        );
+
+        // The thresholds were changed by another program.
+        assert_eq!(
+            reconcile(&full, &state((50, 60), "Standard")),
+            Reconcile::SetPreset("Long Life")
+        );
+        assert_eq!(reconcile(&full, &state((86, 90), "Standard")), Reconcile::SetPreset("Custom"));
+
+        // Presets without thresholds of their own leave the thresholds alone.
+        assert_eq!(reconcile(&full, &state((96, 100), "Custom")), Reconcile::Nothing);
+        assert_eq!(reconcile(&full, &full), Reconcile::Nothing);
+
+        let unsupported = ChargeState { thresholds: (96, 100), preset: None };
+        let changed = ChargeState { thresholds: (50, 60), preset: None };
+        assert_eq!(reconcile(&unsupported, &changed), Reconcile::Nothing);
+    }
+
+    #[test]
+    fn multiple_batteries() {
+        let batteries = vec!["BAT0".to_owned(), "BAT1".to_owned()];
+        let mut overrides = BatteryThresholds::new();
+        overrides.insert("BAT0".to_owned(), (40, 80));
+
+        assert_eq!(primary_battery(&batteries, &overrides), Some("BAT1"));
+        assert_eq!(
+            targets(&batteries, (50, 60), &overrides),
+            vec![("BAT0".to_owned(), (40, 80)), ("BAT1".to_owned(), (50, 60))]
+        );
+
+        let actual = vec![("BAT0".to_owned(), (40, 80)), ("BAT1".to_owned(), (96, 100))];
+        let report = compliance(&actual, &overrides);
+        assert!(report.iter().all(|&(_, _, _, compliant)| compliant));
+
+        // Without overrides, every battery should match the first.
+        let report = compliance(&actual, &BatteryThresholds::new());
+        assert_eq!(report[1], ("BAT1".to_owned(), 96, 100, false));
+    }
 }
@@ -0,0 +1,73 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Detection of clamshell operation, where a laptop runs with its lid closed and an external
+//! display connected. The closed lid covers the vents of many models, so less heat is shed
+//! through the keyboard deck than the fan curve expects.
+
+use std::{fs, path::Path};
+
+const LID_DIR: &str = "/proc/acpi/button/lid";
+const DRM_DIR: &str = "/sys/class/drm";
+
+// Connectors of panels which are built into the laptop.
+const INTERNAL_CONNECTORS: &[&str] = &["eDP", "LVDS", "DSI"];
+
+/// Whether the system has a lid, and so may be operated as a clamshell.
+pub fn has_lid() -> bool { fs::read_dir(LID_DIR).map_or(false, |mut dir| dir.next().is_some()) }
+
+/// Whether the lid is closed, according to `/proc/acpi/button/lid/*/state`.
+pub fn lid_closed() -> bool {
+    let lids = match fs::read_dir(LID_DIR) {
+        Ok(lids) => lids,
+        Err(_) => return false,
+    };
+
+    lids.filter_map(Result::ok).any(|lid| {
+        fs::read_to_string(lid.path().join("state"))
+            .map_or(false, |state| state.split_whitespace().last() == Some("closed"))
+    })
+}
+
+/// Whether a DRM connector, named such as `card0-eDP-1`, drives a built-in panel.
+fn is_internal(connector: &str) -> bool {
+    let kind = connector.splitn(2, '-').nth(1).unwrap_or("");
+    INTERNAL_CONNECTORS.iter().any(|internal| kind.starts_with(internal))
+}
+
+/// Whether a display is connected to any connector other than a built-in panel.
+pub fn external_display_connected() -> bool {
+    let connectors = match fs::read_dir(DRM_DIR) {
+        Ok(connectors) => connectors,
+        Err(_) => return false,
+    };
+
+    connectors.filter_map(Result::ok).any(|entry| {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        name.starts_with("card")
+            && name.contains('-')
+            && !is_internal(&name)
+            && read_status(&entry.path()).as_deref() == Some("connected")
+    })
+}
+
+fn read_status(connector: &Path) -> Option<String> {
+    fs::read_to_string(connector.join("status")).ok().map(|status| status.trim().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn internal_connectors() {
+        assert!(is_internal("card0-eDP-1"));
+        assert!(is_internal("card1-LVDS-1"));
+        assert!(is_internal("card0-DSI-1"));
+        assert!(!is_internal("card0-HDMI-A-1"));
+        assert!(!is_internal("card1-DP-2"));
+        assert!(!is_internal("card0-DVI-D-1"));
+    }
+}
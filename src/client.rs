@@ -2,19 +2,70 @@
 //
 // SPDX-License-Identifier: GPL-3.0-only
 
-use crate::{charge_thresholds::ChargeProfile, err_str, Power, DBUS_IFACE, DBUS_NAME, DBUS_PATH};
+use crate::{
+    charge_thresholds::ChargeProfile,
+    config::{Config, CONFIG_PATH},
+    ec::Color,
+    err_str,
+    export::Settings,
+    graphics::VENDORS,
+    setup::{self, FanPreset, Hardware, Proposal, Usage},
+    temperature::TemperatureUnit,
+    Power, DBUS_IFACE, DBUS_IFACE_V2, DBUS_NAME, DBUS_PATH,
+};
 use clap::ArgMatches;
 use dbus::{
-    arg::Append,
+    arg::{Append, Variant},
     blocking::{BlockingSender, Connection},
+    message::MatchRule,
     Message,
 };
 use intel_pstate::PState;
-use std::{io, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    fs,
+    io::{self, BufRead, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
 use sysfs_class::{Backlight, Brightness, Leds, SysClass};
 
 static TIMEOUT: u64 = 60 * 1000;
 
+/// The version, features, DMI fields, EC firmware release and quirks reported by `GetInfo`.
+type Info = (String, Vec<String>, HashMap<String, String>, String, Vec<String>);
+
+/// The files written, files removed, services toggled and commands run, by `PreviewGraphics`.
+type GraphicsPreview = (Vec<(String, String)>, Vec<String>, Vec<(String, String)>, Vec<String>);
+
+/// The saved, pending, PRIME and configured modes, loaded modules and problems from
+/// `VerifyGraphics`.
+type GraphicsVerification = (String, String, String, String, Vec<String>, Vec<String>);
+
+/// The PCI address, vendor, IDs, video memory, driver and boot VGA flag from `GetGraphicsInfo`.
+type GraphicsInfo = (String, String, String, u64, String, bool);
+
+/// The PCI address, link speed and width, maximum speed and width, and capped speed from
+/// `GetPcieLinks`.
+type PcieLinks = Vec<(String, u8, u8, u8, u8, u8)>;
+
+/// The PCI address, kind, control, status, and active and suspended milliseconds from
+/// `GetRuntimePm`.
+type RuntimePm = Vec<(String, String, String, String, u64, u64)>;
+
+/// The name, color and brightness of each keyboard zone from `GetKeyboardZones`.
+type KeyboardZones = Vec<(String, u32, u8)>;
+
+/// The interval, package power, pressure and top processes from `GetConsumers`.
+type Consumers = (u32, f64, (f64, f64, f64), Vec<(u32, String, f64, f64)>);
+
+/// The name, events, current state and total seconds of each source from `GetThrottling`.
+type Throttling = Vec<(String, u32, bool, u64)>;
+
+// Rebuilding the initramfs may take several minutes on slow disks.
+static GRAPHICS_SWITCH_TIMEOUT: u64 = 10 * 60 * 1000;
+
 pub struct PowerClient {
     bus: Connection,
 }
@@ -35,6 +86,10 @@ impl PowerClient {
             m = m.append1(arg);
         }
 
+        self.send(m)
+    }
+
+    fn send(&mut self, m: Message) -> Result<Message, String> {
         let r = self.bus.send_with_reply_and_block(m, Duration::from_millis(TIMEOUT)).map_err(
             |why| {
                 format!(
@@ -47,10 +102,230 @@ impl PowerClient {
         Ok(r)
     }
 
+    fn get_temperatures(&mut self) -> Result<Vec<(String, f64)>, String> {
+        let r = self.call_method::<bool>("GetTemperatures", None)?;
+        r.get1().ok_or_else(|| "return value not found".to_string())
+    }
+
+    fn get_fan_curve(&mut self) -> Result<Vec<(i32, u16)>, String> {
+        let r = self.call_method::<bool>("GetFanCurve", None)?;
+        r.get1().ok_or_else(|| "return value not found".to_string())
+    }
+
+    fn get_thermal_history(&mut self) -> Result<Vec<(u32, f64, f64, f64)>, String> {
+        let r = self.call_method::<bool>("GetThermalHistory", None)?;
+        r.get1().ok_or_else(|| "return value not found".to_string())
+    }
+
+    fn step_brightness(&mut self, device: &str, increase: bool) -> Result<u8, String> {
+        let method = if increase { "IncreaseBrightness" } else { "DecreaseBrightness" };
+        let r = self.call_method::<&str>(method, Some(device))?;
+        r.get1().ok_or_else(|| "return value not found".to_string())
+    }
+
+    fn get_keyboard_zones(&mut self) -> Result<KeyboardZones, String> {
+        let r = self.call_method::<bool>("GetKeyboardZones", None)?;
+        r.get1().ok_or_else(|| "return value not found".to_string())
+    }
+
+    fn set_keyboard_zone(&mut self, zone: &str, color: Color, percent: u8) -> Result<(), String> {
+        let m = Message::new_method_call(DBUS_NAME, DBUS_PATH, DBUS_IFACE, "SetKeyboardZone")?
+            .append3(zone, color.0, percent);
+        self.send(m).map(|_| ())
+    }
+
+    fn reconcile_graphics(&mut self) -> Result<(), String> {
+        let r = self.call_method::<bool>("Reconcile", None)?;
+        let job: u32 = r.get1().ok_or_else(|| "return value not found".to_string())?;
+        println!("rebuilding initramfs as job {}, reboot once it finishes", job);
+        Ok(())
+    }
+
+    fn verify_graphics(&mut self, repair: bool) -> Result<(), String> {
+        let r = self.call_method::<bool>("VerifyGraphics", None)?;
+        let (saved, pending, prime, configured, modules, problems): GraphicsVerification =
+            r.get1().ok_or_else(|| "return value not found".to_string())?;
+
+        let known = |value: String| if value.is_empty() { "unknown".to_owned() } else { value };
+        println!("Switched to: {}", known(saved));
+        if !pending.is_empty() {
+            println!("Switch to {} graphics has not completed", pending);
+            return Ok(());
+        }
+        if !prime.is_empty() {
+            println!("PRIME mode: {}", prime);
+            println!("Configured: {}", known(configured));
+            let modules = if modules.is_empty() { "none".to_owned() } else { modules.join(", ") };
+            println!("Loaded: {}", modules);
+        }
+
+        if problems.is_empty() {
+            println!("No problems found");
+            return Ok(());
+        }
+
+        for problem in &problems {
+            println!("  - {}", problem);
+        }
+
+        if !repair {
+            return Err(format!("found {} problems, which --repair rewrites", problems.len()));
+        }
+
+        let r = self.call_method::<bool>("RepairGraphics", None)?;
+        let job: u32 = r.get1().ok_or_else(|| "return value not found".to_string())?;
+        println!("rewriting graphics configuration as job {}, reboot once it finishes", job);
+        Ok(())
+    }
+
+    fn preview_graphics(&mut self, vendor: &str) -> Result<(), String> {
+        let r = self.call_method::<&str>("PreviewGraphics", Some(vendor))?;
+        let (files, removed, services, commands): GraphicsPreview =
+            r.get1().ok_or_else(|| "return value not found".to_string())?;
+
+        println!("switching to {} graphics would:", vendor);
+        for (path, contents) in files {
+            println!("write {}:", path);
+            for line in contents.lines() {
+                println!("    {}", line);
+            }
+        }
+        for path in removed {
+            println!("remove {}", path);
+        }
+        for (action, unit) in services {
+            println!("{} {}", action, unit);
+        }
+        for command in commands {
+            println!("run {}", command);
+        }
+
+        Ok(())
+    }
+
+    fn get_battery_thresholds(&mut self) -> Result<Vec<(String, u8, u8, bool)>, String> {
+        let r = self.call_method::<bool>("GetBatteryThresholds", None)?;
+        r.get1().ok_or_else(|| "return value not found".to_string())
+    }
+
+    fn get_suspend_report(&mut self) -> Result<(u64, f64, f64, Vec<String>), String> {
+        let r = self.call_method::<bool>("GetSuspendReport", None)?;
+        r.get1().ok_or_else(|| "return value not found".to_string())
+    }
+
+    fn get_throttling(&mut self) -> Result<Throttling, String> {
+        let r = self.call_method::<bool>("GetThrottling", None)?;
+        r.get1().ok_or_else(|| "return value not found".to_string())
+    }
+
+    fn get_hibernation(&mut self) -> Result<(u64, u64, Vec<String>), String> {
+        let r = self.call_method::<bool>("GetHibernation", None)?;
+        r.get1().ok_or_else(|| "return value not found".to_string())
+    }
+
+    fn get_runtime_pm(&mut self) -> Result<RuntimePm, String> {
+        let r = self.call_method::<bool>("GetRuntimePm", None)?;
+        r.get1().ok_or_else(|| "return value not found".to_string())
+    }
+
+    fn get_consumers(&mut self) -> Result<Consumers, String> {
+        let r = self.call_method::<bool>("GetConsumers", None)?;
+        r.get1().ok_or_else(|| "return value not found".to_string())
+    }
+
+    fn get_gpu_power_states(&mut self) -> Result<Vec<(String, String, String, String)>, String> {
+        let r = self.call_method::<bool>("GetGpuPowerStates", None)?;
+        r.get1().ok_or_else(|| "return value not found".to_string())
+    }
+
+    fn get_nvidia_power(&mut self) -> Result<Vec<(String, String, String)>, String> {
+        let r = self.call_method::<bool>("GetNvidiaPower", None)?;
+        r.get1().ok_or_else(|| "return value not found".to_string())
+    }
+
+    fn get_graphics_topology(&mut self) -> Result<Vec<(String, String, String, bool)>, String> {
+        let r = self.call_method::<bool>("GetGraphicsTopology", None)?;
+        r.get1().ok_or_else(|| "return value not found".to_string())
+    }
+
+    fn get_pcie_links(&mut self) -> Result<PcieLinks, String> {
+        let r = self.call_method::<bool>("GetPcieLinks", None)?;
+        r.get1().ok_or_else(|| "return value not found".to_string())
+    }
+
+    fn get_graphics_info(&mut self) -> Result<Vec<GraphicsInfo>, String> {
+        let r = self.call_method::<bool>("GetGraphicsInfo", None)?;
+        r.get1().ok_or_else(|| "return value not found".to_string())
+    }
+
+    fn set_device_power(&mut self, device: &str, power: bool) -> Result<(), String> {
+        println!("turning {} {}", device, if power { "on" } else { "off" });
+        let m = Message::new_method_call(DBUS_NAME, DBUS_PATH, DBUS_IFACE, "SetDevicePower")?
+            .append2(device, power);
+        self.send(m).map(|_| ())
+    }
+
+    fn get_info(&mut self) -> Result<Info, String> {
+        let r = self.call_method::<bool>("GetInfo", None)?;
+        r.get1().ok_or_else(|| "return value not found".to_string())
+    }
+
+    fn get_bench_mode(&mut self) -> Result<(bool, u32), String> {
+        let r = self.call_method::<bool>("GetBenchMode", None)?;
+        r.get1().ok_or_else(|| "return value not found".to_string())
+    }
+
+    fn set_bench_mode(&mut self, enabled: bool, minutes: u32) -> Result<(), String> {
+        let m = Message::new_method_call(DBUS_NAME, DBUS_PATH, DBUS_IFACE, "SetBenchMode")?
+            .append2(enabled, minutes);
+        self.send(m).map(|_| ())
+    }
+
+    fn get_profile_report(&mut self) -> Result<Vec<(String, String, String)>, String> {
+        let r = self.call_method::<bool>("GetProfileReport", None)?;
+        r.get1().ok_or_else(|| "return value not found".to_string())
+    }
+
+    /// The version of the daemon's interface, which daemons predating versions do not report.
+    fn api_version(&mut self) -> u32 {
+        let m = Message::new_method_call(
+            DBUS_NAME,
+            DBUS_PATH,
+            "org.freedesktop.DBus.Properties",
+            "Get",
+        )
+        .map(|m| m.append2(DBUS_IFACE, "Version"));
+
+        m.and_then(|m| self.send(m))
+            .ok()
+            .and_then(|r| r.get1::<Variant<u32>>())
+            .map_or(1, |version| version.0)
+    }
+
     fn set_profile(&mut self, profile: &str) -> Result<(), String> {
         println!("setting power profile to {}", profile);
-        self.call_method::<bool>(profile, None)?;
-        Ok(())
+
+        // Older daemons only have a method for each profile.
+        let result = if self.api_version() >= 2 {
+            Message::new_method_call(DBUS_NAME, DBUS_PATH, DBUS_IFACE_V2, "SetProfile")
+                .and_then(|m| self.send(m.append1(profile)))
+        } else {
+            self.call_method::<bool>(profile, None)
+        };
+        let result = result.map(|_| ());
+
+        // The outcome of each subsystem is shown even if some failed.
+        if let Ok(report) = self.get_profile_report() {
+            for (subsystem, status, detail) in report {
+                if detail.is_empty() {
+                    println!("  {}: {}", subsystem, status);
+                } else {
+                    println!("  {}: {} ({})", subsystem, status, detail);
+                }
+            }
+        }
+
+        result
     }
 }
 
@@ -88,11 +363,48 @@ impl Power for PowerClient {
 
     fn set_graphics(&mut self, vendor: &str) -> Result<(), String> {
         println!("setting graphics to {}", vendor);
-        let r = self.call_method::<&str>("SetGraphics", Some(vendor)).map(|_| ());
-        if r.is_ok() {
+
+        // The original interface returns once the switch finished, rather than with its job.
+        if self.api_version() < 2 {
+            self.call_method::<&str>("SetGraphics", Some(vendor))?;
             println!("reboot for changes to take effect");
+            return Ok(());
+        }
+
+        // The match is added before the call, so that a switch which finishes quickly is seen.
+        let rule = MatchRule::new_signal(DBUS_IFACE, "JobFinished")
+            .with_sender(DBUS_NAME)
+            .with_path(DBUS_PATH);
+        self.bus.add_match_no_cb(&rule.match_str()).map_err(err_str)?;
+
+        let m = Message::new_method_call(DBUS_NAME, DBUS_PATH, DBUS_IFACE_V2, "SetGraphics")?;
+        let r = self.send(m.append1(vendor))?;
+        let job: u32 = r.get1().ok_or_else(|| "return value not found".to_string())?;
+
+        println!("rebuilding initramfs, this may take a while");
+        let deadline = Instant::now() + Duration::from_millis(GRAPHICS_SWITCH_TIMEOUT);
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            let message = match self.bus.channel().blocking_pop_message(remaining) {
+                Ok(Some(message)) => message,
+                Ok(None) => break,
+                Err(why) => return Err(err_str(why)),
+            };
+
+            if !rule.matches(&message) {
+                continue;
+            }
+
+            match message.read4::<u32, &str, bool, String>() {
+                Ok((finished, _, true, _)) if finished == job => {
+                    println!("reboot for changes to take effect");
+                    return Ok(());
+                }
+                Ok((finished, _, false, why)) if finished == job => return Err(why),
+                _ => (),
+            }
         }
-        r
+
+        Err("timed out waiting for the graphics switch to finish".to_string())
     }
 
     fn get_graphics_power(&mut self) -> Result<bool, String> {
@@ -105,6 +417,15 @@ impl Power for PowerClient {
         self.call_method::<bool>("SetGraphicsPower", Some(power)).map(|_| ())
     }
 
+    fn force_graphics_power_off(&mut self, device: &str) -> Result<(), String> {
+        if device.is_empty() {
+            println!("turning discrete graphics off, stopping the processes using them");
+        } else {
+            println!("turning {} off, stopping the processes using it", device);
+        }
+        self.call_method::<&str>("ForceGraphicsPowerOff", Some(device)).map(|_| ())
+    }
+
     fn auto_graphics_power(&mut self) -> Result<(), String> {
         println!("setting discrete graphics to turn off when not in use");
         self.call_method::<bool>("AutoGraphicsPower", None).map(|_| ())
@@ -123,6 +444,11 @@ impl Power for PowerClient {
         let r = self.call_method::<bool>("GetChargeProfiles", None)?;
         r.get1().ok_or_else(|| "return value not found".to_string())
     }
+
+    fn get_capabilities(&mut self) -> Result<Vec<String>, String> {
+        let r = self.call_method::<bool>("GetCapabilities", None)?;
+        r.get1().ok_or_else(|| "return value not found".to_string())
+    }
 }
 
 fn profile(client: &mut PowerClient) -> io::Result<()> {
@@ -166,6 +492,364 @@ fn profile(client: &mut PowerClient) -> io::Result<()> {
     Ok(())
 }
 
+fn bench_mode(client: &mut PowerClient, matches: &ArgMatches) -> Result<(), String> {
+    let minutes = match matches.value_of("duration") {
+        Some(duration) => {
+            duration.parse::<u32>().map_err(|_| format!("invalid duration `{}`", duration))?
+        }
+        None => 0,
+    };
+
+    match matches.value_of("state") {
+        Some("on") => {
+            client.set_bench_mode(true, minutes)?;
+            let (_, remaining) = client.get_bench_mode()?;
+            println!("benchmark mode on for {} minutes", (remaining + 59) / 60);
+        }
+        Some(_) => {
+            client.set_bench_mode(false, 0)?;
+            println!("benchmark mode off");
+        }
+        None => match client.get_bench_mode()? {
+            (true, remaining) => println!("on ({} minutes remaining)", (remaining + 59) / 60),
+            (false, _) => println!("off"),
+        },
+    }
+
+    Ok(())
+}
+
+fn info(client: &mut PowerClient) -> Result<(), String> {
+    let (version, features, dmi, ec_version, quirks) = client.get_info()?;
+    let list = |items: Vec<String>| if items.is_empty() { "none".into() } else { items.join(", ") };
+
+    println!("Version: {}", version);
+    println!("Features: {}", list(features));
+    for (field, value) in dmi.into_iter().collect::<BTreeMap<_, _>>() {
+        println!("{}: {}", field, value);
+    }
+    if !ec_version.is_empty() {
+        println!("EC firmware: {}", ec_version);
+    }
+    println!("Quirks: {}", list(quirks));
+
+    Ok(())
+}
+
+/// Prints a suspend report, with each line behind `indent`.
+fn print_suspend_report(report: (u64, f64, f64, Vec<String>), indent: &str) {
+    let (seconds, system, cpu, blockers) = report;
+    // Residency which the platform does not report is negative.
+    let percent = |percent: f64| {
+        if percent < 0.0 {
+            "unknown".to_owned()
+        } else {
+            format!("{:.1}%", percent)
+        }
+    };
+
+    println!("{}Suspended: {}s", indent, seconds);
+    println!("{}SLP_S0 residency: {}", indent, percent(system));
+    println!("{}Package C-state residency: {}", indent, percent(cpu));
+    if !blockers.is_empty() {
+        println!("{}Powered IP blocks: {}", indent, blockers.join(", "));
+    }
+}
+
+fn suspend_report(client: &mut PowerClient) -> Result<(), String> {
+    print_suspend_report(client.get_suspend_report()?, "");
+    Ok(())
+}
+
+fn doctor(client: &mut PowerClient) -> Result<(), String> {
+    let gib = |bytes: u64| bytes as f64 / (1024.0 * 1024.0 * 1024.0);
+    let (swap, image_size, problems) = client.get_hibernation()?;
+
+    println!("Hibernation: {}", if problems.is_empty() { "ready" } else { "unavailable" });
+    println!("  Swap on disk: {:.1} GiB, image size: {:.1} GiB", gib(swap), gib(image_size));
+    for problem in problems {
+        println!("  - {}", problem);
+    }
+
+    // The daemon only has a report once the system has been suspended to s2idle.
+    match client.get_suspend_report() {
+        Ok(report) => {
+            let state = if report.1 < 0.0 {
+                "SLP_S0 residency unknown"
+            } else if report.1 <= 0.0 {
+                "did not reach SLP_S0"
+            } else {
+                "reached SLP_S0"
+            };
+            println!("s2idle: {}", state);
+            print_suspend_report(report, "  ");
+        }
+        Err(why) => println!("s2idle: {}", why),
+    }
+
+    Ok(())
+}
+
+fn throttling(client: &mut PowerClient) -> Result<(), String> {
+    println!("{:<12} {:>7} {:>9}  State", "Source", "Events", "Total");
+    for (source, events, throttled, seconds) in client.get_throttling()? {
+        let state = if throttled { "throttled" } else { "-" };
+        println!("{:<12} {:>7} {:>8}s  {}", source, events, seconds, state);
+    }
+
+    Ok(())
+}
+
+fn runtime_pm(client: &mut PowerClient) -> Result<(), String> {
+    println!(
+        "{:<14} {:<5} {:<8} {:<10} {:>12} {:>12}",
+        "Device", "Kind", "Control", "Status", "Active", "Suspended"
+    );
+    for (id, kind, control, status, active, suspended) in client.get_runtime_pm()? {
+        let total = active + suspended;
+        let share = if total == 0 { 0.0 } else { suspended as f64 * 100.0 / total as f64 };
+        println!(
+            "{:<14} {:<5} {:<8} {:<10} {:>11}s {:>11}s ({:.1}% suspended)",
+            id,
+            kind,
+            control,
+            status,
+            active / 1000,
+            suspended / 1000,
+            share
+        );
+    }
+
+    Ok(())
+}
+
+fn keyboard(client: &mut PowerClient, matches: &ArgMatches) -> Result<(), String> {
+    let zones = client.get_keyboard_zones()?;
+    let (zone, color) = match (matches.value_of("zone"), matches.value_of("color")) {
+        (Some(zone), Some(color)) => (zone, Color::try_from(color.to_owned())?),
+        (Some(zone), None) => {
+            let (_, color, _) = zones
+                .into_iter()
+                .find(|(name, ..)| name == zone)
+                .ok_or_else(|| format!("keyboard has no zone named {}", zone))?;
+            println!("{}", Color(color));
+            return Ok(());
+        }
+        _ => {
+            if zones.is_empty() {
+                println!("No keyboard zones");
+            }
+            for (zone, color, percent) in zones {
+                println!("{:<8} {} {}%", zone, Color(color), percent);
+            }
+            return Ok(());
+        }
+    };
+
+    let percent = match matches.value_of("brightness") {
+        Some(percent) => percent
+            .trim_end_matches('%')
+            .parse::<u8>()
+            .ok()
+            .filter(|&percent| percent <= 100)
+            .ok_or_else(|| format!("invalid brightness {}", percent))?,
+        None => zones.first().map_or(100, |&(_, _, percent)| percent),
+    };
+
+    client.set_keyboard_zone(zone, color, percent)
+}
+
+fn consumers(client: &mut PowerClient) -> Result<(), String> {
+    let (seconds, package, (cpu, io, memory), consumers) = client.get_consumers()?;
+    // Values which are not available are negative.
+    let known = |value: f64| if value < 0.0 { None } else { Some(value) };
+
+    println!("Over the last {}s:", seconds);
+    if let Some(watts) = known(package) {
+        println!("CPU package: {:.2} W", watts);
+    }
+    let pressure = |value: f64| known(value).map_or("unknown".to_owned(), |p| format!("{:.1}%", p));
+    println!("Pressure: CPU {}, IO {}, memory {}", pressure(cpu), pressure(io), pressure(memory));
+
+    println!("{:>8} {:>7} {:>8}  Name", "PID", "CPU", "Power");
+    for (pid, name, cpu, watts) in consumers {
+        let watts = known(watts).map_or("-".to_owned(), |watts| format!("{:.2} W", watts));
+        println!("{:>8} {:>6.1}% {:>8}  {}", pid, cpu, watts, name);
+    }
+
+    Ok(())
+}
+
+fn fan(client: &mut PowerClient, unit: Option<&str>, history: bool) -> Result<(), String> {
+    let unit = match unit {
+        Some(unit) => unit.parse()?,
+        None => Config::load().map(|config| config.fan.unit).unwrap_or_default(),
+    };
+
+    println!("Temperatures:");
+    for (sensor, celsius) in client.get_temperatures()? {
+        let millicelsius = TemperatureUnit::Celsius.to_millicelsius(celsius);
+        println!("  {}: {}", sensor, unit.format(millicelsius));
+    }
+
+    println!("Fan Curve:");
+    for (millicelsius, duty) in client.get_fan_curve()? {
+        println!("  {}: {}%", unit.format(millicelsius), f64::from(duty) / 100.0);
+    }
+
+    if history {
+        // Missing readings are reported as negative.
+        let format = |celsius: f64| {
+            if celsius < 0.0 {
+                "-".to_owned()
+            } else {
+                unit.format(TemperatureUnit::Celsius.to_millicelsius(celsius))
+            }
+        };
+
+        println!("History:");
+        for (ago, cpu, gpu, duty) in client.get_thermal_history()? {
+            let duty = if duty < 0.0 { "auto".to_owned() } else { format!("{:.0}%", duty) };
+            println!("  -{}s: CPU {}, GPU {}, fans {}", ago, format(cpu), format(gpu), duty);
+        }
+    }
+
+    Ok(())
+}
+
+fn export(client: &mut PowerClient, toml: bool) -> Result<(), String> {
+    let settings = Settings {
+        profile:           client.get_profile()?.to_lowercase(),
+        graphics:          if client.get_switchable()? {
+            Some(client.get_graphics()?)
+        } else {
+            None
+        },
+        charge_thresholds: client.get_charge_thresholds().ok(),
+        config:            fs::read_to_string(CONFIG_PATH).ok(),
+    };
+
+    if toml {
+        print!("{}", settings.to_toml().map_err(err_str)?);
+    } else {
+        print!("{}", settings.to_shell());
+    }
+
+    Ok(())
+}
+
+/// Asks a question on the terminal, returning the first choice whose first letter the answer
+/// starts with, or the default if the answer is empty.
+fn ask<T: Copy>(question: &str, choices: &[(&str, T)], default: T) -> Result<T, String> {
+    let names: Vec<&str> = choices.iter().map(|(name, _)| *name).collect();
+    loop {
+        print!("{} [{}] ", question, names.join("/"));
+        io::stdout().flush().map_err(err_str)?;
+
+        let mut answer = String::new();
+        if io::stdin().lock().read_line(&mut answer).map_err(err_str)? == 0 {
+            return Err("no answer given".into());
+        }
+
+        let answer = answer.trim().to_lowercase();
+        if answer.is_empty() {
+            return Ok(default);
+        }
+
+        if let Some((_, choice)) = choices.iter().find(|(name, _)| name.starts_with(&answer)) {
+            return Ok(*choice);
+        }
+    }
+}
+
+fn setup(client: &mut PowerClient, output: &Path) -> Result<(), String> {
+    let (_, _, dmi, ..) = client.get_info()?;
+    let capabilities = client.get_capabilities()?;
+    let graphics = if client.get_switchable()? {
+        let discrete =
+            client.get_graphics_topology()?.into_iter().find_map(|(_, vendor, role, _)| {
+                match (vendor.as_str(), role.as_str()) {
+                    ("nvidia", "discrete") => Some("nvidia"),
+                    ("amd", "discrete") => Some("discrete"),
+                    _ => None,
+                }
+            });
+        discrete.map(|discrete| client.get_default_graphics().map(|default| (default, discrete)))
+    } else {
+        None
+    };
+
+    let hardware = Hardware {
+        model:             dmi.get("product_version").cloned().unwrap_or_default(),
+        battery:           setup::has_battery(),
+        charge_thresholds: capabilities.iter().any(|cap| cap == "charge-thresholds"),
+        fan_control:       capabilities.iter().any(|cap| cap == "fan-control"),
+        graphics:          graphics.transpose()?,
+    };
+
+    let usage = ask(
+        "Is this machine mostly used plugged in, or often on battery?",
+        &[("plugged-in", Usage::Desktop), ("battery", Usage::Portable)],
+        if hardware.battery { Usage::Portable } else { Usage::Desktop },
+    )?;
+    let fan = if hardware.fan_control {
+        ask(
+            "Should the fans favor the model's standard curve, quiet, or cool?",
+            &[
+                ("standard", FanPreset::Standard),
+                ("quiet", FanPreset::Quiet),
+                ("cool", FanPreset::Cool),
+            ],
+            FanPreset::Standard,
+        )?
+    } else {
+        FanPreset::Standard
+    };
+
+    let proposal = Proposal::new(&hardware, usage, fan);
+    let config = proposal.to_config(&hardware);
+    println!("\n{}", config);
+    if let Some((start, end)) = proposal.charge_thresholds {
+        println!("Charge thresholds: {}-{}", start, end);
+    }
+    if let Some(ref graphics) = proposal.graphics {
+        println!("Graphics: {}", graphics);
+    }
+
+    if !ask(
+        &format!("Apply these settings, writing {}?", output.display()),
+        &[("yes", true), ("no", false)],
+        false,
+    )? {
+        return Ok(());
+    }
+
+    if output.exists() {
+        let backup = output.with_extension("toml.bak");
+        fs::rename(output, &backup).map_err(|why| {
+            format!("failed to back up {} to {}: {}", output.display(), backup.display(), why)
+        })?;
+        println!("kept the previous configuration as {}", backup.display());
+    }
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent).map_err(err_str)?;
+    }
+    fs::write(output, config)
+        .map_err(|why| format!("failed to write {}: {}", output.display(), why))?;
+    println!("wrote {}, which takes effect once the daemon is restarted", output.display());
+
+    if let Some(thresholds) = proposal.charge_thresholds {
+        client.set_charge_thresholds(thresholds)?;
+    }
+    if let Some(graphics) = proposal.graphics {
+        if client.get_graphics()? != graphics {
+            client.set_graphics(&graphics)?;
+        }
+    }
+
+    Ok(())
+}
+
 pub fn client(subcommand: &str, matches: &ArgMatches) -> Result<(), String> {
     let mut client = PowerClient::new()?;
 
@@ -177,10 +861,30 @@ pub fn client(subcommand: &str, matches: &ArgMatches) -> Result<(), String> {
             _ => profile(&mut client).map_err(err_str),
         },
         "graphics" => match matches.subcommand() {
+            (vendor, _) if matches.is_present("dry-run") && VENDORS.contains(&vendor) => {
+                client.preview_graphics(vendor)
+            }
             ("compute", _) => client.set_graphics("compute"),
+            ("discrete", _) => client.set_graphics("discrete"),
             ("hybrid", _) => client.set_graphics("hybrid"),
             ("integrated", _) | ("intel", _) => client.set_graphics("integrated"),
             ("nvidia", _) => client.set_graphics("nvidia"),
+            ("vfio", _) => client.set_graphics("vfio"),
+            ("reconcile", _) => client.reconcile_graphics(),
+            ("verify", Some(matches)) => client.verify_graphics(matches.is_present("repair")),
+            ("power-states", _) => {
+                // Daemons from before the method was added report nothing for NVIDIA GPUs.
+                let nvidia = client.get_nvidia_power().unwrap_or_default();
+                for (id, status, state, driver) in client.get_gpu_power_states()? {
+                    let state = if state.is_empty() { "unknown" } else { &state };
+                    let driver = if driver.is_empty() { "no driver" } else { &driver };
+                    println!("{}: {} ({}, {})", id, status, state, driver);
+                    for (_, runtime_d3, video_memory) in nvidia.iter().filter(|gpu| gpu.0 == id) {
+                        println!("  Runtime D3: {}, video memory: {}", runtime_d3, video_memory);
+                    }
+                }
+                Ok(())
+            }
             ("switchable", _) => {
                 if client.get_switchable()? {
                     println!("switchable");
@@ -189,7 +893,60 @@ pub fn client(subcommand: &str, matches: &ArgMatches) -> Result<(), String> {
                 }
                 Ok(())
             }
+            ("info", _) => {
+                for (id, vendor, device, vram, driver, boot_vga) in client.get_graphics_info()? {
+                    let driver = if driver.is_empty() { "no driver" } else { driver.as_str() };
+                    print!("{}: {} {} ({})", id, vendor, device, driver);
+                    if vram != 0 {
+                        print!(", {} MiB", vram / (1024 * 1024));
+                    }
+                    println!("{}", if boot_vga { ", boot VGA" } else { "" });
+                }
+                Ok(())
+            }
+            ("link", _) => {
+                let known = |value: u8, unit: &str| {
+                    if value == 0 {
+                        "unknown".to_owned()
+                    } else {
+                        format!("{}{}", unit, value)
+                    }
+                };
+                for (id, speed, width, max_speed, max_width, target) in client.get_pcie_links()? {
+                    print!(
+                        "{}: {} {} of {} {}",
+                        id,
+                        known(speed, "Gen "),
+                        known(width, "x"),
+                        known(max_speed, "Gen "),
+                        known(max_width, "x")
+                    );
+                    if target != 0 && target < max_speed {
+                        print!(", capped at Gen {}", target);
+                    }
+                    println!();
+                }
+                Ok(())
+            }
+            ("topology", _) => {
+                for (id, vendor, role, powered) in client.get_graphics_topology()? {
+                    let power = if powered { "on" } else { "off" };
+                    println!("{}: {} {} ({})", id, vendor, role, power);
+                }
+                Ok(())
+            }
             ("power", Some(matches)) => match matches.value_of("state") {
+                Some("off") if matches.is_present("force") => {
+                    client.force_graphics_power_off(matches.value_of("device").unwrap_or_default())
+                }
+                Some(state) if matches.is_present("device") => {
+                    let device = matches.value_of("device").unwrap_or_default();
+                    match state {
+                        "on" => client.set_device_power(device, true),
+                        "off" => client.set_device_power(device, false),
+                        _ => Err("only on or off may be set for a single device".to_string()),
+                    }
+                }
                 Some("auto") => client.auto_graphics_power(),
                 Some("off") => client.set_graphics_power(false),
                 Some("on") => client.set_graphics_power(true),
@@ -243,6 +1000,37 @@ pub fn client(subcommand: &str, matches: &ArgMatches) -> Result<(), String> {
             println!("Start: {}", start);
             println!("End: {}", end);
 
+            let batteries = client.get_battery_thresholds()?;
+            if batteries.len() > 1 {
+                for (battery, start, end, compliant) in batteries {
+                    let note = if compliant { "" } else { " (not as configured)" };
+                    println!("{}: {}-{}{}", battery, start, end, note);
+                }
+            }
+
+            Ok(())
+        }
+        "export" => export(&mut client, matches.is_present("toml")),
+        "setup" => setup(&mut client, Path::new(matches.value_of("output").unwrap_or(CONFIG_PATH))),
+        "fan" => fan(&mut client, matches.value_of("unit"), matches.is_present("history")),
+        "bench-mode" => bench_mode(&mut client, matches),
+        "info" => info(&mut client),
+        "suspend-report" => suspend_report(&mut client),
+        "doctor" => doctor(&mut client),
+        "consumers" => consumers(&mut client),
+        "throttling" => throttling(&mut client),
+        "runtime-pm" => runtime_pm(&mut client),
+        "brightness" => {
+            let device = matches.value_of("device").unwrap_or_default();
+            let increase = matches.value_of("direction") == Some("up");
+            println!("{}%", client.step_brightness(device, increase)?);
+            Ok(())
+        }
+        "keyboard" => keyboard(&mut client, matches),
+        "capabilities" => {
+            for capability in client.get_capabilities()? {
+                println!("{}", capability);
+            }
             Ok(())
         }
         _ => Err(format!("unknown sub-command {}", subcommand)),
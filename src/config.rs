@@ -0,0 +1,1109 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! System-wide configuration, read from `/etc/system76-power/config.toml`.
+//!
+//! Every setting is optional. Errors are reported with the file, position, and key at fault,
+//! along with a suggestion when a key or value appears to be misspelled.
+
+use crate::{
+    charge_thresholds::BatteryThresholds,
+    devices::{DeviceId, DevicePolicy},
+    ec::Color,
+    hooks::{HookEvent, HookTarget},
+    initramfs::InitramfsTool,
+    knobs::Knob,
+    quiet_hours::Window,
+    temperature::TemperatureUnit,
+};
+use serde::{de, Deserialize, Deserializer};
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fmt::{self, Display, Formatter},
+    fs, io,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+pub const CONFIG_PATH: &str = "/etc/system76-power/config.toml";
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    Battery,
+    Balanced,
+    Performance,
+}
+
+impl Default for Profile {
+    fn default() -> Self { Profile::Balanced }
+}
+
+/// How to coexist with power-profiles-daemon, which also manages CPU settings.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerProfilesDaemon {
+    /// Apply the profile which is selected through power-profiles-daemon, when it is running.
+    Follow,
+    /// Take over the bus name of power-profiles-daemon, causing it to exit.
+    Replace,
+    /// Apply profiles independently, as if it were not installed.
+    Ignore,
+}
+
+impl Default for PowerProfilesDaemon {
+    fn default() -> Self { PowerProfilesDaemon::Follow }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub adapter:          AdapterConfig,
+    pub auto_performance: AutoPerformanceConfig,
+    pub auto_profile:     AutoProfileConfig,
+    /// Charge thresholds of particular batteries, by name such as `BAT1`, which are kept when
+    /// thresholds are set for the others.
+    pub batteries:        BatteryThresholds,
+    pub bench:            BenchConfig,
+    pub brightness:       BrightnessConfig,
+    pub clamshell:        ClamshellConfig,
+    pub consumers:        ConsumersConfig,
+    pub daemon:           DaemonConfig,
+    /// Runtime power management policies of PCI and USB devices, keyed by ID.
+    pub devices:          BTreeMap<DeviceId, DevicePolicy>,
+    pub epp:              EppConfig,
+    pub fan:              FanConfig,
+    pub firmware:         FirmwareConfig,
+    pub graphics:         GraphicsConfig,
+    pub health:           HealthConfig,
+    pub hooks:            HooksConfig,
+    pub hotkeys:          HotkeysConfig,
+    pub keyboard:         KeyboardConfig,
+    /// Attributes of sysfs and sysctl to set with each profile.
+    pub knobs:            Vec<Knob>,
+    pub nvidia:           NvidiaConfig,
+    pub policy:           PolicyConfig,
+    pub quiet_hours:      QuietHoursConfig,
+    pub scheduler:        SchedulerConfig,
+    pub scripts:          ScriptsConfig,
+    pub spindown:         SpindownConfig,
+    pub wifi:             WifiConfig,
+}
+
+/// A lower CPU power limit while the AC adapter is too weak to run the system and charge the
+/// battery at once.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdapterConfig {
+    pub enabled:     bool,
+    /// Adapters which supply fewer watts than this are underpowered, where their wattage is
+    /// exposed. Any adapter is underpowered while the battery discharges on it.
+    pub min_watts:   u32,
+    /// The long term power limit of the CPU on an underpowered adapter, in watts. Zero limits it
+    /// to half of the wattage of the adapter, or 15 watts where that is unknown.
+    pub power_limit: u32,
+}
+
+impl Default for AdapterConfig {
+    fn default() -> Self { AdapterConfig { enabled: false, min_watts: 45, power_limit: 0 } }
+}
+
+/// Applies the performance profile automatically while on AC power, below a temperature, and
+/// not operating as a clamshell. The previous profile is restored when any of these ends.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AutoPerformanceConfig {
+    pub enabled:  bool,
+    /// Degrees Celsius of the hottest CPU or GPU above which the performance profile is left.
+    pub max_temp: u8,
+}
+
+impl Default for AutoPerformanceConfig {
+    fn default() -> Self { AutoPerformanceConfig { enabled: false, max_temp: 75 } }
+}
+
+/// Switches profiles automatically when the power source changes.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AutoProfileConfig {
+    pub enabled:     bool,
+    /// The profile to apply when AC power is connected.
+    pub ac:          Profile,
+    /// The profile to apply when AC power is disconnected.
+    pub battery:     Profile,
+    /// The profile to apply when the battery becomes low, while on battery.
+    pub low_battery: Profile,
+}
+
+impl Default for AutoProfileConfig {
+    fn default() -> Self {
+        AutoProfileConfig {
+            enabled:     false,
+            ac:          Profile::Balanced,
+            battery:     Profile::Battery,
+            low_battery: Profile::Battery,
+        }
+    }
+}
+
+/// A profile and fan curve for clamshell operation, where the lid is closed with an external
+/// display connected on AC power. The previous profile is restored when any of these ends.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClamshellConfig {
+    pub enabled:    bool,
+    pub profile:    Profile,
+    /// Degrees Celsius by which the temperatures of the fan curve are lowered, so that the fans
+    /// ramp up sooner.
+    pub fan_offset: u8,
+}
+
+impl Default for ClamshellConfig {
+    fn default() -> Self {
+        ClamshellConfig { enabled: false, profile: Profile::Balanced, fan_offset: 10 }
+    }
+}
+
+/// Sampling of the processes which use the most energy, which is reported by `system76-power
+/// consumers`. Reading the CPU time of every process has a small cost of its own, so this is off
+/// by default.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConsumersConfig {
+    pub enabled: bool,
+    /// How many processes are reported.
+    pub count:   usize,
+}
+
+impl Default for ConsumersConfig {
+    fn default() -> Self { ConsumersConfig { enabled: false, count: 10 } }
+}
+
+/// Benchmark mode, which locks the CPU frequency and fan duty cycle.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BenchConfig {
+    /// Minutes after which benchmark mode turns itself off, and the longest it may be requested
+    /// for. Zero uses the default of an hour.
+    pub max_duration: u64,
+    /// The duty cycle the fans are held at, in percent.
+    #[serde(deserialize_with = "percent")]
+    pub fan_duty:     u8,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self { BenchConfig { max_duration: 60, fan_duty: 60 } }
+}
+
+/// Firmware update mode, which holds the system steady while firmware is flashed.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FirmwareConfig {
+    /// Minutes after which an update which was not ended is assumed to have failed, and the
+    /// longest it may be requested for. Zero uses the default of half an hour.
+    pub max_duration: u64,
+    /// The duty cycle the fans are held at, in percent, where the daemon controls the fans.
+    #[serde(deserialize_with = "percent")]
+    pub fan_duty:     u8,
+}
+
+impl Default for FirmwareConfig {
+    fn default() -> Self { FirmwareConfig { max_duration: 30, fan_duty: 100 } }
+}
+
+/// Reads a percentage, which may not exceed 100.
+fn percent<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+    match u8::deserialize(deserializer)? {
+        value if value <= 100 => Ok(value),
+        value => {
+            Err(de::Error::custom(format!("invalid percentage `{}`, expected 0 to 100", value)))
+        }
+    }
+}
+
+/// The steps of the brightness methods, which window managers may bind brightness keys to.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BrightnessConfig {
+    /// The percentage of its maximum which screen brightness is stepped by.
+    pub screen_step:   u8,
+    /// The percentage of its maximum which keyboard brightness is stepped by.
+    pub keyboard_step: u8,
+}
+
+impl Default for BrightnessConfig {
+    fn default() -> Self { BrightnessConfig { screen_step: 5, keyboard_step: 10 } }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// The profile to apply when the daemon starts.
+    pub default_profile:       Profile,
+    pub power_profiles_daemon: PowerProfilesDaemon,
+    /// Enables PCI runtime power management, which fails to resume on some systems.
+    pub pci_runtime_pm:        bool,
+    /// Drops the capabilities which are not needed once devices have been opened.
+    pub reduce_privileges:     bool,
+    /// Controls the fans. Disabling this leaves them to the firmware, or to another fan tool.
+    pub manage_fan:            bool,
+    /// Switches graphics modes and powers discrete GPUs, which setups such as bbswitch may do
+    /// instead.
+    pub manage_graphics:       bool,
+    /// Sets the screen and keyboard brightness of profiles, and dims the keyboard backlight.
+    pub manage_backlight:      bool,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        DaemonConfig {
+            default_profile:       Profile::default(),
+            power_profiles_daemon: PowerProfilesDaemon::default(),
+            pci_runtime_pm:        false,
+            reduce_privileges:     true,
+            manage_fan:            true,
+            manage_graphics:       true,
+            manage_backlight:      true,
+        }
+    }
+}
+
+/// The energy performance preference of the CPUs to apply with each profile, such as `power` or
+/// `balance_performance`, where the cpufreq driver supports it. Left unchanged if unset.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EppConfig {
+    pub battery:     Option<String>,
+    pub balanced:    Option<String>,
+    pub performance: Option<String>,
+}
+
+impl EppConfig {
+    pub fn profile(&self, profile: Profile) -> Option<&str> {
+        match profile {
+            Profile::Battery => self.battery.as_deref(),
+            Profile::Balanced => self.balanced.as_deref(),
+            Profile::Performance => self.performance.as_deref(),
+        }
+    }
+}
+
+/// Control of the fans of desktops.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FanConfig {
+    /// The unit of the temperatures in the curve, which is also used to display temperatures.
+    pub unit:      TemperatureUnit,
+    /// Replaces the fan curve of the model. The temperatures must increase from point to point.
+    pub curve:     Vec<FanCurvePoint>,
+    /// Minutes of temperatures and duty cycles to keep for `GetThermalHistory`, which are
+    /// recorded every 10 seconds whether or not the fans are controlled.
+    pub history:   u64,
+    /// The power draw of the dGPU, in watts, above which the fans are kept at `gpu_duty` while on
+    /// AC, as the chassis heats up long before the CPU does under GPU-only loads. Zero disables
+    /// this.
+    pub gpu_power: u32,
+    /// The lowest duty cycle, in percent, while the dGPU draws more than `gpu_power`.
+    pub gpu_duty:  u8,
+}
+
+impl Default for FanConfig {
+    fn default() -> Self {
+        FanConfig {
+            unit:      TemperatureUnit::default(),
+            curve:     Vec::new(),
+            history:   10,
+            gpu_power: 60,
+            gpu_duty:  40,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FanCurvePoint {
+    /// The temperature, in the configured unit.
+    pub temp: f64,
+    /// The duty cycle, in percent.
+    pub duty: u8,
+}
+
+/// A read-only endpoint which reports the state of the daemon as JSON, for monitoring agents.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    pub enabled: bool,
+    /// The path of a unix socket, or a loopback address and port such as `127.0.0.1:7676`.
+    pub listen:  String,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig { enabled: false, listen: String::from("/run/system76-power/health.sock") }
+    }
+}
+
+/// Targets which are notified of events, such as profile changes.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HooksConfig {
+    /// The temperature, in degrees Celsius, at which the `thermal-critical` event is sent. This
+    /// is measured while the daemon controls the fans.
+    pub critical: u8,
+    /// Seconds after which a target which has not responded is abandoned.
+    pub timeout:  u64,
+    pub targets:  Vec<Hook>,
+}
+
+impl Default for HooksConfig {
+    fn default() -> Self { HooksConfig { critical: 95, timeout: 10, targets: Vec::new() } }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hook {
+    /// An `http://` URL to post events to, or the absolute path of a program to run with each
+    /// event on its standard input.
+    pub target: HookTarget,
+    /// The events to send to the target, or every event if empty.
+    #[serde(default)]
+    pub events: Vec<HookEvent>,
+}
+
+impl Hook {
+    pub fn wants(&self, event: HookEvent) -> bool {
+        self.events.is_empty() || self.events.contains(&event)
+    }
+}
+
+/// Actions bound to keys of System76 laptops, which are handled on any desktop once enabled. They
+/// are off by default, as the desktop may bind the same keys.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HotkeysConfig {
+    pub enabled:            bool,
+    /// The key code, from `linux/input-event-codes.h`, which toggles running the fans at full
+    /// speed. Zero leaves the action unbound.
+    pub fan_boost:          u16,
+    /// The key code which toggles the performance profile. Zero leaves the action unbound.
+    pub performance_toggle: u16,
+}
+
+impl Default for HotkeysConfig {
+    fn default() -> Self {
+        // `KEY_PROG1` and `KEY_PROG2`
+        HotkeysConfig {
+            enabled:            false,
+            fan_boost:          148,
+            performance_toggle: 149,
+        }
+    }
+}
+
+/// Settings of NVIDIA GPUs to apply with each profile, while the driver is loaded.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NvidiaConfig {
+    pub battery:     NvidiaProfileConfig,
+    pub balanced:    NvidiaProfileConfig,
+    pub performance: NvidiaProfileConfig,
+}
+
+impl NvidiaConfig {
+    pub fn profile(&self, profile: Profile) -> &NvidiaProfileConfig {
+        match profile {
+            Profile::Battery => &self.battery,
+            Profile::Balanced => &self.balanced,
+            Profile::Performance => &self.performance,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        [&self.battery, &self.balanced, &self.performance].iter().all(|profile| profile.is_empty())
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NvidiaProfileConfig {
+    /// Keeps the driver initialized while no applications use the GPU, running
+    /// `nvidia-persistenced` while enabled where it is installed. Left unchanged if unset.
+    pub persistence_mode:         Option<bool>,
+    /// Locks the graphics clock within a range of MHz, such as `[1200, 1800]`. If unset, clocks
+    /// which were locked by another profile are unlocked.
+    pub locked_clocks:            Option<(u32, u32)>,
+    /// Caps the power of the GPU, in watts, within the limits which the board supports. If
+    /// unset, a limit which was set by another profile is restored.
+    pub power_limit:              Option<u32>,
+    /// Runs `nvidia-powerd`, which shifts the CPU's power budget to the GPU under load on models
+    /// supporting Dynamic Boost. Left unchanged if unset.
+    pub dynamic_boost:            Option<bool>,
+    /// `NVreg_DynamicPowerManagement`, from 0 to 2, which the driver only reads when it is
+    /// loaded. Defaults to 2 if unset.
+    pub dynamic_power_management: Option<DynamicPowerManagement>,
+    /// How long the GPU must be idle before it is suspended, in milliseconds. If unset, a delay
+    /// which was set by another profile is restored.
+    pub autosuspend_delay:        Option<u32>,
+}
+
+impl NvidiaProfileConfig {
+    pub fn is_empty(&self) -> bool {
+        self.persistence_mode.is_none()
+            && self.locked_clocks.is_none()
+            && self.power_limit.is_none()
+            && self.dynamic_boost.is_none()
+            && self.dynamic_power_management.is_none()
+            && self.autosuspend_delay.is_none()
+    }
+}
+
+/// The runtime power management of the NVIDIA driver, as `NVreg_DynamicPowerManagement`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "u8")]
+pub enum DynamicPowerManagement {
+    /// The GPU is never powered off.
+    Disabled = 0,
+    /// The GPU is powered off while no applications use it.
+    Coarse = 1,
+    /// The GPU is also powered off while the applications which use it are idle.
+    Fine = 2,
+}
+
+impl Default for DynamicPowerManagement {
+    fn default() -> Self { DynamicPowerManagement::Fine }
+}
+
+impl TryFrom<u8> for DynamicPowerManagement {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(DynamicPowerManagement::Disabled),
+            1 => Ok(DynamicPowerManagement::Coarse),
+            2 => Ok(DynamicPowerManagement::Fine),
+            _ => Err(format!("invalid dynamic power management `{}`, expected 0 to 2", value)),
+        }
+    }
+}
+
+/// Options of the scheduler to apply with each profile, where the kernel supports them.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchedulerConfig {
+    pub battery:     SchedulerProfileConfig,
+    pub balanced:    SchedulerProfileConfig,
+    pub performance: SchedulerProfileConfig,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        // Energy aware scheduling keeps tasks on the efficient CPUs, which costs performance.
+        let energy_aware = |enabled| SchedulerProfileConfig {
+            energy_aware: Some(enabled),
+            ..SchedulerProfileConfig::default()
+        };
+
+        SchedulerConfig {
+            battery:     energy_aware(true),
+            balanced:    energy_aware(true),
+            performance: energy_aware(false),
+        }
+    }
+}
+
+impl SchedulerConfig {
+    pub fn profile(&self, profile: Profile) -> &SchedulerProfileConfig {
+        match profile {
+            Profile::Battery => &self.battery,
+            Profile::Balanced => &self.balanced,
+            Profile::Performance => &self.performance,
+        }
+    }
+}
+
+/// Each option is left unchanged if unset. Utilization is a fraction of 1024, the capacity of the
+/// largest CPU at its highest frequency.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchedulerProfileConfig {
+    /// Allows energy aware scheduling, on systems whose CPUs differ in capacity.
+    pub energy_aware:      Option<bool>,
+    /// The most which tasks may raise their minimum utilization to, as `sched_util_clamp_min`.
+    pub util_clamp_min:    Option<u16>,
+    /// The most which tasks may raise their maximum utilization to, as `sched_util_clamp_max`.
+    pub util_clamp_max:    Option<u16>,
+    /// The minimum utilization of realtime tasks, which otherwise run at the highest frequency.
+    pub util_clamp_min_rt: Option<u16>,
+}
+
+/// What to do when another tool changes the graphics mode behind the daemon's back.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExternalGraphicsChanges {
+    /// Treat the mode the other tool selected as the current mode.
+    Adopt,
+    /// Switch back to the mode which was last selected through the daemon.
+    Reassert,
+}
+
+impl Default for ExternalGraphicsChanges {
+    fn default() -> Self { ExternalGraphicsChanges::Adopt }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GraphicsConfig {
+    /// Changes which leave the files without a recognizable mode are always reasserted.
+    pub external_changes: ExternalGraphicsChanges,
+    /// Seconds the NVIDIA GPU may go unused in hybrid mode before automatic graphics power
+    /// removes it. Zero, the default, keeps it powered.
+    pub idle_power_off:   u64,
+    /// The tool which rebuilds the initramfs after switching modes, such as `mkinitcpio`.
+    /// Detected from the installed tools if unset.
+    pub initramfs:        Option<InitramfsTool>,
+    pub link_speed:       LinkSpeedConfig,
+    /// IDs of applications, such as `org.blender.Blender`, which launchers always start on the
+    /// discrete GPU in hybrid mode, as exported through the interface of switcheroo-control.
+    pub offload:          Vec<String>,
+    /// Units which are enabled or disabled when switching the NVIDIA driver to each mode.
+    pub services:         GraphicsServices,
+}
+
+impl Default for GraphicsConfig {
+    fn default() -> Self {
+        GraphicsConfig {
+            external_changes: ExternalGraphicsChanges::default(),
+            idle_power_off:   0,
+            initramfs:        None,
+            link_speed:       LinkSpeedConfig::default(),
+            offload:          Vec::new(),
+            services:         GraphicsServices::default(),
+        }
+    }
+}
+
+/// The units of each graphics mode, keyed by the mode. Setting any mode replaces the default,
+/// which only enables `nvidia-fallback.service` of Pop!_OS in NVIDIA mode.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(transparent)]
+pub struct GraphicsServices(BTreeMap<String, ModeServices>);
+
+impl GraphicsServices {
+    /// The units of a graphics mode, which are left alone if it has none.
+    pub fn mode(&self, vendor: &str) -> Option<&ModeServices> { self.0.get(vendor) }
+}
+
+impl Default for GraphicsServices {
+    fn default() -> Self {
+        let fallback = || vec![String::from("nvidia-fallback.service")];
+        let mut modes = BTreeMap::new();
+        let services = ModeServices { enable: fallback(), disable: Vec::new() };
+        modes.insert(String::from("nvidia"), services);
+        for vendor in &["compute", "hybrid", "integrated", "vfio"] {
+            let services = ModeServices { enable: Vec::new(), disable: fallback() };
+            modes.insert((*vendor).to_owned(), services);
+        }
+
+        GraphicsServices(modes)
+    }
+}
+
+/// Units which are enabled or disabled when switching to a graphics mode, where they are
+/// installed.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModeServices {
+    pub enable:  Vec<String>,
+    pub disable: Vec<String>,
+}
+
+/// The highest PCI Express generation of the links of discrete GPUs in each profile, such as 1
+/// for 2.5 GT/s, which lowers their power while idle. The speed is not capped if unset.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LinkSpeedConfig {
+    pub battery:     Option<u8>,
+    pub balanced:    Option<u8>,
+    pub performance: Option<u8>,
+}
+
+impl LinkSpeedConfig {
+    pub fn profile(&self, profile: Profile) -> Option<u8> {
+        match profile {
+            Profile::Battery => self.battery,
+            Profile::Balanced => self.balanced,
+            Profile::Performance => self.performance,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.battery.is_none() && self.balanced.is_none() && self.performance.is_none()
+    }
+}
+
+/// Keyboard backlight changes made by profiles, or while the session is idle or locked.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyboardConfig {
+    /// Milliseconds a change of brightness fades over. Zero changes brightness at once.
+    pub fade:            u64,
+    /// Turns keyboard backlights off once the idle timeout of the desktop passes, restoring them
+    /// when the session is active again.
+    pub off_when_idle:   bool,
+    /// Turns keyboard backlights off while the screen is locked or the displays are turned off,
+    /// restoring them on unlock or when the displays wake.
+    pub off_when_locked: bool,
+    pub colors:          KeyboardColors,
+}
+
+impl Default for KeyboardConfig {
+    fn default() -> Self {
+        KeyboardConfig {
+            fade:            500,
+            off_when_idle:   false,
+            off_when_locked: false,
+            colors:          KeyboardColors::default(),
+        }
+    }
+}
+
+/// Colors which each profile sets on the zones of the keyboard, by zone name such as `left`, or
+/// `all` for every zone which is not named.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyboardColors {
+    pub battery:     BTreeMap<String, Color>,
+    pub balanced:    BTreeMap<String, Color>,
+    pub performance: BTreeMap<String, Color>,
+}
+
+impl KeyboardColors {
+    pub fn profile(&self, profile: Profile) -> &BTreeMap<String, Color> {
+        match profile {
+            Profile::Battery => &self.battery,
+            Profile::Balanced => &self.balanced,
+            Profile::Performance => &self.performance,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.battery.is_empty() && self.balanced.is_empty() && self.performance.is_empty()
+    }
+}
+
+/// A setting which the system policy may lock.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LockedSetting {
+    /// The profile, including holds and power-profiles-daemon requests.
+    Profile,
+    /// The charge thresholds, including battery calibration.
+    ChargeThresholds,
+    /// The graphics mode.
+    Graphics,
+    /// The power of discrete GPUs.
+    GraphicsPower,
+    BenchMode,
+}
+
+impl LockedSetting {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LockedSetting::Profile => "profile",
+            LockedSetting::ChargeThresholds => "charge-thresholds",
+            LockedSetting::Graphics => "graphics",
+            LockedSetting::GraphicsPower => "graphics-power",
+            LockedSetting::BenchMode => "bench-mode",
+        }
+    }
+}
+
+/// Settings which administrators lock, so that only root may change them, whatever the groups
+/// or Polkit authorizations of other users.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyConfig {
+    pub locked: Vec<LockedSetting>,
+}
+
+/// Quieter fans and a lower CPU power limit during certain hours, on top of the active profile.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuietHoursConfig {
+    pub enabled:      bool,
+    /// Daily windows in local time, such as `22:00-07:00`.
+    pub windows:      Vec<Window>,
+    /// The highest fan duty cycle, in percent, until the temperature becomes critical.
+    pub fan_max_duty: u8,
+    /// The long term power limit of the CPU, in watts. Zero leaves the limit unchanged.
+    pub power_limit:  u32,
+}
+
+impl Default for QuietHoursConfig {
+    fn default() -> Self {
+        QuietHoursConfig {
+            enabled:      false,
+            windows:      vec![Window::try_from(String::from("22:00-07:00")).unwrap()],
+            fan_max_duty: 50,
+            power_limit:  0,
+        }
+    }
+}
+
+/// Programs which are run after a profile is applied, for settings the daemon does not manage.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScriptsConfig {
+    pub battery:     Option<PathBuf>,
+    pub balanced:    Option<PathBuf>,
+    pub performance: Option<PathBuf>,
+    /// Seconds after which a script is killed.
+    pub timeout:     u64,
+}
+
+impl ScriptsConfig {
+    pub fn script(&self, profile: Profile) -> Option<&Path> {
+        match profile {
+            Profile::Battery => self.battery.as_deref(),
+            Profile::Balanced => self.balanced.as_deref(),
+            Profile::Performance => self.performance.as_deref(),
+        }
+    }
+}
+
+impl Default for ScriptsConfig {
+    fn default() -> Self {
+        ScriptsConfig { battery: None, balanced: None, performance: None, timeout: 30 }
+    }
+}
+
+/// Spindown of rotational drives, which the battery profile lets spin down when idle. Drives are
+/// left alone by default, as this sets their APM level with `hdparm`, and polls them with it.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpindownConfig {
+    pub enabled: bool,
+}
+
+/// Power saving of wireless interfaces in each profile, which is left alone if unset. While it is
+/// enabled, it is turned off on a poor link, and turned on again once the link has been stable.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WifiConfig {
+    pub battery:     Option<bool>,
+    pub balanced:    Option<bool>,
+    pub performance: Option<bool>,
+    /// The signal level, in dBm, below which a link is poor.
+    pub min_signal:  i32,
+    /// Seconds a link must be stable before power saving is turned on again.
+    pub stable:      u64,
+}
+
+impl WifiConfig {
+    pub fn profile(&self, profile: Profile) -> Option<bool> {
+        match profile {
+            Profile::Battery => self.battery,
+            Profile::Balanced => self.balanced,
+            Profile::Performance => self.performance,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.battery.is_none() && self.balanced.is_none() && self.performance.is_none()
+    }
+}
+
+impl Default for WifiConfig {
+    fn default() -> Self {
+        WifiConfig {
+            battery:     None,
+            balanced:    None,
+            performance: None,
+            min_signal:  -75,
+            stable:      120,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("failed to read {}: {}", _0.display(), _1)]
+    Read(PathBuf, io::Error),
+    #[error("{}", _0)]
+    Invalid(Box<Diagnostic>),
+}
+
+/// Describes where and why a configuration file failed to parse.
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub file:       PathBuf,
+    pub line:       Option<usize>,
+    pub column:     Option<usize>,
+    pub key:        Option<String>,
+    pub message:    String,
+    pub suggestion: Option<String>,
+}
+
+impl Diagnostic {
+    fn new(file: &Path, source: &str, why: &toml::de::Error) -> Self {
+        let text = why.to_string();
+
+        // The position and key are appended to the message, and are reported separately.
+        let message = match text.rfind(" at line ") {
+            Some(pos) if why.line_col().is_some() => &text[..pos],
+            _ => &text,
+        };
+
+        let (message, mut key) = match message.rfind(" for key `") {
+            Some(pos) => {
+                let key = message[pos + 10..].trim_end_matches('`');
+                (&message[..pos], Some(key.to_owned()))
+            }
+            None => (message, None),
+        };
+
+        let mut diagnostic = Diagnostic {
+            file:       file.to_owned(),
+            line:       why.line_col().map(|(line, _)| line + 1),
+            column:     why.line_col().map(|(_, column)| column + 1),
+            key:        None,
+            message:    message.to_owned(),
+            suggestion: None,
+        };
+
+        if let Some(field) = quoted_after(message, "unknown field ") {
+            // Unknown fields are reported against their table, rather than the field itself.
+            key = Some(match key {
+                Some(table) => [&table, field].join("."),
+                None => field.to_owned(),
+            });
+            diagnostic.suggestion = suggest(field, message);
+        } else if let Some(variant) = quoted_after(message, "unknown variant ") {
+            diagnostic.suggestion = suggest(variant, message);
+        }
+
+        // Values are also reported at the start of their table, so point at the key instead.
+        if let Some(ref key) = key {
+            let (table, name) = match key.rfind('.') {
+                Some(pos) => (&key[..pos], &key[pos + 1..]),
+                None => ("", key.as_str()),
+            };
+
+            if let Some((line, column)) = find_key(source, table, name) {
+                diagnostic.line = Some(line);
+                diagnostic.column = Some(column);
+            }
+        }
+
+        diagnostic.key = key;
+        diagnostic
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.file.display())?;
+
+        if let Some(line) = self.line {
+            write!(f, ":{}", line)?;
+            if let Some(column) = self.column {
+                write!(f, ":{}", column)?;
+            }
+        }
+
+        if let Some(ref key) = self.key {
+            write!(f, ": `{}`", key)?;
+        }
+
+        write!(f, ": {}", self.message)?;
+
+        if let Some(ref suggestion) = self.suggestion {
+            write!(f, " (did you mean `{}`?)", suggestion)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Config {
+    /// Loads the system configuration, using the defaults if there is none.
+    pub fn load() -> Result<Config, ConfigError> { Config::load_from(Path::new(CONFIG_PATH)) }
+
+    pub fn load_from(path: &Path) -> Result<Config, ConfigError> {
+        match fs::read_to_string(path) {
+            Ok(source) => Config::parse(path, &source),
+            Err(ref why) if why.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(why) => Err(ConfigError::Read(path.to_owned(), why)),
+        }
+    }
+
+    pub fn parse(path: &Path, source: &str) -> Result<Config, ConfigError> {
+        toml::from_str(source)
+            .map_err(|why| ConfigError::Invalid(Box::new(Diagnostic::new(path, source, &why))))
+    }
+}
+
+/// Checks a configuration file for errors, for the `config validate` command.
+pub fn validate(path: Option<&str>) -> Result<(), String> {
+    let path = Path::new(path.unwrap_or(CONFIG_PATH));
+    if !path.exists() {
+        return Err(format!("{}: does not exist", path.display()));
+    }
+
+    Config::load_from(path).map_err(|why| why.to_string())?;
+    println!("{}: ok", path.display());
+    Ok(())
+}
+
+/// Returns the first backtick-quoted word following the prefix.
+fn quoted_after<'a>(message: &'a str, prefix: &str) -> Option<&'a str> {
+    let rest = &message[message.find(prefix)? + prefix.len()..];
+    let rest = rest.strip_prefix('`')?;
+    Some(&rest[..rest.find('`')?])
+}
+
+/// Suggests the closest of the expected names, which serde lists in the message.
+fn suggest(found: &str, message: &str) -> Option<String> {
+    let expected = &message[message.find("expected")?..];
+    expected
+        .split('`')
+        .skip(1)
+        .step_by(2)
+        .map(|name| (edit_distance(found, name), name))
+        .filter(|&(distance, _)| distance <= 1.max(found.len() / 3))
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, name)| name.to_owned())
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = diagonal + if ca == cb { 0 } else { 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+/// Finds the 1-based line and column where a key is assigned within a table.
+fn find_key(source: &str, table: &str, key: &str) -> Option<(usize, usize)> {
+    let mut current = String::new();
+
+    for (number, line) in source.lines().enumerate() {
+        let trimmed = line.trim_start();
+
+        if trimmed.starts_with('[') {
+            current = trimmed.trim_end().trim_matches(|c| c == '[' || c == ']').trim().to_owned();
+            continue;
+        }
+
+        if current == table && trimmed.starts_with(key) {
+            let rest = trimmed[key.len()..].trim_start();
+            if rest.starts_with('=') {
+                return Some((number + 1, line.len() - trimmed.len() + 1));
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diagnose(source: &str) -> Diagnostic {
+        match Config::parse(Path::new("config.toml"), source) {
+            Err(ConfigError::Invalid(diagnostic)) => *diagnostic,
+            other => panic!("expected a diagnostic, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn misspelled_key() {
+        let diagnostic =
+            diagnose("[daemon]\ndefault_profile = \"battery\"\npci_runtme_pm = true\n");
+        assert_eq!(diagnostic.line, Some(3));
+        assert_eq!(diagnostic.column, Some(1));
+        assert_eq!(diagnostic.key.as_deref(), Some("daemon.pci_runtme_pm"));
+        assert_eq!(diagnostic.suggestion.as_deref(), Some("pci_runtime_pm"));
+    }
+
+    #[test]
+    fn misspelled_value() {
+        let diagnostic = diagnose("[daemon]\ndefault_profile = \"balance\"\n");
+        assert_eq!(diagnostic.line, Some(2));
+        assert_eq!(diagnostic.key.as_deref(), Some("daemon.default_profile"));
+        assert_eq!(diagnostic.suggestion.as_deref(), Some("balanced"));
+    }
+
+    #[test]
+    fn device_policies() {
+        let source =
+            "[devices]\n\"pci:8086:a0f0\" = \"on\"\n\"usb:046d:c52b\" = \"off-on-battery\"\n";
+        let config = Config::parse(Path::new("config.toml"), source).unwrap();
+        let policies: Vec<_> =
+            config.devices.iter().map(|(id, &policy)| (id.to_string(), policy)).collect();
+        assert_eq!(
+            policies,
+            vec![
+                (String::from("pci:8086:a0f0"), DevicePolicy::On),
+                (String::from("usb:046d:c52b"), DevicePolicy::OffOnBattery),
+            ]
+        );
+
+        let diagnostic = diagnose("[devices]\n\"8086:a0f0\" = \"on\"\n");
+        assert!(diagnostic.message.contains("invalid device"), "{}", diagnostic.message);
+    }
+
+    #[test]
+    fn nvidia_power_management() {
+        let source = "[nvidia.battery]\ndynamic_power_management = 1\nautosuspend_delay = 500\n";
+        let config = Config::parse(Path::new("config.toml"), source).unwrap();
+        let battery = config.nvidia.profile(Profile::Battery);
+        assert_eq!(battery.dynamic_power_management, Some(DynamicPowerManagement::Coarse));
+        assert_eq!(battery.autosuspend_delay, Some(500));
+
+        let diagnostic = diagnose("[nvidia.battery]\ndynamic_power_management = 3\n");
+        assert!(diagnostic.message.contains("expected 0 to 2"), "{}", diagnostic.message);
+    }
+
+    #[test]
+    fn fan_duty() {
+        let config =
+            Config::parse(Path::new("config.toml"), "[firmware]\nfan_duty = 80\n").unwrap();
+        assert_eq!(config.firmware.fan_duty, 80);
+
+        let diagnostic = diagnose("[firmware]\nfan_duty = 150\n");
+        assert!(diagnostic.message.contains("expected 0 to 100"), "{}", diagnostic.message);
+    }
+
+    #[test]
+    fn keyboard_colors() {
+        let source = "[keyboard.colors.battery]\nall = \"#FF0000\"\nleft = \"00ff00\"\n";
+        let config = Config::parse(Path::new("config.toml"), source).unwrap();
+        let battery = config.keyboard.colors.profile(Profile::Battery);
+        assert_eq!(battery.get("all"), Some(&Color(0xFF_0000)));
+        assert_eq!(battery.get("left"), Some(&Color(0x00_FF00)));
+        assert!(config.keyboard.colors.profile(Profile::Performance).is_empty());
+
+        let diagnostic = diagnose("[keyboard.colors.balanced]\nright = \"red\"\n");
+        assert!(diagnostic.message.contains("invalid color"), "{}", diagnostic.message);
+    }
+
+    #[test]
+    fn wrong_type() {
+        let diagnostic = diagnose("[daemon]\npci_runtime_pm = \"yes\"\n");
+        assert_eq!(diagnostic.key.as_deref(), Some("daemon.pci_runtime_pm"));
+        assert!(diagnostic.message.contains("expected a boolean"), "{}", diagnostic.message);
+    }
+}
@@ -0,0 +1,273 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Samples which processes use the most energy, similar to the overview of powertop, so that
+//! battery drain can be traced to the processes which cause it.
+//!
+//! The energy of the CPU package is read from RAPL, and attributed to each process by its share
+//! of the CPU time which was not idle. This is only an estimate: energy which devices use on
+//! behalf of a process is not counted, and the idle power of the package is shared among the
+//! processes which ran. Pressure stall information is reported alongside, as processes waiting on
+//! IO or memory keep the system busy without using much CPU time.
+
+use std::{
+    collections::HashMap,
+    fs,
+    time::{Duration, Instant},
+};
+
+const PROC: &str = "/proc";
+const CPU_STAT: &str = "/proc/stat";
+const PRESSURE: &str = "/proc/pressure";
+const RAPL_ENERGY: &str = "/sys/class/powercap/intel-rapl:0/energy_uj";
+// The value at which the energy counter wraps around.
+const RAPL_MAX_ENERGY: &str = "/sys/class/powercap/intel-rapl:0/max_energy_range_uj";
+
+/// A process which used CPU time over the interval of a report.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Consumer {
+    pub pid:   u32,
+    pub name:  String,
+    /// The CPU time used, as a percentage of one CPU, as in `top`.
+    pub cpu:   f64,
+    /// The estimated power, in watts, where RAPL is available.
+    pub watts: Option<f64>,
+}
+
+/// The percentage of the last 10 seconds in which some tasks were stalled on each resource,
+/// where the kernel reports pressure stall information.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Pressure {
+    pub cpu:    Option<f64>,
+    pub io:     Option<f64>,
+    pub memory: Option<f64>,
+}
+
+impl Pressure {
+    pub fn read() -> Self {
+        let read = |resource: &str| {
+            fs::read_to_string([PRESSURE, resource].join("/")).ok().and_then(|psi| some_avg10(&psi))
+        };
+        Pressure { cpu: read("cpu"), io: read("io"), memory: read("memory") }
+    }
+}
+
+/// The top consumers over the last interval, by CPU time.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Report {
+    pub interval:  Duration,
+    /// The power of the CPU package, in watts, where RAPL is available.
+    pub package:   Option<f64>,
+    pub pressure:  Pressure,
+    pub consumers: Vec<Consumer>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct CpuTime {
+    /// Clock ticks of every CPU which were not idle.
+    busy:  u64,
+    /// Clock ticks of every CPU.
+    total: u64,
+    cpus:  usize,
+}
+
+struct Snapshot {
+    time:      Instant,
+    cpu:       CpuTime,
+    /// The energy counter of the package, in microjoules.
+    energy:    Option<u64>,
+    /// The name and clock ticks of each process.
+    processes: HashMap<u32, (String, u64)>,
+}
+
+impl Snapshot {
+    fn read() -> Self {
+        let processes = fs::read_dir(PROC)
+            .map(|entries| {
+                entries
+                    .filter_map(Result::ok)
+                    .filter_map(|entry| {
+                        let pid = entry.file_name().to_str()?.parse().ok()?;
+                        let stat = fs::read_to_string(entry.path().join("stat")).ok()?;
+                        Some((pid, process_ticks(&stat)?))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Snapshot {
+            time: Instant::now(),
+            cpu: fs::read_to_string(CPU_STAT)
+                .ok()
+                .and_then(|stat| cpu_time(&stat))
+                .unwrap_or_default(),
+            energy: read_u64(RAPL_ENERGY),
+            processes,
+        }
+    }
+}
+
+/// Samples the CPU time of every process, reporting the top consumers between each sample.
+pub struct ConsumerSampler {
+    count:      usize,
+    max_energy: Option<u64>,
+    previous:   Option<Snapshot>,
+    report:     Option<Report>,
+}
+
+impl ConsumerSampler {
+    /// A sampler which reports the `count` processes which used the most CPU time.
+    pub fn new(count: usize) -> Self {
+        ConsumerSampler {
+            count,
+            max_energy: read_u64(RAPL_MAX_ENERGY),
+            previous: None,
+            report: None,
+        }
+    }
+
+    pub fn sample(&mut self) {
+        let next = Snapshot::read();
+        if let Some(previous) = self.previous.take() {
+            let mut report = report(&previous, &next, self.max_energy, self.count);
+            report.pressure = Pressure::read();
+            self.report = Some(report);
+        }
+        self.previous = Some(next);
+    }
+
+    /// The report of the last interval, once two samples have been taken.
+    pub fn report(&self) -> Option<&Report> { self.report.as_ref() }
+}
+
+fn report(previous: &Snapshot, next: &Snapshot, max_energy: Option<u64>, count: usize) -> Report {
+    let interval = next.time.saturating_duration_since(previous.time);
+    let busy = next.cpu.busy.saturating_sub(previous.cpu.busy);
+    let total = next.cpu.total.saturating_sub(previous.cpu.total);
+
+    let package = match (previous.energy, next.energy) {
+        (Some(before), Some(after)) if interval > Duration::from_secs(0) => {
+            // The counter wraps around to zero once it exceeds its range.
+            let energy = if after >= before {
+                after - before
+            } else {
+                max_energy.unwrap_or(u64::MAX).saturating_sub(before) + after
+            };
+            Some(energy as f64 / 1_000_000.0 / interval.as_secs_f64())
+        }
+        _ => None,
+    };
+
+    let mut consumers: Vec<Consumer> = next
+        .processes
+        .iter()
+        .filter_map(|(&pid, (name, ticks))| {
+            // Processes which started within the interval are counted from zero.
+            let before = previous.processes.get(&pid).map_or(0, |&(_, ticks)| ticks);
+            let ticks = ticks.saturating_sub(before);
+            if ticks == 0 || total == 0 {
+                return None;
+            }
+
+            Some(Consumer {
+                pid,
+                name: name.clone(),
+                cpu: ticks as f64 * next.cpu.cpus as f64 * 100.0 / total as f64,
+                watts: package.map(|watts| watts * ticks as f64 / busy.max(ticks) as f64),
+            })
+        })
+        .collect();
+
+    consumers.sort_by(|a, b| b.cpu.partial_cmp(&a.cpu).unwrap_or(std::cmp::Ordering::Equal));
+    consumers.truncate(count);
+
+    Report { interval, package, pressure: Pressure::default(), consumers }
+}
+
+fn read_u64(path: &str) -> Option<u64> { fs::read_to_string(path).ok()?.trim().parse().ok() }
+
+/// The name and the user and system clock ticks of a process, from `/proc/<pid>/stat`. The name
+/// is within parentheses, and may itself contain spaces and parentheses.
+fn process_ticks(stat: &str) -> Option<(String, u64)> {
+    let name = &stat[stat.find('(')? + 1..stat.rfind(')')?];
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    // utime and stime are the 14th and 15th fields, counting the PID and name.
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    Some((name.to_owned(), utime + stime))
+}
+
+/// The CPU time of every CPU, from the `cpu` line of `/proc/stat`, whose fields are the clock
+/// ticks spent in user, nice, system, idle, iowait, irq, softirq, and steal.
+fn cpu_time(stat: &str) -> Option<CpuTime> {
+    let mut lines = stat.lines();
+    let ticks: Vec<u64> = lines
+        .next()?
+        .strip_prefix("cpu ")?
+        .split_whitespace()
+        .take(8)
+        .map(|field| field.parse().ok())
+        .collect::<Option<_>>()?;
+    let total: u64 = ticks.iter().sum();
+    let idle = ticks.get(3)? + ticks.get(4).unwrap_or(&0);
+    let cpus = lines.filter(|line| line.starts_with("cpu")).count();
+
+    Some(CpuTime { busy: total - idle, total, cpus })
+}
+
+/// The `avg10` of the `some` line of a pressure file, such as
+/// `some avg10=1.50 avg60=0.80 avg300=0.20 total=123456`.
+fn some_avg10(psi: &str) -> Option<f64> {
+    psi.lines()
+        .find(|line| line.starts_with("some "))?
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix("avg10="))?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn top_consumers() {
+        let stat = "1234 (Web Content (1)) S 1 1234 1234 0 -1 4194560 100 0 0 0 250 50 0 0 20 0 \
+                    30 0 1000 0 0";
+        assert_eq!(process_ticks(stat), Some(("Web Content (1)".to_owned(), 300)));
+
+        let stat = "cpu  100 0 100 700 100 0 0 0 0 0\ncpu0 50 0 50 350 50 0 0 0 0 0\ncpu1 50 0 50 \
+                    350 50 0 0 0 0 0\nintr 0\n";
+        assert_eq!(cpu_time(stat), Some(CpuTime { busy: 200, total: 1000, cpus: 2 }));
+
+        let psi = "some avg10=1.50 avg60=0.80 avg300=0.20 total=123456\nfull avg10=0.50 \
+                   avg60=0.10 avg300=0.00 total=23456\n";
+        assert_eq!(some_avg10(psi), Some(1.5));
+
+        let start = Instant::now();
+        let snapshot =
+            |seconds: u64, busy: u64, energy: u64, processes: &[(u32, &str, u64)]| Snapshot {
+                time:      start + Duration::from_secs(seconds),
+                cpu:       CpuTime { busy, total: busy * 4, cpus: 2 },
+                energy:    Some(energy),
+                processes: processes
+                    .iter()
+                    .map(|&(pid, name, ticks)| (pid, (name.to_owned(), ticks)))
+                    .collect(),
+            };
+
+        let previous = snapshot(0, 1000, 9_000_000, &[(1, "init", 10), (2, "firefox", 500)]);
+        let next =
+            snapshot(10, 1200, 4_000_000, &[(1, "init", 10), (2, "firefox", 650), (3, "make", 50)]);
+
+        // 5 J over 10 s, with the counter wrapping around at 10 J.
+        let report = report(&previous, &next, Some(10_000_000), 1);
+        assert_eq!(report.interval, Duration::from_secs(10));
+        assert_eq!(report.package, Some(0.5));
+        assert_eq!(report.consumers.len(), 1);
+        assert_eq!(report.consumers[0].name, "firefox");
+        assert!((report.consumers[0].cpu - 37.5).abs() < f64::EPSILON);
+        assert_eq!(report.consumers[0].watts, Some(0.375));
+    }
+}
@@ -3,59 +3,226 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
 use dbus::{
-    arg,
+    arg::{self, OwnedFd, PropMap, RefArg, Variant},
+    blocking::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged,
     channel::{MatchingReceiver, Sender},
-    message::{MatchRule, Message},
-    nonblock::SyncConnection,
+    message::{MatchRule, Message, SignalArgs},
+    nonblock::{MsgMatch, SyncConnection},
 };
-use dbus_crossroads::{Crossroads, IfaceBuilder, MethodErr};
+use dbus_crossroads::{Context, Crossroads, IfaceBuilder, MethodErr};
 use dbus_tokio::connection;
 use std::{
+    cmp,
+    collections::HashMap,
+    ffi::CString,
     fmt::Debug,
     fs,
+    future::Future,
+    io, mem,
+    process::Command,
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        Arc, Mutex,
     },
     thread,
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
 use tokio::{
+    io::unix::AsyncFd,
     signal::unix::{signal, SignalKind},
-    time::sleep,
+    time::{self, MissedTickBehavior},
 };
 
-use futures::future::FutureExt;
+use futures::{
+    channel::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    future::{self, FutureExt},
+    StreamExt,
+};
+use intel_pstate::PState;
 
 use crate::{
+    acpi_platform,
+    adapter::{Adapter, AdapterLimit},
+    capabilities::Capability,
     charge_thresholds::{
-        get_charge_profiles, get_charge_thresholds, set_charge_thresholds, ChargeProfile,
+        self, battery_thresholds, get_charge_profiles, get_charge_thresholds,
+        profile_for_thresholds, set_charge_thresholds, BatteryThresholds, ChargeProfile,
+        ChargeSync,
     },
+    clamshell,
+    config::{
+        AutoPerformanceConfig, AutoProfileConfig, BenchConfig, BrightnessConfig, ClamshellConfig,
+        Config, EppConfig, ExternalGraphicsChanges, FirmwareConfig, KeyboardColors,
+        LinkSpeedConfig, PowerProfilesDaemon, Profile, SchedulerConfig,
+    },
+    consumers::ConsumerSampler,
+    devices::DevicePolicies,
+    drm::{self, DrmUser},
+    ec::{Color, EcError},
     err_str,
     errors::ProfileError,
-    fan::FanDaemon,
-    graphics::Graphics,
+    fan::{FanCurve, FanDaemon, FanReading},
+    graphics::{GpuRole, Graphics, GraphicsDevice, Switchable, TopologyDevice},
+    health::{self, Health, Sensors},
+    hibernate::Hibernation,
     hid_backlight,
+    hooks::Hooks,
+    hotkeys::{self, Bindings, Hotkey, HotkeyDevice},
     hotplug::{Detect, HotPlugDetect},
+    info,
     kernel_parameters::{KernelParameter, NmiWatchdog},
+    knobs::Knobs,
+    logind,
     mux::DisplayPortMux,
-    polkit, Power, DBUS_IFACE, DBUS_NAME, DBUS_PATH,
+    nvml::SharedNvml,
+    pci, polkit,
+    power_limit::{PowerLimit, Reason},
+    power_profiles::{self, PPD_IFACE, PPD_NAME, PPD_PATH},
+    power_source::PowerSource,
+    privileges,
+    quiet_hours::QuietHours,
+    s0ix::{self, Residency, SuspendReport},
+    safe_mode, safety, sched,
+    sensors::SensorCache,
+    state::StateStore,
+    switcheroo::{self, SWITCHEROO_IFACE, SWITCHEROO_NAME, SWITCHEROO_PATH},
+    uevent::{Uevent, UeventSocket},
+    upower, Power, DBUS_IFACE, DBUS_IFACE_V2, DBUS_NAME, DBUS_PATH,
 };
 
+mod access;
+mod auto_performance;
+mod bench;
+mod brightness;
+mod firmware;
+mod gpu_power;
+mod history;
+mod holds;
+mod jobs;
+mod keyboard;
+mod nvidia;
 mod profiles;
-
-use self::profiles::*;
+mod rate_limit;
+mod runtime_control;
+mod scripts;
+mod spindown;
+mod throttle;
+mod wifi;
+
+use self::{
+    auto_performance::AutoPerformance,
+    bench::BenchMode,
+    brightness::BrightnessMemory,
+    firmware::FirmwareUpdate,
+    gpu_power::AutoPower,
+    history::ThermalHistory,
+    holds::{ProfileHold, ProfileHolds},
+    jobs::{Calibration, Job, JobKind, Jobs},
+    keyboard::IdleDimmer,
+    nvidia::NvidiaProfiles,
+    profiles::*,
+    rate_limit::RateLimiter,
+    runtime_control::NvidiaPower,
+    scripts::ScriptRunner,
+    spindown::Spindown,
+    throttle::ThrottleMonitor,
+    wifi::WifiPowerSave,
+};
 
 const THRESHOLD_POLICY: &str = "com.system76.powerdaemon.set-charge-thresholds";
 
-static CONTINUE: AtomicBool = AtomicBool::new(true);
+const LIMITS_EXCEEDED: &str = "org.freedesktop.DBus.Error.LimitsExceeded";
+const ACCESS_DENIED: &str = "org.freedesktop.DBus.Error.AccessDenied";
+const POLICY_LOCKED: &str = "com.system76.PowerDaemon.Error.PolicyLocked";
+
+// Profile requests arriving within this window of the last profile change are coalesced, so
+// that a client quickly stepping through profiles only applies the final one.
+const PROFILE_DEBOUNCE: Duration = Duration::from_millis(500);
+
+// Applying a profile should not take longer than this, so that switching feels immediate.
+const PROFILE_LATENCY_TARGET: Duration = Duration::from_millis(500);
+
+// Each DBus sender may burst this many state-changing requests, refilled at the given rate.
+const RATE_LIMIT_BURST: u32 = 10;
+const RATE_LIMIT_PER_SECOND: u32 = 2;
+
+// How often fan duty cycles are updated.
+const FAN_INTERVAL: Duration = Duration::from_secs(1);
+
+// How often temperatures are recorded for the thermal history, which is coarser than the fan
+// interval so that recording does not keep the daemon waking up every second.
+const HISTORY_INTERVAL: Duration = Duration::from_secs(10);
+
+// How long the fans run at full speed during a fan test, which is long enough for them to spin
+// up fully.
+const FAN_TEST_DURATION: Duration = Duration::from_secs(15);
+
+// The charge thresholds of the battery before calibration lifted them.
+const CALIBRATION_STATE: &str = "battery-calibration";
+
+// The power of the dGPU which was last set manually, which is restored at startup in hybrid mode.
+const GRAPHICS_POWER_STATE: &str = "graphics-power";
+
+// Thresholds which charge the battery fully, whatever its current charge.
+const CALIBRATION_THRESHOLDS: (u8, u8) = (99, 100);
+
+// How often the AC adapter is checked for its wattage, which USB Power Delivery may negotiate
+// some time after it is plugged in.
+const ADAPTER_INTERVAL: Duration = Duration::from_secs(15);
+
+// How often the clock is checked for the start or end of quiet hours.
+const QUIET_HOURS_INTERVAL: Duration = Duration::from_secs(60);
 
-fn signal_handling() {
+// How often the charge thresholds and firmware preset are checked for changes made elsewhere.
+const CHARGE_INTERVAL: Duration = Duration::from_secs(15);
+
+// How often rotational drives are checked for spinning up.
+const SPINDOWN_INTERVAL: Duration = Duration::from_secs(60);
+
+// How often wireless links are checked for their quality, while a profile has power saving on.
+const WIFI_INTERVAL: Duration = Duration::from_secs(10);
+
+// How often the CPUs and GPUs are checked for throttling, which bounds how precisely the duration
+// of each event is known.
+const THROTTLE_INTERVAL: Duration = Duration::from_secs(10);
+
+// How often the graphics configuration files are checked for changes made by other tools.
+const GRAPHICS_INTERVAL: Duration = Duration::from_secs(30);
+
+// How often the NVIDIA GPU is checked for demand, while automatic graphics power is on in hybrid
+// mode.
+const GPU_POWER_INTERVAL: Duration = Duration::from_secs(2);
+
+// How often the lid and displays are checked for the start or end of clamshell operation.
+const CLAMSHELL_INTERVAL: Duration = Duration::from_secs(5);
+
+// How often the power source, lid, and temperature are checked for automatic performance.
+const AUTO_PERFORMANCE_INTERVAL: Duration = Duration::from_secs(10);
+
+// How often the CPU time of every process is sampled, while sampling is enabled.
+const CONSUMERS_INTERVAL: Duration = Duration::from_secs(10);
+
+// How often displays are checked for being turned off, while the keyboard backlight follows them
+// and DRM uevents cannot be listened to.
+const DPMS_INTERVAL: Duration = Duration::from_secs(2);
+
+// How often hotplug and display port mux state is polled, on hardware which requires it.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Registers handlers for termination signals, returning a future which resolves once any of
+/// them has been received.
+///
+/// Handlers are registered immediately, so that signals arriving before the future is first
+/// polled are not lost.
+fn exit_signal() -> impl Future<Output = ()> {
     let mut int = signal(SignalKind::interrupt()).unwrap();
     let mut hup = signal(SignalKind::hangup()).unwrap();
     let mut term = signal(SignalKind::terminate()).unwrap();
 
-    tokio::spawn(async move {
+    async move {
         let sig = futures::select! {
             _ = int.recv().fuse() => "SIGINT",
             _ = hup.recv().fuse() => "SIGHUP",
@@ -63,10 +230,221 @@ fn signal_handling() {
         };
 
         log::info!("caught signal: {}", sig);
-        CONTINUE.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Waits for the uevent socket to become readable, and reads all queued events. If the socket
+/// could not be created, this never resolves.
+async fn next_uevents(uevents: &Option<AsyncFd<UeventSocket>>) -> io::Result<Vec<Uevent>> {
+    let uevents = match uevents {
+        Some(uevents) => uevents,
+        None => return future::pending().await,
+    };
+
+    let mut guard = uevents.readable().await?;
+    let events = guard.get_inner().events();
+    guard.clear_ready();
+    events
+}
+
+/// Waits for the next message of a signal stream. If there is no stream, this never resolves.
+async fn next_message(stream: &mut Option<UnboundedReceiver<Message>>) -> Option<Message> {
+    match stream {
+        Some(stream) => stream.next().await,
+        None => future::pending().await,
+    }
+}
+
+/// Turns the keyboard backlight off or back on as the active session is locked or unlocked.
+async fn lock_changed(c: &SyncConnection, idle_dimmer: &mut IdleDimmer) {
+    match logind::active_session_locked(c).await {
+        Ok(locked) => idle_dimmer.locked_changed(locked),
+        Err(why) => log::warn!("failed to get whether the session is locked: {}", why),
+    }
+}
+
+/// Waits for one of the hotkey devices to become readable, and reads the hotkeys which were
+/// pressed on it, along with its index. If there are no devices, this never resolves.
+async fn next_hotkeys(
+    devices: &[AsyncFd<HotkeyDevice>],
+    bindings: &Bindings,
+) -> (usize, io::Result<Vec<Hotkey>>) {
+    if devices.is_empty() {
+        return future::pending().await;
+    }
+
+    let readable = devices.iter().map(|device| Box::pin(device.readable()));
+    let (guard, index, _) = future::select_all(readable).await;
+    let hotkeys = guard.and_then(|mut guard| {
+        let hotkeys = guard.get_inner().hotkeys(bindings);
+        guard.clear_ready();
+        hotkeys
     });
+
+    (index, hotkeys)
+}
+
+/// Opens the devices which report a bound hotkey.
+fn hotkey_devices(bindings: &Bindings) -> Vec<AsyncFd<HotkeyDevice>> {
+    hotkeys::devices(bindings)
+        .into_iter()
+        .filter_map(|device| {
+            log::debug!("listening for hotkeys on {}", device.path().display());
+            AsyncFd::new(device)
+                .map_err(|why| log::warn!("failed to listen for hotkeys: {}", why))
+                .ok()
+        })
+        .collect()
+}
+
+/// Reads the power source from UPower, falling back to sysfs for good if UPower fails.
+async fn read_power_source(
+    c: &SyncConnection,
+    upower: &mut Option<UnboundedReceiver<Message>>,
+) -> PowerSource {
+    if upower.is_some() {
+        match upower::power_source(c).await {
+            Ok(source) => return source,
+            Err(why) => {
+                log::info!("failed to read power source from UPower, using sysfs: {}", why);
+                *upower = None;
+            }
+        }
+    }
+
+    PowerSource::from_sysfs()
+}
+
+/// Subscribes to notifications of clients disconnecting from the bus, which releases their
+/// profile holds.
+async fn watch_disconnects(
+    c: &SyncConnection,
+) -> Result<(MsgMatch, UnboundedReceiver<Message>), dbus::Error> {
+    let rule = MatchRule::new_signal("org.freedesktop.DBus", "NameOwnerChanged")
+        .with_sender("org.freedesktop.DBus");
+    Ok(c.add_match(rule).await?.msg_stream())
+}
+
+/// Delays sleep, so that the system is prepared for sleep before it suspends.
+async fn inhibit_sleep(c: &SyncConnection) -> Option<OwnedFd> {
+    logind::inhibit_sleep(c, "Preparing devices for sleep")
+        .await
+        .map_err(|why| log::warn!("failed to inhibit sleep: {}", why))
+        .ok()
+}
+
+/// Delays shutdown, so that a pending graphics switch can be completed before rebooting.
+async fn inhibit_shutdown(c: &SyncConnection) -> Option<OwnedFd> {
+    logind::inhibit_shutdown(c, "Completing graphics switches")
+        .await
+        .map_err(|why| log::warn!("failed to inhibit shutdown: {}", why))
+        .ok()
+}
+
+/// Restores the charge thresholds which were saved before calibrating the battery.
+fn restore_charge_thresholds(overrides: &BatteryThresholds) {
+    let store = StateStore::default();
+    let thresholds = match store.load::<(u8, u8)>(CALIBRATION_STATE) {
+        Some(thresholds) => thresholds,
+        None => return,
+    };
+
+    if let Err(why) = set_charge_thresholds(thresholds, overrides) {
+        log::warn!("failed to restore charge thresholds: {}", why);
+        return;
+    }
+
+    if let Err(why) = store.remove(CALIBRATION_STATE) {
+        log::warn!("failed to remove saved charge thresholds: {}", why);
+    }
+}
+
+/// Checks that every fan sped up while running at full speed.
+fn check_fan_speeds(idle: &[u32], full: &[u32]) -> Result<(), String> {
+    if full.is_empty() {
+        return Err("no fan speeds are reported".into());
+    }
+
+    let slow: Vec<String> = full
+        .iter()
+        .enumerate()
+        .filter(|&(i, &speed)| speed == 0 || idle.get(i).map_or(false, |&idle| speed <= idle))
+        .map(|(i, speed)| {
+            format!(
+                "fan {} did not speed up ({} RPM idle, {} RPM at full speed)",
+                i + 1,
+                idle.get(i).cloned().unwrap_or_default(),
+                speed
+            )
+        })
+        .collect();
+
+    if slow.is_empty() {
+        Ok(())
+    } else {
+        Err(slow.join(", "))
+    }
+}
+
+fn profile_fn(profile: Profile) -> (ProfileFn, &'static str) {
+    match profile {
+        Profile::Battery => (battery, "Battery"),
+        Profile::Balanced => (balanced, "Balanced"),
+        Profile::Performance => (performance, "Performance"),
+    }
+}
+
+/// Parses a profile name, as returned by `GetProfile`, ignoring case.
+fn profile_from_name(name: &str) -> Option<Profile> {
+    match name.to_lowercase().as_str() {
+        "battery" => Some(Profile::Battery),
+        "balanced" => Some(Profile::Balanced),
+        "performance" => Some(Profile::Performance),
+        _ => None,
+    }
+}
+
+/// Events which wake the daemon's main loop.
+enum Event {
+    Exit,
+    FanTick,
+    HistoryTick,
+    PollTick,
+    PendingProfile,
+    QuietHoursTick,
+    AdapterTick,
+    ChargeTick,
+    SpindownTick,
+    ThrottleTick,
+    WifiTick,
+    GraphicsTick,
+    UdevSettled,
+    PlatformProfileChanged,
+    GpuPowerTick,
+    ClamshellTick,
+    AutoPerformanceTick,
+    ConsumersTick,
+    DpmsTick,
+    Uevents(io::Result<Vec<Uevent>>),
+    Hotkeys(usize, io::Result<Vec<Hotkey>>),
+    Job(JobMessage),
+    FanTestDone,
+    BenchExpired,
+    FirmwareUpdateExpired,
+    UPower(Option<Message>),
+    PowerProfiles(Option<Message>),
+    NameOwnerChanged(Option<Message>),
+    IdleChanged(Option<Message>),
+    LockChanged(Option<Message>),
+    PrepareForShutdown(Option<Message>),
+    PrepareForSleep(Option<Message>),
+    ForcePowerOff(PowerOffRequest),
 }
 
+/// A request to force a discrete GPU off, or the switched GPUs if the device is empty, once the
+/// processes which had it open were stopped, along with where to send the result.
+type PowerOffRequest = (String, oneshot::Sender<Result<(), String>>);
+
 // Disabled by default because some systems have quirky ACPI tables that fail to resume from
 // suspension.
 static PCI_RUNTIME_PM: AtomicBool = AtomicBool::new(false);
@@ -74,292 +452,3208 @@ static PCI_RUNTIME_PM: AtomicBool = AtomicBool::new(false);
 // TODO: Whitelist system76 hardware that's known to work with this setting.
 pub(crate) fn pci_runtime_pm_support() -> bool { PCI_RUNTIME_PM.load(Ordering::SeqCst) }
 
+type ProfileFn = fn(&mut ProfileReport, bool);
+
+/// Messages about jobs, sent to the main loop.
+pub(crate) enum JobMessage {
+    /// A job which ran on another thread has finished.
+    Finished(u32, Result<(), String>),
+    /// Fan tests are run by the main loop, which controls the fans.
+    StartFanTest(u32),
+    CancelFanTest(u32),
+    /// A profile script exited, with the number it was queued as.
+    ScriptFinished(u64, Result<(), String>),
+}
+
+/// A fan test in progress, which runs the fans at full speed for a while.
+struct FanTest {
+    id:       u32,
+    idle:     Vec<u32>,
+    boost:    bool,
+    deadline: time::Instant,
+}
+
 struct PowerDaemon {
-    initial_set:     bool,
-    graphics:        Graphics,
-    power_profile:   String,
-    profile_errors:  Vec<ProfileError>,
-    dbus_connection: Arc<SyncConnection>,
+    initial_set:      bool,
+    graphics:         Graphics,
+    capabilities:     Vec<Capability>,
+    power_profile:    String,
+    /// How each subsystem fared when the profile was last applied.
+    profile_report:   ProfileReport,
+    profile_changed:  Option<Instant>,
+    pending_profile:  Option<(ProfileFn, &'static str)>,
+    auto_profile:     AutoProfileConfig,
+    auto_performance: AutoPerformance,
+    clamshell:        ClamshellConfig,
+    /// The profile to restore once clamshell operation ends, which is set while it lasts.
+    clamshell_from:   Option<Profile>,
+    power_source:     Option<PowerSource>,
+    brightness:       BrightnessMemory,
+    brightness_step:  BrightnessConfig,
+    /// The screen brightness of a new power source was restored, so the profile applied for it
+    /// should leave the brightness alone.
+    keep_brightness:  bool,
+    /// Whether the brightness of the screen and keyboard is managed, as configured.
+    manage_lights:    bool,
+    keyboard_colors:  KeyboardColors,
+    ppd_replaced:     bool,
+    holds:            ProfileHolds,
+    /// The profile to restore once every hold has been released.
+    unheld_profile:   Option<Profile>,
+    /// The profile to restore when the performance hotkey is pressed again.
+    toggled_from:     Option<Profile>,
+    quiet_hours:      QuietHours,
+    adapter:          AdapterLimit,
+    power_limit:      PowerLimit,
+    /// The points of the fan curve, in millidegrees Celsius and hundredths of a percent.
+    fan_curve:        Vec<(i32, u16)>,
+    nvidia:           NvidiaProfiles,
+    scheduler:        SchedulerConfig,
+    epp:              EppConfig,
+    knobs:            Knobs,
+    devices:          DevicePolicies,
+    spindown:         Spindown,
+    throttle:         ThrottleMonitor,
+    wifi:             WifiPowerSave,
+    scripts:          ScriptRunner,
+    jobs:             Jobs,
+    /// Callers which are waiting for a job to finish, such as of the original SetGraphics.
+    job_waiters:      Vec<(u32, oneshot::Sender<Result<(), String>>)>,
+    /// Charge thresholds of batteries which the configuration sets apart from the others.
+    bat_thresholds:   BatteryThresholds,
+    bench:            Option<BenchMode>,
+    bench_config:     BenchConfig,
+    firmware:         Option<FirmwareUpdate>,
+    firmware_config:  FirmwareConfig,
+    external_gfx:     ExternalGraphicsChanges,
+    link_speed:       LinkSpeedConfig,
+    /// Applications which are always launched on the discrete GPU.
+    offload:          Vec<String>,
+    /// Whether the interface of switcheroo-control is served here.
+    switcheroo:       bool,
+    /// Whether graphics modes and the power of discrete GPUs are managed, as configured.
+    manage_gfx:       bool,
+    /// The configured graphics mode and the mode which the loaded modules match, if they
+    /// disagreed at startup.
+    gfx_mismatch:     Option<(String, &'static str)>,
+    /// Whether the graphics configuration is yet to be compared with the loaded modules, which
+    /// waits until udev has settled, or a client asks.
+    gfx_unchecked:    bool,
+    gpu_power:        AutoPower,
+    history:          ThermalHistory,
+    /// hwmon readings, shared with the fan daemon.
+    sensors:          Arc<SensorCache>,
+    /// NVML, which is kept loaded between uses.
+    nvml:             Arc<SharedNvml>,
+    hooks:            Hooks,
+    /// The ACPI platform profile when it was last checked.
+    platform_prof:    Option<String>,
+    /// How much of the last s2idle suspend was spent in low power states.
+    suspend_report:   Option<SuspendReport>,
+    /// The power of the dGPU when the system went to sleep, which is restored on resume.
+    sleep_gpu_power:  Option<bool>,
+    /// Samples the processes which use the most energy, if enabled.
+    consumers:        Option<ConsumerSampler>,
+    /// The job and stage of a battery calibration in progress.
+    calibration:      Option<(u32, Calibration)>,
+    job_sender:       UnboundedSender<JobMessage>,
+    rate_limiter:     RateLimiter,
+    dbus_connection:  Arc<SyncConnection>,
 }
 
 impl PowerDaemon {
-    fn new(dbus_connection: Arc<SyncConnection>) -> Result<PowerDaemon, String> {
-        let graphics = Graphics::new().map_err(err_str)?;
-        Ok(PowerDaemon {
+    fn new(
+        dbus_connection: Arc<SyncConnection>,
+        job_sender: UnboundedSender<JobMessage>,
+    ) -> PowerDaemon {
+        let graphics = Graphics::new().unwrap_or_else(|why| {
+            log::warn!("failed to enumerate graphics devices: {}", why);
+            Graphics::default()
+        });
+
+        PowerDaemon {
             initial_set: false,
             graphics,
+            capabilities: Vec::new(),
             power_profile: String::new(),
-            profile_errors: Vec::new(),
+            profile_report: ProfileReport::default(),
+            profile_changed: None,
+            pending_profile: None,
+            auto_profile: AutoProfileConfig::default(),
+            auto_performance: AutoPerformance::new(AutoPerformanceConfig::default()),
+            clamshell: ClamshellConfig::default(),
+            clamshell_from: None,
+            power_source: None,
+            brightness: BrightnessMemory::load(),
+            brightness_step: BrightnessConfig::default(),
+            keep_brightness: false,
+            manage_lights: true,
+            keyboard_colors: KeyboardColors::default(),
+            ppd_replaced: false,
+            holds: ProfileHolds::default(),
+            unheld_profile: None,
+            toggled_from: None,
+            quiet_hours: QuietHours::new(Default::default()),
+            adapter: AdapterLimit::new(Default::default()),
+            power_limit: PowerLimit::default(),
+            fan_curve: Vec::new(),
+            nvidia: NvidiaProfiles::default(),
+            scheduler: SchedulerConfig::default(),
+            epp: EppConfig::default(),
+            knobs: Knobs::default(),
+            devices: DevicePolicies::default(),
+            spindown: Spindown::default(),
+            throttle: ThrottleMonitor::default(),
+            wifi: WifiPowerSave::default(),
+            scripts: ScriptRunner::new(Default::default(), job_sender.clone()),
+            jobs: Jobs::default(),
+            job_waiters: Vec::new(),
+            bat_thresholds: BatteryThresholds::new(),
+            bench: None,
+            bench_config: BenchConfig::default(),
+            firmware: None,
+            firmware_config: FirmwareConfig::default(),
+            external_gfx: ExternalGraphicsChanges::default(),
+            link_speed: LinkSpeedConfig::default(),
+            offload: Vec::new(),
+            switcheroo: false,
+            manage_gfx: true,
+            gfx_mismatch: None,
+            gfx_unchecked: false,
+            gpu_power: AutoPower::default(),
+            history: ThermalHistory::default(),
+            sensors: Arc::new(SensorCache::new(FAN_INTERVAL)),
+            nvml: Arc::new(SharedNvml::default()),
+            hooks: Hooks::default(),
+            platform_prof: None,
+            suspend_report: None,
+            sleep_gpu_power: None,
+            consumers: None,
+            calibration: None,
+            job_sender,
+            rate_limiter: RateLimiter::new(RATE_LIMIT_BURST, RATE_LIMIT_PER_SECOND),
             dbus_connection,
-        })
+        }
     }
 
-    fn apply_profile(
-        &mut self,
-        func: fn(&mut Vec<ProfileError>, bool),
-        name: &str,
-    ) -> Result<(), String> {
-        if self.power_profile == name {
-            log::info!("profile was already set");
+    /// Applies a profile, unless another profile change happened very recently. In that case the
+    /// request is deferred, replacing any other deferred request, and applied once the requests
+    /// settle down by `apply_pending_profile`.
+    fn apply_profile(&mut self, func: ProfileFn, name: &'static str) -> Result<(), String> {
+        if self.firmware.is_some() {
+            log::info!("deferring {} profile until the firmware update ends", name);
+            self.pending_profile = Some((func, name));
             return Ok(());
         }
 
-        func(&mut self.profile_errors, self.initial_set);
+        if let Some(changed) = self.profile_changed {
+            if changed.elapsed() < PROFILE_DEBOUNCE {
+                log::info!("deferring {} profile", name);
+                self.pending_profile = Some((func, name));
+                return Ok(());
+            }
+        }
 
-        let message =
-            Message::new_signal(DBUS_PATH, DBUS_NAME, "PowerProfileSwitch").unwrap().append1(name);
+        self.pending_profile = None;
+        self.apply_profile_now(func, name)
+    }
 
-        if let Err(()) = self.dbus_connection.send(message) {
-            log::error!("failed to send power profile switch message");
+    /// The profile which is in effect once the deferred profile request, if any, is applied, so
+    /// that a client which was debounced reads back the profile it asked for.
+    fn requested_profile(&self) -> &str {
+        self.pending_profile.as_ref().map_or(&self.power_profile, |&(_, name)| name)
+    }
+
+    /// When the deferred profile request, if any, should be applied.
+    fn pending_profile_deadline(&self) -> Option<Instant> {
+        if self.firmware.is_some() {
+            return None;
         }
 
-        self.power_profile = name.into();
+        self.pending_profile.as_ref()?;
+        Some(self.profile_changed.map_or_else(Instant::now, |changed| changed + PROFILE_DEBOUNCE))
+    }
 
-        if self.profile_errors.is_empty() {
-            Ok(())
-        } else {
-            let mut error_message = String::from("Errors found when setting profile:");
-            for error in self.profile_errors.drain(..) {
-                error_message = format!("{}\n    - {}", error_message, error);
-            }
+    /// Applies the last deferred profile request, if the debounce period has elapsed.
+    fn apply_pending_profile(&mut self) {
+        let ready =
+            self.profile_changed.map_or(true, |changed| changed.elapsed() >= PROFILE_DEBOUNCE);
+        if !ready {
+            return;
+        }
 
-            Err(error_message)
+        if let Some((func, name)) = self.pending_profile.take() {
+            if let Err(why) = self.apply_profile_now(func, name) {
+                log::warn!("{}", why);
+            }
         }
     }
-}
 
-impl Power for PowerDaemon {
-    fn battery(&mut self) -> Result<(), String> {
-        self.apply_profile(battery, "Battery").map_err(err_str)
-    }
+    /// Applies a profile which was selected by the user or the configuration, replacing any
+    /// profile that applications are holding.
+    fn set_profile(&mut self, profile: Profile) -> Result<(), String> {
+        for cookie in self.holds.release_all() {
+            self.profile_released(cookie);
+        }
 
-    fn balanced(&mut self) -> Result<(), String> {
-        self.apply_profile(balanced, "Balanced").map_err(err_str)
+        self.unheld_profile = None;
+        let (func, name) = profile_fn(profile);
+        self.apply_profile(func, name)
     }
 
-    fn performance(&mut self) -> Result<(), String> {
-        self.apply_profile(performance, "Performance").map_err(err_str)
-    }
+    fn hold_profile(&mut self, hold: ProfileHold) -> Result<u32, String> {
+        if !ProfileHolds::can_hold(hold.profile) {
+            return Err(format!("the {:?} profile cannot be held", hold.profile));
+        }
 
-    fn get_external_displays_require_dgpu(&mut self) -> Result<bool, String> {
-        self.graphics.get_external_displays_require_dgpu().map_err(err_str)
-    }
+        log::info!(
+            "{} ({}) is holding the {:?} profile: {}",
+            hold.app_id,
+            hold.owner,
+            hold.profile,
+            hold.reason
+        );
 
-    fn get_default_graphics(&mut self) -> Result<String, String> {
-        self.graphics.get_default_graphics().map_err(err_str)
+        if self.holds.is_empty() {
+            self.unheld_profile = profile_from_name(&self.power_profile);
+        }
+
+        let cookie = self.holds.hold(hold);
+        self.apply_held_profile()?;
+        Ok(cookie)
     }
 
-    fn get_graphics(&mut self) -> Result<String, String> {
-        self.graphics.get_vendor().map_err(err_str)
+    fn release_profile(&mut self, cookie: u32) -> Result<(), String> {
+        let hold =
+            self.holds.release(cookie).ok_or_else(|| format!("no hold with cookie {}", cookie))?;
+        log::info!("{} released the {:?} profile", hold.app_id, hold.profile);
+        self.profile_released(cookie);
+        self.apply_held_profile()
     }
 
-    fn get_profile(&mut self) -> Result<String, String> { Ok(self.power_profile.clone()) }
+    /// Releases the holds of a client which disconnected from the bus.
+    fn release_owner(&mut self, owner: &str) {
+        if self.firmware.as_ref().map_or(false, |update| update.owner == owner) {
+            log::warn!("{} disconnected during a firmware update", owner);
+            self.end_firmware_update();
+        }
+
+        let cookies = self.holds.release_owner(owner);
+        if cookies.is_empty() {
+            return;
+        }
 
-    fn get_switchable(&mut self) -> Result<bool, String> { Ok(self.graphics.can_switch()) }
+        log::info!("{} disconnected, releasing {} profile holds", owner, cookies.len());
+        for cookie in cookies {
+            self.profile_released(cookie);
+        }
 
-    fn set_graphics(&mut self, vendor: &str) -> Result<(), String> {
-        self.graphics.set_vendor(vendor).map_err(err_str)
+        if let Err(why) = self.apply_held_profile() {
+            log::warn!("{}", why);
+        }
     }
 
-    fn get_graphics_power(&mut self) -> Result<bool, String> {
-        self.graphics.get_power().map_err(err_str)
+    /// Applies the profile required by the remaining holds, or restores the profile which was
+    /// active before the first hold.
+    fn apply_held_profile(&mut self) -> Result<(), String> {
+        let profile = match self.holds.profile() {
+            Some(profile) => profile,
+            None => match self.unheld_profile.take() {
+                Some(profile) => profile,
+                None => return Ok(()),
+            },
+        };
+
+        let (func, name) = profile_fn(profile);
+        self.apply_profile(func, name)
     }
 
-    fn set_graphics_power(&mut self, power: bool) -> Result<(), String> {
-        self.graphics.set_power(power).map_err(err_str)
+    fn profile_released(&self, cookie: u32) {
+        let mut messages = vec![Message::new_signal(DBUS_PATH, DBUS_NAME, "ProfileReleased")
+            .unwrap()
+            .append1(cookie)];
+        if self.ppd_replaced {
+            messages.push(
+                Message::new_signal(PPD_PATH, PPD_IFACE, "ProfileReleased")
+                    .unwrap()
+                    .append1(cookie),
+            );
+        }
+
+        for message in messages {
+            if let Err(()) = self.dbus_connection.send(message) {
+                log::error!("failed to send profile released message");
+            }
+        }
     }
 
-    fn auto_graphics_power(&mut self) -> Result<(), String> {
-        self.graphics.auto_power().map_err(err_str)
+    /// The value of the `ActiveProfileHolds` property of power-profiles-daemon.
+    fn ppd_holds(&self) -> Vec<PropMap> {
+        self.holds
+            .iter()
+            .map(|hold| {
+                let profile = power_profiles::to_ppd(profile_fn(hold.profile).1);
+                let mut map = PropMap::new();
+                map.insert(
+                    "ApplicationId".into(),
+                    Variant(Box::new(hold.app_id.clone()) as Box<dyn RefArg>),
+                );
+                map.insert("Profile".into(), Variant(Box::new(profile.to_owned())));
+                map.insert("Reason".into(), Variant(Box::new(hold.reason.clone())));
+                map
+            })
+            .collect()
     }
 
-    fn get_charge_thresholds(&mut self) -> Result<(u8, u8), String> { get_charge_thresholds() }
+    fn on_battery(&self) -> bool { self.power_source.map_or(false, |source| source.on_battery) }
 
-    fn set_charge_thresholds(&mut self, thresholds: (u8, u8)) -> Result<(), String> {
-        // NOTE: This method is not actually called by daemon
-        set_charge_thresholds(thresholds)
-    }
+    /// Records the current power source. If automatic profile switching is enabled, a profile
+    /// is applied when AC power is connected or disconnected, or the battery becomes low.
+    fn power_source_changed(&mut self, source: PowerSource) {
+        self.calibration_step(&source);
 
-    fn get_charge_profiles(&mut self) -> Result<Vec<ChargeProfile>, String> {
-        Ok(get_charge_profiles())
-    }
-}
+        if source.on_battery != self.on_battery() {
+            self.devices.apply(source.on_battery);
+        }
 
-#[tokio::main(flavor = "current_thread")]
-pub async fn daemon() -> Result<(), String> {
-    signal_handling();
-    let pci_runtime_pm = std::env::var("S76_POWER_PCI_RUNTIME_PM").ok().map_or(false, |v| v == "1");
+        // The profile at startup is left alone, as it was chosen by the configuration.
+        let previous = match self.power_source.replace(source) {
+            Some(previous) if previous != source => previous,
+            _ => return,
+        };
 
-    log::info!(
-        "Starting daemon{}",
-        if pci_runtime_pm { " with pci runtime pm support enabled" } else { "" }
-    );
-    PCI_RUNTIME_PM.store(pci_runtime_pm, Ordering::SeqCst);
+        log::debug!("power source changed: {:?}", source);
+        let restored = source.on_battery != previous.on_battery
+            && self.manage_lights
+            && self.brightness.power_source_changed(previous.on_battery, source.on_battery);
 
-    log::info!("Connecting to dbus system bus");
-    let (resource, c) = connection::new_system_sync().map_err(err_str)?;
+        if !self.auto_profile.enabled {
+            return;
+        }
 
-    tokio::spawn(async {
-        let err = resource.await;
-        panic!("Lost connection to D-Bus: {}", err);
-    });
+        let profile = if source.on_battery && source.low && !(previous.on_battery && previous.low) {
+            log::info!("battery is low, switching profile");
+            self.auto_profile.low_battery
+        } else if source.on_battery != previous.on_battery {
+            log::info!(
+                "AC power {}, switching profile",
+                if source.on_battery { "disconnected" } else { "connected" }
+            );
+            if source.on_battery {
+                self.auto_profile.battery
+            } else {
+                self.auto_profile.ac
+            }
+        } else {
+            return;
+        };
 
-    let mut daemon = PowerDaemon::new(c.clone())?;
-    let nvidia_exists = !daemon.graphics.nvidia.is_empty();
+        self.keep_brightness = restored;
+        if let Err(why) = self.set_profile(profile) {
+            log::warn!("failed to switch profile automatically: {}", why);
+        }
+    }
 
-    log::info!("Disabling NMI Watchdog (for kernel debugging only)");
-    NmiWatchdog::default().set(b"0");
+    /// Applies the clamshell profile once the lid is closed with an external display connected on
+    /// AC power, and restores the previous profile once that ends, unless the profile was changed
+    /// in the meantime. Returns whether clamshell operation started or ended.
+    fn update_clamshell(&mut self) -> Option<bool> {
+        let active = !self.on_battery()
+            && clamshell::lid_closed()
+            && clamshell::external_display_connected();
+        if active == self.clamshell_from.is_some() {
+            return None;
+        }
 
-    // Get the NVIDIA device ID before potentially removing it.
-    let nvidia_device_id = if nvidia_exists {
-        fs::read_to_string("/sys/bus/pci/devices/0000:01:00.0/device").ok()
-    } else {
-        None
-    };
+        let profile = self.clamshell.profile;
+        let res = if active {
+            log::info!("operating as a clamshell, applying the {:?} profile", profile);
+            self.clamshell_from =
+                Some(profile_from_name(&self.power_profile).unwrap_or(Profile::Balanced));
+            self.set_profile(profile)
+        } else {
+            let previous = self.clamshell_from.take().unwrap_or(Profile::Balanced);
+            if profile_from_name(&self.power_profile) != Some(profile) {
+                return Some(false);
+            }
+
+            log::info!("no longer operating as a clamshell, restoring the {:?} profile", previous);
+            self.set_profile(previous)
+        };
 
-    log::info!("Setting automatic graphics power");
-    match daemon.auto_graphics_power() {
-        Ok(()) => (),
-        Err(err) => {
-            log::warn!("Failed to set automatic graphics power: {}", err);
+        if let Err(why) = res {
+            log::warn!("failed to switch profile for clamshell operation: {}", why);
         }
+
+        Some(active)
     }
 
-    log::info!("Initializing with the balanced profile");
-    if let Err(why) = daemon.balanced() {
-        log::warn!("Failed to set initial profile: {}", why);
+    /// The temperature of the hottest CPU or GPU, in thousandths of a Celsius. NVIDIA GPUs are
+    /// only read through NVML while they are awake, as reading them would wake them up.
+    fn hottest_temp(&self) -> Option<u32> {
+        let active: Vec<&GraphicsDevice> =
+            self.graphics.nvidia.iter().filter(|gpu| gpu.is_active()).collect();
+        let nvidia = if active.is_empty() {
+            None
+        } else {
+            match self.nvml.get() {
+                Ok(nvml) => active
+                    .iter()
+                    .filter_map(|gpu| nvml.device_by_pci_id(gpu.id()).ok()?.temperature().ok())
+                    .max()
+                    .map(|celsius| celsius * 1000),
+                Err(why) => {
+                    log::debug!("failed to read NVIDIA temperatures: {}", why);
+                    None
+                }
+            }
+        };
+
+        cmp::max(self.sensors.temp(), nvidia)
     }
-    daemon.initial_set = true;
 
-    log::info!("Registering dbus name {}", DBUS_NAME);
-    c.request_name(DBUS_NAME, false, true, false).await.map_err(err_str)?;
+    /// Applies the performance profile while on AC power, below the maximum temperature, and not
+    /// operating as a clamshell, and restores the previous profile once any of these ends. Held
+    /// profiles are left alone.
+    fn update_auto_performance(&mut self) {
+        if !self.holds.is_empty() {
+            return;
+        }
 
-    log::info!("Adding dbus path {} with interface {}", DBUS_PATH, DBUS_IFACE);
-    let mut cr = Crossroads::new();
-    cr.set_async_support(Some((
-        c.clone(),
-        Box::new(|x| {
-            tokio::spawn(x);
-        }),
-    )));
-    let iface_token = cr.register(DBUS_IFACE, |b| {
-        sync_action_method(b, "Performance", PowerDaemon::performance);
-        sync_action_method(b, "Balanced", PowerDaemon::balanced);
-        sync_action_method(b, "Battery", PowerDaemon::battery);
-        sync_get_method(
-            b,
-            "GetExternalDisplaysRequireDGPU",
-            "required",
-            PowerDaemon::get_external_displays_require_dgpu,
-        );
-        sync_get_method(b, "GetDefaultGraphics", "vendor", PowerDaemon::get_default_graphics);
-        sync_get_method(b, "GetGraphics", "vendor", PowerDaemon::get_graphics);
-        sync_set_method(b, "SetGraphics", "vendor", |d, s: String| d.set_graphics(&s));
-        sync_get_method(b, "GetProfile", "profile", PowerDaemon::get_profile);
-        sync_get_method(b, "GetSwitchable", "switchable", PowerDaemon::get_switchable);
-        sync_get_method(b, "GetGraphicsPower", "power", PowerDaemon::get_graphics_power);
-        sync_set_method(b, "SetGraphicsPower", "power", PowerDaemon::set_graphics_power);
-        sync_get_method(b, "GetChargeThresholds", "thresholds", PowerDaemon::get_charge_thresholds);
-        let c_clone = c.clone();
-        b.method_with_cr_async(
-            "SetChargeThresholds",
-            ("thresholds",),
-            (),
-            move |mut ctx, _cr, (thresholds,): ((u8, u8),)| {
-                let sender = ctx.message().sender().unwrap().into_static();
-                let c = c_clone.clone();
-                let res = async move {
-                    let pid = polkit::get_connection_unix_process_id(&c, sender)
-                        .await
-                        .map_err(err_str)?;
-                    let permitted = if pid == 0 {
-                        true
-                    } else {
-                        polkit::check_authorization(&c, pid, 0, THRESHOLD_POLICY)
-                            .await
-                            .map_err(err_str)?
-                    };
-                    if permitted {
-                        set_charge_thresholds(thresholds)?;
-                        Ok(())
-                    } else {
-                        Err("Operation not permitted by Polkit".to_string())
-                    }
-                };
-                async move { ctx.reply(res.await.map_err(|e| MethodErr::failed(&e))) }
-            },
-        );
-        sync_get_method(b, "GetChargeProfiles", "profiles", PowerDaemon::get_charge_profiles);
-        b.signal::<(u64,), _>("HotPlugDetect", ("port",));
-        b.signal::<(&str,), _>("PowerProfileSwitch", ("profile",));
-    });
-    cr.insert(DBUS_PATH, &[iface_token], daemon);
+        let temp = self.hottest_temp();
 
-    let cr = Arc::new(std::sync::Mutex::new(cr));
-    c.start_receive(
-        MatchRule::new_method_call(),
-        Box::new(move |msg, c| {
-            cr.lock().unwrap().handle_message(msg, c).unwrap();
-            true
-        }),
-    );
+        let clamshell = clamshell::lid_closed() && clamshell::external_display_connected();
+        let current = profile_from_name(&self.power_profile);
+        let profile = match self.auto_performance.poll(!self.on_battery(), clamshell, temp, current)
+        {
+            Some(profile) => profile,
+            None => return,
+        };
 
-    // Spawn hid backlight daemon
-    let _hid_backlight = thread::spawn(hid_backlight::daemon);
+        log::info!("automatically applying the {:?} profile", profile);
+        if let Err(why) = self.set_profile(profile) {
+            log::warn!("failed to switch profile automatically: {}", why);
+        }
+    }
 
-    let mut fan_daemon = FanDaemon::new(nvidia_exists);
+    /// Switches to the performance profile, or back to the profile which was active before it.
+    fn toggle_performance(&mut self) -> Result<(), String> {
+        let profile = if self.requested_profile() == "Performance" {
+            self.toggled_from.take().unwrap_or(Profile::Balanced)
+        } else {
+            self.toggled_from = profile_from_name(self.requested_profile());
+            Profile::Performance
+        };
 
-    let mut hpd_res = unsafe { HotPlugDetect::new(nvidia_device_id) };
+        self.set_profile(profile)
+    }
 
-    let mux_res = unsafe { DisplayPortMux::new() };
+    fn job_progress(&mut self, id: u32, stage: &'static str, progress: Option<u8>) {
+        let job = match self.jobs.update(id, stage, progress) {
+            Some(job) => job,
+            None => return,
+        };
 
-    let mut hpd = || -> [bool; 4] {
-        if let Ok(ref mut hpd) = hpd_res {
-            unsafe { hpd.detect() }
-        } else {
-            [false; 4]
+        log::info!("{} job {}: {}", job.kind.as_str(), id, stage);
+        let message = Message::new_signal(DBUS_PATH, DBUS_NAME, "JobProgress")
+            .unwrap()
+            .append3(id, job.kind.as_str(), stage)
+            .append1(job.progress.map_or(-1, i32::from));
+        if let Err(()) = self.dbus_connection.send(message) {
+            log::error!("failed to send job progress message");
         }
-    };
 
-    let mut last = hpd();
+        if job.kind == JobKind::GraphicsSwitch {
+            let vendor = job.description.clone();
+            self.graphics_switch_progress(&vendor, stage);
+        }
+    }
 
-    log::info!("Handling dbus requests");
-    while CONTINUE.load(Ordering::SeqCst) {
-        sleep(Duration::from_millis(1000)).await;
+    /// Reports the stage of a graphics switch, for clients which only follow graphics switches
+    /// rather than every job.
+    fn graphics_switch_progress(&self, vendor: &str, stage: &str) {
+        let message = Message::new_signal(DBUS_PATH, DBUS_NAME, "GraphicsSwitchProgress")
+            .unwrap()
+            .append2(vendor, stage);
+        if let Err(()) = self.dbus_connection.send(message) {
+            log::error!("failed to send graphics switch progress message");
+        }
+    }
 
-        fan_daemon.step();
+    fn job_finished(&mut self, id: u32, res: Result<(), String>) {
+        let job = match self.jobs.finish(id) {
+            Some(job) => job,
+            None => return,
+        };
 
-        let hpd = hpd();
-        for i in 0..hpd.len() {
-            if hpd[i] != last[i] && hpd[i] {
-                log::info!("HotPlugDetect {}", i);
-                c.send(
-                    Message::new_signal(DBUS_PATH, DBUS_NAME, "HotPlugDetect")
-                        .unwrap()
-                        .append1(i as u64),
-                )
-                .map_err(|()| "failed to send message".to_string())?;
+        let error = match res {
+            Ok(()) => {
+                if job.kind == JobKind::GraphicsSwitch {
+                    log::info!(
+                        "Switched to {} graphics, reboot for changes to take effect",
+                        job.description
+                    );
+                    self.hooks.graphics_switched(&job.description);
+                    self.offload_applications_changed();
+                } else {
+                    log::info!("{} job {} finished", job.kind.as_str(), id);
+                }
+                String::new()
+            }
+            Err(why) => {
+                log::error!("{} job {} failed: {}", job.kind.as_str(), id, why);
+                why
             }
+        };
+
+        if job.kind == JobKind::GraphicsSwitch {
+            let stage = if error.is_empty() { "done" } else { "failed" };
+            self.graphics_switch_progress(&job.description, stage);
         }
 
-        last = hpd;
+        let (waiters, others) =
+            self.job_waiters.drain(..).partition::<Vec<_>, _>(|(job, _)| *job == id);
+        self.job_waiters = others;
+        for (_, waiter) in waiters {
+            let _ = waiter.send(if error.is_empty() { Ok(()) } else { Err(error.clone()) });
+        }
 
-        if let Ok(ref mux) = mux_res {
-            unsafe {
-                mux.step();
-            }
+        let message = Message::new_signal(DBUS_PATH, DBUS_NAME, "JobFinished")
+            .unwrap()
+            .append3(id, job.kind.as_str(), error.is_empty())
+            .append1(error);
+        if let Err(()) = self.dbus_connection.send(message) {
+            log::error!("failed to send job finished message");
         }
     }
 
-    log::info!("daemon exited from loop");
-    Ok(())
-}
+    /// Adds how a profile script exited to the report of its profile, unless another profile was
+    /// applied since.
+    fn script_finished(&mut self, number: u64, res: Result<(), String>) {
+        if !self.scripts.is_last(number) {
+            return;
+        }
 
-fn sync_method<IA, OA, F>(
+        match res {
+            Ok(()) => self.profile_report.applied("script"),
+            Err(why) => self.profile_report.failed("script", ProfileError::Script(why)),
+        }
+    }
+
+    fn cancel_job(&mut self, id: u32) -> Result<(), String> {
+        let kind = self.jobs.get(id).ok_or_else(|| format!("no job with ID {}", id))?.kind;
+        if !kind.is_cancellable() {
+            return Err(format!("{} jobs cannot be cancelled", kind.as_str()));
+        }
+
+        match kind {
+            JobKind::BatteryCalibration => {
+                self.calibration = None;
+                restore_charge_thresholds(&self.bat_thresholds);
+                self.job_finished(id, Err("cancelled".into()));
+            }
+            JobKind::FanTest => {
+                // The fans are restored by the main loop before the job finishes.
+                let _ = self.job_sender.unbounded_send(JobMessage::CancelFanTest(id));
+            }
+            JobKind::GraphicsSwitch => (),
+        }
+
+        Ok(())
+    }
+
+    /// Switches the graphics mode, returning the ID of a job which rebuilds the initramfs in the
+    /// background.
+    fn start_graphics_switch(&mut self, vendor: &str) -> Result<u32, String> {
+        self.graphics_managed()?;
+        if let Some(ref update) = self.firmware {
+            return Err(format!("a firmware update is in progress: {}", update.reason));
+        }
+
+        if let Some(job) = self.jobs.find(JobKind::GraphicsSwitch) {
+            return Err(format!(
+                "switch to {} graphics is in progress as job {}",
+                job.description, job.id
+            ));
+        }
+
+        self.graphics_switch_progress(vendor, "writing-modprobe");
+        let transaction = match self.graphics.begin_switch(vendor) {
+            Ok(transaction) => transaction,
+            Err(why) => {
+                self.graphics_switch_progress(vendor, "failed");
+                return Err(err_str(why));
+            }
+        };
+
+        let displays = self.graphics.displays_lost_by(vendor);
+        if !displays.is_empty() {
+            log::warn!(
+                "external displays on {} will go dark in {} graphics",
+                displays.join(", "),
+                vendor
+            );
+            let message = Message::new_signal(DBUS_PATH, DBUS_NAME, "ExternalDisplaysLost")
+                .unwrap()
+                .append2(vendor, displays);
+            if let Err(()) = self.dbus_connection.send(message) {
+                log::error!("failed to send external displays lost message");
+            }
+        }
+
+        let id = self.jobs.start(JobKind::GraphicsSwitch, vendor.to_owned(), "starting")?;
+        self.job_progress(id, "rebuilding-initramfs", None);
+
+        let sender = self.job_sender.clone();
+        thread::spawn(move || {
+            let res = Graphics::finish_switch(transaction).map_err(err_str);
+            let _ = sender.unbounded_send(JobMessage::Finished(id, res));
+        });
+
+        Ok(id)
+    }
+
+    /// Completes a switch which was interrupted, such as by a crash, by rewriting its
+    /// configuration and rebuilding the initramfs in the background. It is not left until
+    /// shutdown, as logind only waits briefly for the rebuild.
+    fn complete_graphics_switch(&mut self, vendor: String) -> Result<u32, String> {
+        let switchable = self.graphics.switchable().ok_or("graphics are not switchable")?;
+        let initramfs = self.graphics.initramfs;
+        let services = self.graphics.services.clone();
+        let backends = self.graphics.backends().clone();
+
+        let id = self.jobs.start(JobKind::GraphicsSwitch, vendor, "starting")?;
+        self.job_progress(id, "rebuilding-initramfs", None);
+
+        let sender = self.job_sender.clone();
+        thread::spawn(move || {
+            let res =
+                Graphics::complete_pending_switch(&switchable, initramfs, &services, &backends)
+                    .map(|_| ())
+                    .map_err(err_str);
+            let _ = sender.unbounded_send(JobMessage::Finished(id, res));
+        });
+
+        Ok(id)
+    }
+
+    /// Reconciles the saved graphics mode with configuration files which another tool rewrote,
+    /// either adopting the mode they select or switching back.
+    fn reconcile_graphics(&mut self) {
+        if self.jobs.find(JobKind::GraphicsSwitch).is_some() {
+            return;
+        }
+
+        let (saved, configured) = match self.graphics.external_change() {
+            Some(change) => change,
+            None => return,
+        };
+
+        match configured {
+            Some(vendor) if self.external_gfx == ExternalGraphicsChanges::Adopt => {
+                log::info!("graphics were switched from {} to {} by another tool", saved, vendor);
+                if let Err(why) = self.graphics.adopt_vendor(&vendor) {
+                    log::warn!("failed to adopt {} graphics: {}", vendor, why);
+                    return;
+                }
+
+                let message = Message::new_signal(DBUS_PATH, DBUS_NAME, "GraphicsChanged")
+                    .unwrap()
+                    .append1(&vendor);
+                if let Err(()) = self.dbus_connection.send(message) {
+                    log::error!("failed to send graphics changed message");
+                }
+                self.offload_applications_changed();
+            }
+            _ => {
+                log::warn!(
+                    "graphics configuration was changed by another tool, restoring {} graphics",
+                    saved
+                );
+                if let Err(why) = self.start_graphics_switch(&saved) {
+                    log::warn!("failed to restore {} graphics: {}", saved, why);
+                }
+            }
+        }
+    }
+
+    /// Compares the graphics configuration with the modules which were loaded at startup, if
+    /// that has not been done yet, suggesting a repair where they disagree.
+    fn check_graphics(&mut self) {
+        if !mem::replace(&mut self.gfx_unchecked, false) {
+            return;
+        }
+
+        let verification = self.graphics.verify();
+        for problem in &verification.problems {
+            log::warn!("graphics configuration: {}", problem);
+        }
+
+        if let (Some(configured), Some(loaded)) = (verification.configured, verification.loaded) {
+            let message = Message::new_signal(DBUS_PATH, DBUS_NAME, "RepairSuggested")
+                .unwrap()
+                .append2(configured, loaded);
+            if let Err(()) = self.dbus_connection.send(message) {
+                log::error!("failed to send repair suggested message");
+            }
+
+            self.gfx_mismatch = Some((configured.to_owned(), loaded));
+            self.repair_suggestion_changed();
+        }
+    }
+
+    /// The configured graphics mode and the mode which the loaded modules match, where they
+    /// disagree, or empty strings.
+    fn repair_suggestion(&self) -> (String, String) {
+        self.gfx_mismatch
+            .as_ref()
+            .map(|(configured, loaded)| (configured.clone(), (*loaded).to_owned()))
+            .unwrap_or_default()
+    }
+
+    /// The applications which are launched on the dGPU, with the environment which selects it.
+    fn offload_applications(&self) -> HashMap<String, Vec<String>> {
+        switcheroo::offload_applications(&self.offload, &self.graphics.gpus())
+    }
+
+    /// Reports the offload applications after the graphics mode changed, as the environment
+    /// which selects the dGPU, if any, depends on the mode.
+    fn offload_applications_changed(&self) {
+        let mut interfaces = vec![(DBUS_PATH, DBUS_IFACE), (DBUS_PATH, DBUS_IFACE_V2)];
+        if self.switcheroo {
+            interfaces.push((SWITCHEROO_PATH, SWITCHEROO_IFACE));
+        }
+
+        let applications = self.offload_applications();
+        for (path, interface) in interfaces {
+            let mut changed = PropertiesPropertiesChanged {
+                interface_name:         interface.into(),
+                changed_properties:     PropMap::new(),
+                invalidated_properties: Vec::new(),
+            };
+            changed
+                .changed_properties
+                .insert("OffloadApplications".into(), Variant(Box::new(applications.clone())));
+
+            if let Err(()) = self.dbus_connection.send(changed.to_emit_message(&path.into())) {
+                log::error!("failed to send offload applications change message");
+            }
+        }
+    }
+
+    fn repair_suggestion_changed(&self) {
+        for &interface in &[DBUS_IFACE, DBUS_IFACE_V2] {
+            let mut changed = PropertiesPropertiesChanged {
+                interface_name:         interface.into(),
+                changed_properties:     PropMap::new(),
+                invalidated_properties: Vec::new(),
+            };
+            changed
+                .changed_properties
+                .insert("RepairSuggestion".into(), Variant(Box::new(self.repair_suggestion())));
+
+            if let Err(()) = self.dbus_connection.send(changed.to_emit_message(&DBUS_PATH.into())) {
+                log::error!("failed to send repair suggestion change message");
+            }
+        }
+    }
+
+    /// Rewrites the graphics configuration to match the modules which were loaded at startup,
+    /// returning the ID of the job which rebuilds the initramfs.
+    fn reconcile_loaded_graphics(&mut self) -> Result<u32, String> {
+        self.check_graphics();
+        let (configured, loaded) = match self.gfx_mismatch {
+            Some(ref mismatch) => mismatch.clone(),
+            None => return Err("graphics configuration matches the loaded modules".to_owned()),
+        };
+
+        log::info!("Reconciling {} graphics configuration with {} modules", configured, loaded);
+        let job = self.start_graphics_switch(loaded)?;
+        self.gfx_mismatch = None;
+        self.repair_suggestion_changed();
+        Ok(job)
+    }
+
+    /// Rewrites the configuration of the graphics mode which was last switched to and rebuilds
+    /// the initramfs, where verifying it found problems, returning the ID of the job.
+    fn repair_graphics(&mut self) -> Result<u32, String> {
+        let verification = self.graphics.verify();
+        if let Some(pending) = verification.pending {
+            return Err(format!("switch to {} graphics has not completed", pending));
+        }
+
+        if verification.problems.is_empty() {
+            return Err("graphics configuration is consistent".to_owned());
+        }
+
+        let configured = verification.configured.map(str::to_owned);
+        let vendor = match verification.saved.or(configured) {
+            Some(vendor) => vendor,
+            None => self.graphics.get_vendor().map_err(err_str)?,
+        };
+
+        log::info!("Repairing the configuration of {} graphics", vendor);
+        self.start_graphics_switch(&vendor)
+    }
+
+    /// Turns benchmark mode on for a number of minutes, or off. Zero minutes, or more than the
+    /// configuration allows, keep it on for the configured maximum.
+    fn set_bench_mode(&mut self, enabled: bool, minutes: u32) -> Result<(), String> {
+        if !enabled {
+            return self.stop_bench_mode();
+        }
+
+        if let Some(ref update) = self.firmware {
+            return Err(format!("a firmware update is in progress: {}", update.reason));
+        }
+
+        let max = match self.bench_config.max_duration {
+            0 => BenchConfig::default().max_duration,
+            max => max,
+        };
+        let minutes = if minutes == 0 { max } else { u64::from(minutes).min(max) };
+        let duration = Duration::from_secs(minutes * 60);
+
+        match self.bench {
+            Some(ref mut bench) => bench.extend(duration),
+            None => {
+                let bench =
+                    BenchMode::start(duration, self.bench_config.fan_duty).map_err(err_str)?;
+                self.bench = Some(bench);
+            }
+        }
+
+        log::info!("benchmark mode is on for {} minutes", minutes);
+        Ok(())
+    }
+
+    fn stop_bench_mode(&mut self) -> Result<(), String> {
+        match self.bench.take() {
+            Some(bench) => {
+                log::info!("benchmark mode is off");
+                bench.stop().map_err(err_str)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Whether benchmark mode is on, and the seconds until it expires.
+    fn bench_mode(&self) -> (bool, u32) {
+        match self.bench {
+            Some(ref bench) => {
+                let remaining = bench.deadline().saturating_duration_since(Instant::now());
+                (true, remaining.as_secs() as u32)
+            }
+            None => (false, 0),
+        }
+    }
+
+    /// Holds the system steady while firmware is flashed, for a number of minutes. Zero minutes,
+    /// or more than the configuration allows, hold it for the configured maximum.
+    fn start_firmware_update(
+        &mut self,
+        reason: String,
+        owner: String,
+        minutes: u32,
+    ) -> Result<(), String> {
+        let max = match self.firmware_config.max_duration {
+            0 => FirmwareConfig::default().max_duration,
+            max => max,
+        };
+        let minutes = if minutes == 0 { max } else { u64::from(minutes).min(max) };
+        let duration = Duration::from_secs(minutes * 60);
+
+        match self.firmware {
+            Some(ref mut update) if update.owner == owner => update.extend(duration),
+            Some(ref update) => {
+                return Err(format!("a firmware update is in progress: {}", update.reason));
+            }
+            None => {
+                log::info!("{} is updating firmware for {} minutes: {}", owner, minutes, reason);
+                self.force_balanced_profile();
+                let fan_duty = self.firmware_config.fan_duty;
+                let lock_frequency = self.bench.is_none();
+                self.firmware =
+                    Some(FirmwareUpdate::start(reason, owner, duration, fan_duty, lock_frequency));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Applies the balanced profile for a firmware update, deferring the profile which was in
+    /// effect, unless another request was already deferred, until the update ends.
+    fn force_balanced_profile(&mut self) {
+        let current = profile_from_name(&self.power_profile);
+        if current == Some(Profile::Balanced) {
+            return;
+        }
+
+        if self.pending_profile.is_none() {
+            self.pending_profile = current.map(profile_fn);
+        }
+
+        let (func, name) = profile_fn(Profile::Balanced);
+        if let Err(why) = self.apply_profile_now(func, name) {
+            log::warn!("{}", why);
+        }
+    }
+
+    /// Ends the firmware update, applying any profile which was deferred during it.
+    fn end_firmware_update(&mut self) {
+        if let Some(update) = self.firmware.take() {
+            log::info!("firmware update ended");
+            update.end();
+            self.apply_pending_profile();
+        }
+    }
+
+    /// Whether a firmware update is in progress, the seconds until it expires, and its reason.
+    fn firmware_update(&self) -> (bool, u32, String) {
+        match self.firmware {
+            Some(ref update) => {
+                let remaining = update.deadline().saturating_duration_since(Instant::now());
+                (true, remaining.as_secs() as u32, update.reason.clone())
+            }
+            None => (false, 0, String::new()),
+        }
+    }
+
+    fn start_fan_test(&mut self) -> Result<u32, String> {
+        let id = self.jobs.start(JobKind::FanTest, String::new(), "starting")?;
+        let _ = self.job_sender.unbounded_send(JobMessage::StartFanTest(id));
+        Ok(id)
+    }
+
+    /// Starts calibrating the battery, which lifts the charge thresholds until it completes.
+    fn start_battery_calibration(&mut self) -> Result<u32, String> {
+        let source = self.power_source.filter(|source| source.percentage.is_some());
+        let source = source.ok_or_else(|| "no battery was found".to_string())?;
+
+        if self.jobs.find(JobKind::BatteryCalibration).is_none() {
+            // The previous thresholds are saved, so that they are restored even if the daemon
+            // is restarted during calibration.
+            let thresholds = get_charge_thresholds(&self.bat_thresholds)?;
+            StateStore::default().store(CALIBRATION_STATE, &thresholds).map_err(err_str)?;
+            // Every battery is calibrated, including those with thresholds of their own.
+            set_charge_thresholds(CALIBRATION_THRESHOLDS, &BatteryThresholds::new())?;
+        }
+
+        let id = self.jobs.start(JobKind::BatteryCalibration, String::new(), "starting")?;
+        self.calibration = Some((id, Calibration::Charging));
+        self.calibration_step(&source);
+        Ok(id)
+    }
+
+    /// Advances the battery calibration, if any, when the power source changes.
+    fn calibration_step(&mut self, source: &PowerSource) {
+        let (id, stage) = match self.calibration {
+            Some(calibration) => calibration,
+            None => return,
+        };
+
+        let next = stage.next(source);
+        if next == Calibration::Done {
+            self.calibration = None;
+            restore_charge_thresholds(&self.bat_thresholds);
+            self.job_finished(id, Ok(()));
+            return;
+        }
+
+        self.calibration = Some((id, next));
+        let progress = next.progress(source);
+        let job = self.jobs.get(id);
+        if job.map_or(false, |job| job.stage != next.as_str() || job.progress != progress) {
+            self.job_progress(id, next.as_str(), progress);
+        }
+    }
+
+    /// Caps the link speed of each discrete GPU for a profile, lifting the cap of another profile
+    /// if it has none.
+    fn apply_link_speed(&self, profile: Profile, report: &mut ProfileReport) {
+        let discrete: Vec<TopologyDevice> = self
+            .graphics
+            .topology()
+            .into_iter()
+            .filter(|dev| dev.role == GpuRole::Discrete)
+            .collect();
+        if discrete.is_empty() {
+            report.skipped("pcie_link", "no device");
+            return;
+        }
+
+        let generation = self.link_speed.profile(profile);
+        let mut failed = false;
+        for dev in discrete {
+            if let Err(why) = pci::set_link_speed(&dev.id, dev.parent.as_deref(), generation) {
+                failed = true;
+                report.failed("pcie_link", ProfileError::PcieLink(dev.id, why));
+            }
+        }
+
+        if !failed {
+            report.applied("pcie_link");
+        }
+    }
+
+    /// Refuses changes to the graphics if they are left to another tool.
+    fn graphics_managed(&self) -> Result<(), String> {
+        if self.manage_gfx {
+            Ok(())
+        } else {
+            Err("graphics are not managed by system76-power, as configured".to_owned())
+        }
+    }
+
+    /// Powers a single discrete GPU on or off, independently of the graphics mode. With `force`,
+    /// processes which have it open are stopped before it is powered off.
+    fn set_device_power(&mut self, device: &str, power: bool, force: bool) -> Result<(), String> {
+        self.graphics_managed()?;
+        let topology = self.graphics.topology();
+        let nvidia = topology.iter().any(|dev| dev.id == device && dev.vendor == "nvidia");
+
+        if nvidia && !power {
+            self.nvidia.power_off(&[device]);
+        }
+
+        self.graphics.set_device_power(device, power, force).map_err(err_str)?;
+
+        if nvidia && power {
+            if let Some(profile) = profile_from_name(&self.power_profile) {
+                self.nvidia.apply(profile, &self.graphics.nvidia);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Powers the NVIDIA GPU on or off, reapplying the settings of the active profile, which
+    /// could not be applied while it was off.
+    fn set_gpu_power(&mut self, power: bool, force: bool) -> Result<(), String> {
+        self.graphics_managed()?;
+        if let Some(ref update) = self.firmware {
+            return Err(format!("a firmware update is in progress: {}", update.reason));
+        }
+
+        if !power {
+            let nvidia = self.graphics.nvidia.iter().filter(|gpu| gpu.exists());
+            let ids: Vec<&str> = nvidia.map(GraphicsDevice::id).collect();
+            self.nvidia.power_off(&ids);
+        }
+
+        self.graphics.set_power(power, force).map_err(err_str)?;
+
+        if power {
+            if let Some(profile) = profile_from_name(&self.power_profile) {
+                self.nvidia.apply(profile, &self.graphics.nvidia);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Powers the NVIDIA GPU in hybrid mode on when an application wants to offload rendering to
+    /// it, and off once nothing has used it for the idle period. An AMD dGPU is instead left to
+    /// the runtime power management of amdgpu.
+    fn auto_gpu_power(&mut self) {
+        // nouveau suspends the GPU itself through runtime power management.
+        if !self.gpu_power.is_enabled()
+            || self.firmware.is_some()
+            || self.graphics.nouveau
+            || !matches!(self.graphics.switchable(), Some(Switchable::Nvidia(_)))
+            || self.graphics.get_vendor().ok().as_deref() != Some("hybrid")
+        {
+            return;
+        }
+
+        let powered = self.graphics.get_power().unwrap_or(true);
+        let graphics = &self.graphics;
+        let power = self.gpu_power.poll(Instant::now(), powered, || {
+            if powered {
+                drm::nvidia_client_exists(&graphics.nvidia_nodes())
+            } else {
+                drm::offload_client_exists()
+            }
+        });
+
+        if let Some(power) = power {
+            log::info!(
+                "automatic graphics power: powering the GPU {}",
+                if power { "on" } else { "off" }
+            );
+            if let Err(why) = self.set_gpu_power(power, false) {
+                log::warn!("failed to set graphics power: {}", why);
+            }
+        }
+    }
+
+    /// Steps the brightness of the screen or keyboard up or down, returning the new brightness as
+    /// a percentage.
+    fn step_brightness(&mut self, device: &str, increase: bool) -> Result<u8, String> {
+        if !self.manage_lights {
+            return Err("brightness is not managed by system76-power, as configured".to_owned());
+        }
+
+        match device {
+            "screen" => {
+                let step = u64::from(self.brightness_step.screen_step);
+                brightness::step_screen(step, increase).map_err(err_str)
+            }
+            "keyboard" => {
+                let step = u64::from(self.brightness_step.keyboard_step);
+                keyboard::step_percent(step, increase)
+                    .map_err(err_str)?
+                    .ok_or_else(|| "no keyboard backlight".to_owned())
+            }
+            _ => Err(format!("unknown device {}, expected screen or keyboard", device)),
+        }
+    }
+
+    /// Sets the colors of the keyboard zones which a profile configures.
+    fn apply_keyboard_colors(&self, profile: Profile, report: &mut ProfileReport) {
+        let colors = self.keyboard_colors.profile(profile);
+        if colors.is_empty() {
+            report.skipped("keyboard_color", "none configured");
+            return;
+        } else if !self.manage_lights {
+            report.skipped("keyboard_color", "not managed");
+            return;
+        }
+
+        match keyboard::set_colors(colors) {
+            Ok(()) => report.applied("keyboard_color"),
+            Err(EcError::Unsupported(_)) => report.skipped("keyboard_color", "no device"),
+            Err(why) => report.failed("keyboard_color", ProfileError::KeyboardColor(why)),
+        }
+    }
+
+    /// The zones of the keyboard, with the color of each and the brightness of the keyboard.
+    fn get_keyboard_zones(&mut self) -> Result<Vec<(String, u32, u8)>, String> {
+        let zones = keyboard::zones().map_err(err_str)?;
+        Ok(zones.into_iter().map(|(zone, color, percent)| (zone, color.0, percent)).collect())
+    }
+
+    /// Sets the color of a keyboard zone, or every zone with `all`, and the brightness of the
+    /// keyboard as a percentage.
+    fn set_keyboard_zone(&mut self, zone: &str, color: u32, percent: u8) -> Result<(), String> {
+        if !self.manage_lights {
+            return Err("backlights are not managed by system76-power, as configured".to_owned());
+        }
+
+        if percent > 100 {
+            return Err(format!("invalid brightness {}%", percent));
+        }
+
+        keyboard::set_zone(zone, Color(color & 0xFF_FFFF), percent).map_err(err_str)
+    }
+
+    /// Limits the power of the CPU while the AC adapter is underpowered, announcing when that or
+    /// its wattage changes.
+    fn adapter_changed(&mut self, adapter: Option<Adapter>) {
+        if !self.adapter.update(adapter) {
+            return;
+        }
+
+        self.power_limit.set(Reason::Adapter, self.adapter.power_limit());
+
+        let message = Message::new_signal(DBUS_PATH, DBUS_NAME, "AdapterChanged")
+            .unwrap()
+            .append2(self.adapter.watts().unwrap_or(0), self.adapter.is_underpowered());
+        if let Err(()) = self.dbus_connection.send(message) {
+            log::error!("failed to send adapter changed message");
+        }
+    }
+
+    /// Remembers the power of the dGPU before the system sleeps. Sleep waits for this, as the
+    /// delay lock which is taken on sleep is only released once it returns.
+    fn sleeping(&mut self) {
+        if self.manage_gfx && self.graphics.can_switch() {
+            self.sleep_gpu_power = self.graphics.get_power().ok();
+        }
+    }
+
+    /// Applies the graphics power and the settings of the active profile again after resuming,
+    /// as some firmware powers a removed dGPU back on, or resets what the profile changed.
+    fn resumed(&mut self) {
+        if let Some(power) = self.sleep_gpu_power.take() {
+            if self.firmware.is_none() && self.graphics.get_power().ok() != Some(power) {
+                log::info!(
+                    "graphics power changed during sleep, powering the GPU {} again",
+                    if power { "on" } else { "off" }
+                );
+                if let Err(why) = self.set_gpu_power(power, false) {
+                    log::warn!("failed to restore graphics power: {}", why);
+                }
+            }
+
+            // Nothing could use the GPU while asleep, so the idle period starts over.
+            if self.gpu_power.is_enabled() {
+                self.gpu_power.set_enabled(true);
+            }
+        }
+
+        let profile = match profile_from_name(&self.power_profile) {
+            Some(profile) if self.firmware.is_none() => profile,
+            _ => return,
+        };
+
+        // The profile is not changing, so it is not announced, and the brightness is kept.
+        let (func, name) = profile_fn(profile);
+        log::info!("reapplying the {} profile after resume", name);
+        let mut report = ProfileReport::default();
+        func(&mut report, false);
+        self.apply_settings(profile, &mut report);
+        for why in report.errors() {
+            log::warn!("failed to reapply the {} profile: {}", name, why);
+        }
+    }
+
+    /// Reports how much of an s2idle suspend was spent in low power states, which is much less
+    /// than all of it when a device keeps the platform awake.
+    fn suspended(&mut self, report: SuspendReport) {
+        if report.system == Some(Duration::from_secs(0)) {
+            let blockers = if report.blockers.is_empty() {
+                String::from("unknown")
+            } else {
+                report.blockers.join(", ")
+            };
+            log::warn!(
+                "did not reach SLP_S0 in {:?} of s2idle, powered IP blocks: {}",
+                report.duration,
+                blockers
+            );
+        } else if let Some(percent) = report.system_percent() {
+            log::info!("spent {:.1}% of {:?} of s2idle in SLP_S0", percent, report.duration);
+        }
+
+        self.suspend_report = Some(report);
+    }
+
+    fn sample_throttling(&mut self) {
+        let nvidia = self.graphics.nvidia.iter().filter(|gpu| gpu.exists());
+        let ids: Vec<&str> = nvidia.map(GraphicsDevice::id).collect();
+        self.throttle.sample(Instant::now(), &ids);
+    }
+
+    /// The state reported by the health endpoint.
+    fn health(&self, uptime: Duration) -> Health {
+        Health::new(
+            self.power_profile.clone(),
+            self.capabilities.iter().map(|cap| cap.as_str().to_owned()).collect(),
+            self.power_source,
+            Sensors::read(&self.sensors),
+            self.spindown.stats(),
+            self.throttle.stats(Instant::now()),
+            uptime,
+        )
+    }
+
+    /// Accepts a platform profile which was selected outside of the daemon, such as by a firmware
+    /// hotkey, as the active profile.
+    fn sync_platform_profile(&mut self) {
+        let value = match acpi_platform::get() {
+            Some(value) => value,
+            None => return,
+        };
+
+        if self.platform_prof.as_deref() == Some(value.as_str()) {
+            return;
+        }
+
+        let profile = acpi_platform::profile_for(&value);
+        self.platform_prof = Some(value);
+        let profile = match profile {
+            Some(profile) if profile_from_name(&self.power_profile) != Some(profile) => profile,
+            _ => return,
+        };
+
+        log::info!("platform profile was changed, applying the {:?} profile", profile);
+        if let Err(why) = self.set_profile(profile) {
+            log::warn!("{}", why);
+        }
+    }
+
+    /// Applies the settings of a profile beyond those of `ProfileFn`, which is also done again
+    /// after resume, as the firmware may have reset them.
+    fn apply_settings(&mut self, profile: Profile, report: &mut ProfileReport) {
+        self.devices.apply(self.on_battery());
+        self.power_limit.profile_applied();
+        if !self.manage_gfx {
+            report.skipped("pcie_link", "graphics not managed");
+        } else {
+            self.nvidia.apply(profile, &self.graphics.nvidia);
+            if self.link_speed.is_empty() {
+                report.skipped("pcie_link", "none configured");
+            } else {
+                self.apply_link_speed(profile, report);
+            }
+        }
+        let errors = self.spindown.apply(profile);
+        if !self.spindown.is_configured() {
+            report.skipped("spindown", "not enabled");
+        } else if !self.spindown.is_enabled() {
+            report.skipped("spindown", "no device");
+        } else if errors.is_empty() {
+            report.applied("spindown");
+        }
+        for why in errors {
+            report.failed("spindown", why.into());
+        }
+        if !self.wifi.is_enabled() {
+            report.skipped("wifi", "none configured");
+        } else {
+            let errors = self.wifi.apply(profile);
+            if errors.is_empty() {
+                report.applied("wifi");
+            }
+            for why in errors {
+                report.failed("wifi", why);
+            }
+        }
+        self.apply_keyboard_colors(profile, report);
+        match sched::apply(self.scheduler.profile(profile)) {
+            Ok(true) => report.applied("scheduler"),
+            Ok(false) => report.skipped("scheduler", "unsupported"),
+            Err(why) => report.failed("scheduler", ProfileError::Scheduler(why)),
+        }
+        match self.epp.profile(profile) {
+            Some(preference) => energy_performance_preference(report, preference),
+            None => report.skipped("epp", "none configured"),
+        }
+        if self.knobs.is_empty() {
+            report.skipped("knobs", "none configured");
+        } else {
+            let errors = self.knobs.apply(profile);
+            if errors.is_empty() {
+                report.applied("knobs");
+            }
+            for why in errors {
+                report.failed("knobs", ProfileError::Knob(why));
+            }
+        }
+
+        // The profile may have changed the settings which benchmark mode locks.
+        if let Some(ref mut bench) = self.bench {
+            if let Err(why) = bench.relock() {
+                log::warn!("failed to lock CPU frequency for benchmark mode: {}", why);
+            }
+        }
+    }
+
+    fn apply_profile_now(&mut self, func: ProfileFn, name: &str) -> Result<(), String> {
+        let _span = tracing::info_span!("apply_profile", profile = name).entered();
+        let set_brightness = self.initial_set
+            && self.manage_lights
+            && !mem::replace(&mut self.keep_brightness, false);
+
+        if self.power_profile == name {
+            log::info!("profile was already set");
+            return Ok(());
+        }
+
+        let started = Instant::now();
+        self.profile_changed = Some(started);
+        let mut report = ProfileReport::default();
+        func(&mut report, set_brightness);
+        if let Some(profile) = profile_from_name(name) {
+            self.apply_settings(profile, &mut report);
+            // Scripts run in the background, and are added to the report once they exit.
+            if !self.scripts.profile_applied(profile, name) {
+                report.skipped("script", "none configured");
+            }
+        }
+
+        let elapsed = started.elapsed();
+        if elapsed > PROFILE_LATENCY_TARGET {
+            log::warn!("applying the {} profile took {:?}", name, elapsed);
+        }
+
+        let message =
+            Message::new_signal(DBUS_PATH, DBUS_NAME, "PowerProfileSwitch").unwrap().append1(name);
+
+        if let Err(()) = self.dbus_connection.send(message) {
+            log::error!("failed to send power profile switch message");
+        }
+
+        if self.ppd_replaced {
+            let message = power_profiles::active_profile_changed(name);
+            if let Err(()) = self.dbus_connection.send(message) {
+                log::error!("failed to send active profile change message");
+            }
+        }
+
+        self.power_profile = name.into();
+        self.hooks.profile_changed(name);
+
+        let result = if report.errors().is_empty() {
+            Ok(())
+        } else {
+            let mut error_message = String::from("Errors found when setting profile:");
+            for error in report.errors() {
+                error_message = format!("{}\n    - {}", error_message, error);
+            }
+
+            Err(error_message)
+        };
+
+        self.profile_report = report;
+        result
+    }
+
+    /// The processes to stop before forcing a discrete GPU off, or the switched discrete GPUs if
+    /// `device` is empty.
+    fn power_off_users(&self, device: &str) -> Result<Vec<DrmUser>, String> {
+        self.graphics_managed()?;
+        if let Some(update) = self.firmware.as_ref().filter(|_| device.is_empty()) {
+            return Err(format!("a firmware update is in progress: {}", update.reason));
+        }
+
+        self.graphics.blocking_users(device).map_err(err_str)
+    }
+}
+
+impl Power for PowerDaemon {
+    fn battery(&mut self) -> Result<(), String> { self.set_profile(Profile::Battery) }
+
+    fn balanced(&mut self) -> Result<(), String> { self.set_profile(Profile::Balanced) }
+
+    fn performance(&mut self) -> Result<(), String> { self.set_profile(Profile::Performance) }
+
+    fn get_external_displays_require_dgpu(&mut self) -> Result<bool, String> {
+        self.graphics.get_external_displays_require_dgpu().map_err(err_str)
+    }
+
+    fn get_default_graphics(&mut self) -> Result<String, String> {
+        self.graphics.get_default_graphics().map_err(err_str)
+    }
+
+    fn get_graphics(&mut self) -> Result<String, String> {
+        self.graphics.get_vendor().map_err(err_str)
+    }
+
+    fn get_profile(&mut self) -> Result<String, String> { Ok(self.requested_profile().to_owned()) }
+
+    fn get_switchable(&mut self) -> Result<bool, String> { Ok(self.graphics.can_switch()) }
+
+    fn set_graphics(&mut self, vendor: &str) -> Result<(), String> {
+        self.start_graphics_switch(vendor).map(|_| ())
+    }
+
+    fn get_graphics_power(&mut self) -> Result<bool, String> {
+        self.graphics.get_power().map_err(err_str)
+    }
+
+    fn set_graphics_power(&mut self, power: bool) -> Result<(), String> {
+        self.gpu_power.set_enabled(false);
+        self.set_gpu_power(power, false)?;
+        remember_graphics_power(Some(power));
+        Ok(())
+    }
+
+    /// Powers off a discrete GPU, or the switched discrete GPUs if `device` is empty, even with
+    /// external displays connected. The processes which had them open were already stopped.
+    fn force_graphics_power_off(&mut self, device: &str) -> Result<(), String> {
+        if device.is_empty() {
+            self.gpu_power.set_enabled(false);
+            self.set_gpu_power(false, true)?;
+            remember_graphics_power(Some(false));
+            Ok(())
+        } else {
+            self.set_device_power(device, false, true)
+        }
+    }
+
+    fn auto_graphics_power(&mut self) -> Result<(), String> {
+        self.graphics_managed()?;
+        self.gpu_power.set_enabled(true);
+        remember_graphics_power(None);
+        self.graphics.auto_power().map_err(err_str)
+    }
+
+    fn get_charge_thresholds(&mut self) -> Result<(u8, u8), String> {
+        get_charge_thresholds(&self.bat_thresholds)
+    }
+
+    fn set_charge_thresholds(&mut self, thresholds: (u8, u8)) -> Result<(), String> {
+        // NOTE: This method is not actually called by daemon
+        set_charge_thresholds(thresholds, &self.bat_thresholds)
+    }
+
+    fn get_charge_profiles(&mut self) -> Result<Vec<ChargeProfile>, String> {
+        Ok(get_charge_profiles())
+    }
+
+    fn get_capabilities(&mut self) -> Result<Vec<String>, String> {
+        Ok(self.capabilities.iter().map(|cap| cap.as_str().to_owned()).collect())
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+pub async fn daemon() -> Result<(), String> {
+    let started = Instant::now();
+    let exit = exit_signal();
+    safety::install();
+
+    let safe_mode = safe_mode::is_enabled();
+    let config = if safe_mode {
+        log::warn!("{} is set, ignoring the configuration", safe_mode::PARAMETER);
+        Config::default()
+    } else {
+        Config::load().unwrap_or_else(|why| {
+            log::error!("invalid configuration, using defaults: {}", why);
+            Config::default()
+        })
+    };
+
+    let pci_runtime_pm = config.daemon.pci_runtime_pm
+        || std::env::var("S76_POWER_PCI_RUNTIME_PM").ok().map_or(false, |v| v == "1");
+
+    log::info!(
+        "Starting daemon{}",
+        if pci_runtime_pm { " with pci runtime pm support enabled" } else { "" }
+    );
+    PCI_RUNTIME_PM.store(pci_runtime_pm, Ordering::SeqCst);
+
+    log::info!("Connecting to dbus system bus");
+    let (resource, c) = connection::new_system_sync().map_err(err_str)?;
+
+    tokio::spawn(async {
+        let err = resource.await;
+        panic!("Lost connection to D-Bus: {}", err);
+    });
+
+    let (job_sender, mut job_messages) = mpsc::unbounded();
+    brightness::set_connection(c.clone());
+    let mut daemon = PowerDaemon::new(c.clone(), job_sender);
+    daemon.manage_gfx = config.daemon.manage_graphics;
+    daemon.manage_lights = config.daemon.manage_backlight;
+    daemon.graphics.initramfs = config.graphics.initramfs;
+    daemon.graphics.services = config.graphics.services.clone();
+
+    if let Some(vendor) = daemon.graphics.pending_vendor().filter(|_| daemon.manage_gfx) {
+        log::warn!("Switch to {} graphics is incomplete, completing it", vendor);
+        if let Err(why) = daemon.complete_graphics_switch(vendor) {
+            log::error!("failed to complete graphics switch: {}", why);
+        }
+    }
+    let nvidia_exists = !daemon.graphics.nvidia.is_empty();
+
+    log::info!("Disabling NMI Watchdog (for kernel debugging only)");
+    NmiWatchdog::default().set(b"0");
+
+    // Get the NVIDIA device ID before potentially removing it.
+    let nvidia_device_id = if nvidia_exists {
+        fs::read_to_string("/sys/bus/pci/devices/0000:01:00.0/device").ok()
+    } else {
+        None
+    };
+
+    daemon.gpu_power = AutoPower::new(Duration::from_secs(config.graphics.idle_power_off));
+    if daemon.manage_gfx {
+        // The other modes depend on the power of the dGPU, so it is only kept in hybrid mode.
+        let hybrid = daemon.graphics.get_vendor().ok().as_deref() == Some("hybrid");
+        let manual = StateStore::default().load::<bool>(GRAPHICS_POWER_STATE).filter(|_| hybrid);
+        let res = match manual {
+            Some(power) => {
+                log::info!("Restoring graphics power {}", if power { "on" } else { "off" });
+                daemon.set_graphics_power(power)
+            }
+            None => {
+                log::info!("Setting automatic graphics power");
+                daemon.auto_graphics_power()
+            }
+        };
+        if let Err(err) = res {
+            log::warn!("Failed to set graphics power: {}", err);
+        }
+    }
+
+    daemon.nvidia = NvidiaProfiles::new(config.nvidia.clone(), daemon.nvml.clone());
+    daemon.throttle = ThrottleMonitor::new(daemon.nvml.clone());
+    daemon.scheduler = config.scheduler.clone();
+    daemon.epp = config.epp.clone();
+    daemon.knobs = Knobs::new(config.knobs.clone());
+    daemon.wifi = WifiPowerSave::new(config.wifi.clone());
+    daemon.spindown = Spindown::new(config.spindown.clone());
+    daemon.devices = DevicePolicies::new(config.devices.clone());
+    daemon.link_speed = config.graphics.link_speed.clone();
+    daemon.scripts = ScriptRunner::new(config.scripts.clone(), daemon.job_sender.clone());
+    daemon.hooks = Hooks::new(config.hooks.clone());
+    daemon.history = ThermalHistory::new(Duration::from_secs(config.fan.history * 60));
+    if config.consumers.enabled {
+        daemon.consumers = Some(ConsumerSampler::new(config.consumers.count));
+    }
+    keyboard::set_fade_duration(Duration::from_millis(config.keyboard.fade));
+    daemon.keyboard_colors = config.keyboard.colors.clone();
+
+    // A calibration which was interrupted by a restart is not resumed.
+    daemon.bat_thresholds = config.batteries.clone();
+    restore_charge_thresholds(&daemon.bat_thresholds);
+
+    // Benchmark mode does not survive a restart, so the frequency it locked is restored before
+    // the profile is applied over it.
+    if let Err(why) = bench::recover() {
+        log::error!("failed to restore CPU frequency after benchmark mode: {}", why);
+    }
+
+    let res = daemon.set_profile(config.daemon.default_profile);
+    log::info!("Initialized with the {:?} profile", config.daemon.default_profile);
+    if let Err(why) = res {
+        log::warn!("Failed to set initial profile: {}", why);
+    }
+    daemon.initial_set = true;
+    daemon.auto_profile = config.auto_profile.clone();
+    daemon.auto_performance = AutoPerformance::new(config.auto_performance.clone());
+    daemon.clamshell = config.clamshell.clone();
+    daemon.quiet_hours = QuietHours::new(config.quiet_hours.clone());
+    daemon.adapter = AdapterLimit::new(config.adapter.clone());
+    daemon.bench_config = config.bench.clone();
+    daemon.brightness_step = config.brightness.clone();
+    daemon.firmware_config = config.firmware.clone();
+    daemon.external_gfx = config.graphics.external_changes;
+    daemon.offload = config.graphics.offload.clone();
+    let graphics_switchable = daemon.manage_gfx && daemon.graphics.can_switch();
+    if safe_mode && graphics_switchable {
+        match daemon.graphics.get_vendor() {
+            Ok(ref vendor) if vendor == "integrated" => (),
+            Ok(vendor) => {
+                log::warn!("Reverting from {} to integrated graphics for safe mode", vendor);
+                if let Err(why) = daemon.start_graphics_switch("integrated") {
+                    log::error!("failed to revert to integrated graphics: {}", why);
+                }
+            }
+            Err(why) => log::warn!("failed to get graphics mode: {}", why),
+        }
+    }
+    // The modules may still be loading, so the graphics configuration is compared with them
+    // once udev has settled.
+    daemon.gfx_unchecked = !safe_mode && graphics_switchable;
+    let quiet_hours_enabled = daemon.quiet_hours.is_enabled();
+
+    // Spawn hid backlight daemon
+    let _hid_backlight =
+        if daemon.manage_lights { Some(thread::spawn(hid_backlight::daemon)) } else { None };
+
+    let sensors = daemon.sensors.clone();
+    let mut fan_daemon = FanDaemon::new(nvidia_exists, sensors.clone());
+    if !config.daemon.manage_fan {
+        log::info!("Leaving the fans unmanaged, as configured");
+        fan_daemon.set_managed(false);
+    }
+    match FanCurve::from_config(&config.fan) {
+        Ok(Some(curve)) => fan_daemon.set_curve(curve),
+        Ok(None) => (),
+        Err(why) => log::error!("invalid fan curve, using the default: {}", why),
+    }
+    daemon.fan_curve = fan_daemon.curve().points().collect();
+    fan_daemon.set_gpu_floor(config.fan.gpu_power, config.fan.gpu_duty);
+    let nvidia_ids = daemon.graphics.nvidia.iter().map(|gpu| gpu.id().to_owned()).collect();
+    fan_daemon.set_nvidia(daemon.nvml.clone(), nvidia_ids);
+
+    let mut hpd = unsafe { HotPlugDetect::new(nvidia_device_id) }.ok();
+
+    let mux = unsafe { DisplayPortMux::new() }.ok();
+
+    let mut last = hpd.as_mut().map_or([false; 4], |hpd| unsafe { hpd.detect() });
+
+    let hibernation = Hibernation::read().problems();
+    for problem in &hibernation {
+        log::debug!("hibernation: {}", problem);
+    }
+
+    // Each of these features depends on hardware or drivers which may be absent, in which case
+    // the rest of the daemon continues to function without it.
+    daemon.capabilities = [
+        (Capability::ChargeThresholds, charge_thresholds::is_supported()),
+        (Capability::DisplayPortMux, mux.is_some()),
+        (Capability::EnergyAwareScheduling, sched::energy_aware_supported()),
+        (Capability::FanControl, fan_daemon.is_supported()),
+        (Capability::GraphicsSwitching, graphics_switchable),
+        (Capability::Hibernation, hibernation.is_empty()),
+        (Capability::HotPlugDetect, hpd.is_some()),
+        (Capability::IntelPstate, PState::new().is_ok()),
+        (Capability::KeyboardBacklight, daemon.manage_lights && hid_backlight::is_supported()),
+    ]
+    .iter()
+    .filter_map(|&(capability, supported)| {
+        if supported {
+            Some(capability)
+        } else {
+            log::info!("{} is not supported on this system", capability);
+            None
+        }
+    })
+    .collect();
+
+    // Devices such as `/dev/mem` have been opened, so the capabilities needed to open them are no
+    // longer required.
+    // `hdparm` needs `CAP_SYS_RAWIO` to send commands to drives.
+    if config.daemon.reduce_privileges {
+        let retain_rawio = ModelProfiles::new().is_some() || config.spindown.enabled;
+        if let Err(why) = privileges::reduce(retain_rawio) {
+            log::warn!("failed to reduce capabilities: {}", why);
+        }
+    }
+
+    log::info!("Registering dbus name {}", DBUS_NAME);
+    c.request_name(DBUS_NAME, false, true, false).await.map_err(err_str)?;
+
+    let ppd_mode = config.daemon.power_profiles_daemon;
+    if ppd_mode == PowerProfilesDaemon::Replace {
+        log::info!("Replacing power-profiles-daemon as {}", PPD_NAME);
+        match power_profiles::replace(&c).await {
+            Ok(true) => daemon.ppd_replaced = true,
+            Ok(false) => log::warn!("failed to claim {}", PPD_NAME),
+            Err(why) => log::warn!("failed to claim {}: {}", PPD_NAME, why),
+        }
+    }
+    let ppd_replaced = daemon.ppd_replaced;
+
+    let switcheroo = match switcheroo::claim(&c).await {
+        Ok(claimed) => claimed,
+        Err(why) => {
+            log::warn!("failed to claim {}: {}", SWITCHEROO_NAME, why);
+            false
+        }
+    };
+
+    if !switcheroo {
+        log::info!("{} is provided by another service", SWITCHEROO_NAME);
+    }
+    daemon.switcheroo = switcheroo;
+
+    log::info!("Adding dbus path {} with interface {}", DBUS_PATH, DBUS_IFACE);
+    let mut cr = Crossroads::new();
+    cr.set_async_support(Some((
+        c.clone(),
+        Box::new(|x| {
+            tokio::spawn(x);
+        }),
+    )));
+    let (power_off_sender, mut power_off_requests) = mpsc::unbounded::<PowerOffRequest>();
+    let iface_token = cr.register(DBUS_IFACE, |b| register_interface(b, &c, &power_off_sender, 1));
+    let iface_v2_token =
+        cr.register(DBUS_IFACE_V2, |b| register_interface(b, &c, &power_off_sender, 2));
+    cr.insert(DBUS_PATH, &[iface_token, iface_v2_token], daemon);
+
+    // Desktops which support power-profiles-daemon use this to select profiles.
+    if ppd_replaced {
+        let ppd_token = cr.register(PPD_IFACE, |b: &mut IfaceBuilder<()>| {
+            b.property("ActiveProfile")
+                .get_with_cr(|_, cr| {
+                    Ok(power_profiles::to_ppd(power_daemon(cr)?.requested_profile()).to_owned())
+                })
+                .set_with_cr(|ctx, cr, profile: String| {
+                    log::info!("DBUS Received ActiveProfile {} property", profile);
+                    let profile = power_profiles::from_ppd(&profile)
+                        .ok_or_else(|| MethodErr::invalid_arg(&profile))?;
+                    let daemon = power_daemon(cr)?;
+                    check_rate_limit(daemon, ctx.message(), "ActiveProfile property")?;
+
+                    // The change is announced once the profile has been applied.
+                    daemon.set_profile(profile).map_err(|why| MethodErr::failed(&why))?;
+                    Ok(None)
+                });
+            b.property("Profiles").get(|_, _| Ok(power_profiles::profiles())).emits_changed_const();
+            b.property("Actions").get(|_, _| Ok(Vec::<String>::new())).emits_changed_const();
+            b.property("PerformanceDegraded").get(|_, _| Ok(String::new()));
+            b.property("ActiveProfileHolds").get_with_cr(|_, cr| Ok(power_daemon(cr)?.ppd_holds()));
+            b.method_with_cr(
+                "HoldProfile",
+                ("profile", "reason", "application_id"),
+                ("cookie",),
+                |ctx, cr, (profile, reason, app_id): (String, String, String)| {
+                    let held = power_profiles::from_ppd(&profile);
+                    hold_profile_method(ctx, cr, held, reason, app_id)
+                },
+            );
+            b.method_with_cr("ReleaseProfile", ("cookie",), (), |ctx, cr, (cookie,): (u32,)| {
+                release_profile_method(ctx, cr, cookie)
+            });
+            b.signal::<(u32,), _>("ProfileReleased", ("cookie",));
+        });
+        cr.insert(PPD_PATH, &[ppd_token], ());
+    }
+
+    if switcheroo {
+        let switcheroo_token = cr.register(SWITCHEROO_IFACE, |b: &mut IfaceBuilder<()>| {
+            b.property("HasDualGpu")
+                .get_with_cr(|_, cr| Ok(power_daemon(cr)?.graphics.gpus().len() > 1));
+            b.property("NumGPUs")
+                .get_with_cr(|_, cr| Ok(power_daemon(cr)?.graphics.gpus().len() as u32));
+            b.property("GPUs").get_with_cr(|_, cr| {
+                Ok(switcheroo::gpu_properties(&power_daemon(cr)?.graphics.gpus()))
+            });
+            b.property("OffloadApplications")
+                .get_with_cr(|_, cr| Ok(power_daemon(cr)?.offload_applications()));
+        });
+        cr.insert(SWITCHEROO_PATH, &[switcheroo_token], ());
+    }
+
+    let cr = Arc::new(Mutex::new(cr));
+    let cr_clone = cr.clone();
+    let c_clone = c.clone();
+    let locked = config.policy.locked.clone();
+    let off_when_locked = config.daemon.manage_backlight && config.keyboard.off_when_locked;
+    c.start_receive(
+        MatchRule::new_method_call(),
+        Box::new(move |msg, c| {
+            let locked = access::locked_setting(&msg, &locked);
+            if locked.is_none() && access::is_unprivileged(&msg) {
+                cr_clone.lock().unwrap().handle_message(msg, c).unwrap();
+                return true;
+            }
+
+            // Requests which change state are dispatched once the sender is authorized, and
+            // those which change locked settings only once it is root. The brightness and the
+            // keyboard may be changed from the active session, unless the keyboard is turned off
+            // while that is locked.
+            let session = access::needs_active_session(&msg);
+            let action = access::action_of(&msg);
+            let cr = cr_clone.clone();
+            let c = c_clone.clone();
+            let sender = msg.sender().map(|sender| sender.into_static());
+            tokio::spawn(async move {
+                let authorized = match sender {
+                    Some(sender) => {
+                        let res = if locked.is_some() {
+                            access::is_root(&c, sender.clone()).await
+                        } else if session {
+                            access::from_active_session(&c, sender.clone(), off_when_locked).await
+                        } else {
+                            access::authorize(&c, sender.clone(), action).await
+                        };
+
+                        res.unwrap_or_else(|why| {
+                            log::warn!("failed to authorize {}: {}", sender, why);
+                            false
+                        })
+                    }
+                    None => false,
+                };
+
+                if authorized {
+                    cr.lock().unwrap().handle_message(msg, &*c).unwrap();
+                } else if let Some(setting) = locked {
+                    log::warn!(
+                        "denied {} method from {}, as {} is locked by the system policy",
+                        msg.member().as_deref().unwrap_or_default(),
+                        msg.sender().as_deref().unwrap_or_default(),
+                        setting.as_str()
+                    );
+                    let message = format!("{} is locked by the system policy", setting.as_str());
+                    let reply = msg.error(&POLICY_LOCKED.into(), &CString::new(message).unwrap());
+                    let _ = c.send(reply);
+                } else {
+                    log::warn!(
+                        "denied {} method from {}",
+                        msg.member().as_deref().unwrap_or_default(),
+                        msg.sender().as_deref().unwrap_or_default()
+                    );
+                    let reply = msg.error(
+                        &ACCESS_DENIED.into(),
+                        &CString::new("Operation not permitted").unwrap(),
+                    );
+                    let _ = c.send(reply);
+                }
+            });
+            true
+        }),
+    );
+
+    if config.health.enabled {
+        let cr = cr.clone();
+        let snapshot = move || with_daemon(&cr, |daemon| daemon.health(started.elapsed()));
+        match health::serve(&config.health.listen, snapshot).await {
+            Ok(()) => log::info!("Serving health on {}", config.health.listen),
+            Err(why) => log::warn!("failed to start health endpoint: {}", why),
+        }
+    }
+
+    // Device enumerations are cached, and only updated when the kernel reports a change.
+    let mut uevents = UeventSocket::new()
+        .and_then(AsyncFd::new)
+        .map_err(|why| log::warn!("failed to listen for uevents, polling instead: {}", why))
+        .ok();
+
+    let mut fan_test: Option<FanTest> = None;
+
+    let bindings = Bindings::new(&config.hotkeys);
+    let mut hotkeys = if config.hotkeys.enabled { hotkey_devices(&bindings) } else { Vec::new() };
+
+    // Rather than waking up on a fixed interval, the loop sleeps until a timer that is actually
+    // needed expires, or the kernel reports a device change. DBus requests are handled by the
+    // connection's own task.
+    let mut fan_interval = time::interval(FAN_INTERVAL);
+    fan_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let history_enabled = config.fan.history > 0;
+    let mut history_interval = time::interval(HISTORY_INTERVAL);
+    history_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last_reading = None;
+
+    let mut poll_interval = time::interval(POLL_INTERVAL);
+    poll_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut quiet_hours_interval = time::interval(QUIET_HOURS_INTERVAL);
+    quiet_hours_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let adapter_enabled = config.adapter.enabled;
+    let mut adapter_interval = time::interval(ADAPTER_INTERVAL);
+    adapter_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut charge_sync = ChargeSync::new(config.batteries.clone());
+    let mut charge_interval = time::interval(CHARGE_INTERVAL);
+    charge_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut graphics_interval = time::interval(GRAPHICS_INTERVAL);
+    graphics_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    // Firmware hotkeys and other tools may change the ACPI platform profile, which the kernel
+    // reports to pollers of the file.
+    let (settled_sender, mut udev_settled) = mpsc::unbounded::<()>();
+    if with_daemon(&cr, |daemon| daemon.gfx_unchecked) == Some(true) {
+        thread::spawn(move || {
+            let status = Command::new("udevadm").args(&["settle", "--timeout=60"]).status();
+            if let Err(why) = status {
+                log::warn!("failed to wait for udev to settle: {}", why);
+            }
+            let _ = settled_sender.unbounded_send(());
+        });
+    }
+
+    let (platform_sender, mut platform_changes) = mpsc::unbounded::<()>();
+    if acpi_platform::supported() {
+        thread::spawn(move || {
+            if let Err(why) = acpi_platform::watch(|| platform_sender.unbounded_send(()).is_ok()) {
+                log::warn!("failed to watch the ACPI platform profile: {}", why);
+            }
+        });
+    }
+
+    let mut gpu_power_interval = time::interval(GPU_POWER_INTERVAL);
+    gpu_power_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let clamshell_enabled = config.clamshell.enabled && clamshell::has_lid();
+    let mut clamshell_interval = time::interval(CLAMSHELL_INTERVAL);
+    clamshell_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut auto_performance_interval = time::interval(AUTO_PERFORMANCE_INTERVAL);
+    auto_performance_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut consumers_interval = time::interval(CONSUMERS_INTERVAL);
+    consumers_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let spindown_needed = with_daemon(&cr, |daemon| daemon.spindown.is_enabled()) == Some(true);
+    let mut spindown_interval = time::interval(SPINDOWN_INTERVAL);
+    spindown_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let wifi_enabled = with_daemon(&cr, |daemon| daemon.wifi.is_enabled()) == Some(true);
+    let mut wifi_interval = time::interval(WIFI_INTERVAL);
+    wifi_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let throttle_needed =
+        with_daemon(&cr, |daemon| daemon.throttle.is_supported(!daemon.graphics.nvidia.is_empty()))
+            == Some(true);
+    let mut throttle_interval = time::interval(THROTTLE_INTERVAL);
+    throttle_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    // UPower is preferred, as it reports the battery level that the desktop shows. Otherwise,
+    // the power supplies in sysfs are read whenever the kernel reports a change to them.
+    let (_upower_match, mut upower) = match upower::watch(&c).await {
+        Ok((upower_match, upower)) => (Some(upower_match), Some(upower)),
+        Err(why) => {
+            log::info!("failed to watch UPower, using sysfs: {}", why);
+            (None, None)
+        }
+    };
+
+    let source = read_power_source(&c, &mut upower).await;
+    with_daemon(&cr, |daemon| daemon.power_source_changed(source));
+
+    // Following applies the profile of power-profiles-daemon whenever it changes, including
+    // that of an instance which is started after this daemon.
+    let (_ppd_match, mut ppd) = if ppd_mode == PowerProfilesDaemon::Follow {
+        match power_profiles::watch(&c).await {
+            Ok((ppd_match, ppd)) => (Some(ppd_match), Some(ppd)),
+            Err(why) => {
+                log::warn!("failed to watch power-profiles-daemon: {}", why);
+                (None, None)
+            }
+        }
+    } else {
+        (None, None)
+    };
+
+    if ppd.is_some() && power_profiles::is_running(&c).await.unwrap_or(false) {
+        log::info!("Following the profile of power-profiles-daemon");
+        match power_profiles::active_profile(&c).await {
+            Ok(Some(profile)) => follow_profile(&cr, profile),
+            Ok(None) => (),
+            Err(why) => log::warn!("failed to get the power-profiles-daemon profile: {}", why),
+        }
+    }
+
+    let (_disconnects_match, mut disconnects) = match watch_disconnects(&c).await {
+        Ok((disconnects_match, disconnects)) => (Some(disconnects_match), Some(disconnects)),
+        Err(why) => {
+            log::warn!("failed to watch for disconnected clients: {}", why);
+            (None, None)
+        }
+    };
+
+    // A graphics switch which was interrupted would otherwise boot with the initramfs and
+    // module configuration of different modes.
+    let (_shutdown_match, mut shutdown) = match logind::watch_shutdown(&c).await {
+        Ok((shutdown_match, shutdown)) => (Some(shutdown_match), Some(shutdown)),
+        Err(why) => {
+            log::warn!("failed to watch for shutdown: {}", why);
+            (None, None)
+        }
+    };
+
+    // Low power residency is compared from before suspend to after resume, where the platform
+    // reports it, and the graphics power and profile are applied again after resume.
+    let (_sleep_match, mut sleep) = match logind::watch_sleep(&c).await {
+        Ok((sleep_match, sleep)) => (Some(sleep_match), Some(sleep)),
+        Err(why) => {
+            log::warn!("failed to watch for suspend: {}", why);
+            (None, None)
+        }
+    };
+    let mut asleep: Option<(SystemTime, Residency, Vec<String>)> = None;
+    let mut sleep_lock = match sleep {
+        Some(_) => inhibit_sleep(&c).await,
+        None => None,
+    };
+
+    let mut idle_dimmer = IdleDimmer::default();
+    let manage_lights = config.daemon.manage_backlight;
+    let (_idle_match, mut idle) = if manage_lights && config.keyboard.off_when_idle {
+        match logind::watch_idle(&c).await {
+            Ok((idle_match, idle)) => (Some(idle_match), Some(idle)),
+            Err(why) => {
+                log::warn!("failed to watch for idle sessions: {}", why);
+                (None, None)
+            }
+        }
+    } else {
+        (None, None)
+    };
+
+    let (_lock_match, mut locks) = if off_when_locked {
+        match logind::watch_locks(&c).await {
+            Ok((lock_match, locks)) => {
+                lock_changed(&c, &mut idle_dimmer).await;
+                (Some(lock_match), Some(locks))
+            }
+            Err(why) => {
+                log::warn!("failed to watch for locked sessions: {}", why);
+                (None, None)
+            }
+        }
+    } else {
+        (None, None)
+    };
+    let mut dpms_interval = time::interval(DPMS_INTERVAL);
+    dpms_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut shutdown_lock = match shutdown {
+        Some(_) => inhibit_shutdown(&c).await,
+        None => None,
+    };
+
+    tokio::pin!(exit);
+
+    log::info!("Handling dbus requests");
+    loop {
+        let fan_test_deadline = fan_test.as_ref().map(|test| test.deadline);
+
+        let bench = with_daemon(&cr, |daemon| {
+            daemon.bench.as_ref().map(|bench| (bench.deadline(), bench.fan_duty()))
+        })
+        .flatten();
+        let firmware = with_daemon(&cr, |daemon| {
+            daemon.firmware.as_ref().map(|update| (update.deadline(), update.fan_duty()))
+        })
+        .flatten();
+        fan_daemon.set_fixed_duty(firmware.or(bench).map(|(_, duty)| duty));
+        fan_daemon.set_on_battery(with_daemon(&cr, |daemon| daemon.on_battery()).unwrap_or(false));
+        let bench_deadline = bench.map(|(deadline, _)| time::Instant::from_std(deadline));
+        let firmware_deadline = firmware.map(|(deadline, _)| time::Instant::from_std(deadline));
+        let pending_profile = with_daemon(&cr, |daemon| daemon.pending_profile_deadline())
+            .flatten()
+            .map(time::Instant::from_std);
+
+        // Without uevents, hwmon devices must be rediscovered on every tick.
+        let fan_needed = fan_daemon.is_supported() || uevents.is_none();
+        let poll_needed = hpd.is_some() || mux.is_some();
+
+        let event = tokio::select! {
+            _ = &mut exit => Event::Exit,
+            _ = fan_interval.tick(), if fan_needed => Event::FanTick,
+            _ = history_interval.tick(), if history_enabled => Event::HistoryTick,
+            _ = poll_interval.tick(), if poll_needed => Event::PollTick,
+            _ = quiet_hours_interval.tick(), if quiet_hours_enabled => Event::QuietHoursTick,
+            _ = adapter_interval.tick(), if adapter_enabled => Event::AdapterTick,
+            _ = charge_interval.tick(), if charge_sync.is_some() => Event::ChargeTick,
+            _ = spindown_interval.tick(), if spindown_needed => Event::SpindownTick,
+            _ = throttle_interval.tick(), if throttle_needed => Event::ThrottleTick,
+            _ = wifi_interval.tick(), if wifi_enabled => Event::WifiTick,
+            _ = graphics_interval.tick(), if graphics_switchable => Event::GraphicsTick,
+            Some(()) = udev_settled.next() => Event::UdevSettled,
+            Some(request) = power_off_requests.next() => Event::ForcePowerOff(request),
+            Some(()) = platform_changes.next() => Event::PlatformProfileChanged,
+            _ = gpu_power_interval.tick(), if graphics_switchable => Event::GpuPowerTick,
+            _ = clamshell_interval.tick(), if clamshell_enabled => Event::ClamshellTick,
+            _ = auto_performance_interval.tick(), if config.auto_performance.enabled => {
+                Event::AutoPerformanceTick
+            }
+            _ = consumers_interval.tick(), if config.consumers.enabled => Event::ConsumersTick,
+            _ = dpms_interval.tick(), if off_when_locked && uevents.is_none() => Event::DpmsTick,
+            _ = time::sleep_until(pending_profile.unwrap_or_else(time::Instant::now)),
+                if pending_profile.is_some() => Event::PendingProfile,
+            events = next_uevents(&uevents) => Event::Uevents(events),
+            (index, pressed) = next_hotkeys(&hotkeys, &bindings) => Event::Hotkeys(index, pressed),
+            Some(message) = job_messages.next() => Event::Job(message),
+            _ = time::sleep_until(fan_test_deadline.unwrap_or_else(time::Instant::now)),
+                if fan_test_deadline.is_some() => Event::FanTestDone,
+            _ = time::sleep_until(bench_deadline.unwrap_or_else(time::Instant::now)),
+                if bench_deadline.is_some() => Event::BenchExpired,
+            _ = time::sleep_until(firmware_deadline.unwrap_or_else(time::Instant::now)),
+                if firmware_deadline.is_some() => Event::FirmwareUpdateExpired,
+            message = next_message(&mut upower) => Event::UPower(message),
+            message = next_message(&mut ppd) => Event::PowerProfiles(message),
+            message = next_message(&mut disconnects) => Event::NameOwnerChanged(message),
+            message = next_message(&mut idle) => Event::IdleChanged(message),
+            message = next_message(&mut locks) => Event::LockChanged(message),
+            message = next_message(&mut shutdown) => Event::PrepareForShutdown(message),
+            message = next_message(&mut sleep) => Event::PrepareForSleep(message),
+        };
+
+        match event {
+            Event::Exit => break,
+            Event::FanTick => {
+                sensors.next_tick();
+                if uevents.is_none() {
+                    fan_daemon.rediscover();
+                }
+
+                let reading = fan_daemon.step().unwrap_or_else(|| FanReading {
+                    cpu:  sensors.cpu_temp(),
+                    gpu:  sensors.gpu_temp(),
+                    duty: None,
+                });
+                last_reading = Some(reading);
+                if let Some(temp) = reading.temp() {
+                    with_daemon(&cr, |daemon| daemon.hooks.temperature(temp));
+                }
+            }
+            Event::HistoryTick => {
+                // Temperatures are recorded whether or not the fans are controlled, so the sensors
+                // are read here when the fans were not stepped since the last tick.
+                let stepped = last_reading.take();
+                let reading = stepped.unwrap_or_else(|| {
+                    sensors.next_tick();
+                    FanReading { cpu: sensors.cpu_temp(), gpu: sensors.gpu_temp(), duty: None }
+                });
+                with_daemon(&cr, |daemon| {
+                    daemon.history.record(Instant::now(), reading);
+                    if let (None, Some(temp)) = (stepped, reading.temp()) {
+                        daemon.hooks.temperature(temp);
+                    }
+                });
+            }
+            Event::PollTick => {
+                if let Some(ref mut hpd) = hpd {
+                    let hpd = unsafe { hpd.detect() };
+                    for i in 0..hpd.len() {
+                        if hpd[i] != last[i] && hpd[i] {
+                            log::info!("HotPlugDetect {}", i);
+                            c.send(
+                                Message::new_signal(DBUS_PATH, DBUS_NAME, "HotPlugDetect")
+                                    .unwrap()
+                                    .append1(i as u64),
+                            )
+                            .map_err(|()| "failed to send message".to_string())?;
+                        }
+                    }
+
+                    last = hpd;
+                }
+
+                if let Some(ref mux) = mux {
+                    unsafe {
+                        mux.step();
+                    }
+                }
+            }
+            Event::AdapterTick => {
+                let adapter = Adapter::from_sysfs();
+                with_daemon(&cr, |daemon| daemon.adapter_changed(adapter));
+            }
+            Event::QuietHoursTick => {
+                let max_duty = with_daemon(&cr, |daemon| {
+                    if daemon.quiet_hours.update() {
+                        let limit = daemon.quiet_hours.power_limit();
+                        daemon.power_limit.set(Reason::QuietHours, limit);
+                        Some(daemon.quiet_hours.fan_max_duty())
+                    } else {
+                        None
+                    }
+                })
+                .flatten();
+
+                if let Some(max_duty) = max_duty {
+                    fan_daemon.set_quiet(max_duty);
+                    fan_daemon.step();
+                }
+            }
+            Event::ChargeTick => {
+                if let Some(thresholds) = charge_sync.as_mut().and_then(ChargeSync::poll) {
+                    charge_thresholds_changed(&c, thresholds);
+                }
+            }
+            Event::SpindownTick => {
+                with_daemon(&cr, |daemon| daemon.spindown.poll());
+            }
+            Event::ThrottleTick => {
+                with_daemon(&cr, PowerDaemon::sample_throttling);
+            }
+            Event::WifiTick => {
+                with_daemon(&cr, |daemon| daemon.wifi.poll());
+            }
+            Event::GraphicsTick => {
+                with_daemon(&cr, PowerDaemon::reconcile_graphics);
+            }
+            Event::ForcePowerOff((device, reply)) => {
+                let res = with_daemon(&cr, |daemon| daemon.force_graphics_power_off(&device))
+                    .unwrap_or_else(|| Err("daemon state is missing".to_owned()));
+                let _ = reply.send(res);
+            }
+            Event::UdevSettled => {
+                with_daemon(&cr, PowerDaemon::check_graphics);
+            }
+            Event::PlatformProfileChanged => {
+                with_daemon(&cr, PowerDaemon::sync_platform_profile);
+            }
+            Event::GpuPowerTick => {
+                with_daemon(&cr, PowerDaemon::auto_gpu_power);
+            }
+            Event::ClamshellTick => {
+                if let Some(active) = with_daemon(&cr, PowerDaemon::update_clamshell).flatten() {
+                    let offset = if active { Some(config.clamshell.fan_offset) } else { None };
+                    fan_daemon.set_clamshell(offset);
+                    fan_daemon.step();
+                }
+            }
+            Event::AutoPerformanceTick => {
+                with_daemon(&cr, PowerDaemon::update_auto_performance);
+            }
+            Event::ConsumersTick => {
+                with_daemon(&cr, |daemon| daemon.consumers.as_mut().map(ConsumerSampler::sample));
+            }
+            Event::DpmsTick => idle_dimmer.displays_off_changed(drm::displays_off()),
+            Event::PendingProfile => {
+                with_daemon(&cr, PowerDaemon::apply_pending_profile);
+            }
+            Event::Uevents(events) => {
+                let added = |events: &[Uevent], subsystem: &str| {
+                    events.iter().any(|event| event.subsystem == subsystem && event.action == "add")
+                };
+                // An eGPU may be plugged in or unplugged at any time.
+                let pci_changed = |events: &[Uevent]| {
+                    events.iter().any(|event| {
+                        event.subsystem == "pci"
+                            && (event.action == "add" || event.action == "remove")
+                    })
+                };
+
+                let (
+                    hwmon_changed,
+                    pci_changed,
+                    usb_added,
+                    power_changed,
+                    input_added,
+                    drm_changed,
+                ) = match events {
+                    Ok(events) => (
+                        events.iter().any(|event| event.subsystem == "hwmon"),
+                        pci_changed(&events),
+                        added(&events, "usb"),
+                        events.iter().any(|event| event.subsystem == "power_supply"),
+                        added(&events, "input"),
+                        events.iter().any(|event| event.subsystem == "drm"),
+                    ),
+                    Err(ref why) if why.raw_os_error() == Some(libc::ENOBUFS) => {
+                        log::warn!("uevents were dropped, rescanning devices");
+                        (true, true, true, true, true, true)
+                    }
+                    Err(why) => {
+                        log::warn!("failed to read uevents, polling instead: {}", why);
+                        uevents = None;
+                        (true, true, true, true, true, true)
+                    }
+                };
+
+                // Connectors report a change when displays are turned off or on.
+                if drm_changed && off_when_locked {
+                    idle_dimmer.displays_off_changed(drm::displays_off());
+                }
+
+                if input_added && config.hotkeys.enabled {
+                    hotkeys = hotkey_devices(&bindings);
+                }
+
+                if power_changed {
+                    if let Some(thresholds) = charge_sync.as_mut().and_then(ChargeSync::poll) {
+                        charge_thresholds_changed(&c, thresholds);
+                    }
+                }
+
+                if power_changed && adapter_enabled {
+                    let adapter = Adapter::from_sysfs();
+                    with_daemon(&cr, |daemon| daemon.adapter_changed(adapter));
+                }
+
+                if power_changed && upower.is_none() {
+                    let source = PowerSource::from_sysfs();
+                    with_daemon(&cr, |daemon| daemon.power_source_changed(source));
+                }
+
+                if hwmon_changed {
+                    fan_daemon.rediscover();
+                }
+
+                if pci_changed {
+                    with_daemon(&cr, |daemon| {
+                        if let Err(why) = daemon.graphics.refresh() {
+                            log::warn!("failed to enumerate graphics devices: {}", why);
+                        }
+                    });
+                }
+
+                if pci_changed || usb_added {
+                    with_daemon(&cr, |daemon| daemon.devices.apply(daemon.on_battery()));
+                }
+            }
+            Event::Hotkeys(index, pressed) => {
+                let pressed = match pressed {
+                    Ok(pressed) => pressed,
+                    Err(why) => {
+                        // Most likely, the device was unplugged.
+                        let device = hotkeys.remove(index);
+                        log::info!(
+                            "stopped listening for hotkeys on {}: {}",
+                            device.get_ref().path().display(),
+                            why
+                        );
+                        continue;
+                    }
+                };
+
+                for hotkey in pressed {
+                    let state = match hotkey {
+                        Hotkey::FanBoost => {
+                            if !fan_daemon.is_supported() {
+                                log::info!("fan boost is not supported on this system");
+                                continue;
+                            }
+
+                            let boost = !fan_daemon.boost();
+                            fan_daemon.set_boost(boost);
+                            fan_daemon.step();
+                            String::from(if boost { "on" } else { "off" })
+                        }
+                        Hotkey::PerformanceToggle => with_daemon(&cr, |daemon| {
+                            if let Err(why) = daemon.toggle_performance() {
+                                log::warn!("failed to toggle performance profile: {}", why);
+                            }
+                            daemon.requested_profile().to_owned()
+                        })
+                        .unwrap_or_default(),
+                    };
+
+                    log::info!("{} hotkey pressed: {}", hotkey.as_str(), state);
+                    let message = Message::new_signal(DBUS_PATH, DBUS_NAME, "HotkeyPressed")
+                        .unwrap()
+                        .append2(hotkey.as_str(), state);
+                    if let Err(()) = c.send(message) {
+                        log::error!("failed to send hotkey message");
+                    }
+                }
+            }
+            Event::Job(JobMessage::Finished(id, res)) => {
+                with_daemon(&cr, |daemon| daemon.job_finished(id, res));
+            }
+            Event::Job(JobMessage::ScriptFinished(number, res)) => {
+                with_daemon(&cr, |daemon| daemon.script_finished(number, res));
+            }
+            Event::Job(JobMessage::StartFanTest(id)) => {
+                if !fan_daemon.is_supported() {
+                    let res = Err("fan control is not supported on this system".into());
+                    with_daemon(&cr, |daemon| daemon.job_finished(id, res));
+                    continue;
+                }
+
+                fan_test = Some(FanTest {
+                    id,
+                    idle: fan_daemon.fan_speeds(),
+                    boost: fan_daemon.boost(),
+                    deadline: time::Instant::now() + FAN_TEST_DURATION,
+                });
+
+                fan_daemon.set_boost(true);
+                fan_daemon.step();
+                with_daemon(&cr, |daemon| daemon.job_progress(id, "full-speed", None));
+            }
+            Event::Job(JobMessage::CancelFanTest(id)) => {
+                if let Some(test) = fan_test.take() {
+                    fan_daemon.set_boost(test.boost);
+                    fan_daemon.step();
+                }
+
+                with_daemon(&cr, |daemon| daemon.job_finished(id, Err("cancelled".into())));
+            }
+            Event::FanTestDone => {
+                if let Some(test) = fan_test.take() {
+                    let full = fan_daemon.fan_speeds();
+                    fan_daemon.set_boost(test.boost);
+                    fan_daemon.step();
+
+                    let res = check_fan_speeds(&test.idle, &full);
+                    with_daemon(&cr, |daemon| daemon.job_finished(test.id, res));
+                }
+            }
+            Event::BenchExpired => {
+                log::info!("benchmark mode expired");
+                if let Some(Err(why)) = with_daemon(&cr, PowerDaemon::stop_bench_mode) {
+                    log::error!("failed to restore CPU frequency after benchmark mode: {}", why);
+                }
+            }
+            Event::FirmwareUpdateExpired => {
+                log::warn!("firmware update expired without being ended");
+                with_daemon(&cr, PowerDaemon::end_firmware_update);
+            }
+            Event::UPower(message) => {
+                if message.is_none() {
+                    log::warn!("lost UPower signal stream, using sysfs");
+                    upower = None;
+                }
+
+                let source = read_power_source(&c, &mut upower).await;
+                with_daemon(&cr, |daemon| daemon.power_source_changed(source));
+            }
+            Event::PowerProfiles(message) => match message {
+                Some(message) => {
+                    if let Some(profile) = power_profiles::changed_profile(&message) {
+                        follow_profile(&cr, profile);
+                    }
+                }
+                None => {
+                    log::warn!("lost power-profiles-daemon signal stream");
+                    ppd = None;
+                }
+            },
+            Event::NameOwnerChanged(message) => match message {
+                Some(message) => {
+                    if let Ok((name, _, new_owner)) = message.read3::<&str, &str, &str>() {
+                        if new_owner.is_empty() && name.starts_with(':') {
+                            with_daemon(&cr, |daemon| daemon.release_owner(name));
+                        }
+                    }
+                }
+                None => {
+                    log::warn!("lost name owner signal stream");
+                    disconnects = None;
+                }
+            },
+            Event::IdleChanged(message) => match message {
+                Some(message) => {
+                    if let Some(idle) = logind::idle_changed(&message) {
+                        idle_dimmer.idle_changed(idle);
+                    }
+                }
+                None => {
+                    log::warn!("lost idle signal stream");
+                    idle = None;
+                }
+            },
+            Event::LockChanged(message) => match message {
+                Some(message) => {
+                    if logind::lock_may_have_changed(&message) {
+                        lock_changed(&c, &mut idle_dimmer).await;
+                    }
+                }
+                None => {
+                    log::warn!("lost lock signal stream");
+                    locks = None;
+                }
+            },
+            Event::PrepareForShutdown(message) => match message {
+                Some(message) => {
+                    if message.read1::<bool>().unwrap_or(false) {
+                        // A switch which is still in progress is finished by its own job, which
+                        // logind waits for until `InhibitDelayMaxSec` runs out.
+                        let switching = || {
+                            with_daemon(&cr, |daemon| {
+                                daemon.jobs.find(JobKind::GraphicsSwitch).is_some()
+                            })
+                            .unwrap_or(false)
+                        };
+
+                        if switching() {
+                            log::info!("Waiting for graphics switch to finish");
+                        }
+
+                        while switching() {
+                            match job_messages.next().await {
+                                Some(JobMessage::Finished(id, res)) => {
+                                    with_daemon(&cr, |daemon| daemon.job_finished(id, res));
+                                }
+                                Some(_) => (),
+                                None => break,
+                            }
+                        }
+
+                        // One which failed, or which was interrupted and whose job is not
+                        // running, is completed while the lock still delays shutdown.
+                        let (switchable, initramfs, services, backends) =
+                            with_daemon(&cr, |daemon| {
+                                let graphics = &daemon.graphics;
+                                let switchable = graphics
+                                    .pending_vendor()
+                                    .filter(|_| daemon.manage_gfx)
+                                    .and_then(|_| graphics.switchable());
+                                let services = graphics.services.clone();
+                                let backends = graphics.backends().clone();
+                                (switchable, graphics.initramfs, services, backends)
+                            })
+                            .unwrap_or_default();
+                        let res = switchable.map_or(Ok(None), |switchable| {
+                            Graphics::complete_pending_switch(
+                                &switchable,
+                                initramfs,
+                                &services,
+                                &backends,
+                            )
+                        });
+                        match res {
+                            Ok(Some(vendor)) => log::info!("Switched to {} graphics", vendor),
+                            Ok(None) => (),
+                            Err(why) => log::error!("failed to complete graphics switch: {}", why),
+                        }
+
+                        // Dropping the lock allows shutdown to proceed.
+                        shutdown_lock = None;
+                    } else if shutdown_lock.is_none() {
+                        shutdown_lock = inhibit_shutdown(&c).await;
+                    }
+                }
+                None => {
+                    log::warn!("lost shutdown signal stream");
+                    shutdown = None;
+                }
+            },
+            Event::PrepareForSleep(message) => match message {
+                Some(message) => {
+                    if message.read1::<bool>().unwrap_or(false) {
+                        with_daemon(&cr, PowerDaemon::sleeping);
+                        asleep = if s0ix::is_supported() && s0ix::uses_s2idle() {
+                            let blockers = s0ix::powered_blocks();
+                            Some((SystemTime::now(), Residency::read(), blockers))
+                        } else {
+                            None
+                        };
+
+                        // The system is ready for sleep, which the lock was holding up.
+                        sleep_lock = None;
+                    } else {
+                        if let Some((started, before, blockers)) = asleep.take() {
+                            // The monotonic clock does not advance while suspended.
+                            let duration = started.elapsed().unwrap_or_default();
+                            let after = Residency::read();
+                            let report = SuspendReport::new(before, after, duration, blockers);
+                            with_daemon(&cr, |daemon| daemon.suspended(report));
+                        }
+
+                        with_daemon(&cr, PowerDaemon::resumed);
+                        // The firmware may have taken the fans back while asleep.
+                        fan_daemon.rediscover();
+                        fan_daemon.step();
+
+                        if sleep_lock.is_none() {
+                            sleep_lock = inhibit_sleep(&c).await;
+                        }
+                    }
+                }
+                None => {
+                    log::warn!("lost sleep signal stream");
+                    sleep = None;
+                }
+            },
+        }
+    }
+
+    with_daemon(&cr, |daemon| {
+        daemon.power_limit.restore();
+        if let Err(why) = daemon.stop_bench_mode() {
+            log::error!("failed to restore CPU frequency after benchmark mode: {}", why);
+        }
+    });
+
+    log::info!("daemon exited from loop");
+    Ok(())
+}
+
+/// Keeps the power of the dGPU which was set manually, or forgets it once automatic power is
+/// selected again.
+fn remember_graphics_power(power: Option<bool>) {
+    let store = StateStore::default();
+    let res = match power {
+        Some(power) => store.store(GRAPHICS_POWER_STATE, &power),
+        None => store.remove(GRAPHICS_POWER_STATE),
+    };
+    if let Err(why) = res {
+        log::warn!("failed to save graphics power: {}", why);
+    }
+}
+
+/// Announces new charge thresholds, along with the profile they belong to, if any.
+fn charge_thresholds_changed(c: &SyncConnection, (start, end): (u8, u8)) {
+    let profile = profile_for_thresholds((start, end)).map(|profile| profile.id);
+    log::info!("charge thresholds changed to {}-{} ({:?})", start, end, profile);
+
+    let message = Message::new_signal(DBUS_PATH, DBUS_NAME, "ChargeThresholdsChanged")
+        .unwrap()
+        .append3(start, end, profile.unwrap_or_default());
+    if let Err(()) = c.send(message) {
+        log::error!("failed to send charge thresholds message");
+    }
+}
+
+/// Runs a closure on the daemon state owned by the crossroads instance.
+fn with_daemon<R>(cr: &Mutex<Crossroads>, f: impl FnOnce(&mut PowerDaemon) -> R) -> Option<R> {
+    cr.lock().unwrap().data_mut::<PowerDaemon>(&DBUS_PATH.into()).map(f)
+}
+
+/// Finds the daemon state from a handler of another object.
+fn power_daemon(cr: &mut Crossroads) -> Result<&mut PowerDaemon, MethodErr> {
+    let path = DBUS_PATH.into();
+    cr.data_mut::<PowerDaemon>(&path).ok_or_else(|| MethodErr::no_path(&path))
+}
+
+/// Applies a profile which was selected through power-profiles-daemon.
+fn follow_profile(cr: &Mutex<Crossroads>, profile: Profile) {
+    log::info!("power-profiles-daemon selected the {:?} profile", profile);
+    with_daemon(cr, |daemon| {
+        if let Err(why) = daemon.set_profile(profile) {
+            log::warn!("failed to follow power-profiles-daemon: {}", why);
+        }
+    });
+}
+
+/// Holds a profile on behalf of the sender, until it is released or the sender disconnects.
+fn hold_profile_method(
+    ctx: &mut Context,
+    cr: &mut Crossroads,
+    profile: Option<Profile>,
+    reason: String,
+    app_id: String,
+) -> Result<(u32,), MethodErr> {
+    log::info!("DBUS Received HoldProfile({:?}, {:?}, {:?}) method", profile, reason, app_id);
+    let daemon = power_daemon(cr)?;
+    check_rate_limit(daemon, Some(ctx.message()), "HoldProfile method")?;
+
+    let profile = profile.ok_or_else(|| MethodErr::invalid_arg("profile"))?;
+    let owner = ctx.message().sender().map(|sender| sender.to_string()).unwrap_or_default();
+    daemon
+        .hold_profile(ProfileHold { profile, reason, app_id, owner })
+        .map(|cookie| (cookie,))
+        .map_err(|why| MethodErr::failed(&why))
+}
+
+/// Holds the system steady on behalf of the sender, until it ends the update or disconnects.
+fn start_firmware_update_method(
+    ctx: &mut Context,
+    cr: &mut Crossroads,
+    reason: String,
+    minutes: u32,
+) -> Result<(), MethodErr> {
+    log::info!("DBUS Received StartFirmwareUpdate({:?}, {}) method", reason, minutes);
+    let daemon = power_daemon(cr)?;
+    check_rate_limit(daemon, Some(ctx.message()), "StartFirmwareUpdate method")?;
+
+    let owner = ctx.message().sender().map(|sender| sender.to_string()).unwrap_or_default();
+    daemon.start_firmware_update(reason, owner, minutes).map_err(|why| MethodErr::failed(&why))
+}
+
+fn release_profile_method(
+    ctx: &mut Context,
+    cr: &mut Crossroads,
+    cookie: u32,
+) -> Result<(), MethodErr> {
+    log::info!("DBUS Received ReleaseProfile({}) method", cookie);
+    let daemon = power_daemon(cr)?;
+    check_rate_limit(daemon, Some(ctx.message()), "ReleaseProfile method")?;
+    daemon.release_profile(cookie).map_err(|why| MethodErr::failed(&why))
+}
+
+/// Registers the methods of a version of the daemon's interface. Versions are served side by
+/// side, so that clients of the original interface keep working while newer clients move on.
+fn register_interface(
+    b: &mut IfaceBuilder<PowerDaemon>,
+    c: &Arc<SyncConnection>,
+    power_off: &UnboundedSender<PowerOffRequest>,
+    version: u32,
+) {
+    b.property("Version").get(move |_, _| Ok(version)).emits_changed_const();
+    if version == 1 {
+        deprecated_action_method(b, "Performance", "SetProfile", PowerDaemon::performance);
+        deprecated_action_method(b, "Balanced", "SetProfile", PowerDaemon::balanced);
+        deprecated_action_method(b, "Battery", "SetProfile", PowerDaemon::battery);
+    } else {
+        sync_set_method(b, "SetProfile", "profile", |d, profile: String| {
+            let profile = profile_from_name(&profile)
+                .ok_or_else(|| format!("unknown profile `{}`", profile))?;
+            d.set_profile(profile)
+        });
+    }
+    sync_get_method(
+        b,
+        "GetExternalDisplaysRequireDGPU",
+        "required",
+        PowerDaemon::get_external_displays_require_dgpu,
+    );
+    sync_get_method(b, "GetDefaultGraphics", "vendor", PowerDaemon::get_default_graphics);
+    sync_get_method(b, "GetGraphics", "vendor", PowerDaemon::get_graphics);
+    if version == 1 {
+        // The original interface returns once the switch finished, rather than with its job.
+        b.method_with_cr_async(
+            "SetGraphics",
+            ("vendor",),
+            (),
+            |mut ctx, cr, (vendor,): (String,)| {
+                log::info!("DBUS Received SetGraphics({:?}) method", vendor);
+                let finished = power_daemon(cr).and_then(|daemon| {
+                    check_rate_limit(daemon, Some(ctx.message()), "SetGraphics method")?;
+                    let id = daemon
+                        .start_graphics_switch(&vendor)
+                        .map_err(|why| MethodErr::failed(&why))?;
+                    let (waiter, finished) = oneshot::channel();
+                    daemon.job_waiters.push((id, waiter));
+                    Ok(finished)
+                });
+                async move {
+                    let res = match finished {
+                        Ok(finished) => match finished.await {
+                            Ok(res) => res.map_err(|why| MethodErr::failed(&why)),
+                            Err(_) => Err(MethodErr::failed(&"the daemon is exiting")),
+                        },
+                        Err(why) => Err(why),
+                    };
+                    ctx.reply(res)
+                }
+            },
+        );
+    } else {
+        sync_method(b, "SetGraphics", ("vendor",), ("job",), true, |d, (s,): (String,)| {
+            d.start_graphics_switch(&s).map(|job| (job,))
+        });
+    }
+    sync_method(
+        b,
+        "PreviewGraphics",
+        ("vendor",),
+        ("preview",),
+        true,
+        |d, (vendor,): (String,)| {
+            let plan = d.graphics.preview_vendor(&vendor).map_err(err_str)?;
+            let files = plan.files.into_iter().map(|(path, contents)| {
+                (path.to_owned(), String::from_utf8_lossy(&contents).into_owned())
+            });
+            let removed = plan.removed.into_iter().map(str::to_owned);
+            let services =
+                plan.services.into_iter().map(|(action, unit)| (action.as_str().to_owned(), unit));
+            Ok(((
+                files.collect::<Vec<_>>(),
+                removed.collect::<Vec<_>>(),
+                services.collect::<Vec<_>>(),
+                plan.commands,
+            ),))
+        },
+    );
+    sync_method(b, "Reconcile", (), ("job",), true, |d, _: ()| {
+        d.reconcile_loaded_graphics().map(|job| (job,))
+    });
+    b.property("OffloadApplications")
+        .get_with_cr(|_, cr| Ok(power_daemon(cr)?.offload_applications()));
+    b.property("RepairSuggestion").get_with_cr(|_, cr| Ok(power_daemon(cr)?.repair_suggestion()));
+    sync_get_method(b, "VerifyGraphics", "verification", |d| {
+        let verification = d.graphics.verify();
+        Ok((
+            verification.saved.unwrap_or_default(),
+            verification.pending.unwrap_or_default(),
+            verification.prime.unwrap_or_default(),
+            verification.configured.unwrap_or_default().to_owned(),
+            verification.modules.into_iter().map(str::to_owned).collect::<Vec<_>>(),
+            verification.problems,
+        ))
+    });
+    sync_method(b, "RepairGraphics", (), ("job",), true, |d, _: ()| {
+        d.repair_graphics().map(|job| (job,))
+    });
+    sync_get_method(b, "GetProfile", "profile", PowerDaemon::get_profile);
+    sync_get_method(b, "GetProfileReport", "subsystems", |d| {
+        let outcomes = d.profile_report.outcomes().iter().map(|(subsystem, outcome)| {
+            ((*subsystem).to_owned(), outcome.as_str().to_owned(), outcome.detail().to_owned())
+        });
+        Ok(outcomes.collect::<Vec<_>>())
+    });
+    sync_get_method(b, "GetSwitchable", "switchable", PowerDaemon::get_switchable);
+    sync_get_method(b, "GetGraphicsPower", "power", PowerDaemon::get_graphics_power);
+    sync_set_method(b, "SetGraphicsPower", "power", PowerDaemon::set_graphics_power);
+    let power_off = power_off.clone();
+    b.method_with_cr_async(
+        "ForceGraphicsPowerOff",
+        ("device",),
+        (),
+        move |mut ctx, cr, (device,): (String,)| {
+            log::info!("DBUS Received ForceGraphicsPowerOff({:?}) method", device);
+            let users = power_daemon(cr).and_then(|daemon| {
+                check_rate_limit(daemon, Some(ctx.message()), "ForceGraphicsPowerOff method")?;
+                daemon.power_off_users(&device).map_err(|why| MethodErr::failed(&why))
+            });
+            let power_off = power_off.clone();
+            async move {
+                let res = match users {
+                    Ok(users) => force_power_off(&power_off, device, users)
+                        .await
+                        .map_err(|why| MethodErr::failed(&why)),
+                    Err(why) => Err(why),
+                };
+                ctx.reply(res)
+            }
+        },
+    );
+    sync_method(b, "AutoGraphicsPower", (), (), true, |d, _: ()| d.auto_graphics_power());
+    sync_get_method(b, "GetGpuPowerStates", "functions", |d| {
+        let states = d.graphics.power_states().into_iter().map(|func| {
+            (
+                func.id,
+                func.runtime_status,
+                func.power_state.unwrap_or_default(),
+                func.driver.unwrap_or_default(),
+            )
+        });
+        Ok(states.collect::<Vec<_>>())
+    });
+    sync_get_method(b, "GetNvidiaPower", "gpus", |d| {
+        let power = d.graphics.nvidia.iter().filter_map(|gpu| {
+            let power = NvidiaPower::read(gpu.id())?;
+            Some((gpu.id().to_owned(), power.runtime_d3, power.video_memory))
+        });
+        Ok(power.collect::<Vec<_>>())
+    });
+    sync_get_method(b, "GetPcieLinks", "links", |d| {
+        // Values which are not available are zero.
+        let links = d.graphics.topology().into_iter().filter_map(|dev| {
+            if dev.role != GpuRole::Discrete {
+                return None;
+            }
+            let link = pci::PcieLink::read(&dev.id);
+            let value = |value: Option<u8>| value.unwrap_or(0);
+            Some((
+                dev.id,
+                value(link.speed),
+                value(link.width),
+                value(link.max_speed),
+                value(link.max_width),
+                value(link.target_speed),
+            ))
+        });
+        Ok(links.collect::<Vec<_>>())
+    });
+    sync_get_method(b, "GetRuntimePm", "devices", |_| {
+        let devices = pci::RuntimePm::read_all().into_iter().map(|dev| {
+            let kind = dev.kind.as_str().to_owned();
+            (dev.id, kind, dev.control, dev.status, dev.active_time, dev.suspended_time)
+        });
+        Ok(devices.collect::<Vec<_>>())
+    });
+    sync_get_method(b, "GetGraphicsInfo", "devices", |d| {
+        let inventory = d.graphics.inventory().into_iter().map(|gpu| {
+            let vram = gpu.vram.unwrap_or(0);
+            let driver = gpu.driver.unwrap_or_default();
+            (gpu.id, gpu.vendor.to_owned(), gpu.device, vram, driver, gpu.boot_vga)
+        });
+        Ok(inventory.collect::<Vec<_>>())
+    });
+    sync_get_method(b, "GetGraphicsTopology", "devices", |d| {
+        let topology =
+            d.graphics.topology().into_iter().map(|dev| {
+                (dev.id, dev.vendor.to_owned(), dev.role.as_str().to_owned(), dev.powered)
+            });
+        Ok(topology.collect::<Vec<_>>())
+    });
+    sync_method(
+        b,
+        "SetDevicePower",
+        ("device", "power"),
+        (),
+        true,
+        |d, (device, power): (String, bool)| d.set_device_power(&device, power, false),
+    );
+    sync_method(
+        b,
+        "IncreaseBrightness",
+        ("device",),
+        ("percent",),
+        true,
+        |d, (device,): (String,)| d.step_brightness(&device, true).map(|percent| (percent,)),
+    );
+    sync_method(
+        b,
+        "DecreaseBrightness",
+        ("device",),
+        ("percent",),
+        true,
+        |d, (device,): (String,)| d.step_brightness(&device, false).map(|percent| (percent,)),
+    );
+    sync_get_method(b, "GetKeyboardZones", "zones", PowerDaemon::get_keyboard_zones);
+    sync_method(
+        b,
+        "SetKeyboardZone",
+        ("zone", "color", "brightness"),
+        (),
+        true,
+        |d, (zone, color, percent): (String, u32, u8)| d.set_keyboard_zone(&zone, color, percent),
+    );
+    sync_get_method(b, "GetChargeThresholds", "thresholds", PowerDaemon::get_charge_thresholds);
+    sync_get_method(b, "GetBatteryThresholds", "batteries", |d| {
+        battery_thresholds(&d.bat_thresholds)
+    });
+    let c_clone = c.clone();
+    b.method_with_cr_async(
+        "SetChargeThresholds",
+        ("thresholds",),
+        (),
+        move |mut ctx, cr, (thresholds,): ((u8, u8),)| {
+            let sender = ctx.message().sender().unwrap().into_static();
+            let limited = cr
+                .data_mut::<PowerDaemon>(ctx.path())
+                .map_or(false, |daemon| !daemon.rate_limiter.check(&sender, Instant::now()));
+            let overrides = cr
+                .data_mut::<PowerDaemon>(ctx.path())
+                .map(|daemon| daemon.bat_thresholds.clone())
+                .unwrap_or_default();
+            let c = c_clone.clone();
+            let res = async move {
+                let pid =
+                    polkit::get_connection_unix_process_id(&c, sender).await.map_err(err_str)?;
+                let permitted = if pid == 0 {
+                    true
+                } else {
+                    polkit::check_authorization(&c, pid, 0, THRESHOLD_POLICY)
+                        .await
+                        .map_err(err_str)?
+                };
+                if permitted {
+                    set_charge_thresholds(thresholds, &overrides)?;
+                    Ok(())
+                } else {
+                    Err("Operation not permitted by Polkit".to_string())
+                }
+            };
+            async move {
+                if limited {
+                    return ctx.reply(Err(MethodErr::from((LIMITS_EXCEEDED, "too many requests"))));
+                }
+
+                ctx.reply(res.await.map_err(|e| MethodErr::failed(&e)))
+            }
+        },
+    );
+    sync_get_method(b, "GetChargeProfiles", "profiles", PowerDaemon::get_charge_profiles);
+    sync_get_method(b, "GetCapabilities", "capabilities", PowerDaemon::get_capabilities);
+    sync_get_method(b, "GetInfo", "info", |_| {
+        let dmi = info::dmi();
+        let model = dmi.get("product_version").map_or("", String::as_str);
+        let mut quirks = info::quirks(model, ModelProfiles::new().is_some());
+        if safe_mode::is_enabled() {
+            quirks.push("safe-mode".to_owned());
+        }
+
+        let features = info::features().into_iter().map(String::from).collect::<Vec<_>>();
+        let ec_version = info::ec_version().unwrap_or_default();
+        Ok((info::version().to_owned(), features, dmi, ec_version, quirks))
+    });
+    b.method_with_cr(
+        "HoldProfile",
+        ("profile", "reason", "app_id"),
+        ("cookie",),
+        |ctx, cr, (profile, reason, app_id): (String, String, String)| {
+            hold_profile_method(ctx, cr, profile_from_name(&profile), reason, app_id)
+        },
+    );
+    b.method_with_cr("ReleaseProfile", ("cookie",), (), |ctx, cr, (cookie,): (u32,)| {
+        release_profile_method(ctx, cr, cookie)
+    });
+    sync_get_method(b, "GetTemperatures", "temperatures", |d| {
+        let temperatures = Sensors::read(&d.sensors).temperatures.into_iter();
+        Ok(temperatures.map(|temp| (temp.sensor, temp.celsius)).collect::<Vec<_>>())
+    });
+    sync_get_method(b, "GetFanCurve", "points", |d| Ok(d.fan_curve.clone()));
+    sync_get_method(b, "GetSuspendReport", "report", |d| {
+        let report = d
+            .suspend_report
+            .as_ref()
+            .ok_or_else(|| String::from("no s2idle suspend since the daemon started"))?;
+        Ok((
+            report.duration.as_secs(),
+            report.system_percent().unwrap_or(-1.0),
+            report.cpu_percent().unwrap_or(-1.0),
+            report.blockers.clone(),
+        ))
+    });
+    sync_get_method(b, "GetHibernation", "hibernation", |_| {
+        let hibernation = Hibernation::read();
+        Ok((hibernation.swap, hibernation.image_size, hibernation.problems()))
+    });
+    sync_get_method(b, "GetAdapter", "adapter", |d| {
+        Ok((d.adapter.watts().unwrap_or(0), d.adapter.is_underpowered()))
+    });
+    sync_get_method(b, "GetConsumers", "report", |d| {
+        let sampler = d
+            .consumers
+            .as_ref()
+            .ok_or_else(|| String::from("process sampling is disabled in the configuration"))?;
+        let report = sampler
+            .report()
+            .ok_or_else(|| String::from("processes have not been sampled twice yet"))?;
+        // Values which are not available are negative.
+        let unknown = |value: Option<f64>| value.unwrap_or(-1.0);
+        let pressure = report.pressure;
+        let consumers = report.consumers.iter().map(|consumer| {
+            (consumer.pid, consumer.name.clone(), consumer.cpu, unknown(consumer.watts))
+        });
+        Ok((
+            report.interval.as_secs() as u32,
+            unknown(report.package),
+            (unknown(pressure.cpu), unknown(pressure.io), unknown(pressure.memory)),
+            consumers.collect::<Vec<_>>(),
+        ))
+    });
+    sync_get_method(b, "GetThermalHistory", "readings", |d| {
+        let celsius = |temp: Option<u32>| temp.map_or(-1.0, |temp| f64::from(temp) / 1000.0);
+        let readings = d.history.readings(Instant::now()).map(|(ago, reading)| {
+            let duty = reading.duty.map_or(-1.0, |duty| f64::from(duty) * 100.0 / 255.0);
+            (ago.as_secs() as u32, celsius(reading.cpu), celsius(reading.gpu), duty)
+        });
+        Ok(readings.collect::<Vec<_>>())
+    });
+    sync_get_method(b, "GetThrottling", "sources", |d| {
+        let now = Instant::now();
+        let sources = d.throttle.sources();
+        let sources = sources.iter().map(|source| {
+            let seconds = source.total(now).as_secs();
+            (source.name().to_owned(), source.events(), source.is_throttled(), seconds)
+        });
+        Ok(sources.collect::<Vec<_>>())
+    });
+    sync_get_method(b, "GetBattery", "battery", |d| {
+        let source = d.power_source.unwrap_or_default();
+        Ok((source.on_battery, source.percentage.unwrap_or(-1.0), source.low))
+    });
+    sync_get_method(b, "GetJobs", "jobs", |d| {
+        Ok(d.jobs.iter().map(Job::to_dbus).collect::<Vec<_>>())
+    });
+    sync_set_method(b, "CancelJob", "job", PowerDaemon::cancel_job);
+    sync_get_method(b, "GetBenchMode", "state", |d| Ok(d.bench_mode()));
+    sync_method(
+        b,
+        "SetBenchMode",
+        ("enabled", "minutes"),
+        (),
+        true,
+        |d, (enabled, minutes): (bool, u32)| d.set_bench_mode(enabled, minutes),
+    );
+    sync_get_method(b, "GetFirmwareUpdate", "state", |d| Ok(d.firmware_update()));
+    b.method_with_cr(
+        "StartFirmwareUpdate",
+        ("reason", "minutes"),
+        (),
+        |ctx, cr, (reason, minutes): (String, u32)| {
+            start_firmware_update_method(ctx, cr, reason, minutes)
+        },
+    );
+    sync_method(b, "EndFirmwareUpdate", (), (), true, |d, _: ()| {
+        d.end_firmware_update();
+        Ok(())
+    });
+    sync_method(b, "StartFanTest", (), ("job",), true, |d, _: ()| {
+        d.start_fan_test().map(|job| (job,))
+    });
+    sync_method(b, "StartBatteryCalibration", (), ("job",), true, |d, _: ()| {
+        d.start_battery_calibration().map(|job| (job,))
+    });
+
+    // Signals are only emitted on the original interface, which clients of every version watch.
+    if version == 1 {
+        b.signal::<(u64,), _>("HotPlugDetect", ("port",));
+        b.signal::<(&str,), _>("GraphicsChanged", ("vendor",));
+        b.signal::<(&str, &str), _>("GraphicsSwitchProgress", ("vendor", "stage"));
+        b.signal::<(&str, Vec<String>), _>("ExternalDisplaysLost", ("vendor", "connectors"));
+        b.signal::<(&str, &str), _>("RepairSuggested", ("configured", "loaded"));
+        b.signal::<(&str,), _>("PowerProfileSwitch", ("profile",));
+        b.signal::<(u32,), _>("ProfileReleased", ("cookie",));
+        b.signal::<(&str, &str), _>("HotkeyPressed", ("action", "state"));
+        b.signal::<(u8, u8, &str), _>("ChargeThresholdsChanged", ("start", "end", "profile"));
+        b.signal::<(u32, bool), _>("AdapterChanged", ("watts", "underpowered"));
+        b.signal::<(u32, &str, &str, i32), _>("JobProgress", ("job", "kind", "stage", "progress"));
+        b.signal::<(u32, &str, bool, &str), _>("JobFinished", ("job", "kind", "success", "error"));
+    }
+}
+
+/// Stops the processes which have a discrete GPU open, without holding up the main loop while
+/// they exit, and then has the main loop power it off.
+async fn force_power_off(
+    requests: &UnboundedSender<PowerOffRequest>,
+    device: String,
+    users: Vec<DrmUser>,
+) -> Result<(), String> {
+    drm::terminate(&users)
+        .await
+        .map_err(|why| format!("failed to stop the processes using the GPU: {}", why))?;
+
+    let (reply, result) = oneshot::channel();
+    requests.unbounded_send((device, reply)).map_err(|_| "the daemon is exiting".to_owned())?;
+    result.await.map_err(|_| "the daemon is exiting".to_owned())?
+}
+
+/// Methods which change state are rate limited per sender, so that a misbehaving client cannot
+/// hammer sysfs with requests.
+fn check_rate_limit(
+    daemon: &mut PowerDaemon,
+    message: Option<&Message>,
+    name: &str,
+) -> Result<(), MethodErr> {
+    if let Some(sender) = message.and_then(Message::sender) {
+        if !daemon.rate_limiter.check(&sender, Instant::now()) {
+            log::warn!("rate limiting {} from {}", name, sender);
+            return Err(MethodErr::from((LIMITS_EXCEEDED, "too many requests")));
+        }
+    }
+
+    Ok(())
+}
+
+fn sync_method<IA, OA, F>(
     b: &mut IfaceBuilder<PowerDaemon>,
     name: &'static str,
     input_args: IA::strs,
     output_args: OA::strs,
+    rate_limited: bool,
     f: F,
 ) where
     IA: arg::ArgAll + arg::ReadAll + Debug,
@@ -368,22 +3662,46 @@ fn sync_method<IA, OA, F>(
 {
     b.method_with_cr(name, input_args, output_args, move |ctx, cr, args| {
         log::info!("DBUS Received {}{:?} method", name, args);
-        match cr.data_mut(ctx.path()) {
-            Some(daemon) => match f(daemon, args) {
-                Ok(ret) => Ok(ret),
-                Err(err) => Err(MethodErr::failed(&err)),
-            },
+        match cr.data_mut::<PowerDaemon>(ctx.path()) {
+            Some(daemon) => {
+                if rate_limited {
+                    check_rate_limit(daemon, Some(ctx.message()), &format!("{} method", name))?;
+                }
+
+                match f(daemon, args) {
+                    Ok(ret) => Ok(ret),
+                    Err(err) => Err(MethodErr::failed(&err)),
+                }
+            }
             None => Err(MethodErr::no_path(ctx.path())),
         }
     });
 }
 
-/// DBus wrapper for a method taking no argument and returning no values
-fn sync_action_method<F>(b: &mut IfaceBuilder<PowerDaemon>, name: &'static str, f: F)
-where
+/// DBus wrapper for a method of the original interface which a newer version replaces, warning
+/// whenever a client still calls it.
+fn deprecated_action_method<F>(
+    b: &mut IfaceBuilder<PowerDaemon>,
+    name: &'static str,
+    replacement: &'static str,
+    f: F,
+) where
     F: Fn(&mut PowerDaemon) -> Result<(), String> + Send + 'static,
 {
-    sync_method(b, name, (), (), move |d, _: ()| f(d));
+    b.method_with_cr(name, (), (), move |ctx, cr, _: ()| {
+        log::info!("DBUS Received {} method", name);
+        log::warn!(
+            "{} called the deprecated {} method, which is replaced by {} of {}",
+            ctx.message().sender().as_deref().unwrap_or_default(),
+            name,
+            replacement,
+            DBUS_IFACE_V2
+        );
+        let daemon =
+            cr.data_mut::<PowerDaemon>(ctx.path()).ok_or_else(|| MethodErr::no_path(ctx.path()))?;
+        check_rate_limit(daemon, Some(ctx.message()), &format!("{} method", name))?;
+        f(daemon).map_err(|err| MethodErr::failed(&err))
+    });
 }
 
 /// DBus wrapper for method taking no arguments and returning one value
@@ -396,7 +3714,7 @@ fn sync_get_method<T, F>(
     T: arg::Arg + arg::Append + Debug,
     F: Fn(&mut PowerDaemon) -> Result<T, String> + Send + 'static,
 {
-    sync_method(b, name, (), (output_arg,), move |d, _: ()| f(d).map(|x| (x,)));
+    sync_method(b, name, (), (output_arg,), false, move |d, _: ()| f(d).map(|x| (x,)));
 }
 
 /// DBus wrapper for method taking one argument and returning no values
@@ -409,5 +3727,5 @@ fn sync_set_method<T, F>(
     T: arg::Arg + for<'z> arg::Get<'z> + Debug,
     F: Fn(&mut PowerDaemon, T) -> Result<(), String> + Send + 'static,
 {
-    sync_method(b, name, (input_arg,), (), move |d, (arg,)| f(d, arg))
+    sync_method(b, name, (input_arg,), (), true, move |d, (arg,)| f(d, arg))
 }
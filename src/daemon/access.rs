@@ -0,0 +1,299 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Decides which DBus requests unprivileged users may make.
+//!
+//! Any local user may query the state of the daemon, so that status bars do not need elevated
+//! helpers. Requests which change state are only accepted from root, members of the groups which
+//! the bus policy has always admitted, or users which Polkit authorizes. This is enforced here as
+//! well, so that a permissive bus policy cannot expose them.
+//!
+//! The brightness of the screen and the lighting of the keyboard may be changed by any user of
+//! the active session.
+//!
+//! Settings which the system policy locks may only be changed by root.
+
+use crate::{
+    config::LockedSetting, logind, polkit, power_profiles::PPD_IFACE, DBUS_IFACE, DBUS_IFACE_V2,
+};
+use dbus::{message::Message, nonblock::SyncConnection, strings::BusName};
+use std::{ffi::CString, mem, ptr};
+
+pub const MANAGE_POLICY: &str = "com.system76.powerdaemon.manage";
+
+/// Holding a profile is allowed without authenticating from the active session, as
+/// power-profiles-daemon allows, so that applications may hold one while they run.
+pub const HOLD_POLICY: &str = "com.system76.powerdaemon.hold-profile";
+
+/// Groups whose members may change state without authenticating.
+const ADMIN_GROUPS: &[&str] = &["adm", "wheel"];
+
+/// Methods of the daemon's interface which only report state.
+const READ_ONLY_METHODS: &[&str] = &[
+    "GetAdapter",
+    "GetBenchMode",
+    "GetBattery",
+    "GetBatteryThresholds",
+    "GetCapabilities",
+    "GetChargeProfiles",
+    "GetChargeThresholds",
+    "GetConsumers",
+    "GetDefaultGraphics",
+    "GetExternalDisplaysRequireDGPU",
+    "GetFanCurve",
+    "GetFirmwareUpdate",
+    "GetGpuPowerStates",
+    "GetGraphics",
+    "GetGraphicsInfo",
+    "GetGraphicsPower",
+    "GetGraphicsTopology",
+    "GetHibernation",
+    "GetInfo",
+    "GetJobs",
+    "GetKeyboardZones",
+    "GetNvidiaPower",
+    "GetPcieLinks",
+    "GetProfile",
+    "GetProfileReport",
+    "GetRuntimePm",
+    "GetSuspendReport",
+    "GetSwitchable",
+    "GetTemperatures",
+    "GetThermalHistory",
+    "GetThrottling",
+    "PreviewGraphics",
+    "VerifyGraphics",
+];
+
+/// Methods which change the brightness or the lighting of the keyboard, which any user of the
+/// active session may, as they sit in front of it. Window managers bind keys to them.
+const ACTIVE_SESSION_METHODS: &[&str] =
+    &["DecreaseBrightness", "IncreaseBrightness", "SetKeyboardZone"];
+
+/// Methods which perform their own Polkit check, with an action of their own.
+const SELF_AUTHORIZED_METHODS: &[&str] = &["SetChargeThresholds"];
+
+/// Whether a method call may be made by any user. Unknown methods are assumed to change state.
+pub fn is_unprivileged(message: &Message) -> bool {
+    let interface = message.interface();
+    let member = match message.member() {
+        Some(member) => member,
+        None => return false,
+    };
+
+    match interface.as_deref() {
+        Some("org.freedesktop.DBus.Introspectable") | Some("org.freedesktop.DBus.Peer") => true,
+        Some("org.freedesktop.DBus.Properties") => &*member != "Set",
+        Some(interface) if interface == DBUS_IFACE || interface == DBUS_IFACE_V2 => {
+            READ_ONLY_METHODS.contains(&&*member) || SELF_AUTHORIZED_METHODS.contains(&&*member)
+        }
+        _ => false,
+    }
+}
+
+/// Whether a method call may be made by any user of the active session.
+pub fn needs_active_session(message: &Message) -> bool {
+    let (interface, member) = match (message.interface(), message.member()) {
+        (Some(interface), Some(member)) => (interface, member),
+        _ => return false,
+    };
+
+    (&*interface == DBUS_IFACE || &*interface == DBUS_IFACE_V2)
+        && ACTIVE_SESSION_METHODS.contains(&&*member)
+}
+
+/// The setting which a method call changes, if the system policy may lock it.
+fn setting_of(message: &Message) -> Option<LockedSetting> {
+    let interface = message.interface()?;
+    let member = message.member()?;
+
+    if &*interface == PPD_IFACE {
+        return Some(LockedSetting::Profile);
+    }
+
+    if &*interface == "org.freedesktop.DBus.Properties" {
+        let target: Option<&str> = message.get1();
+        return match (&*member, target) {
+            ("Set", Some(PPD_IFACE)) => Some(LockedSetting::Profile),
+            _ => None,
+        };
+    }
+
+    if &*interface != DBUS_IFACE && &*interface != DBUS_IFACE_V2 {
+        return None;
+    }
+
+    let setting = match &*member {
+        "Battery" | "Balanced" | "Performance" | "SetProfile" | "HoldProfile" => {
+            LockedSetting::Profile
+        }
+        "SetChargeThresholds" | "StartBatteryCalibration" => LockedSetting::ChargeThresholds,
+        "SetGraphics" | "Reconcile" | "RepairGraphics" => LockedSetting::Graphics,
+        "SetGraphicsPower" | "AutoGraphicsPower" | "SetDevicePower" | "ForceGraphicsPowerOff" => {
+            LockedSetting::GraphicsPower
+        }
+        "SetBenchMode" => LockedSetting::BenchMode,
+        _ => return None,
+    };
+
+    Some(setting)
+}
+
+/// The Polkit action which authorizes a method call which changes state.
+pub fn action_of(message: &Message) -> &'static str {
+    let (interface, member) = match (message.interface(), message.member()) {
+        (Some(interface), Some(member)) => (interface, member),
+        _ => return MANAGE_POLICY,
+    };
+
+    let holds = &*member == "HoldProfile" || &*member == "ReleaseProfile";
+    if holds
+        && (&*interface == DBUS_IFACE || &*interface == DBUS_IFACE_V2 || &*interface == PPD_IFACE)
+    {
+        HOLD_POLICY
+    } else {
+        MANAGE_POLICY
+    }
+}
+
+/// The setting which a method call changes, if the system policy locks it.
+pub fn locked_setting(message: &Message, locked: &[LockedSetting]) -> Option<LockedSetting> {
+    setting_of(message).filter(|setting| locked.contains(setting))
+}
+
+fn group_id(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    let mut group: libc::group = unsafe { mem::zeroed() };
+    let mut result = ptr::null_mut();
+    let mut buf: Vec<libc::c_char> = vec![0; 4096];
+
+    let res = unsafe {
+        libc::getgrnam_r(name.as_ptr(), &mut group, buf.as_mut_ptr(), buf.len(), &mut result)
+    };
+
+    if res == 0 && !result.is_null() {
+        Some(group.gr_gid)
+    } else {
+        None
+    }
+}
+
+/// Checks whether the sender of a request is root, which alone may change locked settings.
+pub async fn is_root(c: &SyncConnection, sender: BusName<'_>) -> Result<bool, dbus::Error> {
+    Ok(polkit::get_connection_credentials(c, sender).await?.uid == Some(0))
+}
+
+/// Checks whether the sender of a request is root, or belongs to the active session of the first
+/// seat. Neither may while that session is locked, if `unlocked` is set, as the keyboard is
+/// turned off then.
+pub async fn from_active_session(
+    c: &SyncConnection,
+    sender: BusName<'_>,
+    unlocked: bool,
+) -> Result<bool, dbus::Error> {
+    if unlocked && logind::active_session_locked(c).await? {
+        return Ok(false);
+    }
+
+    let credentials = polkit::get_connection_credentials(c, sender).await?;
+    if credentials.uid == Some(0) {
+        return Ok(true);
+    }
+
+    match credentials.pid {
+        Some(pid) => logind::in_active_session(c, pid).await,
+        None => Ok(false),
+    }
+}
+
+/// Checks whether the sender of a request may change the state of the daemon, as the Polkit
+/// `action` of the request allows.
+pub async fn authorize(
+    c: &SyncConnection,
+    sender: BusName<'_>,
+    action: &str,
+) -> Result<bool, dbus::Error> {
+    let credentials = polkit::get_connection_credentials(c, sender).await?;
+    if credentials.uid == Some(0) {
+        return Ok(true);
+    }
+
+    let admin = ADMIN_GROUPS
+        .iter()
+        .filter_map(|name| group_id(name))
+        .any(|gid| credentials.groups.contains(&gid));
+    if admin {
+        return Ok(true);
+    }
+
+    match credentials.pid {
+        Some(pid) => polkit::check_authorization(c, pid, 0, action).await,
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(interface: &str, member: &str) -> Message {
+        Message::new_method_call("com.system76.PowerDaemon", "/", interface, member).unwrap()
+    }
+
+    #[test]
+    fn unprivileged_methods() {
+        assert!(is_unprivileged(&call(DBUS_IFACE, "GetProfile")));
+        assert!(is_unprivileged(&call(DBUS_IFACE, "GetTemperatures")));
+        assert!(is_unprivileged(&call(DBUS_IFACE_V2, "GetProfile")));
+        assert!(is_unprivileged(&call("org.freedesktop.DBus.Properties", "GetAll")));
+        assert!(is_unprivileged(&call("org.freedesktop.DBus.Introspectable", "Introspect")));
+
+        assert!(!is_unprivileged(&call(DBUS_IFACE, "Performance")));
+        assert!(!is_unprivileged(&call(DBUS_IFACE, "SetGraphics")));
+        assert!(!is_unprivileged(&call(DBUS_IFACE_V2, "SetProfile")));
+        assert!(!is_unprivileged(&call("org.freedesktop.DBus.Properties", "Set")));
+        assert!(!is_unprivileged(&call("net.hadess.PowerProfiles", "HoldProfile")));
+    }
+
+    #[test]
+    fn active_session_methods() {
+        assert!(needs_active_session(&call(DBUS_IFACE, "SetKeyboardZone")));
+        assert!(needs_active_session(&call(DBUS_IFACE_V2, "SetKeyboardZone")));
+        assert!(!is_unprivileged(&call(DBUS_IFACE, "SetKeyboardZone")));
+        assert!(needs_active_session(&call(DBUS_IFACE, "IncreaseBrightness")));
+        assert!(!is_unprivileged(&call(DBUS_IFACE, "IncreaseBrightness")));
+
+        assert!(!needs_active_session(&call(DBUS_IFACE, "GetProfile")));
+        assert!(!needs_active_session(&call("org.freedesktop.DBus.Properties", "Set")));
+    }
+
+    #[test]
+    fn actions() {
+        assert_eq!(action_of(&call(DBUS_IFACE, "HoldProfile")), HOLD_POLICY);
+        assert_eq!(action_of(&call(DBUS_IFACE_V2, "ReleaseProfile")), HOLD_POLICY);
+        assert_eq!(action_of(&call(PPD_IFACE, "HoldProfile")), HOLD_POLICY);
+
+        assert_eq!(action_of(&call(DBUS_IFACE, "SetGraphics")), MANAGE_POLICY);
+        assert_eq!(action_of(&call("org.freedesktop.DBus.Properties", "Set")), MANAGE_POLICY);
+    }
+
+    #[test]
+    fn locked_settings() {
+        let locked = &[LockedSetting::ChargeThresholds, LockedSetting::Profile];
+        let setting = |message: &Message| locked_setting(message, locked);
+
+        assert_eq!(
+            setting(&call(DBUS_IFACE, "SetChargeThresholds")),
+            Some(LockedSetting::ChargeThresholds)
+        );
+        assert_eq!(setting(&call(DBUS_IFACE, "Performance")), Some(LockedSetting::Profile));
+        assert_eq!(setting(&call(DBUS_IFACE_V2, "SetProfile")), Some(LockedSetting::Profile));
+        assert_eq!(setting(&call(PPD_IFACE, "HoldProfile")), Some(LockedSetting::Profile));
+        let set = call("org.freedesktop.DBus.Properties", "Set").append1(PPD_IFACE);
+        assert_eq!(setting(&set), Some(LockedSetting::Profile));
+
+        assert_eq!(setting(&call(DBUS_IFACE, "SetGraphics")), None);
+        assert_eq!(setting(&call(DBUS_IFACE, "GetChargeThresholds")), None);
+    }
+}
@@ -0,0 +1,123 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Automatic performance, which applies the performance profile while the system is on AC power,
+//! cool enough, and not operating as a clamshell, and restores the previous profile once any of
+//! these ends.
+//!
+//! Choosing another profile while the performance profile is applied is respected until the
+//! conditions end, rather than being switched back on the next check.
+
+use crate::config::{AutoPerformanceConfig, Profile};
+
+// Degrees Celsius below the maximum temperature to which the system must cool before the
+// performance profile is applied again, so that its own heat does not switch it back and forth.
+const HYSTERESIS: u8 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Idle,
+    /// The performance profile was applied, replacing the profile which is restored later.
+    Applied(Profile),
+    /// Another profile was chosen while the performance profile was applied.
+    Overridden,
+}
+
+pub struct AutoPerformance {
+    config: AutoPerformanceConfig,
+    state:  State,
+}
+
+impl AutoPerformance {
+    pub fn new(config: AutoPerformanceConfig) -> Self {
+        AutoPerformance { config, state: State::Idle }
+    }
+
+    /// Whether the performance profile is wanted, given whether the system is on AC power and
+    /// operating as a clamshell, and the highest temperature in thousandths of a Celsius.
+    fn wanted(&self, on_ac: bool, clamshell: bool, temp: Option<u32>) -> bool {
+        let max_temp = match self.state {
+            State::Applied(_) | State::Overridden => self.config.max_temp,
+            State::Idle => self.config.max_temp.saturating_sub(HYSTERESIS),
+        };
+
+        on_ac && !clamshell && temp.map_or(false, |temp| temp < u32::from(max_temp) * 1000)
+    }
+
+    /// Takes the conditions and the active profile, returning the profile to switch to, if any.
+    pub fn poll(
+        &mut self,
+        on_ac: bool,
+        clamshell: bool,
+        temp: Option<u32>,
+        current: Option<Profile>,
+    ) -> Option<Profile> {
+        if !self.config.enabled {
+            return None;
+        }
+
+        let wanted = self.wanted(on_ac, clamshell, temp);
+        match (self.state, wanted) {
+            (State::Idle, true) => {
+                self.state = State::Applied(current.unwrap_or(Profile::Balanced));
+                if current == Some(Profile::Performance) {
+                    None
+                } else {
+                    Some(Profile::Performance)
+                }
+            }
+            (State::Applied(_), true) => {
+                if current != Some(Profile::Performance) {
+                    self.state = State::Overridden;
+                }
+                None
+            }
+            (State::Applied(previous), false) => {
+                self.state = State::Idle;
+                if current == Some(Profile::Performance) && previous != Profile::Performance {
+                    Some(previous)
+                } else {
+                    None
+                }
+            }
+            (State::Overridden, false) => {
+                self.state = State::Idle;
+                None
+            }
+            (State::Idle, false) | (State::Overridden, true) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_performance() {
+        let mut auto = AutoPerformance::new(AutoPerformanceConfig { enabled: true, max_temp: 75 });
+        let balanced = Some(Profile::Balanced);
+        let performance = Some(Profile::Performance);
+
+        // Performance is only applied on AC power, out of clamshell operation, once cool enough.
+        assert_eq!(auto.poll(false, false, Some(50_000), balanced), None);
+        assert_eq!(auto.poll(true, true, Some(50_000), balanced), None);
+        assert_eq!(auto.poll(true, false, Some(70_000), balanced), None);
+        assert_eq!(auto.poll(true, false, None, balanced), None);
+        assert_eq!(auto.poll(true, false, Some(60_000), balanced), performance);
+
+        // It is kept until the maximum temperature, and the previous profile is then restored.
+        assert_eq!(auto.poll(true, false, Some(70_000), performance), None);
+        assert_eq!(auto.poll(true, false, Some(80_000), performance), balanced);
+
+        // Another profile which is chosen meanwhile is kept until the conditions end.
+        assert_eq!(auto.poll(true, false, Some(60_000), balanced), performance);
+        assert_eq!(auto.poll(true, false, Some(60_000), Some(Profile::Battery)), None);
+        assert_eq!(auto.poll(true, false, Some(60_000), Some(Profile::Battery)), None);
+        assert_eq!(auto.poll(false, false, Some(60_000), Some(Profile::Battery)), None);
+        assert_eq!(auto.poll(true, false, Some(60_000), Some(Profile::Battery)), performance);
+
+        assert_eq!(auto.poll(false, false, Some(60_000), performance), Some(Profile::Battery));
+    }
+}
@@ -0,0 +1,215 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Benchmark mode, which holds the system in a steady state so that benchmarks are reproducible.
+//!
+//! Every CPU is locked to its base frequency, with the minimum and maximum frequencies equal and
+//! turbo disabled, and the fans are run at a fixed duty cycle. The previous settings are restored
+//! when benchmark mode is turned off, or when it expires, so that a forgotten benchmark mode does
+//! not hold the system at a fixed frequency indefinitely. The previous settings are also kept on
+//! disk until then, so that they are restored when the daemon starts again after a crash.
+
+use crate::{state::StateStore, util};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+
+const CPUFREQ: &str = "/sys/devices/system/cpu/cpufreq";
+
+// Turbo is disabled through `no_turbo` with intel_pstate, and `boost` otherwise.
+const NO_TURBO: &str = "/sys/devices/system/cpu/intel_pstate/no_turbo";
+const BOOST: &str = "/sys/devices/system/cpu/cpufreq/boost";
+
+const BENCH_STATE: &str = "bench";
+
+#[derive(Debug, Error)]
+pub enum BenchError {
+    #[error("CPU frequency scaling is not available")]
+    Unsupported,
+    #[error("failed to read {}: {}", _0.display(), _1)]
+    Read(PathBuf, io::Error),
+    #[error("failed to write {}: {}", _0.display(), _1)]
+    Write(PathBuf, io::Error),
+}
+
+fn read(path: &Path) -> Result<String, BenchError> {
+    fs::read_to_string(path)
+        .map(|value| value.trim().to_owned())
+        .map_err(|why| BenchError::Read(path.to_owned(), why))
+}
+
+fn write(path: &Path, value: &str) -> Result<(), BenchError> {
+    fs::write(path, value).map_err(|why| BenchError::Write(path.to_owned(), why))
+}
+
+/// The frequency limits of a cpufreq policy, in kHz.
+#[derive(Deserialize, Serialize)]
+struct Policy {
+    path: PathBuf,
+    min:  String,
+    max:  String,
+}
+
+impl Policy {
+    /// The frequency the policy is locked to, which is the base frequency where the driver
+    /// reports it, and otherwise the highest frequency without turbo.
+    fn locked_frequency(&self) -> Result<String, BenchError> {
+        read(&self.path.join("base_frequency"))
+            .or_else(|_| read(&self.path.join("cpuinfo_max_freq")))
+    }
+
+    fn set(&self, min: &str, max: &str) -> Result<(), BenchError> {
+        // The minimum may not exceed the maximum at any point, so the order depends on the
+        // direction of the change.
+        let current = read(&self.path.join("scaling_max_freq"))?;
+        let raising = max.parse::<u64>().ok() > current.parse::<u64>().ok();
+        if raising {
+            write(&self.path.join("scaling_max_freq"), max)?;
+            write(&self.path.join("scaling_min_freq"), min)
+        } else {
+            write(&self.path.join("scaling_min_freq"), min)?;
+            write(&self.path.join("scaling_max_freq"), max)
+        }
+    }
+}
+
+fn read_turbo() -> Option<(PathBuf, String)> {
+    [NO_TURBO, BOOST]
+        .iter()
+        .find_map(|&path| read(Path::new(path)).ok().map(|value| (PathBuf::from(path), value)))
+}
+
+/// The settings which benchmark mode replaced, to be restored when it ends.
+#[derive(Deserialize, Serialize)]
+struct Saved {
+    /// The boot during which the settings were saved, as they do not survive a reboot.
+    boot_id:  Option<String>,
+    policies: Vec<Policy>,
+    /// The path and previous value of the turbo setting, if there is one.
+    turbo:    Option<(PathBuf, String)>,
+}
+
+impl Saved {
+    fn read() -> Result<Saved, BenchError> {
+        let entries = fs::read_dir(CPUFREQ).map_err(|_| BenchError::Unsupported)?;
+
+        let mut policies = Vec::new();
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            if !entry.file_name().to_string_lossy().starts_with("policy") {
+                continue;
+            }
+
+            let min = read(&path.join("scaling_min_freq"))?;
+            let max = read(&path.join("scaling_max_freq"))?;
+            policies.push(Policy { path, min, max });
+        }
+
+        if policies.is_empty() {
+            return Err(BenchError::Unsupported);
+        }
+
+        Ok(Saved { boot_id: util::boot_id(), policies, turbo: read_turbo() })
+    }
+
+    /// Keeps the settings on disk, to be restored by `recover` if the daemon does not exit
+    /// cleanly.
+    fn store(&self) {
+        if let Err(why) = StateStore::default().store(BENCH_STATE, self) {
+            log::warn!("failed to save settings from before benchmark mode: {}", why);
+        }
+    }
+
+    fn lock(&self) -> Result<(), BenchError> {
+        match self.turbo {
+            Some((ref path, _)) if path == Path::new(NO_TURBO) => write(path, "1")?,
+            Some((ref path, _)) => write(path, "0")?,
+            None => (),
+        }
+
+        for policy in &self.policies {
+            let frequency = policy.locked_frequency()?;
+            policy.set(&frequency, &frequency)?;
+        }
+
+        Ok(())
+    }
+
+    fn restore(&self) -> Result<(), BenchError> {
+        for policy in &self.policies {
+            policy.set(&policy.min, &policy.max)?;
+        }
+
+        if let Some((ref path, ref value)) = self.turbo {
+            write(path, value)?;
+        }
+
+        if let Err(why) = StateStore::default().remove(BENCH_STATE) {
+            log::warn!("failed to remove saved benchmark mode settings: {}", why);
+        }
+
+        Ok(())
+    }
+}
+
+/// Restores the settings from before a benchmark mode which was still on when the daemon last
+/// exited. Settings saved during a previous boot are discarded, as the kernel has reset them.
+pub fn recover() -> Result<(), BenchError> {
+    let store = StateStore::default();
+    let saved = match store.load::<Saved>(BENCH_STATE) {
+        Some(saved) => saved,
+        None => return Ok(()),
+    };
+
+    if saved.boot_id.is_some() && saved.boot_id == util::boot_id() {
+        log::info!("restoring CPU frequency from a benchmark mode which did not end");
+        saved.restore()
+    } else {
+        let _ = store.remove(BENCH_STATE);
+        Ok(())
+    }
+}
+
+pub struct BenchMode {
+    saved:    Saved,
+    deadline: Instant,
+    /// The duty cycle the fans are held at, in percent.
+    fan_duty: u8,
+}
+
+impl BenchMode {
+    /// Locks the CPU frequency, until the duration has passed.
+    pub fn start(duration: Duration, fan_duty: u8) -> Result<BenchMode, BenchError> {
+        let saved = Saved::read()?;
+        saved.store();
+        if let Err(why) = saved.lock() {
+            let _ = saved.restore();
+            return Err(why);
+        }
+
+        Ok(BenchMode { saved, deadline: Instant::now() + duration, fan_duty })
+    }
+
+    pub fn deadline(&self) -> Instant { self.deadline }
+
+    /// Keeps benchmark mode on until the duration has passed from now.
+    pub fn extend(&mut self, duration: Duration) { self.deadline = Instant::now() + duration; }
+
+    pub fn fan_duty(&self) -> u8 { self.fan_duty }
+
+    /// Locks the CPU frequency again, after a profile may have changed turbo. The turbo setting
+    /// of the profile is the one which will be restored.
+    pub fn relock(&mut self) -> Result<(), BenchError> {
+        self.saved.turbo = read_turbo();
+        self.saved.store();
+        self.saved.lock()
+    }
+
+    /// Restores the settings from before benchmark mode.
+    pub fn stop(self) -> Result<(), BenchError> { self.saved.restore() }
+}
@@ -0,0 +1,172 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+use crate::{logind, state::StateStore};
+use dbus::nonblock::SyncConnection;
+use serde::{Deserialize, Serialize};
+use std::{cell::RefCell, collections::BTreeMap, convert::TryFrom, io, sync::Arc};
+use sysfs_class::{Backlight, Brightness, SysClass};
+
+const BRIGHTNESS_STATE: &str = "screen-brightness";
+
+thread_local! {
+    /// The daemon's connection to the system bus, which runs on the same thread as everything
+    /// which sets brightness.
+    static CONNECTION: RefCell<Option<Arc<SyncConnection>>> = RefCell::new(None);
+}
+
+/// Sets brightness through logind on the daemon's connection to the system bus, from now on.
+pub fn set_connection(conn: Arc<SyncConnection>) {
+    CONNECTION.with(|connection| *connection.borrow_mut() = Some(conn));
+}
+
+/// Sets the brightness of a backlight through logind where a session is active, which avoids
+/// surprising the session with a change it did not make, and directly otherwise. The request to
+/// logind completes in the background, falling back to setting the brightness directly if it
+/// fails.
+pub fn set(backlight: &Backlight, brightness: u64) -> io::Result<()> {
+    let conn = CONNECTION.with(|connection| connection.borrow().clone());
+    let (conn, value) = match (conn, u32::try_from(brightness)) {
+        (Some(conn), Ok(value)) => (conn, value),
+        _ => return backlight.set_brightness(brightness),
+    };
+
+    let id = backlight.id().to_owned();
+    let path = backlight.path().to_owned();
+    tokio::spawn(async move {
+        let why = match logind::set_brightness(&conn, "backlight", &id, value).await {
+            Ok(()) => return,
+            Err(why) => why,
+        };
+
+        log::debug!("failed to set brightness of {} through logind: {}", id, why);
+        let res =
+            Backlight::from_path(&path).and_then(|backlight| backlight.set_brightness(brightness));
+        if let Err(why) = res {
+            log::warn!("failed to set brightness of {}: {}", id, why);
+        }
+    });
+
+    Ok(())
+}
+
+/// Lowers the brightness of a backlight to a percentage of its maximum, if it is brighter.
+pub fn set_if_lower_than(backlight: &Backlight, percent: u64) -> io::Result<()> {
+    let brightness = backlight.max_brightness()? * percent / 100;
+    if brightness < backlight.brightness()? {
+        set(backlight, brightness)
+    } else {
+        Ok(())
+    }
+}
+
+/// The level one step up or down from `current`, where a step is a percentage of `max`, kept
+/// within `min..=max`.
+pub fn step(current: u64, min: u64, max: u64, percent: u64, increase: bool) -> u64 {
+    let step = (max * percent / 100).max(1);
+    if increase {
+        current.saturating_add(step).min(max).max(min)
+    } else {
+        current.saturating_sub(step).max(min).min(max)
+    }
+}
+
+/// Steps the brightness of every screen backlight up or down by a percentage of its maximum,
+/// never turning it off. Returns the new brightness of the first, as a percentage.
+pub fn step_screen(percent: u64, increase: bool) -> io::Result<u8> {
+    let mut first = None;
+    for backlight in Backlight::iter() {
+        let backlight = backlight?;
+        let max = backlight.max_brightness()?;
+        let brightness = step(backlight.brightness()?, 1, max, percent, increase);
+        set(&backlight, brightness)?;
+
+        if first.is_none() && max > 0 {
+            first = Some((brightness * 100 / max) as u8);
+        }
+    }
+
+    first.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no screen backlight"))
+}
+
+/// The screen brightness last chosen on AC and on battery power, keyed by backlight.
+///
+/// When the power source changes, the brightness of the source being left is remembered, and
+/// that of the new source is restored in place of the static value of the profile.
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct BrightnessMemory {
+    ac:      BTreeMap<String, u64>,
+    battery: BTreeMap<String, u64>,
+}
+
+impl BrightnessMemory {
+    pub fn load() -> Self { StateStore::default().load(BRIGHTNESS_STATE).unwrap_or_default() }
+
+    fn levels(&mut self, on_battery: bool) -> &mut BTreeMap<String, u64> {
+        if on_battery {
+            &mut self.battery
+        } else {
+            &mut self.ac
+        }
+    }
+
+    /// Swaps the brightness of the source being left for that of the new source. Returns whether
+    /// any backlight was restored, in which case the profile should leave the brightness alone.
+    pub fn power_source_changed(&mut self, was_on_battery: bool, on_battery: bool) -> bool {
+        let backlights: Vec<Backlight> = Backlight::iter()
+            .filter_map(|backlight| {
+                backlight.map_err(|why| log::warn!("failed to iterate backlight: {}", why)).ok()
+            })
+            .collect();
+
+        let current = backlights
+            .iter()
+            .filter_map(|backlight| Some((backlight.id().to_owned(), backlight.brightness().ok()?)))
+            .collect();
+        *self.levels(was_on_battery) = current;
+
+        if let Err(why) = StateStore::default().store(BRIGHTNESS_STATE, self) {
+            log::warn!("failed to store screen brightness: {}", why);
+        }
+
+        let levels = self.levels(on_battery);
+        let mut restored = false;
+        for backlight in &backlights {
+            let brightness = match levels.get(backlight.id()) {
+                Some(&brightness) => brightness,
+                None => continue,
+            };
+
+            // A level which no longer fits the backlight is not restored.
+            if backlight.max_brightness().map_or(true, |max| brightness > max) {
+                continue;
+            }
+
+            match set(backlight, brightness) {
+                Ok(()) => restored = true,
+                Err(why) => {
+                    log::warn!("failed to restore brightness of {}: {}", backlight.id(), why)
+                }
+            }
+        }
+
+        restored
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps() {
+        assert_eq!(step(50, 1, 100, 5, true), 55);
+        assert_eq!(step(98, 1, 100, 5, true), 100);
+        assert_eq!(step(3, 1, 100, 5, false), 1);
+        assert_eq!(step(0, 0, 5, 10, false), 0);
+
+        // Steps are at least one level, even where the percentage is less.
+        assert_eq!(step(2, 0, 5, 10, true), 3);
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Firmware update mode, which holds the system steady while an updater such as fwupd flashes
+//! firmware.
+//!
+//! The balanced profile is applied, and every CPU is locked to its base frequency with turbo
+//! disabled, as in benchmark mode, which also caps the power it draws. The wakeup latency of the
+//! CPUs is limited through PM QoS, which keeps them out of deep C-states for as long as
+//! `/dev/cpu_dma_latency` is held open. Where the daemon controls the fans, as on desktops, they
+//! run at a fixed duty cycle; the EC of a laptop keeps control of its own. Profile changes are
+//! deferred, and the discrete GPU is neither switched nor powered on or off, until the update
+//! ends. It ends when the updater ends it or disconnects, or when it expires, so that a crashed
+//! updater does not hold the system in this state.
+
+use super::bench::BenchMode;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+const CPU_DMA_LATENCY: &str = "/dev/cpu_dma_latency";
+
+pub struct FirmwareUpdate {
+    pub reason: String,
+    /// The unique bus name of the updater, which ends the update when it disconnects.
+    pub owner:  String,
+    deadline:   Instant,
+    /// The duty cycle the fans are held at, in percent.
+    fan_duty:   u8,
+    /// The latency limit, which is released when the file is closed.
+    _latency:   Option<File>,
+    /// The frequency lock, unless benchmark mode already held one when the update started.
+    frequency:  Option<BenchMode>,
+}
+
+impl FirmwareUpdate {
+    pub fn start(
+        reason: String,
+        owner: String,
+        duration: Duration,
+        fan_duty: u8,
+        lock_frequency: bool,
+    ) -> Self {
+        let latency = match limit_latency() {
+            Ok(file) => Some(file),
+            Err(why) => {
+                log::warn!("failed to limit CPU latency for firmware update: {}", why);
+                None
+            }
+        };
+
+        let frequency = if lock_frequency {
+            match BenchMode::start(duration, fan_duty) {
+                Ok(lock) => Some(lock),
+                Err(why) => {
+                    log::warn!("failed to lock CPU frequency for firmware update: {}", why);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        FirmwareUpdate {
+            reason,
+            owner,
+            deadline: Instant::now() + duration,
+            fan_duty,
+            _latency: latency,
+            frequency,
+        }
+    }
+
+    pub fn deadline(&self) -> Instant { self.deadline }
+
+    /// Keeps the update going until the duration has passed from now.
+    pub fn extend(&mut self, duration: Duration) { self.deadline = Instant::now() + duration; }
+
+    pub fn fan_duty(&self) -> u8 { self.fan_duty }
+
+    /// Restores the CPU frequency and latency from before the update.
+    pub fn end(self) {
+        if let Some(lock) = self.frequency {
+            if let Err(why) = lock.stop() {
+                log::error!("failed to restore CPU frequency after firmware update: {}", why);
+            }
+        }
+    }
+}
+
+/// Requests a wakeup latency of zero from every CPU, for as long as the file is open.
+fn limit_latency() -> io::Result<File> {
+    let mut file = OpenOptions::new().write(true).open(CPU_DMA_LATENCY)?;
+    file.write_all(&0i32.to_ne_bytes())?;
+    Ok(file)
+}
@@ -0,0 +1,113 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Automatic power of the NVIDIA GPU in hybrid mode, which follows demand rather than only being
+//! applied when the daemon starts.
+//!
+//! The GPU is powered on once an application is launched to offload rendering to it, and removed
+//! again once nothing has used it for the idle period. Setting the power manually suspends this
+//! until automatic power is selected again.
+
+use std::time::{Duration, Instant};
+
+// How often a powered GPU is checked for users, which requires searching the open files of
+// every process.
+const USE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+pub struct AutoPower {
+    enabled:    bool,
+    /// How long the GPU may go unused before it is removed. Zero keeps it powered.
+    idle:       Duration,
+    idle_since: Option<Instant>,
+    checked:    Option<Instant>,
+}
+
+impl Default for AutoPower {
+    fn default() -> Self { AutoPower::new(Duration::from_secs(0)) }
+}
+
+impl AutoPower {
+    pub fn new(idle: Duration) -> Self {
+        AutoPower { enabled: true, idle, idle_since: None, checked: None }
+    }
+
+    pub fn is_enabled(&self) -> bool { self.enabled }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.idle_since = None;
+        self.checked = None;
+    }
+
+    /// Decides whether the GPU should be powered on or off. While it is off, `in_use` reports
+    /// whether an application wants to offload rendering to it, and while it is on, whether
+    /// anything is using it.
+    pub fn poll<F: FnOnce() -> bool>(
+        &mut self,
+        now: Instant,
+        powered: bool,
+        in_use: F,
+    ) -> Option<bool> {
+        if !self.enabled {
+            return None;
+        }
+
+        if !powered {
+            self.idle_since = None;
+            self.checked = None;
+            return if in_use() { Some(true) } else { None };
+        }
+
+        if self.idle == Duration::from_secs(0)
+            || self.checked.map_or(false, |checked| now - checked < USE_CHECK_INTERVAL)
+        {
+            return None;
+        }
+
+        self.checked = Some(now);
+        if in_use() {
+            self.idle_since = None;
+            return None;
+        }
+
+        let since = *self.idle_since.get_or_insert(now);
+        if now - since >= self.idle {
+            self.idle_since = None;
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_demand() {
+        let start = Instant::now();
+        let seconds = |seconds: u64| start + Duration::from_secs(seconds);
+        let mut power = AutoPower::new(Duration::from_secs(60));
+
+        // An offload client powers the GPU on.
+        assert_eq!(power.poll(seconds(0), false, || false), None);
+        assert_eq!(power.poll(seconds(2), false, || true), Some(true));
+
+        // It stays on while in use, and is only checked periodically.
+        assert_eq!(power.poll(seconds(4), true, || true), None);
+        assert_eq!(power.poll(seconds(6), true, || panic!("checked too soon")), None);
+
+        // It is removed once unused for the idle period.
+        assert_eq!(power.poll(seconds(34), true, || false), None);
+        assert_eq!(power.poll(seconds(64), true, || false), None);
+        assert_eq!(power.poll(seconds(94), true, || false), Some(false));
+
+        // Nothing changes while disabled, or with no idle period.
+        power.set_enabled(false);
+        assert_eq!(power.poll(seconds(100), false, || true), None);
+        let mut power = AutoPower::new(Duration::from_secs(0));
+        assert_eq!(power.poll(seconds(0), true, || false), None);
+    }
+}
@@ -0,0 +1,71 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! The temperatures and fan duty cycles of the last few minutes, so that a graph can show recent
+//! history as soon as it is opened, rather than starting out empty.
+
+use crate::fan::FanReading;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+pub struct ThermalHistory {
+    length:   Duration,
+    readings: VecDeque<(Instant, FanReading)>,
+}
+
+impl Default for ThermalHistory {
+    fn default() -> Self { ThermalHistory::new(Duration::from_secs(0)) }
+}
+
+impl ThermalHistory {
+    pub fn new(length: Duration) -> Self { ThermalHistory { length, readings: VecDeque::new() } }
+
+    /// Records a reading, forgetting those which are older than the length of the history.
+    pub fn record(&mut self, now: Instant, reading: FanReading) {
+        while self.readings.front().map_or(false, |&(at, _)| now - at >= self.length) {
+            self.readings.pop_front();
+        }
+
+        if self.length > Duration::from_secs(0) {
+            self.readings.push_back((now, reading));
+        }
+    }
+
+    /// The readings from oldest to newest, with how long ago each was taken.
+    pub fn readings(&self, now: Instant) -> impl Iterator<Item = (Duration, FanReading)> + '_ {
+        self.readings.iter().map(move |&(at, reading)| (now.saturating_duration_since(at), reading))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forgets_old_readings() {
+        let start = Instant::now();
+        let seconds = |seconds: u64| start + Duration::from_secs(seconds);
+        let reading = |cpu: u32| FanReading { cpu: Some(cpu), gpu: None, duty: Some(128) };
+
+        let mut history = ThermalHistory::new(Duration::from_secs(60));
+        for (second, cpu) in [(0, 40_000), (30, 50_000), (60, 60_000), (90, 70_000)].iter() {
+            history.record(seconds(*second), reading(*cpu));
+        }
+
+        let readings: Vec<_> = history.readings(seconds(90)).collect();
+        assert_eq!(
+            readings,
+            vec![
+                (Duration::from_secs(30), reading(60_000)),
+                (Duration::from_secs(0), reading(70_000))
+            ]
+        );
+
+        let mut history = ThermalHistory::default();
+        history.record(start, reading(40_000));
+        assert_eq!(history.readings(start).count(), 0);
+    }
+}
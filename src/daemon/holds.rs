@@ -0,0 +1,114 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+use crate::config::Profile;
+use std::collections::BTreeMap;
+
+/// A temporary profile requested by an application, such as a game or a video call.
+#[derive(Clone, Debug)]
+pub struct ProfileHold {
+    pub profile: Profile,
+    pub reason:  String,
+    pub app_id:  String,
+    /// The unique bus name of the caller, whose holds are released when it disconnects.
+    pub owner:   String,
+}
+
+/// Holds which are active, keyed by the cookie returned to their caller.
+#[derive(Default)]
+pub struct ProfileHolds {
+    holds:       BTreeMap<u32, ProfileHold>,
+    next_cookie: u32,
+}
+
+impl ProfileHolds {
+    /// Only the battery and performance profiles may be held, as balanced is the default.
+    pub fn can_hold(profile: Profile) -> bool { profile != Profile::Balanced }
+
+    pub fn hold(&mut self, hold: ProfileHold) -> u32 {
+        loop {
+            self.next_cookie = self.next_cookie.wrapping_add(1);
+            if !self.holds.contains_key(&self.next_cookie) {
+                break;
+            }
+        }
+
+        self.holds.insert(self.next_cookie, hold);
+        self.next_cookie
+    }
+
+    pub fn release(&mut self, cookie: u32) -> Option<ProfileHold> { self.holds.remove(&cookie) }
+
+    /// Releases every hold of a caller, returning their cookies.
+    pub fn release_owner(&mut self, owner: &str) -> Vec<u32> {
+        let cookies: Vec<u32> = self
+            .holds
+            .iter()
+            .filter(|(_, hold)| hold.owner == owner)
+            .map(|(&cookie, _)| cookie)
+            .collect();
+
+        for cookie in &cookies {
+            self.holds.remove(cookie);
+        }
+
+        cookies
+    }
+
+    /// Releases every hold, returning their cookies.
+    pub fn release_all(&mut self) -> Vec<u32> {
+        let cookies = self.holds.keys().cloned().collect();
+        self.holds.clear();
+        cookies
+    }
+
+    pub fn is_empty(&self) -> bool { self.holds.is_empty() }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ProfileHold> { self.holds.values() }
+
+    /// The profile to apply while holds are active. Battery takes precedence over performance,
+    /// so that a request to save power is never overridden.
+    pub fn profile(&self) -> Option<Profile> {
+        if self.holds.values().any(|hold| hold.profile == Profile::Battery) {
+            Some(Profile::Battery)
+        } else {
+            self.holds.values().next().map(|hold| hold.profile)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hold(profile: Profile, owner: &str) -> ProfileHold {
+        ProfileHold {
+            profile,
+            reason: String::new(),
+            app_id: String::new(),
+            owner: owner.to_owned(),
+        }
+    }
+
+    #[test]
+    fn battery_takes_precedence() {
+        let mut holds = ProfileHolds::default();
+        assert_eq!(holds.profile(), None);
+
+        let game = holds.hold(hold(Profile::Performance, ":1.1"));
+        assert_eq!(holds.profile(), Some(Profile::Performance));
+
+        holds.hold(hold(Profile::Battery, ":1.2"));
+        holds.hold(hold(Profile::Performance, ":1.2"));
+        assert_eq!(holds.profile(), Some(Profile::Battery));
+
+        // Releasing the caller's holds leaves those of other callers.
+        assert_eq!(holds.release_owner(":1.2").len(), 2);
+        assert_eq!(holds.profile(), Some(Profile::Performance));
+
+        assert!(holds.release(game).is_some());
+        assert!(holds.release(game).is_none());
+        assert!(holds.is_empty());
+    }
+}
@@ -0,0 +1,211 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+use crate::power_source::PowerSource;
+use std::{
+    collections::BTreeMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Operations which take minutes or more, and run in the background.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JobKind {
+    GraphicsSwitch,
+    BatteryCalibration,
+    FanTest,
+}
+
+impl JobKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobKind::GraphicsSwitch => "graphics-switch",
+            JobKind::BatteryCalibration => "battery-calibration",
+            JobKind::FanTest => "fan-test",
+        }
+    }
+
+    /// A graphics switch cannot be cancelled, as stopping the initramfs rebuild part way would
+    /// leave an image which may not boot.
+    pub fn is_cancellable(self) -> bool { self != JobKind::GraphicsSwitch }
+}
+
+#[derive(Clone, Debug)]
+pub struct Job {
+    pub id:          u32,
+    pub kind:        JobKind,
+    pub description: String,
+    pub stage:       &'static str,
+    /// Progress through the current stage, in percent, if it is known.
+    pub progress:    Option<u8>,
+    /// Seconds since the Unix epoch.
+    pub started:     u64,
+}
+
+impl Job {
+    /// The job as returned by `GetJobs`, with a progress of -1 when it is unknown.
+    pub fn to_dbus(&self) -> (u32, String, String, String, i32, bool, u64) {
+        (
+            self.id,
+            self.kind.as_str().to_owned(),
+            self.description.clone(),
+            self.stage.to_owned(),
+            self.progress.map_or(-1, i32::from),
+            self.kind.is_cancellable(),
+            self.started,
+        )
+    }
+}
+
+/// Jobs which are running, keyed by the ID returned to the client which started them.
+#[derive(Default)]
+pub struct Jobs {
+    jobs:    BTreeMap<u32, Job>,
+    last_id: u32,
+}
+
+impl Jobs {
+    /// Starts tracking a job. Only one job of each kind may run at a time.
+    pub fn start(
+        &mut self,
+        kind: JobKind,
+        description: String,
+        stage: &'static str,
+    ) -> Result<u32, String> {
+        if let Some(job) = self.find(kind) {
+            return Err(format!("{} is already running as job {}", kind.as_str(), job.id));
+        }
+
+        // Zero is never used, so that clients may use it to mean no job.
+        loop {
+            self.last_id = self.last_id.wrapping_add(1);
+            if self.last_id != 0 && !self.jobs.contains_key(&self.last_id) {
+                break;
+            }
+        }
+
+        let started =
+            SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+        let id = self.last_id;
+        self.jobs.insert(id, Job { id, kind, description, stage, progress: None, started });
+        Ok(id)
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Job> { self.jobs.get(&id) }
+
+    pub fn find(&self, kind: JobKind) -> Option<&Job> {
+        self.jobs.values().find(|job| job.kind == kind)
+    }
+
+    pub fn update(&mut self, id: u32, stage: &'static str, progress: Option<u8>) -> Option<&Job> {
+        let job = self.jobs.get_mut(&id)?;
+        job.stage = stage;
+        job.progress = progress;
+        Some(job)
+    }
+
+    pub fn finish(&mut self, id: u32) -> Option<Job> { self.jobs.remove(&id) }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Job> { self.jobs.values() }
+}
+
+// Charge levels at which the battery is considered full or empty while calibrating.
+const CALIBRATION_FULL: f64 = 99.5;
+const CALIBRATION_EMPTY: f64 = 5.0;
+
+/// Battery calibration charges the battery fully, waits for it to be discharged on battery
+/// power, and charges it fully again, so that the gauge relearns its capacity.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Calibration {
+    Charging,
+    Discharging,
+    Recharging,
+    Done,
+}
+
+impl Calibration {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Calibration::Charging => "charging",
+            Calibration::Discharging => "discharging",
+            Calibration::Recharging => "recharging",
+            Calibration::Done => "done",
+        }
+    }
+
+    /// The stage to advance to, given the current power source.
+    pub fn next(self, source: &PowerSource) -> Calibration {
+        let percentage = match source.percentage {
+            Some(percentage) => percentage,
+            None => return self,
+        };
+
+        let full = !source.on_battery && percentage >= CALIBRATION_FULL;
+        match self {
+            Calibration::Charging if full => Calibration::Discharging,
+            Calibration::Discharging if source.on_battery && percentage <= CALIBRATION_EMPTY => {
+                Calibration::Recharging
+            }
+            Calibration::Recharging if full => Calibration::Done,
+            stage => stage,
+        }
+    }
+
+    /// Progress through the stage, in percent.
+    pub fn progress(self, source: &PowerSource) -> Option<u8> {
+        let percentage = source.percentage?.clamp(0.0, 100.0);
+        let progress = match self {
+            Calibration::Charging | Calibration::Recharging => percentage,
+            Calibration::Discharging => (100.0 - percentage) * 100.0 / (100.0 - CALIBRATION_EMPTY),
+            Calibration::Done => 100.0,
+        };
+
+        Some(progress.min(100.0) as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_job_per_kind() {
+        let mut jobs = Jobs::default();
+        let switch = jobs.start(JobKind::GraphicsSwitch, "nvidia".into(), "rebuilding").unwrap();
+        assert!(jobs.start(JobKind::GraphicsSwitch, "hybrid".into(), "rebuilding").is_err());
+
+        let test = jobs.start(JobKind::FanTest, String::new(), "measuring").unwrap();
+        assert_ne!(switch, test);
+        assert_eq!(
+            jobs.update(test, "full-speed", Some(50)).map(|job| job.stage),
+            Some("full-speed")
+        );
+
+        assert!(jobs.finish(switch).is_some());
+        assert!(jobs.start(JobKind::GraphicsSwitch, "hybrid".into(), "rebuilding").is_ok());
+    }
+
+    #[test]
+    fn calibration_stages() {
+        let source = |on_battery, percentage| PowerSource {
+            on_battery,
+            percentage: Some(percentage),
+            low: false,
+        };
+
+        let stage = Calibration::Charging;
+        assert_eq!(stage.next(&source(false, 80.0)), Calibration::Charging);
+        assert_eq!(stage.next(&source(false, 100.0)), Calibration::Discharging);
+
+        // Discharging only counts while on battery.
+        let stage = Calibration::Discharging;
+        assert_eq!(stage.next(&source(false, 4.0)), Calibration::Discharging);
+        assert_eq!(stage.next(&source(true, 50.0)), Calibration::Discharging);
+        assert_eq!(stage.progress(&source(true, 52.5)), Some(50));
+        assert_eq!(stage.next(&source(true, 5.0)), Calibration::Recharging);
+
+        let stage = Calibration::Recharging;
+        assert_eq!(stage.next(&source(true, 100.0)), Calibration::Recharging);
+        assert_eq!(stage.next(&source(false, 100.0)), Calibration::Done);
+    }
+}
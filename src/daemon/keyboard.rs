@@ -0,0 +1,280 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Fades keyboard backlights between levels, rather than switching them abruptly.
+//!
+//! A fade steps through every level which the backlight supports, so that the firmware's own
+//! granularity sets how smooth it is, unless that would take steps shorter than a frame. Fades
+//! run on a thread of their own, and a new fade supersedes any fade still in progress.
+//!
+//! The zones of RGB keyboards are set through the embedded controller, either by clients or by
+//! the colors which each profile configures.
+
+use super::brightness;
+use crate::{
+    ec::{Color, EcError, KeyboardBacklight},
+    errors::BacklightError,
+};
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    thread,
+    time::Duration,
+};
+use sysfs_class::{Brightness, Leds, SysClass};
+
+// The shortest step of a fade, which is about a frame.
+const MIN_STEP: Duration = Duration::from_millis(16);
+
+static FADE_MILLIS: AtomicU64 = AtomicU64::new(500);
+
+// Incremented by each fade, so that a fade in progress stops once it has been superseded.
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets how long a fade takes. Zero disables fading.
+pub fn set_fade_duration(duration: Duration) {
+    FADE_MILLIS.store(duration.as_millis() as u64, Ordering::SeqCst);
+}
+
+fn fade_duration() -> Duration { Duration::from_millis(FADE_MILLIS.load(Ordering::SeqCst)) }
+
+/// The levels a fade passes through, ending with the target, and the time between them.
+fn fade_steps(from: u64, to: u64, duration: Duration) -> (Vec<u64>, Duration) {
+    let levels = if from > to { from - to } else { to - from };
+    let count = levels.min(duration.as_millis() as u64 / MIN_STEP.as_millis() as u64).max(1);
+
+    let steps = (1..=count)
+        .map(|step| {
+            if from > to {
+                from - (from - to) * step / count
+            } else {
+                from + (to - from) * step / count
+            }
+        })
+        .collect();
+
+    (steps, duration / count as u32)
+}
+
+fn keyboards() -> Vec<Leds> {
+    Leds::iter_keyboards()
+        .filter_map(|keyboard| {
+            keyboard.map_err(|why| log::warn!("failed to iterate keyboard backlight: {}", why)).ok()
+        })
+        .collect()
+}
+
+/// Whether there is a keyboard backlight.
+pub fn is_present() -> bool { !keyboards().is_empty() }
+
+fn set_brightness(keyboard: &Leds, brightness: u64) -> Result<(), BacklightError> {
+    keyboard
+        .set_brightness(brightness)
+        .map_err(|why| BacklightError::Set(keyboard.id().to_owned(), why))
+}
+
+/// Fades each keyboard backlight to its target level.
+fn fade(targets: Vec<(Leds, u64)>) -> Result<(), BacklightError> {
+    let generation = GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+    let duration = fade_duration();
+
+    let mut fades = Vec::new();
+    for (keyboard, target) in targets {
+        let current = keyboard.brightness().unwrap_or(target);
+        if current == target {
+            continue;
+        }
+
+        if duration == Duration::from_secs(0) {
+            set_brightness(&keyboard, target)?;
+        } else {
+            fades.push((keyboard, fade_steps(current, target, duration)));
+        }
+    }
+
+    for (keyboard, (steps, interval)) in fades {
+        thread::spawn(move || {
+            for brightness in steps {
+                if GENERATION.load(Ordering::SeqCst) != generation {
+                    return;
+                }
+
+                if let Err(why) = set_brightness(&keyboard, brightness) {
+                    log::warn!("{}", why);
+                    return;
+                }
+
+                thread::sleep(interval);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Fades every keyboard backlight to a percentage of its maximum. With `only_lower`, backlights
+/// which are already dimmer are left alone, as `Brightness::set_if_lower_than` does.
+pub fn fade_to_percent(percent: u64, only_lower: bool) -> Result<(), BacklightError> {
+    let mut targets = Vec::new();
+    for keyboard in keyboards() {
+        let max = keyboard
+            .max_brightness()
+            .map_err(|why| BacklightError::Set(keyboard.id().to_owned(), why))?;
+        let target = max * percent / 100;
+        if only_lower && keyboard.brightness().map_or(true, |current| current <= target) {
+            continue;
+        }
+
+        targets.push((keyboard, target));
+    }
+
+    fade(targets)
+}
+
+/// Steps every keyboard backlight up or down by a percentage of its maximum, fading to the new
+/// level. Returns the new brightness of the first, as a percentage, if there is a keyboard
+/// backlight.
+pub fn step_percent(percent: u64, increase: bool) -> Result<Option<u8>, BacklightError> {
+    let mut targets = Vec::new();
+    let mut first = None;
+    for keyboard in keyboards() {
+        let error = |why| BacklightError::Set(keyboard.id().to_owned(), why);
+        let max = keyboard.max_brightness().map_err(error)?;
+        let current = keyboard.brightness().map_err(error)?;
+        let target = brightness::step(current, 0, max, percent, increase);
+
+        if first.is_none() && max > 0 {
+            first = Some((target * 100 / max) as u8);
+        }
+        targets.push((keyboard, target));
+    }
+
+    fade(targets)?;
+    Ok(first)
+}
+
+/// The zones of the keyboard, with the color of each and the brightness of the keyboard as a
+/// percentage, which the firmware shares between every zone.
+pub fn zones() -> Result<Vec<(String, Color, u8)>, EcError> {
+    let keyboard = match KeyboardBacklight::new() {
+        Some(keyboard) => keyboard,
+        None => return Ok(Vec::new()),
+    };
+
+    let max = keyboard.max_brightness()?.max(1);
+    let percent = (keyboard.brightness()? * 100 / max) as u8;
+    keyboard
+        .zones()
+        .into_iter()
+        .map(|zone| Ok((zone.to_owned(), keyboard.zone_color(zone)?, percent)))
+        .collect()
+}
+
+/// Sets the color of a zone, or of every zone with `all`, and the brightness of the keyboard as
+/// a percentage. This supersedes any fade in progress.
+pub fn set_zone(zone: &str, color: Color, percent: u8) -> Result<(), EcError> {
+    let keyboard = KeyboardBacklight::new().ok_or(EcError::Unsupported("keyboard colors"))?;
+    keyboard.set_zone_color(zone, color)?;
+
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+    let max = keyboard.max_brightness()?;
+    keyboard.set_brightness(max * u32::from(percent.min(100)) / 100)
+}
+
+/// Sets the colors which a profile configures for each zone, leaving the others alone.
+pub fn set_colors(colors: &BTreeMap<String, Color>) -> Result<(), EcError> {
+    let keyboard = KeyboardBacklight::new().ok_or(EcError::Unsupported("keyboard colors"))?;
+    for (zone, &color) in colors {
+        keyboard.set_zone_color(zone, color)?;
+    }
+
+    Ok(())
+}
+
+/// Turns keyboard backlights off while the session is idle or locked, or the displays are off,
+/// and back on once none of these holds.
+#[derive(Default)]
+pub struct IdleDimmer {
+    idle:         bool,
+    locked:       bool,
+    displays_off: bool,
+    /// The level of each backlight before it was turned off.
+    levels:       Option<BTreeMap<String, u64>>,
+}
+
+impl IdleDimmer {
+    pub fn idle_changed(&mut self, idle: bool) {
+        self.idle = idle;
+        self.update();
+    }
+
+    pub fn locked_changed(&mut self, locked: bool) {
+        self.locked = locked;
+        self.update();
+    }
+
+    pub fn displays_off_changed(&mut self, displays_off: bool) {
+        self.displays_off = displays_off;
+        self.update();
+    }
+
+    fn update(&mut self) {
+        let result = if self.idle || self.locked || self.displays_off {
+            if self.levels.is_some() {
+                return;
+            }
+
+            let keyboards = keyboards();
+            self.levels = Some(
+                keyboards
+                    .iter()
+                    .filter_map(|keyboard| {
+                        Some((keyboard.id().to_owned(), keyboard.brightness().ok()?))
+                    })
+                    .collect(),
+            );
+            fade(keyboards.into_iter().map(|keyboard| (keyboard, 0)).collect())
+        } else {
+            let levels = match self.levels.take() {
+                Some(levels) => levels,
+                None => return,
+            };
+
+            let targets = keyboards()
+                .into_iter()
+                .filter_map(|keyboard| {
+                    let level = *levels.get(keyboard.id())?;
+                    Some((keyboard, level))
+                })
+                .collect();
+            fade(targets)
+        };
+
+        if let Err(why) = result {
+            log::warn!("failed to fade keyboard backlight: {}", why);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps() {
+        // Each level is stepped through when there is time.
+        let (steps, interval) = fade_steps(0, 5, Duration::from_millis(500));
+        assert_eq!(steps, vec![1, 2, 3, 4, 5]);
+        assert_eq!(interval, Duration::from_millis(100));
+
+        // Otherwise levels are skipped, so that steps are no shorter than a frame.
+        let (steps, interval) = fade_steps(255, 0, Duration::from_millis(160));
+        assert_eq!(steps.len(), 10);
+        assert_eq!(steps.last(), Some(&0));
+        assert!(steps.windows(2).all(|pair| pair[0] > pair[1]));
+        assert_eq!(interval, Duration::from_millis(16));
+
+        assert_eq!(fade_steps(3, 0, Duration::from_millis(1)).0, vec![0]);
+    }
+}
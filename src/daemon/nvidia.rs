@@ -0,0 +1,244 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+use super::runtime_control::GraphicsRuntimeControl;
+use crate::{
+    config::{DynamicPowerManagement, NvidiaConfig, Profile},
+    graphics::{GraphicsDevice, MODPROBE_PM_PATH},
+    nvml::{Device, Nvml, NvmlError, SharedNvml},
+    service::{self, Action},
+    state, verify,
+};
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    fs,
+    path::Path,
+    sync::Arc,
+};
+
+// The service which implements Dynamic Boost, shipped with the NVIDIA driver.
+const POWERD_UNIT: &str = "nvidia-powerd.service";
+
+const UNIT_DIRS: [&str; 3] =
+    ["/etc/systemd/system", "/lib/systemd/system", "/usr/lib/systemd/system"];
+
+const PCI_DEVICES: &str = "/sys/bus/pci/devices";
+
+/// Applies the NVIDIA settings of each profile through NVML, so that compute workloads do not
+/// need a separate `nvidia-persistenced` configuration.
+#[derive(Default)]
+pub struct NvidiaProfiles {
+    config:        NvidiaConfig,
+    /// Whether clocks were locked by a profile, and must be unlocked by the next.
+    clocks_locked: bool,
+    /// The power limit of each GPU, in milliwatts, by PCI address, before a profile changed it.
+    /// GPUs are not kept by their NVML index, which changes as other GPUs are removed.
+    saved_limits:  BTreeMap<String, u32>,
+    /// The autosuspend delay of each GPU, by PCI address, before a profile changed it.
+    saved_delays:  BTreeMap<String, String>,
+    runtime:       GraphicsRuntimeControl,
+    nvml:          Arc<SharedNvml>,
+}
+
+fn warn_unless_unsupported(what: &str, device: &str, why: NvmlError) {
+    if why.is_not_supported() {
+        log::info!("{} is not supported by NVIDIA GPU {}", what, device);
+    } else {
+        log::warn!("failed to set {} of NVIDIA GPU {}: {}", what, device, why);
+    }
+}
+
+/// Whether a systemd unit is installed.
+pub(super) fn unit_installed(unit: &str) -> bool {
+    UNIT_DIRS.iter().any(|dir| Path::new(dir).join(unit).exists())
+}
+
+/// Starts or stops `nvidia-powerd`, if it is installed. The service itself checks whether the
+/// model supports Dynamic Boost.
+fn set_dynamic_boost(enabled: bool) {
+    if !unit_installed(POWERD_UNIT) {
+        return;
+    }
+
+    let action = if enabled { Action::Start } else { Action::Stop };
+    if let Err(why) = service::run(action, POWERD_UNIT, false) {
+        log::warn!("failed to {} {}: {}", action.as_str(), POWERD_UNIT, why);
+    }
+}
+
+/// The module options which select a mode of dynamic power management.
+fn modprobe_pm(pm: DynamicPowerManagement) -> String {
+    format!(
+        "# Automatically generated by system76-power\noptions nvidia \
+         NVreg_DynamicPowerManagement=0x{:02x}\n",
+        pm as u8
+    )
+}
+
+/// Configures the dynamic power management of the driver, which takes effect once the driver is
+/// loaded again, such as at the next boot.
+fn set_dynamic_power_management(pm: DynamicPowerManagement) {
+    let contents = modprobe_pm(pm);
+    if fs::read_to_string(MODPROBE_PM_PATH).ok().as_deref() == Some(contents.as_str()) {
+        return;
+    }
+
+    log::info!("Creating {}", MODPROBE_PM_PATH);
+    if let Err(why) = state::write_atomic(Path::new(MODPROBE_PM_PATH), contents.as_bytes()) {
+        log::warn!("failed to write {}: {}", MODPROBE_PM_PATH, why);
+    }
+}
+
+impl NvidiaProfiles {
+    pub fn new(config: NvidiaConfig, nvml: Arc<SharedNvml>) -> Self {
+        NvidiaProfiles {
+            config,
+            clocks_locked: false,
+            saved_limits: BTreeMap::new(),
+            saved_delays: BTreeMap::new(),
+            runtime: GraphicsRuntimeControl::default(),
+            nvml,
+        }
+    }
+
+    /// Sets the autosuspend delay of each GPU, restoring the delay which a GPU had before if
+    /// the profile does not set one.
+    fn set_autosuspend_delays(&mut self, delay: Option<u32>, gpus: &[GraphicsDevice]) {
+        for gpu in gpus.iter().filter(|gpu| gpu.exists()) {
+            let path = Path::new(PCI_DEVICES).join(gpu.id()).join("power/autosuspend_delay_ms");
+            let res = match delay {
+                Some(delay) => {
+                    if let Entry::Vacant(entry) = self.saved_delays.entry(gpu.id().to_owned()) {
+                        if let Ok(saved) = verify::read(&path) {
+                            entry.insert(saved);
+                        }
+                    }
+                    verify::write(&path, &delay.to_string())
+                }
+                None => match self.saved_delays.remove(gpu.id()) {
+                    Some(saved) => verify::write(&path, &saved),
+                    None => Ok(()),
+                },
+            };
+
+            if let Err(why) = res {
+                log::warn!("failed to set autosuspend delay of NVIDIA GPU {}: {}", gpu.id(), why);
+            }
+        }
+    }
+
+    /// The GPUs which are powered on, with their PCI addresses.
+    fn devices<'a, I>(nvml: &'a Nvml, ids: I) -> Vec<(&'a str, Device<'a>)>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        ids.into_iter()
+            .filter_map(|id| match nvml.device_by_pci_id(id) {
+                Ok(device) => Some((id, device)),
+                Err(why) => {
+                    log::warn!("failed to find NVIDIA GPU {}: {}", id, why);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Applies the settings of a profile to the NVIDIA GPUs. Settings which depend on NVML are
+    /// not applied if the NVIDIA driver is not loaded, such as while the GPU is powered off.
+    pub fn apply(&mut self, profile: Profile, gpus: &[GraphicsDevice]) {
+        let settings = self.config.profile(profile).clone();
+        if let Some(enabled) = settings.dynamic_boost {
+            set_dynamic_boost(enabled);
+        }
+
+        let persistence = settings.persistence_mode;
+        if !gpus.is_empty() {
+            set_dynamic_power_management(settings.dynamic_power_management.unwrap_or_default());
+            self.set_autosuspend_delays(settings.autosuspend_delay, gpus);
+            if let Some(enabled) = persistence {
+                self.runtime.set_persistence(enabled);
+            }
+        }
+
+        if settings.is_empty() && !self.clocks_locked && self.saved_limits.is_empty() {
+            return;
+        }
+
+        let nvml = match self.nvml.get() {
+            Ok(nvml) => nvml,
+            Err(why) => {
+                log::debug!("not applying NVIDIA settings: {}", why);
+                return;
+            }
+        };
+
+        let ids = gpus.iter().filter(|gpu| gpu.exists()).map(GraphicsDevice::id);
+        for (id, device) in Self::devices(&nvml, ids) {
+            if let Some(enabled) = persistence {
+                if let Err(why) = device.set_persistence_mode(enabled) {
+                    warn_unless_unsupported("persistence mode", id, why);
+                }
+            }
+
+            let res = match settings.locked_clocks {
+                Some((min, max)) => device.set_locked_clocks(min, max),
+                None if self.clocks_locked => device.reset_locked_clocks(),
+                None => Ok(()),
+            };
+
+            if let Err(why) = res {
+                warn_unless_unsupported("locked clocks", id, why);
+            }
+
+            let res = match settings.power_limit {
+                Some(watts) => {
+                    if let Entry::Vacant(entry) = self.saved_limits.entry(id.to_owned()) {
+                        if let Ok(limit) = device.power_limit() {
+                            entry.insert(limit);
+                        }
+                    }
+                    device.set_power_limit(watts.saturating_mul(1000))
+                }
+                None => match self.saved_limits.remove(id) {
+                    Some(limit) => device.set_power_limit(limit),
+                    None => Ok(()),
+                },
+            };
+
+            if let Err(why) = res {
+                warn_unless_unsupported("power limit", id, why);
+            }
+        }
+
+        self.clocks_locked = settings.locked_clocks.is_some();
+    }
+
+    /// Disables persistence mode before GPUs are powered off, by PCI address, as the driver would
+    /// otherwise be kept initialized for a device which is being removed, and releases NVML.
+    /// Other GPUs keep their settings.
+    pub fn power_off(&mut self, ids: &[&str]) {
+        // The driver sets the default power limit again when the GPU returns, as does the kernel
+        // for the autosuspend delay. Resetting the locked clocks of a returning GPU is harmless,
+        // so whether they were locked is kept for the GPUs which stay on.
+        for id in ids {
+            self.saved_limits.remove(*id);
+            self.saved_delays.remove(*id);
+        }
+        self.runtime.power_off();
+
+        if !self.config.is_empty() {
+            if let Ok(nvml) = self.nvml.get() {
+                for (id, device) in Self::devices(&nvml, ids.iter().copied()) {
+                    if device.persistence_mode().unwrap_or(false) {
+                        if let Err(why) = device.set_persistence_mode(false) {
+                            warn_unless_unsupported("persistence mode", id, why);
+                        }
+                    }
+                }
+            }
+        }
+
+        self.nvml.release();
+    }
+}
@@ -2,40 +2,117 @@
 //
 // SPDX-License-Identifier: GPL-3.0-only
 
-use super::pci_runtime_pm_support;
+use super::{brightness, keyboard, pci_runtime_pm_support};
 use crate::{
-    errors::{BacklightError, ModelError, PciDeviceError, ProfileError, ScsiHostError},
+    acpi_platform,
+    config::Profile,
+    errors::{
+        BacklightError, CpuError, CpuSettingError, ModelError, PciDeviceError, ProfileError,
+        ScsiHostError,
+    },
     kernel_parameters::{DeviceList, Dirty, KernelParameter, LaptopMode},
     radeon::RadeonDevice,
+    service::{self, Action, ServiceError},
+    verify,
 };
-use intel_pstate::{PState, PStateError};
+use intel_pstate::PState;
 use std::{
     fs,
     io::{self, Read, Seek, SeekFrom, Write},
-    path::Path,
+    path::{Path, PathBuf},
     process::Command,
 };
-use sysfs_class::{
-    Backlight, Brightness, Leds, PciDevice, RuntimePM, RuntimePowerManagement, ScsiHost, SysClass,
-};
+use sysfs_class::{Backlight, Brightness, PciDevice, RuntimePowerManagement, ScsiHost, SysClass};
+
+/// How the setting of a subsystem fared when a profile was applied.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    Applied,
+    /// The subsystem was left alone, for the given reason.
+    Skipped(&'static str),
+    Failed(String),
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Applied => "ok",
+            Outcome::Skipped(_) => "skipped",
+            Outcome::Failed(_) => "failed",
+        }
+    }
+
+    /// Why the subsystem was skipped or failed.
+    pub fn detail(&self) -> &str {
+        match self {
+            Outcome::Applied => "",
+            Outcome::Skipped(reason) => reason,
+            Outcome::Failed(message) => message,
+        }
+    }
+}
+
+/// The outcome of each subsystem which a profile touched, in the order they were set, along with
+/// every error which occurred.
+#[derive(Debug, Default)]
+pub struct ProfileReport {
+    outcomes: Vec<(&'static str, Outcome)>,
+    errors:   Vec<ProfileError>,
+}
+
+impl ProfileReport {
+    pub fn applied(&mut self, subsystem: &'static str) { self.record(subsystem, Outcome::Applied) }
+
+    pub fn skipped(&mut self, subsystem: &'static str, reason: &'static str) {
+        self.record(subsystem, Outcome::Skipped(reason))
+    }
+
+    pub fn failed(&mut self, subsystem: &'static str, why: ProfileError) {
+        self.record(subsystem, Outcome::Failed(why.to_string()));
+        self.errors.push(why);
+    }
+
+    /// A subsystem which is set in several steps fails if any step fails, with the message of
+    /// each failure.
+    fn record(&mut self, subsystem: &'static str, outcome: Outcome) {
+        let existing = self.outcomes.iter_mut().find(|(name, _)| *name == subsystem);
+        match (existing, outcome) {
+            (Some((_, Outcome::Failed(message))), Outcome::Failed(more)) => {
+                message.push_str("; ");
+                message.push_str(&more);
+            }
+            (Some((_, Outcome::Failed(_))), _) => (),
+            (Some((_, existing)), outcome) => *existing = outcome,
+            (None, outcome) => self.outcomes.push((subsystem, outcome)),
+        }
+    }
+
+    pub fn outcomes(&self) -> &[(&'static str, Outcome)] { &self.outcomes }
+
+    pub fn errors(&self) -> &[ProfileError] { &self.errors }
+}
 
 /// Instead of returning on the first error, we want to collect all errors that occur while
 /// setting a profile. Even if one parameter fails to set, we'll still be able to set other
 /// parameters successfully.
 macro_rules! catch {
-    ($errors:ident, $result:expr) => {
+    ($report:ident, $subsystem:expr, $result:expr) => {
         match $result {
-            Ok(_) => (),
-            Err(why) => $errors.push(why.into()),
+            Ok(()) => $report.applied($subsystem),
+            Err(why) => $report.failed($subsystem, why.into()),
         }
     };
 }
 
 /// Sets parameters for the balanced profile.
-pub fn balanced(errors: &mut Vec<ProfileError>, set_brightness: bool) {
+pub fn balanced(report: &mut ProfileReport, set_brightness: bool) {
     // Use the ACPI Platform Profile if the hardware is supported by the kernel.
-    if crate::acpi_platform::supported() {
-        crate::acpi_platform::balanced();
+    if acpi_platform::supported() {
+        catch!(
+            report,
+            "platform-profile",
+            acpi_platform::set(Profile::Balanced).map_err(ProfileError::Platform)
+        );
         return;
     }
 
@@ -49,96 +126,227 @@ pub fn balanced(errors: &mut Vec<ProfileError>, set_brightness: bool) {
     LaptopMode::default().set(b"2");
 
     // Sets radeon power profiles for AMD graphics.
-    RadeonDevice::get_devices().for_each(|dev| dev.set_profiles("auto", "performance", "auto"));
+    radeon_profiles(report, "auto", "performance", "auto");
 
     // Enables SCSI / SATA link time power management.
-    catch!(errors, scsi_host_link_time_pm_policy(&["med_power_with_dipm", "medium_power"]));
+    catch!(report, "scsi", scsi_host_link_time_pm_policy(&["med_power_with_dipm", "medium_power"]));
 
-    if set_brightness {
-        // Manage screen backlights.
-        catch!(errors, iterate_backlights(Backlight::iter(), &Brightness::set_if_lower_than, 40));
-
-        // Manage keyboard backlights.
-        catch!(
-            errors,
-            iterate_backlights(Leds::iter_keyboards(), &Brightness::set_if_lower_than, 50)
-        );
-    }
+    // Manage screen and keyboard backlights.
+    backlights(report, set_brightness, 40, 50, true);
 
     // Parameters which may cause on certain systems.
     if pci_runtime_pm_support() {
         // Enables PCI device runtime power management.
-        catch!(errors, pci_device_runtime_pm(RuntimePowerManagement::On));
+        catch!(report, "pci-runtime-pm", pci_device_runtime_pm(RuntimePowerManagement::On));
+    } else {
+        report.skipped("pci-runtime-pm", "unsupported");
     }
 
     // Control Intel PState values, if they exist.
-    catch!(errors, pstate_values(0, 100, false));
+    pstate_values(report, 0, 100, false);
 
-    if let Some(model_profiles) = ModelProfiles::new() {
-        catch!(errors, model_profiles.balanced.set());
-    }
+    model_profile(report, |profiles| &profiles.balanced);
 }
 
 /// Sets parameters for the performance profile
-pub fn performance(errors: &mut Vec<ProfileError>, _set_brightness: bool) {
+pub fn performance(report: &mut ProfileReport, _set_brightness: bool) {
     // Use the ACPI Platform Profile if the hardware is supported by the kernel.
-    if crate::acpi_platform::supported() {
-        crate::acpi_platform::performance();
+    if acpi_platform::supported() {
+        catch!(
+            report,
+            "platform-profile",
+            acpi_platform::set(Profile::Performance).map_err(ProfileError::Platform)
+        );
         return;
     }
 
     Dirty::default().set_max_lost_work(15);
     LaptopMode::default().set(b"0");
-    RadeonDevice::get_devices().for_each(|dev| dev.set_profiles("high", "performance", "auto"));
-    catch!(errors, scsi_host_link_time_pm_policy(&["med_power_with_dipm", "max_performance"]));
-    catch!(errors, pstate_values(50, 100, false));
+    radeon_profiles(report, "high", "performance", "auto");
+    catch!(
+        report,
+        "scsi",
+        scsi_host_link_time_pm_policy(&["med_power_with_dipm", "max_performance"])
+    );
+    pstate_values(report, 50, 100, false);
 
     if pci_runtime_pm_support() {
-        catch!(errors, pci_device_runtime_pm(RuntimePowerManagement::Off));
+        catch!(report, "pci-runtime-pm", pci_device_runtime_pm(RuntimePowerManagement::Off));
+    } else {
+        report.skipped("pci-runtime-pm", "unsupported");
     }
 
-    if let Some(model_profiles) = ModelProfiles::new() {
-        catch!(errors, model_profiles.performance.set());
-    }
+    model_profile(report, |profiles| &profiles.performance);
 }
 
 /// Sets parameters for the battery profile
-pub fn battery(errors: &mut Vec<ProfileError>, set_brightness: bool) {
+pub fn battery(report: &mut ProfileReport, set_brightness: bool) {
     // Use the ACPI Platform Profile if the hardware is supported by the kernel.
-    if crate::acpi_platform::supported() {
-        crate::acpi_platform::battery();
+    if acpi_platform::supported() {
+        catch!(
+            report,
+            "platform-profile",
+            acpi_platform::set(Profile::Battery).map_err(ProfileError::Platform)
+        );
         return;
     }
 
     Dirty::default().set_max_lost_work(15);
     LaptopMode::default().set(b"2");
-    RadeonDevice::get_devices().for_each(|dev| dev.set_profiles("low", "battery", "low"));
-    catch!(errors, scsi_host_link_time_pm_policy(&["min_power", "min_power"]));
-    catch!(errors, pstate_values(0, 50, true));
+    radeon_profiles(report, "low", "battery", "low");
+    catch!(report, "scsi", scsi_host_link_time_pm_policy(&["min_power", "min_power"]));
+    pstate_values(report, 0, 50, true);
+    backlights(report, set_brightness, 10, 0, false);
 
-    if set_brightness {
-        catch!(errors, iterate_backlights(Backlight::iter(), &Brightness::set_if_lower_than, 10));
-        catch!(errors, iterate_backlights(Leds::iter_keyboards(), &Brightness::set_brightness, 0));
+    if pci_runtime_pm_support() {
+        catch!(report, "pci-runtime-pm", pci_device_runtime_pm(RuntimePowerManagement::On));
+    } else {
+        report.skipped("pci-runtime-pm", "unsupported");
     }
 
-    if pci_runtime_pm_support() {
-        catch!(errors, pci_device_runtime_pm(RuntimePowerManagement::On));
+    model_profile(report, |profiles| &profiles.battery);
+}
+
+/// Sets the power profiles of every radeon card.
+fn radeon_profiles(
+    report: &mut ProfileReport,
+    power_profile: &str,
+    dpm_state: &str,
+    dpm_perf: &str,
+) {
+    let mut devices = RadeonDevice::get_devices().peekable();
+    if devices.peek().is_none() {
+        report.skipped("radeon", "no device");
+        return;
     }
 
-    if let Some(model_profiles) = ModelProfiles::new() {
-        catch!(errors, model_profiles.battery.set());
+    for device in devices {
+        catch!(report, "radeon", device.set_profiles(power_profile, dpm_state, dpm_perf));
     }
 }
 
-/// Controls the Intel PState values.
-fn pstate_values(min: u8, max: u8, no_turbo: bool) -> Result<(), PStateError> {
-    if let Ok(pstate) = PState::new() {
-        pstate.set_min_perf_pct(min)?;
-        pstate.set_max_perf_pct(max)?;
-        pstate.set_no_turbo(no_turbo)?;
+/// Dims the screen backlights to `screen` percent, if brighter, and fades the keyboard backlights
+/// to `keyboard` percent. With `only_lower`, brighter keyboard backlights are left alone.
+fn backlights(
+    report: &mut ProfileReport,
+    set_brightness: bool,
+    screen: u64,
+    keyboard: u64,
+    only_lower: bool,
+) {
+    if !set_brightness {
+        report.skipped("backlight", "brightness kept");
+        report.skipped("keyboard", "brightness kept");
+        return;
     }
 
-    Ok(())
+    if Backlight::iter().next().is_none() {
+        report.skipped("backlight", "no device");
+    } else {
+        catch!(
+            report,
+            "backlight",
+            iterate_backlights(Backlight::iter(), &brightness::set_if_lower_than, screen)
+        );
+    }
+
+    if keyboard::is_present() {
+        catch!(report, "keyboard", keyboard::fade_to_percent(keyboard, only_lower));
+    } else {
+        report.skipped("keyboard", "no device");
+    }
+}
+
+/// Sets the limits of the model, which only some models have.
+fn model_profile(report: &mut ProfileReport, profile: fn(&ModelProfiles) -> &ModelProfile) {
+    match ModelProfiles::new() {
+        Some(profiles) => catch!(report, "model", profile(&profiles).set()),
+        None => report.skipped("model", "no model limits"),
+    }
+}
+
+/// Controls the Intel PState values, which are read back to verify that they were accepted.
+/// Each value is set even if another fails.
+fn pstate_values(report: &mut ProfileReport, min: u8, max: u8, no_turbo: bool) {
+    const INTEL_PSTATE: &str = "/sys/devices/system/cpu/intel_pstate";
+
+    if PState::new().is_err() {
+        report.skipped("pstate", "no intel_pstate");
+        return;
+    }
+
+    let path = |name| Path::new(INTEL_PSTATE).join(name);
+    // intel_pstate raises the minimum to the lowest performance which the hardware supports.
+    catch!(
+        report,
+        "pstate",
+        verify::write_accepting(&path("min_perf_pct"), &min.to_string(), |actual| {
+            actual.parse::<u8>().map_or(false, |actual| actual >= min)
+        })
+    );
+    catch!(report, "pstate", verify::write(&path("max_perf_pct"), &max.to_string()));
+    catch!(report, "pstate", verify::write(&path("no_turbo"), if no_turbo { "1" } else { "0" }));
+}
+
+/// Sets the energy performance preference, where the cpufreq driver supports it.
+pub fn energy_performance_preference(report: &mut ProfileReport, preference: &str) {
+    match cpu_energy_performance_preference(preference) {
+        Ok(true) => report.applied("epp"),
+        Ok(false) => report.skipped("epp", "unsupported"),
+        Err(why) => report.failed("epp", why.into()),
+    }
+}
+
+/// Sets the energy performance preference of every online CPU, returning whether the cpufreq
+/// driver supports it. A CPU which fails does not stop the preference from being set on the
+/// others, and offline CPUs are skipped, as they have no cpufreq policy to set.
+fn cpu_energy_performance_preference(preference: &str) -> Result<bool, CpuSettingError> {
+    const CPUS: &str = "/sys/devices/system/cpu";
+    const EPP: &str = "cpufreq/energy_performance_preference";
+
+    let entries = match fs::read_dir(CPUS) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(false),
+    };
+
+    let mut cpus: Vec<(u32, PathBuf)> = entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let cpu = entry.file_name().to_str()?.strip_prefix("cpu")?.parse().ok()?;
+            Some((cpu, entry.path()))
+        })
+        .collect();
+    cpus.sort_by_key(|&(cpu, _)| cpu);
+
+    // A CPU without the preference is only a failure if other CPUs have it.
+    if !cpus.iter().any(|(_, path)| path.join(EPP).exists()) {
+        return Ok(false);
+    }
+
+    let mut failures = Vec::new();
+    for (cpu, path) in cpus {
+        let online =
+            fs::read_to_string(path.join("online")).map_or(true, |online| online.trim() != "0");
+        if !online {
+            continue;
+        }
+
+        let res = if !path.join(EPP).exists() {
+            Err(CpuError::Unsupported)
+        } else {
+            fs::write(path.join(EPP), preference).map_err(CpuError::Io)
+        };
+
+        if let Err(why) = res {
+            failures.push((cpu, why));
+        }
+    }
+
+    if failures.is_empty() {
+        Ok(true)
+    } else {
+        Err(CpuSettingError { setting: "energy performance preference", failures })
+    }
 }
 
 /// Iterates across all backlights in the supplied iterator, executing the given strategy function
@@ -164,8 +372,7 @@ fn iterate_backlights<B: Brightness>(
 fn pci_device_runtime_pm(pm: RuntimePowerManagement) -> Result<(), PciDeviceError> {
     for device in PciDevice::iter() {
         match device {
-            Ok(device) => device
-                .set_runtime_pm(pm)
+            Ok(device) => verify::write(&device.path().join("power/control"), pm.into())
                 .map_err(|why| PciDeviceError::SetRuntimePm(device.id().to_owned(), why))?,
             Err(why) => {
                 log::warn!("failed to iterate PCI device: {}", why);
@@ -217,12 +424,12 @@ impl ModelProfile {
 
     pub fn set(&self) -> Result<(), ModelError> {
         // Thermald sets pl1 and pl2 on its own, conflicting with system76-power
-        let _status = Command::new("systemctl")
-            .arg("stop")
-            .arg("thermald.service")
-            .status()
-            .map_err(ModelError::Thermald)?;
         // TODO: check status, allow thermald to be missing
+        if let Err(why @ ServiceError::Command(..)) =
+            service::run(Action::Stop, "thermald.service", true)
+        {
+            return Err(ModelError::Thermald(why));
+        }
 
         // Set PL1
         if let Some(pl1) = self.pl1 {
@@ -318,3 +525,32 @@ impl ModelProfiles {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn denied(host: &str) -> ProfileError {
+        ScsiHostError::LinkTimePolicy("min_power", host.into(), io::Error::from_raw_os_error(13))
+            .into()
+    }
+
+    #[test]
+    fn report() {
+        let mut report = ProfileReport::default();
+        report.applied("scsi");
+        report.skipped("backlight", "no device");
+        report.failed("scsi", denied("host0"));
+        report.applied("scsi");
+        report.failed("scsi", denied("host1"));
+
+        let statuses: Vec<_> =
+            report.outcomes().iter().map(|(name, outcome)| (*name, outcome.as_str())).collect();
+        assert_eq!(statuses, vec![("scsi", "failed"), ("backlight", "skipped")]);
+        assert!(report.outcomes()[0].1.detail().contains("host0"));
+        assert!(report.outcomes()[0].1.detail().contains("; "));
+        assert!(report.outcomes()[0].1.detail().contains("host1"));
+        assert_eq!(report.outcomes()[1].1.detail(), "no device");
+        assert_eq!(report.errors().len(), 2);
+    }
+}
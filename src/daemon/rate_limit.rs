@@ -0,0 +1,83 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Buckets which have been idle for this long are forgotten.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+struct Bucket {
+    tokens: f64,
+    last:   Instant,
+}
+
+/// A token bucket rate limiter, keyed by the unique bus name of the DBus sender.
+///
+/// Each sender may burst up to `burst` requests, which are then refilled at a rate of
+/// `per_second` requests per second.
+pub struct RateLimiter {
+    burst:      f64,
+    per_second: f64,
+    buckets:    HashMap<String, Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(burst: u32, per_second: u32) -> Self {
+        RateLimiter {
+            burst:      f64::from(burst),
+            per_second: f64::from(per_second),
+            buckets:    HashMap::new(),
+        }
+    }
+
+    /// Consumes a token for the sender, returning `false` if the sender is over its limit.
+    pub fn check(&mut self, sender: &str, now: Instant) -> bool {
+        self.buckets.retain(|_, bucket| now.saturating_duration_since(bucket.last) < IDLE_TIMEOUT);
+
+        let burst = self.burst;
+        let per_second = self.per_second;
+        let bucket = self
+            .buckets
+            .entry(sender.to_owned())
+            .or_insert_with(|| Bucket { tokens: burst, last: now });
+
+        let elapsed = now.saturating_duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(burst);
+        bucket.last = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_then_refill() {
+        let mut limiter = RateLimiter::new(3, 2);
+        let start = Instant::now();
+
+        assert!(limiter.check(":1.1", start));
+        assert!(limiter.check(":1.1", start));
+        assert!(limiter.check(":1.1", start));
+        assert!(!limiter.check(":1.1", start));
+
+        // Other senders have their own bucket.
+        assert!(limiter.check(":1.2", start));
+
+        // Two requests per second are refilled.
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.check(":1.1", later));
+        assert!(!limiter.check(":1.1", later));
+    }
+}
@@ -0,0 +1,134 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Runtime control of the NVIDIA driver: persistence mode, which `nvidia-persistenced` keeps where
+//! it is installed, and the power of video memory, which the driver reports for each GPU.
+
+use super::nvidia::unit_installed;
+use crate::service::{self, Action};
+use std::{fs, path::Path};
+
+// The daemon which keeps the driver initialized, shipped with the NVIDIA driver.
+const PERSISTENCED_UNIT: &str = "nvidia-persistenced.service";
+
+// Written by `nvidia-persistenced` while it runs, however it was started.
+const PERSISTENCED_PID: &str = "/run/nvidia-persistenced/nvidia-persistenced.pid";
+
+// The directory of each GPU which the NVIDIA driver drives, by PCI address.
+const NVIDIA_GPUS: &str = "/proc/driver/nvidia/gpus";
+
+/// The runtime power management of a GPU, as the NVIDIA driver reports it.
+#[derive(Debug, Default, PartialEq)]
+pub struct NvidiaPower {
+    /// Whether the GPU may be powered off while idle, such as `Enabled (fine-grained)`.
+    pub runtime_d3:   String,
+    /// Whether video memory is powered, such as `Active` or `Off`.
+    pub video_memory: String,
+}
+
+impl NvidiaPower {
+    /// The power of a GPU, by PCI address, where the driver is loaded and reports it.
+    pub fn read(id: &str) -> Option<Self> {
+        fs::read_to_string(Path::new(NVIDIA_GPUS).join(id).join("power"))
+            .ok()
+            .map(|text| Self::parse(&text))
+    }
+
+    /// Parses the fields of the report, ignoring the support of the hardware which follows them.
+    fn parse(text: &str) -> Self {
+        let mut power = NvidiaPower::default();
+        for line in text.lines().take_while(|line| !line.trim().is_empty()) {
+            let (key, value) = match line.find(':') {
+                Some(pos) => (line[..pos].trim(), line[pos + 1..].trim().to_owned()),
+                None => continue,
+            };
+
+            match key {
+                "Runtime D3 status" => power.runtime_d3 = value,
+                "Video Memory" => power.video_memory = value,
+                _ => (),
+            }
+        }
+
+        power
+    }
+}
+
+/// Runs `nvidia-persistenced` with each profile which enables persistence mode, where it is
+/// installed. Persistence mode itself is set through NVML, as distributions may run the daemon
+/// with `--no-persistence-mode`.
+#[derive(Default)]
+pub struct GraphicsRuntimeControl {
+    /// Whether `nvidia-persistenced` was started by a profile, rather than already running.
+    persistenced: bool,
+}
+
+impl GraphicsRuntimeControl {
+    /// Starts `nvidia-persistenced` when persistence mode is enabled, unless it is already
+    /// running, and stops it when persistence mode is disabled, if a profile started it.
+    pub fn set_persistence(&mut self, enabled: bool) {
+        if !enabled {
+            self.stop();
+            return;
+        }
+
+        if self.persistenced
+            || !unit_installed(PERSISTENCED_UNIT)
+            || Path::new(PERSISTENCED_PID).exists()
+        {
+            return;
+        }
+
+        // Persistence mode is set once it has started, so that it does not undo it.
+        match service::run(Action::Start, PERSISTENCED_UNIT, true) {
+            Ok(()) => self.persistenced = true,
+            Err(why) => log::warn!("failed to start {}: {}", PERSISTENCED_UNIT, why),
+        }
+    }
+
+    /// Stops `nvidia-persistenced` before GPUs are powered off, if a profile started it, as it
+    /// keeps the device nodes of the driver open. The next profile starts it again.
+    pub fn power_off(&mut self) {
+        if self.persistenced {
+            log::info!("Stopping {} to power off NVIDIA graphics", PERSISTENCED_UNIT);
+            self.stop();
+        }
+    }
+
+    fn stop(&mut self) {
+        if !self.persistenced {
+            return;
+        }
+
+        if let Err(why) = service::run(Action::Stop, PERSISTENCED_UNIT, true) {
+            log::warn!("failed to stop {}: {}", PERSISTENCED_UNIT, why);
+        }
+        self.persistenced = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nvidia_power() {
+        let text = concat!(
+            "Runtime D3 status:          Enabled (fine-grained)\n",
+            "Video Memory:               Off\n",
+            "\n",
+            "GPU Hardware Support:\n",
+            " Video Memory Self Refresh: Supported\n",
+            " Video Memory Off:          Supported\n",
+        );
+        assert_eq!(
+            NvidiaPower::parse(text),
+            NvidiaPower {
+                runtime_d3:   "Enabled (fine-grained)".to_owned(),
+                video_memory: "Off".to_owned(),
+            }
+        );
+        assert_eq!(NvidiaPower::parse("Runtime D3 status: ?\n").video_memory, "");
+    }
+}
@@ -0,0 +1,126 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Runs the scripts configured for each profile, on a thread of their own so that a script which
+//! hangs cannot hold up profile switching.
+//!
+//! Scripts run one at a time, in the order their profiles were applied, through the runner which
+//! hook programs share. Each is also given, where possible, its own network namespace without
+//! any interfaces. How it exited is sent back to the daemon, to be added to the profile report.
+
+use super::JobMessage;
+use crate::{
+    config::{Profile, ScriptsConfig},
+    program::{self, ProgramError},
+};
+use futures::channel::mpsc::UnboundedSender;
+use std::{
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::mpsc::{self, Sender},
+    thread,
+    time::Duration,
+};
+
+pub struct ScriptRunner {
+    config:   ScriptsConfig,
+    sender:   Option<Sender<(u64, PathBuf, String)>>,
+    /// Receives how each script exited, with the number it was queued as.
+    finished: UnboundedSender<JobMessage>,
+    /// The number of scripts which have been queued.
+    queued:   u64,
+}
+
+impl ScriptRunner {
+    pub(crate) fn new(config: ScriptsConfig, finished: UnboundedSender<JobMessage>) -> Self {
+        ScriptRunner { config, sender: None, finished, queued: 0 }
+    }
+
+    /// Queues the script of a profile, if it has one. Returns `false` if it has none.
+    pub fn profile_applied(&mut self, profile: Profile, name: &str) -> bool {
+        let script = match self.config.script(profile) {
+            Some(script) => script.to_owned(),
+            None => return false,
+        };
+
+        let timeout = Duration::from_secs(self.config.timeout);
+        let finished = self.finished.clone();
+        let sender = self.sender.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel::<(u64, PathBuf, String)>();
+            thread::spawn(move || {
+                for (number, script, name) in receiver {
+                    let res = run(&script, &name, timeout).map_err(|why| why.to_string());
+                    if let Err(ref why) = res {
+                        log::warn!("{} profile script: {}", name, why);
+                    }
+                    let _ = finished.unbounded_send(JobMessage::ScriptFinished(number, res));
+                }
+            });
+            sender
+        });
+
+        self.queued += 1;
+        let _ = sender.send((self.queued, script, name.to_owned()));
+        true
+    }
+
+    /// Whether a script which finished was the last one queued, so that its result belongs in
+    /// the report of the active profile.
+    pub fn is_last(&self, number: u64) -> bool { number == self.queued }
+}
+
+/// Runs a script, waiting until it exits or the timeout elapses.
+fn run(script: &Path, profile: &str, timeout: Duration) -> Result<(), ProgramError> {
+    let mut command = program::command(script);
+    command
+        .env("SYSTEM76_POWER_PROFILE", profile)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    unsafe {
+        command.pre_exec(|| {
+            // Scripts tune local settings, and have no need for the network. This fails on
+            // kernels without network namespaces, in which case the script runs regardless.
+            libc::unshare(libc::CLONE_NEWNET);
+            Ok(())
+        });
+    }
+
+    program::run(script, &mut command, &[], timeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, os::unix::fs::PermissionsExt, process, time::Instant};
+
+    #[test]
+    fn guard_rails() {
+        let dir = std::env::temp_dir().join(format!("system76-power-scripts-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let script = |name: &str, body: &str| {
+            let path = dir.join(name);
+            fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            path
+        };
+
+        let timeout = Duration::from_millis(500);
+        let ok = script("ok", "test \"$SYSTEM76_POWER_PROFILE\" = battery");
+        assert!(run(&ok, "battery", timeout).is_ok());
+
+        let failed = script("failed", "exit 3");
+        assert!(matches!(run(&failed, "battery", timeout), Err(ProgramError::Failed(..))));
+
+        let hangs = script("hangs", "sleep 10");
+        let started = Instant::now();
+        assert!(matches!(run(&hangs, "battery", timeout), Err(ProgramError::Timeout(..))));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,257 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Spindown of rotational drives, where enabled. The battery profile lets idle drives spin down,
+//! while the other profiles keep them spinning.
+//!
+//! Every start of a cooled drive wears its motor and heads, so drives are polled for their power
+//! state and `drivetemp` temperature. A drive which is woken cold too often within an hour has
+//! spindown disabled for a while, whatever the profile.
+
+use crate::{
+    config::{Profile, SpindownConfig},
+    disks::{Disk, DiskPower, Disks},
+    errors::DiskPowerError,
+    health::DiskStats,
+};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+// Cold starts are counted over this window.
+const COLD_START_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+// A drive with this many cold starts within the window has its spindown disabled.
+const MAX_COLD_STARTS: usize = 4;
+
+// How long spindown stays disabled once a drive has been started cold too often.
+const BACKOFF: Duration = Duration::from_secs(2 * 60 * 60);
+
+// A drive which spins up below this temperature, in millidegrees Celsius, is starting cold.
+const COLD_TEMPERATURE: i32 = 35_000;
+
+/// APM level and autosuspend delay in milliseconds. APM levels below 128 permit spindown.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct DrivePolicy {
+    apm:         u8,
+    autosuspend: i32,
+}
+
+const SPINDOWN: DrivePolicy = DrivePolicy { apm: 127, autosuspend: 60_000 };
+const NO_SPINDOWN: DrivePolicy = DrivePolicy { apm: 192, autosuspend: -1 };
+const MAX_PERFORMANCE: DrivePolicy = DrivePolicy { apm: 254, autosuspend: -1 };
+
+fn profile_policy(profile: Profile) -> DrivePolicy {
+    match profile {
+        Profile::Battery => SPINDOWN,
+        Profile::Balanced => NO_SPINDOWN,
+        Profile::Performance => MAX_PERFORMANCE,
+    }
+}
+
+#[derive(Debug, Default)]
+struct DriveState {
+    spinning:    Option<bool>,
+    /// The last temperature read while the drive was spinning, in millidegrees Celsius.
+    temperature: Option<i32>,
+    spinups:     u32,
+    cold_starts: VecDeque<Instant>,
+    backed_off:  Option<Instant>,
+}
+
+impl DriveState {
+    /// Records a poll of the drive. The temperature is only read while the drive is spinning,
+    /// because reading it may reset the spindown timer. Returns whether the drive must now have
+    /// its policy reapplied, having entered or left its back-off.
+    fn observe(&mut self, now: Instant, spinning: Option<bool>, temperature: Option<i32>) -> bool {
+        let started = self.spinning == Some(false) && spinning == Some(true);
+        self.spinning = spinning;
+        if temperature.is_some() {
+            self.temperature = temperature;
+        }
+
+        while self.cold_starts.front().map_or(false, |&at| now - at > COLD_START_WINDOW) {
+            self.cold_starts.pop_front();
+        }
+
+        if self.backed_off.map_or(false, |since| now - since >= BACKOFF) {
+            self.backed_off = None;
+            self.cold_starts.clear();
+            return true;
+        }
+
+        if !started {
+            return false;
+        }
+
+        self.spinups += 1;
+
+        // Without drivetemp, every start is assumed to be cold.
+        if temperature.map_or(true, |temperature| temperature < COLD_TEMPERATURE) {
+            self.cold_starts.push_back(now);
+        }
+
+        if self.backed_off.is_none() && self.cold_starts.len() >= MAX_COLD_STARTS {
+            self.backed_off = Some(now);
+            return true;
+        }
+
+        false
+    }
+}
+
+#[derive(Default)]
+pub struct Spindown {
+    config:  SpindownConfig,
+    disks:   Disks,
+    drives:  BTreeMap<String, DriveState>,
+    profile: Option<Profile>,
+}
+
+impl Spindown {
+    pub fn new(config: SpindownConfig) -> Self { Spindown { config, ..Default::default() } }
+
+    /// Whether spindown is enabled in the configuration.
+    pub fn is_configured(&self) -> bool { self.config.enabled }
+
+    /// Whether spindown is enabled and there are drives which spin, and so need to be polled.
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled && self.disks.iter().any(Disk::is_rotational)
+    }
+
+    /// Applies the policy of a profile to every rotational drive.
+    pub fn apply(&mut self, profile: Profile) -> Vec<DiskPowerError> {
+        if !self.config.enabled {
+            return Vec::new();
+        }
+
+        self.profile = Some(profile);
+        let mut errors = Vec::new();
+        for disk in self.disks.iter().filter(|disk| disk.is_rotational()) {
+            errors.extend(self.apply_disk(disk, profile));
+        }
+
+        errors
+    }
+
+    fn apply_disk(&self, disk: &Disk, profile: Profile) -> Vec<DiskPowerError> {
+        let backed_off =
+            self.drives.get(disk.name()).map_or(false, |drive| drive.backed_off.is_some());
+        let policy = if backed_off && profile == Profile::Battery {
+            NO_SPINDOWN
+        } else {
+            profile_policy(profile)
+        };
+
+        let mut errors = Vec::new();
+        if let Err(why) = disk.set_apm_level(policy.apm) {
+            errors.push(why);
+        }
+        if let Err(why) = disk.set_autosuspend_delay(policy.autosuspend) {
+            errors.push(why);
+        }
+
+        errors
+    }
+
+    /// Polls the power state and temperature of each rotational drive, backing off the
+    /// spindown of drives which are started cold too often.
+    pub fn poll(&mut self) {
+        let now = Instant::now();
+        for disk in self.disks.iter().filter(|disk| disk.is_rotational()) {
+            let spinning = disk.is_spinning();
+            let temperature = if spinning == Some(true) { disk.temperature() } else { None };
+
+            let drive = self.drives.entry(disk.name().to_owned()).or_default();
+            if !drive.observe(now, spinning, temperature) {
+                continue;
+            }
+
+            if drive.backed_off.is_some() {
+                log::warn!(
+                    "{} was started cold {} times within {} minutes, disabling its spindown",
+                    disk.name(),
+                    drive.cold_starts.len(),
+                    COLD_START_WINDOW.as_secs() / 60
+                );
+            } else {
+                log::info!("re-enabling spindown of {}", disk.name());
+            }
+
+            if let Some(profile) = self.profile {
+                for why in self.apply_disk(disk, profile) {
+                    log::warn!("{}", why);
+                }
+            }
+        }
+    }
+
+    /// Statistics of each rotational drive, for the health endpoint, which are only gathered
+    /// while spindown is enabled.
+    pub fn stats(&self) -> Vec<DiskStats> {
+        if !self.config.enabled {
+            return Vec::new();
+        }
+
+        self.disks
+            .iter()
+            .filter(|disk| disk.is_rotational())
+            .map(|disk| {
+                let drive = self.drives.get(disk.name());
+                DiskStats {
+                    name:        disk.name().to_owned(),
+                    spinning:    drive.and_then(|drive| drive.spinning),
+                    celsius:     drive
+                        .and_then(|drive| drive.temperature)
+                        .map(|temperature| f64::from(temperature) / 1000.0),
+                    spinups:     drive.map_or(0, |drive| drive.spinups),
+                    cold_starts: drive.map_or(0, |drive| drive.cold_starts.len()),
+                    backed_off:  drive.map_or(false, |drive| drive.backed_off.is_some()),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cold_start_backoff() {
+        let start = Instant::now();
+        let minutes = |minutes: u64| start + Duration::from_secs(minutes * 60);
+        let mut drive = DriveState::default();
+
+        // Warm starts do not count against the drive.
+        for minute in 0..10 {
+            drive.observe(minutes(minute * 2), Some(false), None);
+            assert!(!drive.observe(minutes(minute * 2 + 1), Some(true), Some(40_000)));
+        }
+        assert_eq!(drive.spinups, 10);
+        assert!(drive.cold_starts.is_empty());
+
+        // Cold starts spread over more than the window do not either.
+        for minute in 0..MAX_COLD_STARTS as u64 {
+            drive.observe(minutes(30 + minute * 40), Some(false), None);
+            assert!(!drive.observe(minutes(31 + minute * 40), Some(true), Some(25_000)));
+        }
+        assert!(drive.backed_off.is_none());
+
+        // Too many within the window disable spindown, until the back-off has passed.
+        let mut drive = DriveState::default();
+        for minute in 0..MAX_COLD_STARTS as u64 - 1 {
+            drive.observe(minutes(minute * 10), Some(false), None);
+            assert!(!drive.observe(minutes(minute * 10 + 1), Some(true), None));
+        }
+        drive.observe(minutes(40), Some(false), None);
+        assert!(drive.observe(minutes(41), Some(true), Some(25_000)));
+        assert!(drive.backed_off.is_some());
+
+        assert!(!drive.observe(minutes(100), Some(true), Some(40_000)));
+        assert!(drive.observe(minutes(161), Some(true), Some(40_000)));
+        assert!(drive.backed_off.is_none());
+    }
+}
@@ -0,0 +1,263 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Throttling of the CPU and NVIDIA GPUs, so that drops in performance can be traced to cooling
+//! or to the power limits of a profile.
+//!
+//! The kernel counts how often Intel cores and packages were throttled since boot, which is
+//! compared between samples. NVIDIA GPUs report why their clocks are held down at the moment they
+//! are sampled. An event lasts from the first sample in which a source is throttled until the
+//! first in which it no longer is, so durations are only as precise as the interval.
+
+use crate::{
+    health::ThrottleStats,
+    nvml::{
+        SharedNvml, THROTTLE_HW_POWER_BRAKE, THROTTLE_HW_SLOWDOWN, THROTTLE_HW_THERMAL,
+        THROTTLE_SW_POWER_CAP, THROTTLE_SW_THERMAL,
+    },
+    pci,
+};
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+const CPUS: &str = "/sys/devices/system/cpu";
+
+const GPU_THERMAL: u64 = THROTTLE_SW_THERMAL | THROTTLE_HW_THERMAL | THROTTLE_HW_SLOWDOWN;
+const GPU_POWER: u64 = THROTTLE_SW_POWER_CAP | THROTTLE_HW_POWER_BRAKE;
+
+/// A source of throttling, and its events since the daemon started.
+#[derive(Debug)]
+pub struct ThrottleSource {
+    name:   &'static str,
+    events: u32,
+    /// The time spent throttled by events which have ended.
+    total:  Duration,
+    /// When the ongoing event started, if any.
+    since:  Option<Instant>,
+}
+
+impl ThrottleSource {
+    fn new(name: &'static str) -> Self {
+        ThrottleSource { name, events: 0, total: Duration::from_secs(0), since: None }
+    }
+
+    pub fn name(&self) -> &'static str { self.name }
+
+    /// The events which started, including the ongoing event.
+    pub fn events(&self) -> u32 { self.events }
+
+    pub fn is_throttled(&self) -> bool { self.since.is_some() }
+
+    /// The time spent throttled, including the ongoing event.
+    pub fn total(&self, now: Instant) -> Duration {
+        self.total + self.since.map_or(Duration::from_secs(0), |since| now - since)
+    }
+
+    /// Takes whether the source was throttled as of a sample, returning the duration of an
+    /// event which ended.
+    fn update(&mut self, now: Instant, throttled: bool) -> Option<Duration> {
+        match (self.since, throttled) {
+            (None, true) => {
+                self.events += 1;
+                self.since = Some(now);
+                None
+            }
+            (Some(since), false) => {
+                self.since = None;
+                self.total += now - since;
+                Some(now - since)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The throttle counters of the CPUs, which are absent where the kernel does not count them.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct CpuCounts {
+    thermal: Option<u64>,
+    power:   Option<u64>,
+}
+
+impl CpuCounts {
+    fn read() -> Self {
+        let mut thermal = Counter::default();
+        let mut power = Counter::default();
+        let entries = fs::read_dir(CPUS).into_iter().flatten().filter_map(Result::ok);
+        for entry in entries {
+            let path = entry.path();
+            let throttle = path.join("thermal_throttle");
+            if !throttle.is_dir() {
+                continue;
+            }
+
+            let package = read_u64(&path.join("topology/physical_package_id")).unwrap_or(0);
+            thermal.add(&throttle, "core_throttle_count", "package_throttle_count", package);
+            power.add(&throttle, "core_power_limit_count", "package_power_limit_count", package);
+        }
+
+        CpuCounts { thermal: thermal.total(), power: power.total() }
+    }
+}
+
+/// Sums the throttle counts of every core, and of every package, which each CPU of the package
+/// reports alike.
+#[derive(Default)]
+struct Counter {
+    cores:    Option<u64>,
+    packages: HashMap<u64, u64>,
+}
+
+impl Counter {
+    fn add(&mut self, throttle: &Path, core: &str, package: &str, id: u64) {
+        if let Some(count) = read_u64(&throttle.join(core)) {
+            self.cores = Some(self.cores.unwrap_or(0) + count);
+        }
+        if let Some(count) = read_u64(&throttle.join(package)) {
+            self.packages.insert(id, count);
+        }
+    }
+
+    fn total(&self) -> Option<u64> {
+        if self.cores.is_none() && self.packages.is_empty() {
+            return None;
+        }
+
+        Some(self.cores.unwrap_or(0) + self.packages.values().sum::<u64>())
+    }
+}
+
+fn read_u64(path: &Path) -> Option<u64> { fs::read_to_string(path).ok()?.trim().parse().ok() }
+
+/// Whether a counter increased since the previous sample.
+fn increased(previous: Option<u64>, current: Option<u64>) -> bool {
+    match (previous, current) {
+        (Some(previous), Some(current)) => current > previous,
+        _ => false,
+    }
+}
+
+pub struct ThrottleMonitor {
+    cpu_thermal: ThrottleSource,
+    cpu_power:   ThrottleSource,
+    gpu_thermal: ThrottleSource,
+    gpu_power:   ThrottleSource,
+    /// The counters as of the last sample, or as of creation before the first.
+    counts:      CpuCounts,
+    nvml:        Arc<SharedNvml>,
+}
+
+impl Default for ThrottleMonitor {
+    fn default() -> Self { ThrottleMonitor::new(Arc::default()) }
+}
+
+impl ThrottleMonitor {
+    pub fn new(nvml: Arc<SharedNvml>) -> Self {
+        ThrottleMonitor {
+            cpu_thermal: ThrottleSource::new("cpu-thermal"),
+            cpu_power: ThrottleSource::new("cpu-power"),
+            gpu_thermal: ThrottleSource::new("gpu-thermal"),
+            gpu_power: ThrottleSource::new("gpu-power"),
+            counts: CpuCounts::read(),
+            nvml,
+        }
+    }
+
+    /// Samples the CPUs, and the NVIDIA GPUs by their PCI addresses. GPUs which are suspended
+    /// are skipped rather than woken up, as they are not throttled.
+    pub fn sample(&mut self, now: Instant, nvidia: &[&str]) {
+        let counts = CpuCounts::read();
+        let cpu_thermal = increased(self.counts.thermal, counts.thermal);
+        let cpu_power = increased(self.counts.power, counts.power);
+        self.counts = counts;
+
+        let active: Vec<&str> = nvidia.iter().copied().filter(|id| pci::is_active(id)).collect();
+        let reasons = if active.is_empty() { 0 } else { self.gpu_reasons(&active) };
+
+        let mut updates = [
+            (&mut self.cpu_thermal, cpu_thermal),
+            (&mut self.cpu_power, cpu_power),
+            (&mut self.gpu_thermal, reasons & GPU_THERMAL != 0),
+            (&mut self.gpu_power, reasons & GPU_POWER != 0),
+        ];
+        for (source, throttled) in updates.iter_mut() {
+            if *throttled && !source.is_throttled() {
+                log::debug!("{} throttling started", source.name);
+            }
+            if let Some(duration) = source.update(now, *throttled) {
+                log::info!("{} throttling ended after {}s", source.name, duration.as_secs());
+            }
+        }
+    }
+
+    /// Whether there is anything to sample: the kernel counts throttling of the CPUs, or there
+    /// are NVIDIA GPUs.
+    pub fn is_supported(&self, nvidia: bool) -> bool {
+        self.counts != CpuCounts::default() || nvidia
+    }
+
+    pub fn sources(&self) -> [&ThrottleSource; 4] {
+        [&self.cpu_thermal, &self.cpu_power, &self.gpu_thermal, &self.gpu_power]
+    }
+
+    /// Statistics of each source, for the health endpoint.
+    pub fn stats(&self, now: Instant) -> Vec<ThrottleStats> {
+        self.sources()
+            .iter()
+            .map(|source| ThrottleStats {
+                source:    source.name,
+                events:    source.events,
+                seconds:   source.total(now).as_secs(),
+                throttled: source.is_throttled(),
+            })
+            .collect()
+    }
+
+    /// The throttle reasons of every GPU, combined.
+    fn gpu_reasons(&self, ids: &[&str]) -> u64 {
+        let nvml = match self.nvml.get() {
+            Ok(nvml) => nvml,
+            Err(why) => {
+                log::debug!("failed to read GPU throttle reasons: {}", why);
+                return 0;
+            }
+        };
+
+        ids.iter()
+            .filter_map(|id| nvml.device_by_pci_id(id).and_then(|gpu| gpu.throttle_reasons()).ok())
+            .fold(0, |reasons, gpu| reasons | gpu)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttle_events() {
+        let start = Instant::now();
+        let seconds = |seconds: u64| start + Duration::from_secs(seconds);
+
+        let mut source = ThrottleSource::new("cpu-thermal");
+        assert_eq!(source.update(seconds(0), false), None);
+        assert_eq!(source.update(seconds(10), true), None);
+        assert_eq!(source.update(seconds(20), true), None);
+        assert!(source.is_throttled());
+        assert_eq!(source.total(seconds(25)), Duration::from_secs(15));
+        assert_eq!(source.update(seconds(30), false), Some(Duration::from_secs(20)));
+        assert_eq!(source.update(seconds(40), true), None);
+        assert_eq!(source.update(seconds(45), false), Some(Duration::from_secs(5)));
+        assert_eq!(source.events(), 2);
+        assert_eq!(source.total(seconds(100)), Duration::from_secs(25));
+
+        assert!(increased(Some(3), Some(5)));
+        assert!(!increased(Some(5), Some(5)));
+        assert!(!increased(None, Some(5)));
+    }
+}
@@ -0,0 +1,169 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Power saving of wireless interfaces, which profiles turn on or off.
+//!
+//! Power saving delays packets until the radio wakes, which makes interactive sessions lag on a
+//! link which is already poor. While a profile has power saving on, interfaces are polled for
+//! their signal level, missed beacons, and retries. Power saving is turned off on a poor link,
+//! and turned on again once the link has been stable for a while.
+
+use crate::{
+    config::{Profile, WifiConfig},
+    errors::ProfileError,
+    wifi::{self, LinkStatus},
+};
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+// A link which misses this many beacons between polls is poor.
+const MAX_MISSED_BEACONS: u64 = 8;
+
+// A link which drops this many packets after retrying between polls is poor.
+const MAX_RETRIES: u64 = 16;
+
+#[derive(Debug, Default)]
+struct LinkState {
+    last:       Option<LinkStatus>,
+    /// When the link was last poor, while power saving is backed off.
+    backed_off: Option<Instant>,
+}
+
+impl LinkState {
+    /// Records a poll of the link. Returns whether power saving must now be reapplied, having
+    /// entered or left its back-off.
+    fn observe(
+        &mut self,
+        now: Instant,
+        status: LinkStatus,
+        min_signal: i32,
+        stable: Duration,
+    ) -> bool {
+        let (missed, retries) = match self.last {
+            Some(ref last) => (
+                status.missed_beacons.saturating_sub(last.missed_beacons),
+                status.retries.saturating_sub(last.retries),
+            ),
+            None => (0, 0),
+        };
+
+        let poor =
+            status.signal < min_signal || missed >= MAX_MISSED_BEACONS || retries >= MAX_RETRIES;
+        self.last = Some(status);
+
+        if poor {
+            return self.backed_off.replace(now).is_none();
+        }
+
+        match self.backed_off {
+            Some(since) if now - since >= stable => {
+                self.backed_off = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct WifiPowerSave {
+    config:  WifiConfig,
+    links:   BTreeMap<String, LinkState>,
+    profile: Option<Profile>,
+}
+
+impl WifiPowerSave {
+    pub fn new(config: WifiConfig) -> Self { WifiPowerSave { config, ..Default::default() } }
+
+    /// Whether any profile sets power saving, and so links need to be polled.
+    pub fn is_enabled(&self) -> bool { !self.config.is_empty() }
+
+    fn wanted(&self, interface: &str) -> Option<bool> {
+        let enabled = self.config.profile(self.profile?)?;
+        let backed_off = self.links.get(interface).map_or(false, |link| link.backed_off.is_some());
+        Some(enabled && !backed_off)
+    }
+
+    /// Applies the power saving of a profile to every associated interface.
+    pub fn apply(&mut self, profile: Profile) -> Vec<ProfileError> {
+        self.profile = Some(profile);
+        let mut errors = Vec::new();
+        for status in wifi::link_status() {
+            if let Some(enabled) = self.wanted(&status.interface) {
+                if let Err(why) = wifi::set_power_save(&status.interface, enabled) {
+                    errors.push(ProfileError::Wifi(status.interface, why));
+                }
+            }
+        }
+
+        errors
+    }
+
+    /// Polls the quality of each link, backing off power saving on poor links.
+    pub fn poll(&mut self) {
+        if self.profile.and_then(|profile| self.config.profile(profile)) != Some(true) {
+            return;
+        }
+
+        let now = Instant::now();
+        let min_signal = self.config.min_signal;
+        let stable = Duration::from_secs(self.config.stable);
+        for status in wifi::link_status() {
+            let interface = status.interface.clone();
+            let signal = status.signal;
+            let link = self.links.entry(interface.clone()).or_default();
+            if !link.observe(now, status, min_signal, stable) {
+                continue;
+            }
+
+            let enabled = link.backed_off.is_none();
+            if enabled {
+                log::info!("link of {} is stable, re-enabling its power saving", interface);
+            } else {
+                log::warn!(
+                    "link of {} is poor at {} dBm, disabling its power saving",
+                    interface,
+                    signal
+                );
+            }
+
+            if let Err(why) = wifi::set_power_save(&interface, enabled) {
+                log::warn!("{}", ProfileError::Wifi(interface, why));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(signal: i32, retries: u64, missed_beacons: u64) -> LinkStatus {
+        LinkStatus { interface: String::from("wlan0"), signal, retries, missed_beacons }
+    }
+
+    #[test]
+    fn poor_link_backoff() {
+        let start = Instant::now();
+        let seconds = |seconds: u64| start + Duration::from_secs(seconds);
+        let stable = Duration::from_secs(120);
+        let mut link = LinkState::default();
+
+        // A good link keeps power saving.
+        assert!(!link.observe(seconds(0), status(-60, 100, 50), -75, stable));
+        assert!(!link.observe(seconds(10), status(-60, 110, 52), -75, stable));
+
+        // A weak signal backs it off, as do bursts of missed beacons or retries.
+        assert!(link.observe(seconds(20), status(-80, 110, 52), -75, stable));
+        assert!(!link.observe(seconds(30), status(-60, 110, 60), -75, stable));
+        assert!(!link.observe(seconds(140), status(-60, 130, 60), -75, stable));
+
+        // It is restored once the link has been stable for long enough.
+        assert!(!link.observe(seconds(200), status(-60, 130, 60), -75, stable));
+        assert!(link.observe(seconds(260), status(-60, 130, 60), -75, stable));
+        assert!(link.backed_off.is_none());
+    }
+}
@@ -0,0 +1,181 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Runtime power management policies for individual PCI and USB devices, from the `[devices]`
+//! table of the configuration.
+//!
+//! These are applied after each profile, overriding the runtime power management which the
+//! profile chose, so that devices with buggy autosuspend can be kept powered.
+
+use crate::verify;
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fmt::{self, Display, Formatter},
+    fs, io,
+    path::Path,
+};
+
+const PCI_DEVICES: &str = "/sys/bus/pci/devices";
+const USB_DEVICES: &str = "/sys/bus/usb/devices";
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Bus {
+    Pci,
+    Usb,
+}
+
+/// A device model, written as `pci:8086:a0f0` or `usb:046d:c52b`.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd)]
+#[serde(try_from = "String")]
+pub struct DeviceId {
+    pub bus:     Bus,
+    pub vendor:  u16,
+    pub product: u16,
+}
+
+impl TryFrom<String> for DeviceId {
+    type Error = String;
+
+    fn try_from(id: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("invalid device `{}`, expected an ID like `pci:8086:a0f0`", id);
+
+        let mut parts = id.splitn(3, ':');
+        let bus = match parts.next() {
+            Some("pci") => Bus::Pci,
+            Some("usb") => Bus::Usb,
+            _ => return Err(invalid()),
+        };
+
+        let mut hex = || {
+            parts
+                .next()
+                .filter(|part| part.len() == 4)
+                .and_then(|part| u16::from_str_radix(part, 16).ok())
+                .ok_or_else(invalid)
+        };
+
+        let vendor = hex()?;
+        let product = hex()?;
+        Ok(DeviceId { bus, vendor, product })
+    }
+}
+
+impl Display for DeviceId {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let bus = match self.bus {
+            Bus::Pci => "pci",
+            Bus::Usb => "usb",
+        };
+
+        write!(f, "{}:{:04x}:{:04x}", bus, self.vendor, self.product)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DevicePolicy {
+    /// The device is always powered.
+    On,
+    /// The device is suspended whenever it is idle.
+    Auto,
+    /// The device is suspended when idle while on battery, and always powered on AC.
+    OffOnBattery,
+}
+
+impl DevicePolicy {
+    /// The value of the device's `power/control` attribute.
+    fn control(self, on_battery: bool) -> &'static str {
+        match self {
+            DevicePolicy::On => "on",
+            DevicePolicy::Auto => "auto",
+            DevicePolicy::OffOnBattery if on_battery => "auto",
+            DevicePolicy::OffOnBattery => "on",
+        }
+    }
+}
+
+fn read_id(path: &Path, name: &str) -> Option<u16> {
+    let value = fs::read_to_string(path.join(name)).ok()?;
+    let value = value.trim();
+    u16::from_str_radix(value.strip_prefix("0x").unwrap_or(value), 16).ok()
+}
+
+#[derive(Debug, Default)]
+pub struct DevicePolicies {
+    policies: BTreeMap<DeviceId, DevicePolicy>,
+}
+
+impl DevicePolicies {
+    pub fn new(policies: BTreeMap<DeviceId, DevicePolicy>) -> Self { DevicePolicies { policies } }
+
+    /// Applies the policy of every matching device which is present.
+    pub fn apply(&self, on_battery: bool) {
+        if self.policies.is_empty() {
+            return;
+        }
+
+        self.apply_bus(Bus::Pci, PCI_DEVICES, ("vendor", "device"), on_battery);
+        self.apply_bus(Bus::Usb, USB_DEVICES, ("idVendor", "idProduct"), on_battery);
+    }
+
+    fn apply_bus(&self, bus: Bus, dir: &str, (vendor, product): (&str, &str), on_battery: bool) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(ref why) if why.kind() == io::ErrorKind::NotFound => return,
+            Err(why) => {
+                log::warn!("failed to read {}: {}", dir, why);
+                return;
+            }
+        };
+
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            let id = match (read_id(&path, vendor), read_id(&path, product)) {
+                (Some(vendor), Some(product)) => DeviceId { bus, vendor, product },
+                _ => continue,
+            };
+
+            let policy = match self.policies.get(&id) {
+                Some(&policy) => policy,
+                None => continue,
+            };
+
+            let control = policy.control(on_battery);
+            log::debug!("setting runtime PM of {} ({}) to {}", path.display(), id, control);
+            if let Err(why) = verify::write(&path.join("power/control"), control) {
+                log::warn!("failed to set runtime PM of {} ({}): {}", path.display(), id, why);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(id: &str) -> Result<DeviceId, String> { DeviceId::try_from(id.to_owned()) }
+
+    #[test]
+    fn device_ids() {
+        let wifi = id("pci:8086:A0F0").unwrap();
+        assert_eq!(wifi, DeviceId { bus: Bus::Pci, vendor: 0x8086, product: 0xa0f0 });
+        assert_eq!(wifi.to_string(), "pci:8086:a0f0");
+        assert_eq!(id("usb:046d:c52b").unwrap().bus, Bus::Usb);
+
+        assert!(id("8086:a0f0").is_err());
+        assert!(id("pci:8086").is_err());
+        assert!(id("pci:8086:a0f0x").is_err());
+        assert!(id("pci:86:a0f0").is_err());
+    }
+
+    #[test]
+    fn policies() {
+        assert_eq!(DevicePolicy::On.control(true), "on");
+        assert_eq!(DevicePolicy::Auto.control(false), "auto");
+        assert_eq!(DevicePolicy::OffOnBattery.control(false), "on");
+        assert_eq!(DevicePolicy::OffOnBattery.control(true), "auto");
+    }
+}
@@ -4,13 +4,17 @@
 
 use crate::errors::DiskPowerError;
 use std::{
-    fs::{read_to_string, write},
+    fs::{self, read_to_string, write},
     path::{Path, PathBuf},
     process::{Command, Stdio},
 };
+use sysfs_class::{HwMon, SysClass};
 
 const AUTOSUSPEND: &str = "device/power/autosuspend_delay_ms";
 
+// The hwmon driver which reports the temperature of SATA and SCSI drives.
+const DRIVETEMP: &str = "drivetemp";
+
 pub trait DiskPower {
     fn set_apm_level(&self, level: u8) -> Result<(), DiskPowerError>;
     fn set_autosuspend_delay(&self, ms: i32) -> Result<(), DiskPowerError>;
@@ -38,6 +42,7 @@ impl Default for Disks {
                     }
 
                     disks.push(Disk {
+                        name:          name.clone(),
                         path:          PathBuf::from(["/dev/", &name].concat()),
                         block:         PathBuf::from(["/sys/block/", &name].concat()),
                         is_rotational: {
@@ -54,6 +59,10 @@ impl Default for Disks {
     }
 }
 
+impl Disks {
+    pub fn iter(&self) -> impl Iterator<Item = &Disk> { self.0.iter() }
+}
+
 impl DiskPower for Disks {
     fn set_apm_level(&self, level: u8) -> Result<(), DiskPowerError> {
         self.0.iter().filter(|dev| dev.is_rotational).try_for_each(|dev| dev.set_apm_level(level))
@@ -68,11 +77,51 @@ impl DiskPower for Disks {
 }
 
 pub struct Disk {
+    name:          String,
     path:          PathBuf,
     block:         PathBuf,
     is_rotational: bool,
 }
 
+impl Disk {
+    pub fn name(&self) -> &str { &self.name }
+
+    pub fn is_rotational(&self) -> bool { self.is_rotational }
+
+    /// The temperature reported by the `drivetemp` driver, in millidegrees Celsius.
+    pub fn temperature(&self) -> Option<i32> {
+        let device = fs::canonicalize(self.block.join("device")).ok()?;
+        HwMon::all().ok()?.into_iter().find_map(|hwmon| {
+            if hwmon.name().ok()? != DRIVETEMP
+                || fs::canonicalize(hwmon.path().join("device")).ok()? != device
+            {
+                return None;
+            }
+
+            hwmon.temp(1).ok()?.input().ok().map(|input| input as i32)
+        })
+    }
+
+    /// Whether the drive is spinning, as reported by `hdparm -C`, which does not wake it.
+    pub fn is_spinning(&self) -> Option<bool> {
+        let output = Command::new("hdparm")
+            .arg("-C")
+            .arg(&self.path)
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .ok()?;
+
+        let output = String::from_utf8_lossy(&output.stdout);
+        let state = output.lines().find_map(|line| line.trim().strip_prefix("drive state is:"))?;
+        match state.trim() {
+            "active/idle" | "idle" => Some(true),
+            "standby" | "sleeping" => Some(false),
+            _ => None,
+        }
+    }
+}
+
 impl DiskPower for Disk {
     fn set_apm_level(&self, level: u8) -> Result<(), DiskPowerError> {
         log::debug!("Setting APM level on {:?} to {}", &self.path, level);
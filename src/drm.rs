@@ -0,0 +1,427 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Finds the processes using a GPU's DRM device, such as a compositor or X server which may be
+//! scanning out from it.
+
+use std::{
+    fmt::{self, Display, Formatter},
+    fs::{self, File},
+    io,
+    os::unix::io::{AsRawFd, FromRawFd, RawFd},
+    path::{Path, PathBuf},
+    ptr,
+    time::Duration,
+};
+use tokio::{io::unix::AsyncFd, time};
+
+/// A process which has the DRM card node of a GPU open.
+#[derive(Debug)]
+pub struct DrmUser {
+    pub pid:          u32,
+    pub command:      String,
+    /// The `XDG_SESSION_TYPE` of the process, such as `wayland` or `x11`.
+    pub session_type: Option<String>,
+    /// Whether the process is the DRM master, which controls the displays of the GPU.
+    pub master:       bool,
+    /// When the process started, in clock ticks since boot, which tells it apart from a later
+    /// process which is given the same PID.
+    start_time:       u64,
+}
+
+impl Display for DrmUser {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} (pid {}", self.command, self.pid)?;
+        if let Some(ref session_type) = self.session_type {
+            write!(f, ", {} session", session_type)?;
+        }
+        if self.master {
+            write!(f, ", DRM master")?;
+        }
+        write!(f, ")")
+    }
+}
+
+// Services of the NVIDIA driver which hold its device nodes open, but do not render. Commands
+// are truncated to 15 characters.
+const NVIDIA_SERVICES: &[&str] = &["nvidia-persiste", "nvidia-powerd"];
+
+// How long terminated processes are given to exit before they are killed, and killed processes
+// are given to go away.
+const TERMINATE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The nodes in `/dev` which are named in a directory, such as the DRM nodes of a device in
+/// sysfs, and start with a prefix.
+fn nodes(names: &Path, dev: &Path, prefix: &str) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(names) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.file_name())
+        .filter(|name| name.to_string_lossy().starts_with(prefix))
+        .map(|name| dev.join(name))
+        .collect()
+}
+
+fn drm_nodes(pci_id: &str, prefix: &str) -> Vec<PathBuf> {
+    let drm = Path::new("/sys/bus/pci/devices").join(pci_id).join("drm");
+    nodes(&drm, Path::new("/dev/dri"), prefix)
+}
+
+/// The DRM card nodes of a PCI device, such as `/dev/dri/card1`.
+pub fn card_nodes(pci_id: &str) -> Vec<PathBuf> { drm_nodes(pci_id, "card") }
+
+/// The DRM render nodes of a PCI device, such as `/dev/dri/renderD129`.
+pub fn render_nodes(pci_id: &str) -> Vec<PathBuf> { drm_nodes(pci_id, "renderD") }
+
+/// The device nodes of the NVIDIA driver, such as `/dev/nvidia0` and `/dev/nvidiactl`, which
+/// are shared by every NVIDIA GPU.
+pub fn nvidia_nodes() -> Vec<PathBuf> { nodes(Path::new("/dev"), Path::new("/dev"), "nvidia") }
+
+/// The device node of the NVIDIA driver which belongs to a single GPU, such as `/dev/nvidia1`,
+/// by the minor number which the driver reports for it.
+pub fn nvidia_device_node(pci_id: &str) -> Option<PathBuf> {
+    let information = Path::new("/proc/driver/nvidia/gpus").join(pci_id).join("information");
+    let minor = fs::read_to_string(information).ok().and_then(|info| device_minor(&info))?;
+    Some(PathBuf::from(format!("/dev/nvidia{}", minor)))
+}
+
+/// The `Device Minor` of the information which the NVIDIA driver reports for a GPU.
+fn device_minor(information: &str) -> Option<u32> {
+    information.lines().find_map(|line| line.strip_prefix("Device Minor:")?.trim().parse().ok())
+}
+
+/// Whether a process was launched to render on an NVIDIA GPU through PRIME render offload.
+fn is_offload_client(process: &Path) -> bool {
+    fs::read(process.join("environ")).map_or(false, |environ| {
+        environ.split(|&b| b == 0).any(|var| var == b"__NV_PRIME_RENDER_OFFLOAD=1")
+    })
+}
+
+fn processes() -> impl Iterator<Item = PathBuf> {
+    fs::read_dir("/proc")
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .filter(|process| {
+            process.file_name().to_str().map_or(false, |name| name.parse::<u32>().is_ok())
+        })
+        .map(|process| process.path())
+}
+
+/// Whether any process was launched to render on an NVIDIA GPU through PRIME render offload.
+pub fn offload_client_exists() -> bool { processes().any(|process| is_offload_client(&process)) }
+
+/// Whether any application is using an NVIDIA GPU through one of the nodes, or was launched to
+/// offload rendering to it. The driver's own services are not counted.
+pub fn nvidia_client_exists(nodes: &[PathBuf]) -> bool {
+    processes().any(|process| {
+        let command = fs::read_to_string(process.join("comm")).unwrap_or_default();
+        if NVIDIA_SERVICES.contains(&command.trim()) {
+            return false;
+        }
+
+        is_offload_client(&process)
+            || fs::read_dir(process.join("fd")).map_or(false, |fds| {
+                fds.filter_map(Result::ok)
+                    .filter_map(|fd| fs::read_link(fd.path()).ok())
+                    .any(|target| nodes.contains(&target))
+            })
+    })
+}
+
+/// Finds a process using one of the card nodes, preferring the DRM master.
+///
+/// The master is read from debugfs when it is mounted. Otherwise, and for clients such as DRM
+/// lessees, the open file descriptors of every process are searched.
+pub fn find_user(nodes: &[PathBuf]) -> Option<DrmUser> {
+    nodes.iter().find_map(|node| find_master(node)).or_else(|| find_open(nodes))
+}
+
+fn find_master(node: &Path) -> Option<DrmUser> {
+    let minor = node.file_name()?.to_str()?.trim_start_matches("card");
+    let clients = fs::read_to_string(format!("/sys/kernel/debug/dri/{}/clients", minor)).ok()?;
+
+    // Columns are `command tgid dev master a uid magic`, where the command may contain spaces.
+    clients.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 7 || fields[fields.len() - 4] != "y" {
+            return None;
+        }
+
+        let pid = fields[fields.len() - 6].parse().ok()?;
+        Some(DrmUser {
+            pid,
+            command: fields[..fields.len() - 6].join(" "),
+            session_type: session_type(pid),
+            master: true,
+            start_time: start_time(pid)?,
+        })
+    })
+}
+
+fn find_open(nodes: &[PathBuf]) -> Option<DrmUser> { open_by(nodes).next() }
+
+/// Every process which has one of the nodes open, other than the daemon itself. Besides
+/// applications rendering on the GPU, this includes services such as nvidia-persistenced, which
+/// would keep the device from being removed.
+pub fn find_users(nodes: &[PathBuf]) -> Vec<DrmUser> {
+    let daemon = std::process::id();
+    open_by(nodes).filter(|user| user.pid != daemon).collect()
+}
+
+fn open_by(nodes: &[PathBuf]) -> impl Iterator<Item = DrmUser> + '_ {
+    processes().filter_map(move |process| {
+        let pid: u32 = process.file_name()?.to_str()?.parse().ok()?;
+        let fds = fs::read_dir(process.join("fd")).ok()?;
+        let open = fds
+            .filter_map(Result::ok)
+            .filter_map(|fd| fs::read_link(fd.path()).ok())
+            .any(|target| nodes.contains(&target));
+
+        if !open {
+            return None;
+        }
+
+        let command = fs::read_to_string(process.join("comm")).unwrap_or_default();
+        Some(DrmUser {
+            pid,
+            command: command.trim().to_owned(),
+            session_type: session_type(pid),
+            master: false,
+            start_time: start_time(pid)?,
+        })
+    })
+}
+
+/// A handle to a process through a pidfd, which keeps referring to it after it exits, so that a
+/// process which is later given the same PID is never signalled in its place.
+struct Process<'a> {
+    user:  &'a DrmUser,
+    pidfd: File,
+}
+
+impl<'a> Process<'a> {
+    /// Opens a handle to the process of a user, or `None` if it has exited since it was found.
+    fn open(user: &'a DrmUser) -> io::Result<Option<Self>> {
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, user.pid as libc::pid_t, 0) };
+        if fd < 0 {
+            let why = io::Error::last_os_error();
+            return if why.raw_os_error() == Some(libc::ESRCH) { Ok(None) } else { Err(why) };
+        }
+
+        let pidfd = unsafe { File::from_raw_fd(fd as RawFd) };
+
+        // The PID may have been reused before the handle was opened, which the handle rules out
+        // from here on.
+        if start_time(user.pid) != Some(user.start_time) {
+            return Ok(None);
+        }
+
+        Ok(Some(Process { user, pidfd }))
+    }
+
+    /// Sends a signal to the process, which succeeds if it has already exited.
+    fn signal(&self, signal: libc::c_int) -> io::Result<()> {
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_pidfd_send_signal,
+                self.pidfd.as_raw_fd(),
+                signal,
+                ptr::null::<libc::siginfo_t>(),
+                0,
+            )
+        };
+
+        if res == 0 {
+            return Ok(());
+        }
+
+        let why = io::Error::last_os_error();
+        if why.raw_os_error() == Some(libc::ESRCH) {
+            Ok(())
+        } else {
+            Err(why)
+        }
+    }
+
+    /// Whether the process has exited, which a pidfd reports by becoming readable.
+    fn exited(&self) -> bool {
+        let mut pollfd =
+            libc::pollfd { fd: self.pidfd.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        unsafe { libc::poll(&mut pollfd, 1, 0) > 0 }
+    }
+}
+
+/// Waits until every process has exited, or the timeout has passed.
+async fn wait(processes: &[Process<'_>]) -> io::Result<()> {
+    let fds = processes
+        .iter()
+        .map(|process| AsyncFd::new(process.pidfd.as_raw_fd()))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let exited = async {
+        for fd in &fds {
+            fd.readable().await?.retain_ready();
+        }
+        Ok::<(), io::Error>(())
+    };
+
+    time::timeout(TERMINATE_TIMEOUT, exited).await.unwrap_or(Ok(()))
+}
+
+/// Terminates the processes, killing those which have not exited after a few seconds, and
+/// waits for them to go away. This fails if a process could not be signalled.
+pub async fn terminate(users: &[DrmUser]) -> io::Result<()> {
+    let mut processes = Vec::new();
+    for user in users {
+        if let Some(process) = Process::open(user)? {
+            log::warn!("terminating {}, which has the GPU open", user);
+            process.signal(libc::SIGTERM)?;
+            processes.push(process);
+        }
+    }
+
+    wait(&processes).await?;
+
+    processes.retain(|process| !process.exited());
+    for process in &processes {
+        log::warn!("killing {}, which did not exit", process.user);
+        process.signal(libc::SIGKILL)?;
+    }
+
+    wait(&processes).await
+}
+
+/// Whether every display which is connected and enabled has been turned off through DPMS, as
+/// desktops do once their blank timeout passes. This is false when no display is enabled.
+pub fn displays_off() -> bool {
+    let connectors = fs::read_dir("/sys/class/drm")
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_name().to_string_lossy().contains('-'))
+        .map(|entry| {
+            let read = |attr: &str| {
+                fs::read_to_string(entry.path().join(attr)).unwrap_or_default().trim().to_owned()
+            };
+            (read("status"), read("enabled"), read("dpms"))
+        })
+        .collect::<Vec<_>>();
+
+    all_off(&connectors)
+}
+
+/// Whether the enabled connectors, by their `status`, `enabled` and `dpms` attributes, are all
+/// off, and there is at least one.
+fn all_off(connectors: &[(String, String, String)]) -> bool {
+    let mut enabled = connectors
+        .iter()
+        .filter(|(status, enabled, _)| status == "connected" && enabled == "enabled")
+        .peekable();
+    enabled.peek().is_some() && enabled.all(|(_, _, dpms)| dpms == "Off")
+}
+
+/// The external displays connected to the connectors of a PCI device, such as `HDMI-A-1`, which
+/// go dark once the device is powered off.
+pub fn external_displays(pci_id: &str) -> Vec<String> {
+    let drm = Path::new("/sys/bus/pci/devices").join(pci_id).join("drm");
+    let cards = fs::read_dir(drm)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .filter(|card| card.file_name().to_string_lossy().starts_with("card"));
+
+    cards
+        .flat_map(|card| fs::read_dir(card.path()).into_iter().flatten().filter_map(Result::ok))
+        .filter_map(|connector| {
+            let name = external_connector(&connector.file_name().to_string_lossy())?.to_owned();
+            let status = fs::read_to_string(connector.path().join("status")).ok()?;
+            if status.trim() == "connected" {
+                Some(name)
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// The name of a connector, from its directory such as `card1-HDMI-A-1`, unless it is not a
+/// connector, or is a connector of the internal panel.
+fn external_connector(entry: &str) -> Option<&str> {
+    let name = entry.strip_prefix("card")?;
+    let name = &name[name.find('-')? + 1..];
+    if ["eDP", "LVDS", "DSI"].iter().any(|internal| name.starts_with(internal)) {
+        return None;
+    }
+
+    Some(name)
+}
+
+/// When a process started, in clock ticks since boot, from `/proc/<pid>/stat`.
+fn start_time(pid: u32) -> Option<u64> {
+    parse_start_time(&fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?)
+}
+
+/// The 22nd field of a stat line, counting from the fields after the command, which is in
+/// parentheses and may itself contain spaces and parentheses.
+fn parse_start_time(stat: &str) -> Option<u64> {
+    let fields = &stat[stat.rfind(')')? + 1..];
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+fn session_type(pid: u32) -> Option<String> {
+    let environ = fs::read(format!("/proc/{}/environ", pid)).ok()?;
+    environ
+        .split(|&b| b == 0)
+        .find_map(|var| var.strip_prefix(b"XDG_SESSION_TYPE="))
+        .map(|value| String::from_utf8_lossy(value).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dpms_off() {
+        let connector = |status: &str, enabled: &str, dpms: &str| {
+            (status.to_owned(), enabled.to_owned(), dpms.to_owned())
+        };
+
+        let internal_off = connector("connected", "enabled", "Off");
+        let external_on = connector("connected", "enabled", "On");
+        let unused = connector("disconnected", "disabled", "On");
+        assert!(all_off(&[internal_off.clone(), unused.clone()]));
+        assert!(!all_off(&[internal_off, external_on]));
+        assert!(!all_off(&[unused]));
+    }
+
+    #[test]
+    fn external_connectors() {
+        assert_eq!(external_connector("card1-HDMI-A-1"), Some("HDMI-A-1"));
+        assert_eq!(external_connector("card1-DP-2"), Some("DP-2"));
+        assert_eq!(external_connector("card0-eDP-1"), None);
+        assert_eq!(external_connector("card1"), None);
+        assert_eq!(external_connector("renderD128"), None);
+    }
+
+    #[test]
+    fn stat_start_time() {
+        let stat = "1234 (Web Content (x)) S 1 1234 1234 0 -1 4194560 500 0 0 0 10 5 0 0 20 0 8 0 \
+                    98765 1000000 100 18446744073709551615 1 1 0 0 0 0 0 4096 0 0 0 0 17 3 0 0\n";
+        assert_eq!(parse_start_time(stat), Some(98765));
+        assert_eq!(parse_start_time("1234 (Xorg"), None);
+    }
+
+    #[test]
+    fn nvidia_minor() {
+        let information = "Model: \t\t NVIDIA GeForce RTX 3060\nIRQ:   \t\t 185\nDevice Minor: \t \
+                           1\nBus Location: \t 0000:01:00.0\n";
+        assert_eq!(device_minor(information), Some(1));
+        assert_eq!(device_minor("Model: \t\t NVIDIA GeForce RTX 3060\n"), None);
+    }
+}
@@ -0,0 +1,316 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! The embedded controller of System76 laptops, as exposed in sysfs by the `system76_acpi`
+//! platform driver.
+//!
+//! Each feature depends on the firmware and driver version, so each has its own check for
+//! support. The driver does not expose fan control, only fan speeds.
+
+use crate::sensors::SensorCache;
+use serde::Deserialize;
+use std::{
+    convert::TryFrom,
+    fmt::{self, Display, Formatter},
+    fs, io,
+    path::{Path, PathBuf},
+};
+use sysfs_class::{HwMon, SysClass};
+use thiserror::Error;
+
+/// The ACPI device of System76 firmware, which the driver binds to.
+const ACPI_DEVICE: &str = "/sys/bus/acpi/devices/17761776:00";
+
+// The keyboard backlight, as named by `system76_acpi`, and by the `system76` driver of older
+// models.
+const KBD_BACKLIGHTS: &[&str] =
+    &["/sys/class/leds/system76_acpi::kbd_backlight", "/sys/class/leds/system76::kbd_backlight"];
+
+// The zones of keyboards which are lit in several colors, each with a color attribute of its own.
+const KBD_ZONES: &[&str] = &["left", "center", "right", "extra"];
+
+/// The only zone of keyboards which are lit in a single color.
+pub const KBD_MAIN_ZONE: &str = "main";
+
+/// Sets every zone of a keyboard at once.
+pub const KBD_ALL_ZONES: &str = "all";
+
+const POWER_SUPPLY: &str = "/sys/class/power_supply";
+
+const START_THRESHOLD: &str = "charge_control_start_threshold";
+const END_THRESHOLD: &str = "charge_control_end_threshold";
+
+// The charging preset which the firmware shows, such as `Standard` or `Long Life`.
+const CHARGE_TYPE: &str = "charge_type";
+
+// Older versions of the driver named their hwmon device `system76`.
+const HWMON_NAMES: &[&str] = &["system76_acpi", "system76"];
+
+#[derive(Debug, Error)]
+pub enum EcError {
+    #[error("{} is not supported by this firmware", _0)]
+    Unsupported(&'static str),
+    #[error("failed to read {}: {}", _0.display(), _1)]
+    Read(PathBuf, io::Error),
+    #[error("failed to write {}: {}", _0.display(), _1)]
+    Write(PathBuf, io::Error),
+    #[error("invalid value in {}: {:?}", _0.display(), _1)]
+    Parse(PathBuf, String),
+    #[error("keyboard has no zone named {}", _0)]
+    UnknownZone(String),
+}
+
+/// Whether the system is running System76 firmware.
+pub fn is_present() -> bool { Path::new(ACPI_DEVICE).is_dir() }
+
+fn read(path: &Path) -> Result<String, EcError> {
+    fs::read_to_string(path)
+        .map(|value| value.trim().to_owned())
+        .map_err(|why| EcError::Read(path.to_owned(), why))
+}
+
+fn read_u32(path: &Path, radix: u32) -> Result<u32, EcError> {
+    let value = read(path)?;
+    u32::from_str_radix(&value, radix).map_err(|_| EcError::Parse(path.to_owned(), value))
+}
+
+fn write(path: &Path, value: &str) -> Result<(), EcError> {
+    fs::write(path, value).map_err(|why| EcError::Write(path.to_owned(), why))
+}
+
+fn battery_path(battery: &str) -> PathBuf { Path::new(POWER_SUPPLY).join(battery) }
+
+fn has_thresholds(battery: &Path) -> bool {
+    battery.join(START_THRESHOLD).exists() && battery.join(END_THRESHOLD).exists()
+}
+
+/// The names of the batteries which have charge thresholds, such as `BAT0`, in order.
+pub fn batteries() -> Vec<String> {
+    let entries = match fs::read_dir(POWER_SUPPLY) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut batteries: Vec<String> = entries
+        .filter_map(Result::ok)
+        .filter(|entry| has_thresholds(&entry.path()))
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| name.starts_with("BAT"))
+        .collect();
+    batteries.sort();
+    batteries
+}
+
+pub fn supports_charge_thresholds() -> bool { is_present() && !batteries().is_empty() }
+
+/// The charge percentages of a battery at which charging starts and stops.
+pub fn charge_thresholds(battery: &str) -> Result<(u8, u8), EcError> {
+    let path = battery_path(battery);
+    if !is_present() || !has_thresholds(&path) {
+        return Err(EcError::Unsupported("charge thresholds"));
+    }
+
+    let start = read_u32(&path.join(START_THRESHOLD), 10)?;
+    let end = read_u32(&path.join(END_THRESHOLD), 10)?;
+    Ok((start as u8, end as u8))
+}
+
+/// Sets the charge thresholds of a battery, which must already be validated.
+pub fn set_charge_thresholds(battery: &str, start: u8, end: u8) -> Result<(), EcError> {
+    let path = battery_path(battery);
+    if !is_present() || !has_thresholds(&path) {
+        return Err(EcError::Unsupported("charge thresholds"));
+    }
+
+    // Without this, setting the start threshold may fail if the previous end threshold is
+    // higher.
+    write(&path.join(END_THRESHOLD), "100")?;
+
+    write(&path.join(START_THRESHOLD), &start.to_string())?;
+    write(&path.join(END_THRESHOLD), &end.to_string())
+}
+
+/// Whether the firmware exposes a charging preset alongside the thresholds of a battery.
+pub fn supports_charge_type(battery: &str) -> bool {
+    let path = battery_path(battery);
+    is_present() && has_thresholds(&path) && path.join(CHARGE_TYPE).exists()
+}
+
+pub fn charge_type(battery: &str) -> Result<String, EcError> {
+    if !supports_charge_type(battery) {
+        return Err(EcError::Unsupported("charge types"));
+    }
+
+    read(&battery_path(battery).join(CHARGE_TYPE))
+}
+
+pub fn set_charge_type(battery: &str, charge_type: &str) -> Result<(), EcError> {
+    if !supports_charge_type(battery) {
+        return Err(EcError::Unsupported("charge types"));
+    }
+
+    write(&battery_path(battery).join(CHARGE_TYPE), charge_type)
+}
+
+/// A 24-bit RGB color, written as `#RRGGBB`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Color(pub u32);
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(color: String) -> Result<Self, Self::Error> {
+        let hex = color.strip_prefix('#').unwrap_or(&color);
+        if hex.len() != 6 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(format!("invalid color `{}`, expected a color like `#FF8000`", color));
+        }
+
+        u32::from_str_radix(hex, 16).map(Color).map_err(|why| why.to_string())
+    }
+}
+
+impl Display for Color {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result { write!(f, "#{:06X}", self.0 & 0xFF_FFFF) }
+}
+
+/// The keyboard backlight LED, which the firmware controls directly on laptops with an internal
+/// keyboard.
+pub struct KeyboardBacklight {
+    path: PathBuf,
+}
+
+impl KeyboardBacklight {
+    pub fn new() -> Option<KeyboardBacklight> {
+        KBD_BACKLIGHTS
+            .iter()
+            .map(PathBuf::from)
+            .find(|path| path.is_dir())
+            .map(|path| KeyboardBacklight { path })
+    }
+
+    pub fn path(&self) -> &Path { &self.path }
+
+    pub fn brightness(&self) -> Result<u32, EcError> { read_u32(&self.path.join("brightness"), 10) }
+
+    pub fn max_brightness(&self) -> Result<u32, EcError> {
+        read_u32(&self.path.join("max_brightness"), 10)
+    }
+
+    pub fn set_brightness(&self, brightness: u32) -> Result<(), EcError> {
+        write(&self.path.join("brightness"), &brightness.to_string())
+    }
+
+    /// Only keyboards with RGB backlights support setting a color.
+    pub fn supports_color(&self) -> bool { self.path.join("color").exists() }
+
+    /// The color, as a 24-bit RGB value.
+    pub fn color(&self) -> Result<u32, EcError> { read_u32(&self.path.join("color"), 16) }
+
+    pub fn set_color(&self, color: u32) -> Result<(), EcError> {
+        write(&self.path.join("color"), &format!("{:06X}", color & 0xFF_FFFF))
+    }
+
+    /// The zones which are lit in colors of their own, from left to right. Keyboards with a
+    /// single color have only the main zone, and those without colors have none.
+    pub fn zones(&self) -> Vec<&'static str> {
+        let zones: Vec<&'static str> = KBD_ZONES
+            .iter()
+            .copied()
+            .filter(|zone| self.path.join(format!("color_{}", zone)).exists())
+            .collect();
+
+        if zones.is_empty() && self.supports_color() {
+            vec![KBD_MAIN_ZONE]
+        } else {
+            zones
+        }
+    }
+
+    fn zone_path(&self, zone: &str) -> Result<PathBuf, EcError> {
+        if !self.zones().contains(&zone) {
+            return Err(EcError::UnknownZone(zone.to_owned()));
+        }
+
+        Ok(if zone == KBD_MAIN_ZONE {
+            self.path.join("color")
+        } else {
+            self.path.join(format!("color_{}", zone))
+        })
+    }
+
+    pub fn zone_color(&self, zone: &str) -> Result<Color, EcError> {
+        read_u32(&self.zone_path(zone)?, 16).map(Color)
+    }
+
+    /// Sets the color of a zone, or of every zone with `all`.
+    pub fn set_zone_color(&self, zone: &str, color: Color) -> Result<(), EcError> {
+        if zone != KBD_ALL_ZONES {
+            return write(&self.zone_path(zone)?, &format!("{:06X}", color.0 & 0xFF_FFFF));
+        }
+
+        for zone in self.zones() {
+            self.set_zone_color(zone, color)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The speeds of the fans which the firmware reports, in RPM.
+pub fn fan_speeds(cache: &SensorCache) -> Vec<u32> {
+    let hwmons = match HwMon::all() {
+        Ok(hwmons) => hwmons,
+        Err(_) => return Vec::new(),
+    };
+
+    hwmons
+        .into_iter()
+        .filter(|hwmon| hwmon.name().map_or(false, |name| HWMON_NAMES.contains(&name.as_str())))
+        .flat_map(|hwmon| {
+            (1..)
+                .map(move |fan| cache.read_u32(&hwmon.path().join(format!("fan{}_input", fan))))
+                .take_while(Option::is_some)
+                .flatten()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn colors() {
+        assert_eq!(Color::try_from("#FF8000".to_owned()), Ok(Color(0xFF_8000)));
+        assert_eq!(Color::try_from("00ff00".to_owned()), Ok(Color(0x00_FF00)));
+        assert!(Color::try_from("#F80".to_owned()).is_err());
+        assert!(Color::try_from("#GG0000".to_owned()).is_err());
+        assert_eq!(Color(0x0A_0B0C).to_string(), "#0A0B0C");
+    }
+
+    #[test]
+    fn zones() {
+        let path = std::env::temp_dir().join(format!("system76-power-kbd-{}", process::id()));
+        fs::create_dir_all(&path).unwrap();
+        let keyboard = KeyboardBacklight { path: path.clone() };
+        assert!(keyboard.zones().is_empty());
+
+        fs::write(path.join("color"), "FFFFFF\n").unwrap();
+        assert_eq!(keyboard.zones(), vec![KBD_MAIN_ZONE]);
+
+        fs::write(path.join("color_left"), "FFFFFF\n").unwrap();
+        fs::write(path.join("color_right"), "FFFFFF\n").unwrap();
+        assert_eq!(keyboard.zones(), vec!["left", "right"]);
+
+        keyboard.set_zone_color(KBD_ALL_ZONES, Color(0xFF_0000)).unwrap();
+        keyboard.set_zone_color("right", Color(0x00_00FF)).unwrap();
+        assert_eq!(keyboard.zone_color("left").unwrap(), Color(0xFF_0000));
+        assert_eq!(keyboard.zone_color("right").unwrap(), Color(0x00_00FF));
+        assert!(matches!(keyboard.zone_color("center"), Err(EcError::UnknownZone(_))));
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -2,6 +2,7 @@
 //
 // SPDX-License-Identifier: GPL-3.0-only
 
+use crate::{ec::EcError, service::ServiceError, verify::VerifyError};
 use intel_pstate::PStateError;
 use std::{io, path::PathBuf, process};
 
@@ -11,14 +12,34 @@ pub enum ProfileError {
     Backlight(BacklightError),
     #[error("failed to set disk power profiles: {}", _0)]
     DiskPower(DiskPowerError),
+    #[error("failed to set keyboard colors: {}", _0)]
+    KeyboardColor(EcError),
+    #[error("failed to set knob profiles: {}", _0)]
+    Knob(VerifyError),
     #[error("failed to set model profiles: {}", _0)]
     Model(ModelError),
     #[error("failed to set pci device profiles: {}", _0)]
     PciDevice(PciDeviceError),
+    #[error("failed to set PCIe link speed of {}: {}", _0, _1)]
+    PcieLink(String, io::Error),
+    #[error("failed to set ACPI platform profile: {}", _0)]
+    Platform(io::Error),
     #[error("failed to set pstate profiles: {}", _0)]
     PState(PStateError),
+    #[error("failed to set pstate profiles: {}", _0)]
+    PStateCpus(CpuSettingError),
+    #[error("failed to set pstate profiles: {}", _0)]
+    PStateVerify(VerifyError),
+    #[error("failed to set radeon profiles: {}", _0)]
+    Radeon(RadeonError),
+    #[error("failed to set scheduler profiles: {}", _0)]
+    Scheduler(VerifyError),
+    #[error("profile script failed: {}", _0)]
+    Script(String),
     #[error("failed to set scsi host profiles: {}", _0)]
     ScsiHost(ScsiHostError),
+    #[error("failed to set Wi-Fi power saving of {}: {}", _0, _1)]
+    Wifi(String, io::Error),
 }
 
 impl From<BacklightError> for ProfileError {
@@ -41,6 +62,18 @@ impl From<PStateError> for ProfileError {
     fn from(why: PStateError) -> ProfileError { ProfileError::PState(why) }
 }
 
+impl From<CpuSettingError> for ProfileError {
+    fn from(why: CpuSettingError) -> ProfileError { ProfileError::PStateCpus(why) }
+}
+
+impl From<VerifyError> for ProfileError {
+    fn from(why: VerifyError) -> ProfileError { ProfileError::PStateVerify(why) }
+}
+
+impl From<RadeonError> for ProfileError {
+    fn from(why: RadeonError) -> ProfileError { ProfileError::Radeon(why) }
+}
+
 impl From<ScsiHostError> for ProfileError {
     fn from(why: ScsiHostError) -> ProfileError { ProfileError::ScsiHost(why) }
 }
@@ -62,7 +95,7 @@ pub enum DiskPowerError {
 #[derive(Debug, thiserror::Error)]
 pub enum ModelError {
     #[error("failed to stop thermald: {}", _0)]
-    Thermald(io::Error),
+    Thermald(ServiceError),
     #[error("failed to set PL1: {}", _0)]
     Pl1(io::Error),
     #[error("failed to set PL2: {}", _0)]
@@ -83,10 +116,39 @@ pub enum ModelError {
     Tcc(io::Error),
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum CpuError {
+    #[error("not supported")]
+    Unsupported,
+    #[error("{}", _0)]
+    Io(io::Error),
+}
+
+/// A setting which could not be applied to some CPUs, though it was applied to the others.
+#[derive(Debug, thiserror::Error)]
+#[error("failed to set {} on {}", setting, display_failures(failures))]
+pub struct CpuSettingError {
+    pub setting:  &'static str,
+    /// Each CPU which failed, by number, and why.
+    pub failures: Vec<(u32, CpuError)>,
+}
+
+fn display_failures(failures: &[(u32, CpuError)]) -> String {
+    let failures: Vec<String> =
+        failures.iter().map(|(cpu, why)| format!("cpu{} ({})", cpu, why)).collect();
+    failures.join(", ")
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum PciDeviceError {
     #[error("failed to set PCI device runtime PM on {}: {}", _0, _1)]
-    SetRuntimePm(String, io::Error),
+    SetRuntimePm(String, VerifyError),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RadeonError {
+    #[error("failed to set {} on card{}: {}", _0, _1, _2)]
+    Set(&'static str, u8, io::Error),
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -0,0 +1,95 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Exports the current settings, so that they can be reproduced on a new install.
+//!
+//! The fan curve is carried by the configuration file. Without one, the curve is the default of
+//! the model, which a new install on the same hardware already uses.
+
+use crate::config::CONFIG_PATH;
+use serde::Serialize;
+
+// Ends the configuration file in the shell script, chosen so as not to occur in the file.
+const HEREDOC_DELIMITER: &str = "SYSTEM76_POWER_CONFIG";
+
+#[derive(Debug, Default, Serialize)]
+pub struct Settings {
+    /// The name of the profile, as given to `system76-power profile`.
+    pub profile:           String,
+    /// The graphics mode, on systems with switchable graphics.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub graphics:          Option<String>,
+    /// The start and end charge thresholds, where the firmware supports them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub charge_thresholds: Option<(u8, u8)>,
+    /// The contents of the configuration file, if there is one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config:            Option<String>,
+}
+
+impl Settings {
+    /// A shell script which applies the settings when run as root.
+    pub fn to_shell(&self) -> String {
+        let mut script = format!(
+            "#!/bin/sh\n# Settings exported by system76-power {}. Run as root on the new \
+             install.\nset -e\n",
+            env!("CARGO_PKG_VERSION")
+        );
+
+        if let Some(ref config) = self.config {
+            script.push_str("\n# The configuration takes effect once the daemon is restarted.\n");
+            script.push_str(&format!(
+                "mkdir -p /etc/system76-power\ncat > {} <<'{}'\n{}",
+                CONFIG_PATH, HEREDOC_DELIMITER, config
+            ));
+            if !config.ends_with('\n') {
+                script.push('\n');
+            }
+            script.push_str(HEREDOC_DELIMITER);
+            script.push('\n');
+        }
+
+        script.push_str(&format!("\nsystem76-power profile {}\n", self.profile));
+        if let Some((start, end)) = self.charge_thresholds {
+            script.push_str(&format!("system76-power charge-thresholds {} {}\n", start, end));
+        }
+        if let Some(ref graphics) = self.graphics {
+            script.push_str("# Switching the graphics mode takes effect after a reboot.\n");
+            script.push_str(&format!("system76-power graphics {}\n", graphics));
+        }
+
+        script
+    }
+
+    /// A TOML document holding the settings.
+    pub fn to_toml(&self) -> Result<String, toml::ser::Error> { toml::to_string_pretty(self) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shell_script() {
+        let settings = Settings {
+            profile:           "battery".into(),
+            graphics:          Some("hybrid".into()),
+            charge_thresholds: Some((40, 80)),
+            config:            Some("[fan]\nunit = \"celsius\"".into()),
+        };
+
+        let script = settings.to_shell();
+        assert!(script.contains(
+            "cat > /etc/system76-power/config.toml <<'SYSTEM76_POWER_CONFIG'\n[fan]\nunit = \
+             \"celsius\"\nSYSTEM76_POWER_CONFIG\n"
+        ));
+        assert!(script.contains("\nsystem76-power profile battery\n"));
+        assert!(script.contains("\nsystem76-power charge-thresholds 40 80\n"));
+        assert!(script.ends_with("\nsystem76-power graphics hybrid\n"));
+
+        let settings = Settings { profile: "balanced".into(), ..Settings::default() };
+        assert!(!settings.to_shell().contains("config.toml"));
+        assert_eq!(settings.to_toml().unwrap(), "profile = 'balanced'\n");
+    }
+}
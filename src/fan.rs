@@ -4,12 +4,27 @@
 
 #![allow(clippy::inconsistent_digit_grouping)]
 
+use crate::{
+    config::FanConfig,
+    nvml::{NvmlError, SharedNvml},
+    pci, safety,
+    sensors::SensorCache,
+    verify::{self, VerifyError},
+};
 use std::{
     cell::Cell,
-    cmp, fs, io,
+    cmp,
+    convert::TryFrom,
+    fs, io,
     process::{Command, Stdio},
+    sync::Arc,
 };
 use sysfs_class::{HwMon, SysClass};
+use tracing::field::Empty;
+
+// The prefixes of the desktop models, as in DMI `product_version`. Laptops have their fans
+// controlled by the EC, so no curve is chosen for them.
+const DESKTOP_CHASSIS: &[&str] = &["meer", "thelio"];
 
 #[derive(Debug, thiserror::Error)]
 pub enum FanDaemonError {
@@ -28,31 +43,72 @@ pub struct FanDaemon {
     cpus:              Vec<HwMon>,
     nvidia_exists:     bool,
     displayed_warning: Cell<bool>,
+    /// Whether the last duty cycle failed to set, so that the failure is only logged once.
+    duty_rejected:     Cell<bool>,
+    /// Runs the fans at full speed, regardless of temperature.
+    boost:             bool,
+    /// Replaces the curve during quiet hours.
+    quiet_curve:       Option<FanCurve>,
+    /// Replaces the curve, including during quiet hours, while operating as a clamshell.
+    clamshell_curve:   Option<FanCurve>,
+    /// Holds the fans at a duty cycle in percent, unless the curve calls for more.
+    fixed_duty:        Option<u8>,
+    /// The power draw of the dGPU in milliwatts, above which the fans are kept at a duty cycle
+    /// in percent while on AC.
+    gpu_floor:         Option<(u32, u8)>,
+    /// The PCI addresses of the NVIDIA GPUs, whose power draw is read through NVML.
+    nvidia:            Vec<String>,
+    nvml:              Option<Arc<SharedNvml>>,
+    on_battery:        bool,
+    sensors:           Arc<SensorCache>,
+    /// Whether the fans are controlled at all, rather than left to another tool.
+    managed:           bool,
 }
 
 impl FanDaemon {
-    pub fn new(nvidia_exists: bool) -> Self {
+    pub fn new(nvidia_exists: bool, sensors: Arc<SensorCache>) -> Self {
         let model = fs::read_to_string("/sys/class/dmi/id/product_version").unwrap_or_default();
         let mut daemon = FanDaemon {
-            curve: match model.trim() {
-                "thelio-major-r1" => FanCurve::threadripper2(),
-                "thelio-major-r2" | "thelio-major-r2.1" | "thelio-major-b1" | "thelio-major-b2"
-                | "thelio-major-b3" | "thelio-mega-r1" | "thelio-mega-r1.1" => FanCurve::hedt(),
-                "thelio-massive-b1" => FanCurve::xeon(),
-                _ => FanCurve::standard(),
-            },
+            curve: FanCurve::for_model(model.trim())
+                .map_or_else(FanCurve::standard, |(_, curve)| curve),
             amdgpus: Vec::new(),
             platforms: Vec::new(),
             cpus: Vec::new(),
             nvidia_exists,
             displayed_warning: Cell::new(false),
+            duty_rejected: Cell::new(false),
+            boost: false,
+            quiet_curve: None,
+            clamshell_curve: None,
+            fixed_duty: None,
+            gpu_floor: None,
+            nvidia: Vec::new(),
+            nvml: None,
+            on_battery: false,
+            sensors,
+            managed: true,
         };
 
-        if let Err(err) = daemon.discover() {
+        daemon.rediscover();
+        daemon
+    }
+
+    /// Rediscover hwmon devices, which should be called when hwmon devices are added or removed.
+    pub fn rediscover(&mut self) {
+        if let Err(err) = self.discover() {
             log::error!("fan daemon: {}", err);
         }
 
-        daemon
+        safety::set_fan_controls(
+            self.platforms.iter().map(|platform| platform.path().join("pwm1_enable")),
+        );
+    }
+
+    /// Leaves the fans to the firmware or another tool, without discovering any devices, unless
+    /// `managed` is set.
+    pub fn set_managed(&mut self, managed: bool) {
+        self.managed = managed;
+        self.rediscover();
     }
 
     /// Discover all utilizable hwmon devices
@@ -61,6 +117,10 @@ impl FanDaemon {
         self.platforms.clear();
         self.cpus.clear();
 
+        if !self.managed {
+            return Ok(());
+        }
+
         for hwmon in HwMon::all().map_err(FanDaemonError::HwmonDevices)? {
             if let Ok(name) = hwmon.name() {
                 log::debug!("hwmon: {}", name);
@@ -89,21 +149,28 @@ impl FanDaemon {
     /// Get the maximum measured temperature from any CPU / GPU on the system, in
     /// thousandths of a Celsius. Thousandths celsius is the standard Linux hwmon temperature unit.
     pub fn get_temp(&self) -> Option<u32> {
-        let mut temp_opt = self
-            .cpus
-            .iter()
-            .chain(self.amdgpus.iter())
-            .filter_map(|sensor| sensor.temp(1).ok())
-            .filter_map(|temp| temp.input().ok())
-            .fold(None, |mut temp_opt, input| {
-                // Assume temperatures are always above freezing
-                if temp_opt.map_or(true, |x| input as u32 > x) {
-                    log::debug!("highest hwmon cpu/gpu temp: {}", input);
-                    temp_opt = Some(input as u32);
-                }
+        let temp_opt = cmp::max(self.cpu_temp(), self.gpu_temp());
+        log::debug!("current temp: {:?}", temp_opt);
+        temp_opt
+    }
+
+    /// The highest temperature of the given hwmon sensors, in thousandths of a Celsius.
+    fn hwmon_temp<'a>(&self, sensors: impl Iterator<Item = &'a HwMon>) -> Option<u32> {
+        // Assume temperatures are always above freezing
+        sensors.filter_map(|sensor| self.sensors.read_u32(&sensor.path().join("temp1_input"))).max()
+    }
 
-                temp_opt
-            });
+    /// The highest CPU temperature, in thousandths of a Celsius.
+    pub fn cpu_temp(&self) -> Option<u32> {
+        let temp_opt = self.hwmon_temp(self.cpus.iter());
+        log::debug!("highest hwmon cpu temp: {:?}", temp_opt);
+        temp_opt
+    }
+
+    /// The highest GPU temperature, in thousandths of a Celsius.
+    pub fn gpu_temp(&self) -> Option<u32> {
+        let mut temp_opt = self.hwmon_temp(self.amdgpus.iter());
+        log::debug!("highest hwmon gpu temp: {:?}", temp_opt);
 
         // Fetch NVIDIA temperatures from the `nvidia-smi` tool when it exists.
         if self.nvidia_exists && !self.displayed_warning.get() {
@@ -112,8 +179,7 @@ impl FanDaemon {
                 Ok(()) => {
                     if nv_temp != 0 {
                         log::debug!("highest nvidia temp: {}", nv_temp);
-                        temp_opt =
-                            Some(temp_opt.map_or(nv_temp, |temp| cmp::max(nv_temp * 1000, temp)));
+                        temp_opt = cmp::max(temp_opt, Some(nv_temp * 1000));
                     }
                 }
                 Err(why) => {
@@ -123,16 +189,84 @@ impl FanDaemon {
             }
         }
 
-        log::debug!("current temp: {:?}", temp_opt);
-
         temp_opt
     }
 
+    /// The highest power draw of the dGPUs, in milliwatts.
+    pub fn gpu_power(&self) -> Option<u32> {
+        let mut power_opt = self
+            .amdgpus
+            .iter()
+            .filter_map(|sensor| {
+                // Newer kernels report the instantaneous power rather than an average.
+                let path = sensor.path();
+                let microwatts = self
+                    .sensors
+                    .read_u32(&path.join("power1_average"))
+                    .or_else(|| self.sensors.read_u32(&path.join("power1_input")))?;
+                Some(microwatts / 1000)
+            })
+            .max();
+
+        if !self.displayed_warning.get() {
+            match self.nvidia_power() {
+                Ok(power) => power_opt = cmp::max(power_opt, power),
+                Err(why) => {
+                    log::warn!("failed to get power draw of NVIDIA GPUs: {}", why);
+                    self.displayed_warning.set(true);
+                }
+            }
+        }
+
+        log::debug!("highest gpu power: {:?}", power_opt);
+        power_opt
+    }
+
+    /// The highest power draw of the NVIDIA GPUs, in milliwatts. GPUs which are suspended are
+    /// skipped rather than woken up, as they draw next to nothing, and so are GPUs which do not
+    /// report their power draw.
+    fn nvidia_power(&self) -> Result<Option<u32>, NvmlError> {
+        let nvml = match self.nvml {
+            Some(ref nvml) => nvml,
+            None => return Ok(None),
+        };
+
+        let mut active = self.nvidia.iter().filter(|id| pci::is_active(id)).peekable();
+        if active.peek().is_none() {
+            return Ok(None);
+        }
+
+        let nvml = nvml.get()?;
+        let mut power_opt = None;
+        for id in active {
+            match nvml.device_by_pci_id(id).and_then(|device| device.power_usage()) {
+                Ok(power) => power_opt = cmp::max(power_opt, Some(power)),
+                Err(why) if why.is_not_supported() => (),
+                Err(why) => return Err(why),
+            }
+        }
+
+        Ok(power_opt)
+    }
+
+    /// The lowest duty cycle, from 0 to 255, while the dGPU draws more than the threshold on AC.
+    fn gpu_floor(&self) -> Option<u8> {
+        let (threshold, percent) = self.gpu_floor.filter(|_| !self.on_battery)?;
+        if self.gpu_power()? > threshold {
+            Some(percent_duty(percent))
+        } else {
+            None
+        }
+    }
+
     /// Get the correct duty cycle for a temperature in thousandths Celsius, from 0 to 255
     /// Thousandths celsius is the standard Linux hwmon temperature unit
     /// 0 to 255 is the standard Linux hwmon pwm unit
     pub fn get_duty(&self, temp: u32) -> Option<u8> {
-        self.curve
+        self.clamshell_curve
+            .as_ref()
+            .or_else(|| self.quiet_curve.as_ref())
+            .unwrap_or(&self.curve)
             .get_duty((temp / 10) as i16)
             .map(|duty| (((u32::from(duty)) * 255) / 10_000) as u8)
     }
@@ -140,26 +274,155 @@ impl FanDaemon {
     /// Set the current duty cycle, from 0 to 255
     /// 0 to 255 is the standard Linux hwmon pwm unit
     pub fn set_duty(&self, duty_opt: Option<u8>) {
-        if let Some(duty) = duty_opt {
-            let duty_str = format!("{}", duty);
-            for platform in &self.platforms {
-                let _ = platform.write_file("pwm1_enable", "1");
-                let _ = platform.write_file("pwm1", &duty_str);
-                let _ = platform.write_file("pwm2", &duty_str);
+        match self.try_set_duty(duty_opt) {
+            Ok(()) => self.duty_rejected.set(false),
+            Err(why) => {
+                if !self.duty_rejected.replace(true) {
+                    log::warn!("failed to set fan duty: {}", why);
+                }
             }
-        } else {
-            for platform in &self.platforms {
-                let _ = platform.write_file("pwm1_enable", "2");
+        }
+    }
+
+    /// Sets the duty cycle, or returns the fans to the firmware with `None`, failing if any
+    /// platform did not accept it. Every platform is set, even if an earlier one failed.
+    pub fn try_set_duty(&self, duty_opt: Option<u8>) -> Result<(), VerifyError> {
+        let mut result = Ok(());
+        for platform in &self.platforms {
+            if let Err(why) = Self::set_platform_duty(platform, duty_opt) {
+                result = Err(why);
             }
         }
+
+        result
     }
 
-    /// Calculate the correct duty cycle and apply it to all fans
-    pub fn step(&mut self) {
-        if let Ok(()) = self.discover() {
-            self.set_duty(self.get_temp().and_then(|temp| self.get_duty(temp)));
+    fn set_platform_duty(platform: &HwMon, duty_opt: Option<u8>) -> Result<(), VerifyError> {
+        let path = platform.path();
+        let duty = match duty_opt {
+            Some(duty) => duty.to_string(),
+            None => return verify::write(&path.join("pwm1_enable"), "2"),
+        };
+
+        verify::write(&path.join("pwm1_enable"), "1")?;
+        verify::write(&path.join("pwm1"), &duty)?;
+        // Not every platform has a second fan.
+        if path.join("pwm2").exists() {
+            verify::write(&path.join("pwm2"), &duty)?;
         }
+
+        Ok(())
     }
+
+    /// Whether any devices were found which the fan daemon is able to control.
+    pub fn is_supported(&self) -> bool { !self.platforms.is_empty() && !self.cpus.is_empty() }
+
+    /// The speeds of the fans which are controlled, in RPM.
+    pub fn fan_speeds(&self) -> Vec<u32> {
+        self.platforms
+            .iter()
+            .flat_map(|platform| {
+                (1..)
+                    .map(move |fan| {
+                        self.sensors.read_u32(&platform.path().join(format!("fan{}_input", fan)))
+                    })
+                    .take_while(Option::is_some)
+                    .flatten()
+            })
+            .collect()
+    }
+
+    pub fn boost(&self) -> bool { self.boost }
+
+    pub fn curve(&self) -> &FanCurve { &self.curve }
+
+    /// Replaces the curve of the model, which takes effect on the next step.
+    pub fn set_curve(&mut self, curve: FanCurve) { self.curve = curve; }
+
+    /// Limits the duty cycle, in hundredths of a percent, or restores the full curve. This takes
+    /// effect on the next step.
+    pub fn set_quiet(&mut self, max_duty: Option<u16>) {
+        self.quiet_curve = max_duty.map(|max_duty| self.curve.quiet(max_duty));
+    }
+
+    /// Enables or disables running the fans at full speed, which takes effect on the next step.
+    pub fn set_boost(&mut self, boost: bool) { self.boost = boost; }
+
+    /// Lowers the temperatures of the curve by `offset` degrees Celsius while operating as a
+    /// clamshell, or restores the curve with `None`.
+    pub fn set_clamshell(&mut self, offset: Option<u8>) {
+        self.clamshell_curve = offset.map(|offset| self.curve.lowered(i16::from(offset) * 100));
+    }
+
+    /// Holds the fans at a duty cycle in percent, or returns them to the curve. This takes
+    /// effect on the next step, and running the fans at full speed takes precedence. Should the
+    /// temperature rise to where the curve calls for a higher duty cycle, the curve is followed.
+    pub fn set_fixed_duty(&mut self, duty: Option<u8>) { self.fixed_duty = duty; }
+
+    /// Keeps the fans at a duty cycle in percent while on AC and the dGPU draws more than a
+    /// number of watts, whatever the temperature. Zero watts disables this.
+    pub fn set_gpu_floor(&mut self, watts: u32, duty: u8) {
+        self.gpu_floor = if watts == 0 { None } else { Some((watts * 1000, duty)) };
+    }
+
+    /// Reads the power draw of the NVIDIA GPUs at the given PCI addresses through the shared
+    /// instance of NVML.
+    pub fn set_nvidia(&mut self, nvml: Arc<SharedNvml>, ids: Vec<String>) {
+        self.nvml = Some(nvml);
+        self.nvidia = ids;
+    }
+
+    /// The GPU floor only applies while on AC, which takes effect on the next step.
+    pub fn set_on_battery(&mut self, on_battery: bool) { self.on_battery = on_battery; }
+
+    /// Applies the duty cycle for the current temperature, returning the readings it was chosen
+    /// from.
+    pub fn step(&mut self) -> Option<FanReading> {
+        if !self.is_supported() {
+            return None;
+        }
+
+        let span = tracing::debug_span!("fan_step", temp = Empty, duty = Empty).entered();
+
+        let (cpu, gpu) = (self.cpu_temp(), self.gpu_temp());
+        let temp = cmp::max(cpu, gpu);
+        log::debug!("current temp: {:?}", temp);
+        let duty = if self.boost {
+            Some(255)
+        } else if let Some(percent) = self.fixed_duty {
+            let duty = temp.and_then(|temp| self.get_duty(temp));
+            Some(cmp::max(percent_duty(percent), duty.unwrap_or(0)))
+        } else {
+            let duty = temp.and_then(|temp| self.get_duty(temp));
+            match self.gpu_floor() {
+                Some(floor) => Some(cmp::max(duty.unwrap_or(0), floor)),
+                None => duty,
+            }
+        };
+        if let Some(temp) = temp {
+            span.record("temp", &temp);
+        }
+        if let Some(duty) = duty {
+            span.record("duty", &duty);
+        }
+
+        self.set_duty(duty);
+        Some(FanReading { cpu, gpu, duty })
+    }
+}
+
+/// The temperatures, in thousandths of a Celsius, and the duty cycle, from 0 to 255, of a step of
+/// the fan daemon. The duty cycle is absent while the fans are left to the firmware.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FanReading {
+    pub cpu:  Option<u32>,
+    pub gpu:  Option<u32>,
+    pub duty: Option<u8>,
+}
+
+impl FanReading {
+    /// The highest of the temperatures, which the duty cycle follows.
+    pub fn temp(&self) -> Option<u32> { cmp::max(self.cpu, self.gpu) }
 }
 
 impl Drop for FanDaemon {
@@ -224,6 +487,67 @@ impl FanCurve {
         self
     }
 
+    /// The curve given by the configuration, if any. Temperatures are converted from the
+    /// configured unit.
+    pub fn from_config(config: &FanConfig) -> Result<Option<Self>, String> {
+        if config.curve.is_empty() {
+            return Ok(None);
+        }
+
+        let mut curve = Self::default();
+        for point in &config.curve {
+            let millicelsius = config.unit.to_millicelsius(point.temp);
+            let temp = i16::try_from(millicelsius / 10)
+                .map_err(|_| format!("fan curve temperature {} is out of range", point.temp))?;
+
+            if point.duty > 100 {
+                return Err(format!("fan curve duty {}% is above 100%", point.duty));
+            }
+
+            let duty = u16::from(point.duty) * 100;
+            if let Some(last) = curve.points.last() {
+                if temp <= last.temp || duty < last.duty {
+                    return Err(String::from(
+                        "fan curve temperatures must increase, and duties must not decrease",
+                    ));
+                }
+            }
+
+            curve = curve.append(temp, duty);
+        }
+
+        Ok(Some(curve))
+    }
+
+    /// The points of the curve, as temperatures in millidegrees Celsius and duties in hundredths
+    /// of a percent.
+    pub fn points(&self) -> impl Iterator<Item = (i32, u16)> + '_ {
+        self.points.iter().map(|point| (i32::from(point.temp) * 10, point.duty))
+    }
+
+    /// The curve of models which need their own, and its name. Models without one of their own
+    /// use the curve of their chassis, where it is known.
+    pub fn for_model(model: &str) -> Option<(&'static str, Self)> {
+        match model {
+            "thelio-major-r1" => Some(("threadripper2", FanCurve::threadripper2())),
+            "thelio-major-r2" | "thelio-major-r2.1" | "thelio-major-b1" | "thelio-major-b2"
+            | "thelio-major-b3" | "thelio-mega-r1" | "thelio-mega-r1.1" => {
+                Some(("hedt", FanCurve::hedt()))
+            }
+            "thelio-massive-b1" => Some(("xeon", FanCurve::xeon())),
+            _ => FanCurve::for_chassis(model),
+        }
+    }
+
+    /// The curve of the chassis a desktop is built on, from the prefix of its name.
+    fn for_chassis(model: &str) -> Option<(&'static str, Self)> {
+        if DESKTOP_CHASSIS.iter().any(|prefix| model.starts_with(prefix)) {
+            Some(("desktop", FanCurve::desktop()))
+        } else {
+            None
+        }
+    }
+
     /// The standard fan curve
     pub fn standard() -> Self {
         Self::default()
@@ -239,6 +563,18 @@ impl FanCurve {
             .append(88_00, 100_00)
     }
 
+    /// Fan curve for desktops, whose fans never stop so that the case is always ventilated
+    pub fn desktop() -> Self {
+        Self::default()
+            .append(00_00, 25_00)
+            .append(50_00, 30_00)
+            .append(60_00, 35_00)
+            .append(70_00, 45_00)
+            .append(75_00, 55_00)
+            .append(80_00, 70_00)
+            .append(85_00, 100_00)
+    }
+
     /// Fan curve for threadripper 2
     pub fn threadripper2() -> Self {
         Self::default()
@@ -279,6 +615,38 @@ impl FanCurve {
             .append(78_00, 100_00)
     }
 
+    /// A quieter version of this curve, whose duty is limited to `max_duty` until the last
+    /// point. The last point is kept, so that the fans still ramp up before overheating.
+    pub fn quiet(&self, max_duty: u16) -> Self {
+        let last = self.points.len().saturating_sub(1);
+        let points = self
+            .points
+            .iter()
+            .enumerate()
+            .map(|(i, point)| {
+                if i == last {
+                    *point
+                } else {
+                    FanPoint::new(point.temp, point.duty.min(max_duty))
+                }
+            })
+            .collect();
+
+        FanCurve { points }
+    }
+
+    /// This curve with its temperatures lowered by `offset` hundredths of a degree, so that the
+    /// fans reach each duty sooner.
+    pub fn lowered(&self, offset: i16) -> Self {
+        let points = self
+            .points
+            .iter()
+            .map(|point| FanPoint::new(point.temp.saturating_sub(offset), point.duty))
+            .collect();
+
+        FanCurve { points }
+    }
+
     pub fn get_duty(&self, temp: i16) -> Option<u16> {
         // If the temp is less than the first point, return the first point duty
         if let Some(first) = self.points.first() {
@@ -310,6 +678,9 @@ impl FanCurve {
     }
 }
 
+/// A duty cycle in percent, from 0 to 255.
+pub fn percent_duty(percent: u8) -> u8 { (u32::from(percent.min(100)) * 255 / 100) as u8 }
+
 pub fn nvidia_temperatures<F: FnMut(u32)>(func: F) -> io::Result<()> {
     let output = Command::new("nvidia-smi")
         .arg("--query-gpu=temperature.gpu")
@@ -343,6 +714,44 @@ mod tests {
         assert_eq!(fan_point.get_duty_between_points(next_point, 3500), None);
     }
 
+    #[test]
+    fn quiet_points() {
+        let quiet = FanCurve::standard().quiet(50_00);
+
+        assert_eq!(quiet.get_duty(4500), Some(3000));
+        assert_eq!(quiet.get_duty(7500), Some(5000));
+        assert_eq!(quiet.get_duty(8400), Some(5000));
+        assert_eq!(quiet.get_duty(8700), Some(7500));
+        assert_eq!(quiet.get_duty(8800), Some(10000));
+    }
+
+    #[test]
+    fn lowered_points() {
+        let lowered = FanCurve::standard().lowered(10_00);
+
+        assert_eq!(lowered.get_duty(3500), Some(3000));
+        assert_eq!(lowered.get_duty(6500), Some(5000));
+        assert_eq!(lowered.get_duty(7800), Some(10000));
+    }
+
+    #[test]
+    fn configured_points() {
+        let config: FanConfig = toml::from_str(
+            "unit = \"fahrenheit\"\ncurve = [{ temp = 113, duty = 30 }, { temp = 185, duty = 100 \
+             }]",
+        )
+        .unwrap();
+
+        let curve = FanCurve::from_config(&config).unwrap().unwrap();
+        assert_eq!(curve.points().collect::<Vec<_>>(), vec![(45_000, 30_00), (85_000, 100_00)]);
+        assert_eq!(curve.get_duty(65_00), Some(65_00));
+
+        let config: FanConfig =
+            toml::from_str("curve = [{ temp = 80, duty = 50 }, { temp = 70, duty = 60 }]").unwrap();
+        assert!(FanCurve::from_config(&config).is_err());
+        assert_eq!(FanCurve::from_config(&FanConfig::default()), Ok(None));
+    }
+
     #[test]
     fn standard_points() {
         let standard = FanCurve::standard();
@@ -361,6 +770,15 @@ mod tests {
         assert_eq!(standard.get_duty(10000), Some(10000));
     }
 
+    #[test]
+    fn model_curves() {
+        let name = |model| FanCurve::for_model(model).map(|(name, _)| name);
+        assert_eq!(name("thelio-major-r1"), Some("threadripper2"));
+        assert_eq!(name("thelio-r3"), Some("desktop"));
+        assert_eq!(name("lemp10"), None);
+        assert_eq!(name(""), None);
+    }
+
     #[test]
     fn hedt_points() {
         let hedt = FanCurve::hedt();
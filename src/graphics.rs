@@ -2,18 +2,67 @@
 //
 // SPDX-License-Identifier: GPL-3.0-only
 
-use crate::{hotplug, module::Module, pci::PciBus};
+use crate::{
+    backend::{Backends, CommandBackend, FileBackend, PciBackend},
+    config::GraphicsServices,
+    drm, hotplug,
+    initramfs::{self, InitramfsError, InitramfsTool},
+    module::{self, Module},
+    nvml::Nvml,
+    pci, service,
+    state::StateStore,
+    util,
+};
 use serde::{Deserialize, Serialize};
 use std::{
-    fs,
-    io::{self, Write},
+    fs, io,
     iter::FromIterator,
-    process::{self, ExitStatus},
+    path::{Path, PathBuf},
+    sync::Arc,
 };
 use sysfs_class::{PciDevice, SysClass};
 
 const MODPROBE_PATH: &str = "/etc/modprobe.d/system76-power.conf";
 
+/// The power management of the NVIDIA driver, which the daemon configures with each profile.
+/// It sorts before the configuration of the graphics mode, so that its templates take precedence.
+pub const MODPROBE_PM_PATH: &str = "/etc/modprobe.d/system76-power-nvidia-pm.conf";
+
+// Templates of the module configuration, so that custom options survive switching modes. A
+// template named after a graphics mode, such as `hybrid.conf`, replaces the configuration which
+// is generated for that mode. Every other `.conf` file is appended in every mode, in order of
+// name.
+const MODPROBE_TEMPLATES: &str = "/etc/system76-power/modprobe.d";
+
+/// The graphics modes which may be switched to. In compute mode, the NVIDIA driver is loaded
+/// without nvidia-drm and nvidia-modeset, so that the dGPU runs CUDA and OpenCL workloads without
+/// driving displays. Discrete mode is the counterpart of NVIDIA mode for AMD dGPUs. In VFIO
+/// mode, the NVIDIA dGPU is bound to vfio-pci for passthrough to a virtual machine, leaving the
+/// iGPU to the host.
+pub const VENDORS: &[&str] = &["compute", "discrete", "hybrid", "integrated", "nvidia", "vfio"];
+
+// The modules of NVIDIA dGPUs which show which graphics mode was booted.
+const NVIDIA_MODULES: &[&str] = &["nouveau", "nvidia", "nvidia_drm", "nvidia_modeset"];
+
+// The graphics modes of NVIDIA dGPUs.
+const NVIDIA_VENDORS: &[&str] = &["compute", "hybrid", "integrated", "nvidia", "vfio"];
+
+// The graphics modes of NVIDIA dGPUs driven by nouveau, which neither drives a GPU on its own
+// well enough, nor runs compute workloads.
+const NOUVEAU_VENDORS: &[&str] = &["hybrid", "integrated", "vfio"];
+
+// The graphics modes of AMD dGPUs.
+const AMD_VENDORS: &[&str] = &["discrete", "hybrid", "integrated"];
+
+// The graphics modes of Intel dGPUs, whose driver also drives the iGPU.
+const INTEL_VENDORS: &[&str] = &["hybrid", "integrated"];
+
+// The graphics modes in which discrete GPUs drive no displays.
+const HEADLESS_VENDORS: &[&str] = &["compute", "integrated", "vfio"];
+
+// Makes the AMD dGPU the primary GPU of X in discrete mode.
+const XORG_AMD_PATH: &str = "/etc/X11/xorg.conf.d/20-system76-power-amdgpu.conf";
+
 static MODPROBE_NVIDIA: &[u8] = br#"# Automatically generated by system76-power
 options nvidia-drm modeset=1
 "#;
@@ -21,10 +70,17 @@ options nvidia-drm modeset=1
 static MODPROBE_HYBRID: &[u8] = br#"# Automatically generated by system76-power
 blacklist i2c_nvidia_gpu
 alias i2c_nvidia_gpu off
-options nvidia NVreg_DynamicPowerManagement=0x02
 options nvidia-drm modeset=1
 "#;
 
+// nouveau suspends the dGPU through runtime power management while nothing renders on it, and
+// applications offload rendering to it with `DRI_PRIME`.
+static MODPROBE_NOUVEAU_HYBRID: &[u8] = br#"# Automatically generated by system76-power
+blacklist i2c_nvidia_gpu
+alias i2c_nvidia_gpu off
+options nouveau runpm=1
+"#;
+
 static MODPROBE_COMPUTE: &[u8] = br#"# Automatically generated by system76-power
 blacklist i2c_nvidia_gpu
 blacklist nvidia-drm
@@ -32,7 +88,6 @@ blacklist nvidia-modeset
 alias i2c_nvidia_gpu off
 alias nvidia-drm off
 alias nvidia-modeset off
-options nvidia NVreg_DynamicPowerManagement=0x02
 "#;
 
 static MODPROBE_INTEGRATED: &[u8] = br#"# Automatically generated by system76-power
@@ -48,9 +103,55 @@ alias nvidia-drm off
 alias nvidia-modeset off
 "#;
 
-// Systems using S0ix must enable S0ix-based power management.
+// vfio-pci must claim the audio and USB functions of the dGPU before their own drivers do. The
+// IDs of the functions are appended as `options vfio-pci ids=`.
+static MODPROBE_VFIO: &[u8] = br#"# Automatically generated by system76-power
+blacklist i2c_nvidia_gpu
+blacklist nouveau
+blacklist nvidia
+blacklist nvidia-drm
+blacklist nvidia-modeset
+alias i2c_nvidia_gpu off
+alias nouveau off
+alias nvidia off
+alias nvidia-drm off
+alias nvidia-modeset off
+softdep snd_hda_intel pre: vfio-pci
+softdep xhci_pci pre: vfio-pci
+"#;
+
+// The IOMMU groups, which are only listed while the IOMMU is enabled.
+const IOMMU_GROUPS: &str = "/sys/kernel/iommu_groups";
+
+// The suspend modes of the system, with the one in use in brackets.
+const MEM_SLEEP: &str = "/sys/power/mem_sleep";
+
+// The amdgpu driver also drives an AMD iGPU, so it is only blacklisted alongside Intel graphics.
+static MODPROBE_AMD_INTEGRATED: &[u8] = br#"# Automatically generated by system76-power
+blacklist amdgpu
+blacklist radeon
+alias amdgpu off
+alias radeon off
+"#;
+
+static MODPROBE_AMD_HYBRID: &[u8] = br#"# Automatically generated by system76-power
+options amdgpu runpm=1
+"#;
+
+static MODPROBE_AMD_DISCRETE: &[u8] = br#"# Automatically generated by system76-power
+options amdgpu runpm=0
+"#;
+
+// i915 and xe also drive the Intel iGPU, so nothing is blacklisted. In integrated mode, the dGPU
+// is instead removed from the bus by `auto_power`.
+static MODPROBE_INTEL: &[u8] = br#"# Automatically generated by system76-power
+"#;
+
+// Systems using S0ix must enable S0ix-based power management, which the driver only supports
+// while video memory is preserved.
 static SYSTEM_SLEEP_S0IX: &[u8] = br#"# Preserve video memory through suspend
 options nvidia NVreg_EnableS0ixPowerManagement=1
+options nvidia NVreg_PreserveVideoMemoryAllocations=1
 "#;
 
 // Systems using S3 had suspend issues with WebRender.
@@ -58,22 +159,82 @@ static SYSTEM_SLEEP_S3: &[u8] = br#"# Preserve video memory through suspend
 options nvidia NVreg_PreserveVideoMemoryAllocations=1
 "#;
 
+// Save and restore video memory around suspend and hibernation, which the driver relies upon
+// once `NVreg_PreserveVideoMemoryAllocations` is set. Without them, resume leaves a black screen
+// or corrupted windows.
+const NVIDIA_SLEEP_SERVICES: &[&str] = &[
+    "nvidia-suspend.service",
+    "nvidia-resume.service",
+    "nvidia-hibernate.service",
+    "nvidia-suspend-then-hibernate.service",
+];
+
 const PRIME_DISCRETE_PATH: &str = "/etc/prime-discrete";
 
+// Key in the state store for the last graphics mode that was set.
+const GRAPHICS_STATE: &str = "graphics";
+
+// Key in the state store for a graphics mode which is still being switched to.
+const GRAPHICS_PENDING_STATE: &str = "graphics-pending";
+
+// Key in the state store for the graphics devices which have been classified, so that a device
+// which was removed from the bus is still known after the daemon restarts.
+const GRAPHICS_DEVICES_STATE: &str = "graphics-devices";
+
+/// The graphics devices as they are saved, during the boot which they were saved in. Every
+/// device is back on the bus after a reboot, so those saved during another boot are ignored.
+#[derive(Deserialize, Serialize)]
+struct SavedDevices {
+    boot_id: Option<String>,
+    devices: Vec<SavedDevice>,
+}
+
+/// A graphics device as it is saved, with its vendor as named in the topology.
+#[derive(Deserialize, Serialize)]
+struct SavedDevice {
+    id:      String,
+    vendor:  String,
+    parent:  Option<PathBuf>,
+    pci_ids: Vec<String>,
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum GraphicsDeviceError {
-    #[error("failed to execute {} command: {}", cmd, why)]
-    Command { cmd: &'static str, why: io::Error },
     #[error("{} in use by {}", func, driver)]
     DeviceInUse { func: String, driver: String },
+    #[error(
+        "discrete graphics are open by {}, which must close them first, or be stopped with \
+         --force",
+        _0.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+    )]
+    DeviceOpen(Vec<drm::DrmUser>),
+    #[error("discrete graphics are in use by {}, which must stop using them first", _0)]
+    DisplayInUse(drm::DrmUser),
+    #[error(
+        "external displays are connected to discrete graphics through {}, which would go dark; \
+         unplug them first, or power off with --force",
+        _0.join(", ")
+    )]
+    ExternalDisplays(Vec<String>),
+    #[error("the IOMMU is disabled, which VFIO passthrough requires")]
+    IommuDisabled,
+    #[error("failed to rebuild initramfs: {}", _0)]
+    Initramfs(InitramfsError),
     #[error("failed to probe driver features: {}", _0)]
     Json(io::Error),
-    #[error("failed to open system76-power modprobe file: {}", _0)]
-    ModprobeFileOpen(io::Error),
     #[error("failed to write to system76-power modprobe file: {}", _0)]
     ModprobeFileWrite(io::Error),
     #[error("failed to fetch list of active kernel modules: {}", _0)]
     ModulesFetch(io::Error),
+    #[error("{} is not a discrete GPU", _0)]
+    NotDiscrete(String),
+    #[error(
+        "the NVIDIA driver is not built for kernel {}, which would boot without graphics; \
+         reinstall the NVIDIA driver or rebuild it with DKMS before switching{}",
+        kernels.join(", "),
+        dkms.as_ref().map_or(String::new(), |status| format!(" (dkms status: {})", status))
+    )]
+    NvidiaModuleMissing { kernels: Vec<String>, dkms: Option<String> },
     #[error("does not have switchable graphics")]
     NotSwitchable,
     #[error("PCI driver error on {}: {}", device, why)]
@@ -84,30 +245,486 @@ pub enum GraphicsDeviceError {
     PrimeModeWrite(io::Error),
     #[error("failed to remove PCI device {}: {}", device, why)]
     Remove { device: String, why: io::Error },
+    #[error(
+        "failed to switch to {} graphics, so the previous configuration was restored: {}",
+        vendor,
+        why
+    )]
+    RolledBack { vendor: String, why: Box<GraphicsDeviceError> },
+    #[error(
+        "failed to switch to {} graphics: {}; restoring the previous configuration also failed, \
+         so the switch will be retried when the daemon starts: {}",
+        vendor,
+        why,
+        rollback
+    )]
+    RollbackFailed {
+        vendor:   String,
+        why:      Box<GraphicsDeviceError>,
+        rollback: Box<GraphicsDeviceError>,
+    },
     #[error("failed to rescan PCI bus: {}", _0)]
     Rescan(io::Error),
+    #[error("failed to restore {}: {}", _0, _1)]
+    Restore(&'static str, io::Error),
+    #[error("failed to save {} before switching: {}", _0, _1)]
+    Snapshot(&'static str, io::Error),
+    #[error("failed to save graphics mode: {}", _0)]
+    StateWrite(io::Error),
     #[error("failed to read sysfs info: {}", _0)]
     SysFs(io::Error),
+    #[error("no graphics device {}", _0)]
+    UnknownDevice(String),
+    #[error("unknown graphics mode {}, expected one of {}", _0, VENDORS.join(", "))]
+    UnknownVendor(String),
+    #[error("{} graphics are not supported with {} discrete graphics", _0, _1)]
+    UnsupportedVendor(String, &'static str),
     #[error("failed to unbind {} on PCI driver {}: {}", func, driver, why)]
     Unbind { func: String, driver: String, why: io::Error },
-    #[error("update-initramfs failed with {} status", _0)]
-    UpdateInitramfs(ExitStatus),
-    #[error(display = "update-initramfs didn't found tools and failed with {} status", _0)]
-    UpdateInitramfsNoTools(ExitStatus),
+    #[error("failed to write to system76-power Xorg file: {}", _0)]
+    XorgFileWrite(io::Error),
+}
+
+/// A GPU which applications may be launched on.
+#[derive(Debug)]
+pub struct Gpu {
+    pub name:        String,
+    /// Environment variables which select this GPU, as alternating names and values.
+    pub environment: Vec<String>,
+    pub default:     bool,
+}
+
+impl Gpu {
+    fn new(name: &str, environment: &[&str]) -> Gpu {
+        Gpu {
+            name:        name.to_owned(),
+            environment: environment.iter().map(|&var| var.to_owned()).collect(),
+            default:     false,
+        }
+    }
+}
+
+/// Whether a GPU is built into the processor, or a discrete card.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GpuRole {
+    Integrated,
+    Discrete,
+}
+
+impl GpuRole {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            GpuRole::Integrated => "integrated",
+            GpuRole::Discrete => "discrete",
+        }
+    }
+}
+
+/// A graphics device, as listed in the topology of the system.
+#[derive(Debug)]
+pub struct TopologyDevice {
+    /// The PCI address of the device.
+    pub id:      String,
+    pub vendor:  &'static str,
+    pub role:    GpuRole,
+    pub powered: bool,
+    /// The bridge the device is attached to, which is still known once the device is removed.
+    pub parent:  Option<PathBuf>,
+}
+
+/// A graphics device, with what identifies it to users.
+#[derive(Debug)]
+pub struct GpuInfo {
+    /// The PCI address of the device.
+    pub id:       String,
+    pub vendor:   &'static str,
+    /// The vendor and device IDs, such as `10de:1f95`.
+    pub device:   String,
+    /// The video memory in bytes, where the driver reports it.
+    pub vram:     Option<u64>,
+    /// The driver bound to the device, if any.
+    pub driver:   Option<String>,
+    /// Whether the firmware initialized this device as the primary display.
+    pub boot_vga: bool,
+}
+
+/// The discrete GPUs whose graphics mode is switched.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Switchable {
+    /// NVIDIA dGPUs, configured through their driver and PRIME.
+    Nvidia(NvidiaSwitch),
+    /// AMD dGPUs, configured through amdgpu runtime power management.
+    Amd(AmdSwitch),
+    /// Intel Arc dGPUs, left to the runtime power management of their driver.
+    Intel(IntelSwitch),
+}
+
+impl Switchable {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Switchable::Nvidia(_) => "NVIDIA",
+            Switchable::Amd(_) => "AMD",
+            Switchable::Intel(_) => "Intel",
+        }
+    }
+
+    /// The graphics modes which may be switched to.
+    pub fn vendors(&self) -> &'static [&'static str] {
+        match self {
+            Switchable::Nvidia(nvidia) if nvidia.nouveau => NOUVEAU_VENDORS,
+            Switchable::Nvidia(_) => NVIDIA_VENDORS,
+            Switchable::Amd(_) => AMD_VENDORS,
+            Switchable::Intel(_) => INTEL_VENDORS,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct NvidiaSwitch {
+    /// The vendor and device IDs of the functions of the dGPUs, such as `10de:1f95`, which
+    /// vfio-pci binds in VFIO mode.
+    pub vfio_ids: Vec<String>,
+    /// Whether the dGPUs are driven by nouveau. See `Graphics::nouveau`.
+    pub nouveau:  bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AmdSwitch {
+    /// The PCI addresses of the dGPUs.
+    pub devices: Vec<String>,
+    /// Whether the iGPU is Intel, leaving amdgpu free to be blacklisted in integrated mode.
+    pub intel:   bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct IntelSwitch {
+    /// The PCI addresses of the dGPUs.
+    pub devices: Vec<String>,
+}
+
+/// What switching to a graphics mode changes, besides the state of the daemon.
+#[derive(Debug, Default, PartialEq)]
+pub struct SwitchPlan {
+    /// The files which are written, along with their contents.
+    pub files:    Vec<(&'static str, Vec<u8>)>,
+    /// The files which are removed, if they exist.
+    pub removed:  Vec<&'static str>,
+    /// The services which are enabled or disabled, where they are installed.
+    pub services: Vec<(service::Action, String)>,
+    /// The commands which are run once the files are written.
+    pub commands: Vec<String>,
+}
+
+/// How the graphics configuration compares with the mode which was last switched to, the
+/// modules which are loaded, and the initramfs, from `Graphics::verify`.
+#[derive(Debug, Default, PartialEq)]
+pub struct GraphicsVerification {
+    /// The mode which was last switched to.
+    pub saved:      Option<String>,
+    /// The mode of a switch which has not completed, while nothing else is compared.
+    pub pending:    Option<String>,
+    /// The PRIME mode of NVIDIA dGPUs, such as `on-demand`.
+    pub prime:      Option<String>,
+    /// The mode which the PRIME mode and module configuration select.
+    pub configured: Option<&'static str>,
+    /// The NVIDIA modules which are loaded.
+    pub modules:    Vec<&'static str>,
+    /// The mode which the loaded modules match, where it is not the configured one.
+    pub loaded:     Option<&'static str>,
+    pub problems:   Vec<String>,
+}
+
+impl GraphicsVerification {
+    /// Compares the configuration of NVIDIA dGPUs with the saved mode and the loaded modules.
+    /// Missing files are only reported once a mode was switched to.
+    fn check_nvidia(&mut self, modprobe: Option<&[u8]>) {
+        if modprobe.is_none() && self.saved.is_some() {
+            self.problems.push(format!("{} is missing", MODPROBE_PATH));
+        }
+
+        let prime = match self.prime {
+            Some(ref prime) => prime,
+            None => {
+                if self.saved.is_some() {
+                    self.problems.push(format!("{} is missing", PRIME_DISCRETE_PATH));
+                }
+                return;
+            }
+        };
+
+        let modprobe = modprobe.unwrap_or_default();
+        let configured = match Graphics::infer_vendor(prime, modprobe) {
+            Some(configured) => configured,
+            None => {
+                self.problems.push(format!(
+                    "{} holds an unknown PRIME mode `{}`",
+                    PRIME_DISCRETE_PATH, prime
+                ));
+                return;
+            }
+        };
+        self.configured = Some(configured);
+
+        let blacklisted =
+            String::from_utf8_lossy(modprobe).lines().any(|line| line.trim() == "blacklist nvidia");
+        if blacklisted && (configured == "hybrid" || configured == "nvidia") {
+            self.problems.push(format!(
+                "{} selects {} graphics, but {} blacklists nvidia",
+                PRIME_DISCRETE_PATH, configured, MODPROBE_PATH
+            ));
+        }
+
+        if let Some(ref saved) = self.saved {
+            if saved != configured {
+                self.problems.push(format!(
+                    "{} graphics were switched to, but the configuration selects {} graphics",
+                    saved, configured
+                ));
+            }
+        }
+
+        let loaded = |name| self.modules.contains(&name);
+        // nouveau drives the displays itself, as nvidia-drm does.
+        let nouveau = loaded("nouveau");
+        self.loaded = Graphics::loaded_vendor(
+            configured,
+            loaded("nvidia") || nouveau,
+            loaded("nvidia_drm") || nouveau,
+        );
+        if let Some(loaded) = self.loaded {
+            let modules = match self.modules.len() {
+                0 => "no NVIDIA module is loaded".to_owned(),
+                1 => format!("{} is loaded", self.modules[0]),
+                _ => format!("{} are loaded", self.modules.join(", ")),
+            };
+            self.problems.push(format!(
+                "{} graphics are selected, but {}, as in {} graphics",
+                configured, modules, loaded
+            ));
+        }
+    }
+}
+
+/// The configuration from before a graphics switch, which is restored if the switch fails.
+#[derive(Debug)]
+pub struct SwitchTransaction {
+    vendor:    String,
+    /// The saved graphics mode, if any.
+    previous:  Option<String>,
+    /// The contents of each file which the switch writes or removes, or `None` if it did not
+    /// exist.
+    files:     Vec<(&'static str, Option<Vec<u8>>)>,
+    /// The services as the previous graphics mode configured them.
+    services:  Vec<(service::Action, String)>,
+    /// The configured initramfs tool, if any.
+    initramfs: Option<InitramfsTool>,
+    backends:  Backends,
+}
+
+impl SwitchTransaction {
+    fn begin(
+        graphics: &Graphics,
+        vendor: &str,
+        switchable: &Switchable,
+    ) -> Result<Self, GraphicsDeviceError> {
+        let units = &graphics.services;
+        let backends = graphics.backends.clone();
+        let plan = Graphics::vendor_plan(vendor, switchable, units, &*backends.files);
+        let mut files = Vec::new();
+        for &path in plan.files.iter().map(|(path, _)| path).chain(&plan.removed) {
+            let contents = match backends.files.read(Path::new(path)) {
+                Ok(contents) => Some(contents),
+                Err(why) if why.kind() == io::ErrorKind::NotFound => None,
+                Err(why) => return Err(GraphicsDeviceError::Snapshot(path, why)),
+            };
+            files.push((path, contents));
+        }
+
+        let previous = backends
+            .state
+            .load::<String>(GRAPHICS_STATE)
+            .filter(|previous| switchable.vendors().contains(&previous.as_str()));
+        let services = previous
+            .as_ref()
+            .map(|previous| {
+                Graphics::vendor_plan(previous, switchable, units, &*backends.files).services
+            })
+            .unwrap_or_default();
+
+        Ok(SwitchTransaction {
+            vendor: vendor.to_owned(),
+            previous,
+            files,
+            services,
+            initramfs: graphics.initramfs,
+            backends,
+        })
+    }
+
+    /// Restores the configuration from before the switch, rebuilding the initramfs from it if
+    /// the switch got as far as rebuilding it. Returns the error which the switch failed with,
+    /// noting whether the configuration was restored.
+    fn rollback(self, why: GraphicsDeviceError, initramfs: bool) -> GraphicsDeviceError {
+        log::warn!("failed to switch to {} graphics, rolling back: {}", self.vendor, why);
+        match self.restore(initramfs) {
+            Ok(()) => {
+                GraphicsDeviceError::RolledBack { vendor: self.vendor, why: Box::new(why) }
+            }
+            Err(rollback) => GraphicsDeviceError::RollbackFailed {
+                vendor:   self.vendor,
+                why:      Box::new(why),
+                rollback: Box::new(rollback),
+            },
+        }
+    }
+
+    fn restore(&self, initramfs: bool) -> Result<(), GraphicsDeviceError> {
+        let files = &self.backends.files;
+        for (path, contents) in &self.files {
+            log::info!("Restoring {}", path);
+            let res = match contents {
+                Some(contents) => files.write(Path::new(path), contents),
+                None => files.remove(Path::new(path)),
+            };
+            res.map_err(|why| GraphicsDeviceError::Restore(path, why))?;
+        }
+
+        for (action, unit) in &self.services {
+            let commands = &*self.backends.commands;
+            if let Err(why) = service::run_with(commands, *action, unit, true) {
+                log::warn!("{} (not an error if service does not exist!)", why);
+            }
+        }
+
+        let store = &self.backends.state;
+        match self.previous {
+            Some(ref previous) => store.store(GRAPHICS_STATE, previous),
+            None => store.remove(GRAPHICS_STATE),
+        }
+        .map_err(GraphicsDeviceError::StateWrite)?;
+
+        if initramfs {
+            Graphics::update_initramfs(self.initramfs, &*self.backends.commands)?;
+        }
+
+        // The switch is only left pending if the previous configuration could not be restored.
+        store.remove(GRAPHICS_PENDING_STATE).map_err(GraphicsDeviceError::StateWrite)
+    }
+}
+
+/// The runtime power management state of a PCI function of a GPU.
+#[derive(Debug)]
+pub struct FunctionPower {
+    /// The PCI address of the function.
+    pub id:             String,
+    /// The runtime PM status, such as `active` or `suspended`, or `removed` once the function
+    /// has been removed from the bus.
+    pub runtime_status: String,
+    /// The PCI power state, such as `D0` or `D3cold`, where the kernel reports it.
+    pub power_state:    Option<String>,
+    /// The driver bound to the function, if any.
+    pub driver:         Option<String>,
 }
 
 pub struct GraphicsDevice {
     id:        String,
     functions: Vec<PciDevice>,
+    /// The bridge the device is attached to, which is rescanned to bring it back once removed.
+    parent:    Option<PathBuf>,
+    /// Whether the device is attached through an external port, such as a Thunderbolt eGPU.
+    external:  bool,
+    /// The vendor and device IDs of the functions, which are kept once they are removed.
+    pci_ids:   Vec<String>,
 }
 
 impl GraphicsDevice {
     pub fn new(id: String, functions: Vec<PciDevice>) -> GraphicsDevice {
-        GraphicsDevice { id, functions }
+        let parent = functions
+            .first()
+            .and_then(|func| fs::canonicalize(func.path()).ok())
+            .and_then(|path| path.parent().map(Path::to_path_buf))
+            .filter(|parent| parent.join("rescan").exists());
+
+        // The kernel marks devices behind an external facing port as removable.
+        let external = functions.first().map_or(false, |func| {
+            fs::read_to_string(func.path().join("removable"))
+                .map_or(false, |removable| removable.trim() == "removable")
+        });
+
+        let pci_ids = functions
+            .iter()
+            .filter_map(|func| {
+                Some(format!("{:04x}:{:04x}", func.vendor().ok()?, func.device().ok()?))
+            })
+            .collect();
+
+        GraphicsDevice { id, functions, parent, external, pci_ids }
+    }
+
+    pub fn id(&self) -> &str { &self.id }
+
+    pub fn is_external(&self) -> bool { self.external }
+
+    /// Whether an Intel GPU is discrete, such as an Arc GPU, by its device ID. GPUs whose ID is
+    /// not known are discrete unless they are on the root bus, where the iGPU always is.
+    fn is_intel_discrete(&self) -> bool {
+        let device = |ids: &String| {
+            let device = ids.strip_prefix("8086:")?;
+            u16::from_str_radix(device, 16).ok()
+        };
+
+        // DG1, and the Alchemist and Battlemage generations of Arc.
+        let known =
+            self.pci_ids.iter().filter_map(device).any(
+                |device| matches!(device, 0x4905..=0x4909 | 0x5690..=0x56FF | 0xE200..=0xE2FF),
+            );
+        known || self.id.split(':').nth(1).map_or(false, |bus| bus != "00")
     }
 
     pub fn exists(&self) -> bool { self.functions.iter().any(|func| func.path().exists()) }
 
+    /// Whether the device is powered up, rather than removed or suspended by runtime power
+    /// management. Unlike querying its driver, this does not wake it.
+    pub fn is_active(&self) -> bool { pci::is_active(&self.id) }
+
+    /// The runtime power management state of each function of the device.
+    pub fn function_power(&self) -> Vec<FunctionPower> {
+        self.functions
+            .iter()
+            .map(|func| {
+                let path = func.path();
+                let read = |attr: &str| {
+                    fs::read_to_string(path.join(attr)).ok().map(|value| value.trim().to_owned())
+                };
+
+                if !path.exists() {
+                    return FunctionPower {
+                        id:             func.id().to_owned(),
+                        runtime_status: "removed".to_owned(),
+                        power_state:    None,
+                        driver:         None,
+                    };
+                }
+
+                FunctionPower {
+                    id:             func.id().to_owned(),
+                    runtime_status: read("power/runtime_status")
+                        .unwrap_or_else(|| "unsupported".to_owned()),
+                    power_state:    read("power_state"),
+                    driver:         func.driver().ok().map(|driver| driver.id().to_owned()),
+                }
+            })
+            .collect()
+    }
+
+    /// Whether the firmware initialized this device as the primary display.
+    fn is_boot_vga(&self) -> bool {
+        self.functions.first().map_or(false, |func| {
+            fs::read_to_string(func.path().join("boot_vga"))
+                .map_or(false, |boot_vga| boot_vga.trim() == "1")
+        })
+    }
+
     pub unsafe fn unbind(&self) -> Result<(), GraphicsDeviceError> {
         for func in &self.functions {
             if func.path().exists() {
@@ -188,22 +805,128 @@ struct SupportedGpus {
     chips: Vec<NvidiaDevice>,
 }
 
+#[derive(Default)]
 pub struct Graphics {
-    pub bus:    PciBus,
-    pub amd:    Vec<GraphicsDevice>,
-    pub intel:  Vec<GraphicsDevice>,
-    pub nvidia: Vec<GraphicsDevice>,
-    pub other:  Vec<GraphicsDevice>,
+    backends:      Backends,
+    /// The tool which rebuilds the initramfs, or `None` to detect it.
+    pub initramfs: Option<InitramfsTool>,
+    /// The units which each graphics mode enables or disables.
+    pub services:  GraphicsServices,
+    pub amd:       Vec<GraphicsDevice>,
+    pub intel:     Vec<GraphicsDevice>,
+    pub nvidia:    Vec<GraphicsDevice>,
+    pub other:     Vec<GraphicsDevice>,
+    /// Whether the NVIDIA dGPUs are driven by nouveau, as the NVIDIA driver is not built for the
+    /// running kernel.
+    pub nouveau:   bool,
 }
 
-impl Graphics {
-    pub fn new() -> io::Result<Graphics> {
-        let bus = PciBus::new()?;
+/// Builds a `Graphics` which manages devices through backends other than the real system, so
+/// that programs embedding it can test against mocks.
+///
+/// ```no_run
+/// use system76_power::{graphics::GraphicsBuilder, state::StateStore};
+///
+/// let graphics = GraphicsBuilder::new().state(StateStore::new("/tmp/state")).build()?;
+/// println!("{} NVIDIA GPUs", graphics.nvidia.len());
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Default)]
+pub struct GraphicsBuilder {
+    backends:  Backends,
+    initramfs: Option<InitramfsTool>,
+    services:  GraphicsServices,
+}
 
-        log::info!("Rescanning PCI bus");
-        bus.rescan()?;
+impl GraphicsBuilder {
+    pub fn new() -> Self { Self::default() }
 
-        let devs = PciDevice::all()?;
+    /// Enumerates and rescans the PCI bus through a backend.
+    pub fn pci<B: PciBackend + 'static>(mut self, pci: B) -> Self {
+        self.backends.pci = Arc::new(pci);
+        self
+    }
+
+    /// Reads and writes the configuration of graphics modes through a backend.
+    pub fn files<B: FileBackend + 'static>(mut self, files: B) -> Self {
+        self.backends.files = Arc::new(files);
+        self
+    }
+
+    /// Rebuilds the initramfs and toggles services through a backend.
+    pub fn commands<B: CommandBackend + 'static>(mut self, commands: B) -> Self {
+        self.backends.commands = Arc::new(commands);
+        self
+    }
+
+    /// Saves the graphics mode and removed devices in another directory.
+    pub fn state(mut self, state: StateStore) -> Self {
+        self.backends.state = state;
+        self
+    }
+
+    pub fn initramfs(mut self, tool: InitramfsTool) -> Self {
+        self.initramfs = Some(tool);
+        self
+    }
+
+    pub fn services(mut self, services: GraphicsServices) -> Self {
+        self.services = services;
+        self
+    }
+
+    /// Enumerates the graphics devices on the bus, along with those which were removed from it
+    /// before the daemon restarted during this boot. The bus is not rescanned, which would power
+    /// removed devices back on, until they are powered on with `set_power` or
+    /// `set_device_power`.
+    pub fn build(self) -> io::Result<Graphics> {
+        let GraphicsBuilder { backends, initramfs, services } = self;
+
+        // Until the devices have been saved, removed devices can only be found by rescanning.
+        // Those saved during a previous boot are stale, as nothing has been removed since.
+        let boot_id = util::boot_id();
+        let saved = backends.state.load::<SavedDevices>(GRAPHICS_DEVICES_STATE);
+        let saved = match saved {
+            Some(saved) if saved.boot_id.is_some() && saved.boot_id == boot_id => saved.devices,
+            Some(_) => Vec::new(),
+            None => {
+                log::info!("Rescanning PCI bus");
+                tracing::info_span!("pci_rescan").in_scope(|| backends.pci.rescan(None))?;
+                Vec::new()
+            }
+        };
+
+        let mut graphics = Graphics { backends, initramfs, services, ..Graphics::default() };
+
+        graphics.refresh()?;
+        graphics.restore_removed(saved);
+        graphics.save_devices();
+        graphics.nouveau = !graphics.nvidia.is_empty() && !module::available("nvidia");
+        if graphics.nouveau {
+            log::info!("NVIDIA driver not found, configuring NVIDIA graphics for nouveau");
+        }
+        Ok(graphics)
+    }
+}
+
+impl Graphics {
+    /// Enumerates the graphics devices on the bus of the system. See `GraphicsBuilder::build`.
+    pub fn new() -> io::Result<Graphics> { GraphicsBuilder::new().build() }
+
+    /// The backends which the devices are managed through.
+    pub fn backends(&self) -> &Backends { &self.backends }
+
+    /// Enumerates the PCI bus, classifying graphics devices which are not already known, such as
+    /// an eGPU which was plugged in since the last refresh.
+    ///
+    /// Known devices are not forgotten when they have been removed from the bus, as a removed
+    /// device may be brought back by rescanning the bus, unless they were external devices
+    /// which have been unplugged.
+    #[tracing::instrument(skip(self))]
+    pub fn refresh(&mut self) -> io::Result<()> {
+        self.forget_unplugged();
+        let mut changed = false;
+        let devs = self.backends.pci.devices()?;
 
         let functions = |parent: &PciDevice| -> Vec<PciDevice> {
             let mut functions = Vec::new();
@@ -220,39 +943,336 @@ impl Graphics {
             functions
         };
 
-        let mut amd = Vec::new();
-        let mut intel = Vec::new();
-        let mut nvidia = Vec::new();
-        let mut other = Vec::new();
         for dev in &devs {
+            let known = self
+                .amd
+                .iter_mut()
+                .chain(&mut self.intel)
+                .chain(&mut self.nvidia)
+                .chain(&mut self.other)
+                .find(|known| known.id == dev.id());
+
+            // A device which was restored from the saved state is only complete once it is
+            // back on the bus.
+            if let Some(known) = known {
+                if known.functions.is_empty() {
+                    log::info!("{}: Graphics returned to the bus", dev.id());
+                    *known = GraphicsDevice::new(dev.id().to_owned(), functions(dev));
+                }
+                continue;
+            }
+
             let c = dev.class()?;
             if let 0x03 = (c >> 16) & 0xFF {
+                changed = true;
+                let device = GraphicsDevice::new(dev.id().to_owned(), functions(dev));
+                let location = if device.external { "external " } else { "" };
                 match dev.vendor()? {
                     0x1002 => {
-                        log::info!("{}: AMD graphics", dev.id());
-                        amd.push(GraphicsDevice::new(dev.id().to_owned(), functions(dev)));
+                        log::info!("{}: AMD {}graphics", dev.id(), location);
+                        self.amd.push(device);
                     }
                     0x10DE => {
-                        log::info!("{}: NVIDIA graphics", dev.id());
-                        nvidia.push(GraphicsDevice::new(dev.id().to_owned(), functions(dev)));
+                        log::info!("{}: NVIDIA {}graphics", dev.id(), location);
+                        self.nvidia.push(device);
                     }
                     0x8086 => {
-                        log::info!("{}: Intel graphics", dev.id());
-                        intel.push(GraphicsDevice::new(dev.id().to_owned(), functions(dev)));
+                        log::info!("{}: Intel {}graphics", dev.id(), location);
+                        self.intel.push(device);
                     }
                     vendor => {
-                        log::info!("{}: Other({:X}) graphics", dev.id(), vendor);
-                        other.push(GraphicsDevice::new(dev.id().to_owned(), functions(dev)));
+                        log::info!("{}: Other({:X}) {}graphics", dev.id(), vendor, location);
+                        self.other.push(device);
                     }
                 }
             }
         }
 
-        Ok(Graphics { bus, amd, intel, nvidia, other })
+        if changed {
+            self.save_devices();
+        }
+
+        Ok(())
     }
 
-    pub fn can_switch(&self) -> bool {
-        !self.nvidia.is_empty() && (!self.intel.is_empty() || !self.amd.is_empty())
+    /// Adds the devices which were saved by a previous instance of the daemon, but are no longer
+    /// on the bus, as they were removed to power them off.
+    fn restore_removed(&mut self, saved: Vec<SavedDevice>) {
+        for saved in saved {
+            let known = self
+                .amd
+                .iter()
+                .chain(&self.intel)
+                .chain(&self.nvidia)
+                .chain(&self.other)
+                .any(|known| known.id == saved.id);
+            if known {
+                continue;
+            }
+
+            log::info!("{}: {} graphics removed from the bus", saved.id, saved.vendor);
+            let device = GraphicsDevice {
+                id:        saved.id,
+                functions: Vec::new(),
+                parent:    saved.parent.filter(|parent| parent.join("rescan").exists()),
+                external:  false,
+                pci_ids:   saved.pci_ids,
+            };
+            match saved.vendor.as_str() {
+                "amd" => self.amd.push(device),
+                "intel" => self.intel.push(device),
+                "nvidia" => self.nvidia.push(device),
+                _ => self.other.push(device),
+            }
+        }
+    }
+
+    /// Saves the devices which are built in, as external devices are forgotten once unplugged.
+    fn save_devices(&self) {
+        let vendors: [(&str, &[GraphicsDevice]); 4] = [
+            ("intel", &self.intel),
+            ("amd", &self.amd),
+            ("nvidia", &self.nvidia),
+            ("other", &self.other),
+        ];
+        let devices: Vec<SavedDevice> = vendors
+            .iter()
+            .flat_map(|&(vendor, devices)| {
+                devices.iter().filter(|dev| !dev.external).map(move |dev| SavedDevice {
+                    id:      dev.id.clone(),
+                    vendor:  vendor.to_owned(),
+                    parent:  dev.parent.clone(),
+                    pci_ids: dev.pci_ids.clone(),
+                })
+            })
+            .collect();
+
+        let saved = SavedDevices { boot_id: util::boot_id(), devices };
+        if let Err(why) = self.backends.state.store(GRAPHICS_DEVICES_STATE, &saved) {
+            log::warn!("failed to save graphics devices: {}", why);
+        }
+    }
+
+    /// Forgets external devices which are no longer on the bus, as they were unplugged rather
+    /// than removed by `set_power`, and will be classified again if they are plugged back in.
+    fn forget_unplugged(&mut self) {
+        for devices in &mut [&mut self.amd, &mut self.intel, &mut self.nvidia, &mut self.other] {
+            devices.retain(|dev| {
+                let unplugged = dev.external && !dev.exists();
+                if unplugged {
+                    log::info!("{}: External graphics unplugged", dev.id);
+                }
+                !unplugged
+            });
+        }
+    }
+
+    /// The GPU built into the processor, if any, preferring Intel graphics other than Arc
+    /// dGPUs. Without them, an AMD APU is assumed, preferring the AMD GPU which the firmware
+    /// booted from.
+    fn integrated(&self) -> Option<&GraphicsDevice> {
+        self.intel
+            .iter()
+            .find(|dev| !dev.is_intel_discrete())
+            .or_else(|| self.amd.iter().find(|dev| dev.is_boot_vga()).or_else(|| self.amd.first()))
+    }
+
+    /// Lists every graphics device along with its role. Any number of GPUs of any vendor may be
+    /// discrete, such as on systems with both an AMD and an NVIDIA card.
+    pub fn topology(&self) -> Vec<TopologyDevice> {
+        let integrated = self.integrated().map(|dev| dev.id.as_str());
+        let vendors: [(&'static str, &[GraphicsDevice]); 4] = [
+            ("intel", &self.intel),
+            ("amd", &self.amd),
+            ("nvidia", &self.nvidia),
+            ("other", &self.other),
+        ];
+
+        vendors
+            .iter()
+            .flat_map(|&(vendor, devices)| {
+                devices.iter().map(move |dev| TopologyDevice {
+                    id: dev.id.clone(),
+                    vendor,
+                    role: if Some(dev.id.as_str()) == integrated {
+                        GpuRole::Integrated
+                    } else {
+                        GpuRole::Discrete
+                    },
+                    powered: dev.exists(),
+                    parent: dev.parent.clone(),
+                })
+            })
+            .collect()
+    }
+
+    /// Every graphics device, including those which are powered off. The video memory of NVIDIA
+    /// GPUs is only known while their driver is loaded and they are awake, as they are not woken
+    /// up to ask.
+    pub fn inventory(&self) -> Vec<GpuInfo> {
+        let nvml =
+            if self.nvidia.iter().any(GraphicsDevice::is_active) { Nvml::new().ok() } else { None };
+        let vendors: [(&'static str, &[GraphicsDevice]); 4] = [
+            ("intel", &self.intel),
+            ("amd", &self.amd),
+            ("nvidia", &self.nvidia),
+            ("other", &self.other),
+        ];
+
+        vendors
+            .iter()
+            .flat_map(|&(vendor, devices)| devices.iter().map(move |dev| (vendor, dev)))
+            .map(|(vendor, dev)| {
+                // Removed devices are only known by the IDs which were read at creation.
+                let func = dev
+                    .functions
+                    .iter()
+                    .find(|func| func.id() == dev.id)
+                    .filter(|func| func.path().exists());
+                let vram = match vendor {
+                    "amd" => func
+                        .and_then(|func| {
+                            fs::read_to_string(func.path().join("mem_info_vram_total")).ok()
+                        })
+                        .and_then(|total| total.trim().parse().ok()),
+                    "nvidia" => nvml.as_ref().filter(|_| dev.is_active()).and_then(|nvml| {
+                        Some(nvml.device_by_pci_id(&dev.id).ok()?.memory().ok()?.total)
+                    }),
+                    _ => None,
+                };
+
+                GpuInfo {
+                    id: dev.id.clone(),
+                    vendor,
+                    device: dev.pci_ids.first().cloned().unwrap_or_default(),
+                    vram,
+                    driver: func
+                        .and_then(|func| func.driver().ok())
+                        .map(|driver| driver.id().to_owned()),
+                    boot_vga: dev.is_boot_vga(),
+                }
+            })
+            .collect()
+    }
+
+    /// The runtime power management state of every function of every GPU, unlike `get_power`,
+    /// which only reports whether any switched GPU is on.
+    pub fn power_states(&self) -> Vec<FunctionPower> {
+        self.intel
+            .iter()
+            .chain(&self.amd)
+            .chain(&self.nvidia)
+            .chain(&self.other)
+            .flat_map(GraphicsDevice::function_power)
+            .collect()
+    }
+
+    /// Powers a single discrete GPU on or off, leaving the others as they are.
+    ///
+    /// A device is powered off by removing it from the bus, and powered on by rescanning the
+    /// bridge it was attached to. A device attached directly to the root complex can only be
+    /// brought back by rescanning the whole bus, which also brings back any other removed GPU.
+    ///
+    /// External displays keep the device from being powered off unless it is forced. Processes
+    /// which have it open always do, and are found by `blocking_users` to be stopped first.
+    #[tracing::instrument(skip(self))]
+    pub fn set_device_power(
+        &self,
+        id: &str,
+        power: bool,
+        force: bool,
+    ) -> Result<(), GraphicsDeviceError> {
+        let device = self.discrete_device(id)?;
+        if power {
+            log::info!("{}: Enabling power", id);
+            self.backends.pci.rescan(device.parent.as_deref()).map_err(GraphicsDeviceError::Rescan)
+        } else {
+            log::info!("{}: Disabling power", id);
+            self.check_power_off(&[device], force)?;
+            self.release(&[device])?;
+
+            unsafe {
+                device.unbind()?;
+                device.remove()
+            }
+        }
+    }
+
+    /// A discrete GPU, by its PCI address.
+    fn discrete_device(&self, id: &str) -> Result<&GraphicsDevice, GraphicsDeviceError> {
+        let device = self
+            .amd
+            .iter()
+            .chain(&self.intel)
+            .chain(&self.nvidia)
+            .chain(&self.other)
+            .find(|dev| dev.id == id)
+            .ok_or_else(|| GraphicsDeviceError::UnknownDevice(id.to_owned()))?;
+
+        if self.integrated().map_or(false, |integrated| integrated.id == id) {
+            return Err(GraphicsDeviceError::NotDiscrete(id.to_owned()));
+        }
+
+        Ok(device)
+    }
+
+    /// The AMD GPUs which are not integrated.
+    fn amd_discrete(&self) -> impl Iterator<Item = &GraphicsDevice> + '_ {
+        let integrated = self.integrated().map(|dev| dev.id.as_str());
+        self.amd.iter().filter(move |dev| Some(dev.id.as_str()) != integrated)
+    }
+
+    /// The Intel GPUs which are not integrated.
+    fn intel_discrete(&self) -> impl Iterator<Item = &GraphicsDevice> + '_ {
+        let integrated = self.integrated().map(|dev| dev.id.as_str());
+        self.intel.iter().filter(move |dev| Some(dev.id.as_str()) != integrated)
+    }
+
+    /// The discrete GPUs whose graphics mode is switched, if any. NVIDIA dGPUs take precedence,
+    /// followed by AMD dGPUs, leaving any other dGPU alongside them to its driver.
+    pub fn switchable(&self) -> Option<Switchable> {
+        if !self.nvidia.is_empty() && (!self.intel.is_empty() || !self.amd.is_empty()) {
+            let mut vfio_ids: Vec<String> =
+                self.nvidia.iter().flat_map(|dev| dev.pci_ids.iter().cloned()).collect();
+            vfio_ids.sort();
+            vfio_ids.dedup();
+            return Some(Switchable::Nvidia(NvidiaSwitch { vfio_ids, nouveau: self.nouveau }));
+        }
+
+        let devices: Vec<String> = self.amd_discrete().map(|dev| dev.id.clone()).collect();
+        if !devices.is_empty() {
+            let intel = self.intel.iter().any(|dev| !dev.is_intel_discrete());
+            return Some(Switchable::Amd(AmdSwitch { devices, intel }));
+        }
+
+        // Without an iGPU, an Intel dGPU is the only GPU.
+        let devices: Vec<String> = self.intel_discrete().map(|dev| dev.id.clone()).collect();
+        if devices.is_empty() || self.integrated().is_none() {
+            None
+        } else {
+            Some(Switchable::Intel(IntelSwitch { devices }))
+        }
+    }
+
+    pub fn can_switch(&self) -> bool { self.switchable().is_some() }
+
+    /// The discrete GPUs which are powered on and off with the graphics mode.
+    fn switched_devices(&self) -> Vec<&GraphicsDevice> {
+        match self.switchable() {
+            Some(Switchable::Amd(_)) => self.amd_discrete().collect(),
+            Some(Switchable::Intel(_)) => self.intel_discrete().collect(),
+            _ => self.nvidia.iter().collect(),
+        }
+    }
+
+    /// The external displays connected to the switched discrete GPUs, which go dark in a
+    /// graphics mode where they drive no displays. Empty for every other mode.
+    pub fn displays_lost_by(&self, vendor: &str) -> Vec<String> {
+        if !HEADLESS_VENDORS.contains(&vendor) {
+            return Vec::new();
+        }
+
+        self.switched_devices().iter().flat_map(|dev| drm::external_displays(&dev.id)).collect()
     }
 
     pub fn get_external_displays_require_dgpu(&self) -> Result<bool, GraphicsDeviceError> {
@@ -270,8 +1290,8 @@ impl Graphics {
             .map(|s| s.trim().to_string())
     }
 
-    fn get_nvidia_device_id(&self) -> Result<u32, GraphicsDeviceError> {
-        let device = format!("/sys/bus/pci/devices/{}/device", self.nvidia[0].id);
+    fn get_nvidia_device_id(device: &GraphicsDevice) -> Result<u32, GraphicsDeviceError> {
+        let device = format!("/sys/bus/pci/devices/{}/device", device.id);
         let id = fs::read_to_string(device).map_err(GraphicsDeviceError::SysFs)?;
         let id = id.trim_start_matches("0x").trim();
         u32::from_str_radix(id, 16).map_err(|e| {
@@ -305,11 +1325,19 @@ impl Graphics {
         )))
     }
 
+    /// Whether every NVIDIA GPU supports runtime power management, as hybrid graphics
+    /// requires.
     fn gpu_supports_runtimepm(&self) -> Result<bool, GraphicsDeviceError> {
-        let id = self.get_nvidia_device_id()?;
-        let dev = self.get_nvidia_device(id)?;
-        log::info!("Device 0x{:04} features: {:?}", id, dev.features);
-        Ok(dev.features.contains(&"runtimepm".to_string()))
+        for device in &self.nvidia {
+            let id = Self::get_nvidia_device_id(device)?;
+            let dev = self.get_nvidia_device(id)?;
+            log::info!("Device 0x{:04} features: {:?}", id, dev.features);
+            if !dev.features.contains(&"runtimepm".to_string()) {
+                return Ok(false);
+            }
+        }
+
+        Ok(!self.nvidia.is_empty())
     }
 
     pub fn get_default_graphics(&self) -> Result<String, GraphicsDeviceError> {
@@ -318,6 +1346,16 @@ impl Graphics {
 
         self.switchable_or_fail()?;
 
+        // amdgpu powers an idle dGPU down on any model, as do i915 and xe.
+        if let Some(Switchable::Amd(_)) | Some(Switchable::Intel(_)) = self.switchable() {
+            return Ok("hybrid".to_string());
+        }
+
+        // As does nouveau, which supports no mode that renders on the dGPU alone.
+        if self.nouveau {
+            return Ok("hybrid".to_string());
+        }
+
         let product = fs::read_to_string("/sys/class/dmi/id/product_version")
             .map_err(GraphicsDeviceError::SysFs)
             .map(|s| s.trim().to_string())?;
@@ -341,178 +1379,563 @@ impl Graphics {
         }
     }
 
-    fn get_prime_discrete() -> Result<String, GraphicsDeviceError> {
-        fs::read_to_string(PRIME_DISCRETE_PATH)
+    fn get_prime_discrete(&self) -> Result<String, GraphicsDeviceError> {
+        self.backends
+            .files
+            .read(Path::new(PRIME_DISCRETE_PATH))
             .map_err(GraphicsDeviceError::PrimeModeRead)
-            .map(|mode| mode.trim().to_owned())
-    }
-
-    fn set_prime_discrete(mode: &str) -> Result<(), GraphicsDeviceError> {
-        fs::write(PRIME_DISCRETE_PATH, mode).map_err(GraphicsDeviceError::PrimeModeWrite)
+            .map(|mode| String::from_utf8_lossy(&mode).trim().to_owned())
     }
 
+    /// The graphics mode, which concerns the NVIDIA dGPUs, or else the AMD dGPUs, or else the
+    /// Intel dGPUs. Other discrete GPUs are left to the runtime power management of their
+    /// driver, and may be powered on or off with `set_device_power`.
     pub fn get_vendor(&self) -> Result<String, GraphicsDeviceError> {
+        // amdgpu, i915, and xe are loaded in every mode where they also drive the iGPU, so the
+        // saved mode is relied upon.
+        let switchable = self.switchable().filter(|s| !matches!(s, Switchable::Nvidia(_)));
+        if let Some(switchable) = switchable {
+            let vendor = self
+                .backends
+                .state
+                .load::<String>(GRAPHICS_STATE)
+                .filter(|vendor| switchable.vendors().contains(&vendor.as_str()))
+                .unwrap_or_else(|| "hybrid".to_string());
+            return Ok(vendor);
+        }
+
         let modules = Module::all().map_err(GraphicsDeviceError::ModulesFetch)?;
-        let vendor =
-            if modules.iter().any(|module| module.name == "nouveau" || module.name == "nvidia") {
-                let mode = match Self::get_prime_discrete() {
-                    Ok(m) => m,
-                    Err(_) => "nvidia".to_string(),
-                };
+        let loaded = |name: &str| modules.iter().any(|module| module.name == name);
+        let vendor = if loaded("nouveau") && !loaded("nvidia") {
+            // Every other mode blacklists nouveau.
+            "hybrid".to_string()
+        } else if loaded("nvidia") {
+            // Prefer the saved mode, falling back to the PRIME configuration for systems
+            // which were switched by an older version.
+            let mode = match self.backends.state.load::<String>(GRAPHICS_STATE) {
+                Some(vendor) if vendor == "hybrid" => "on-demand".to_string(),
+                Some(vendor) if vendor == "nvidia" => "on".to_string(),
+                Some(_) => "off".to_string(),
+                None => self.get_prime_discrete().unwrap_or_else(|_| "nvidia".to_string()),
+            };
 
-                if mode == "on-demand" {
-                    "hybrid".to_string()
-                } else if mode == "off" {
-                    "compute".to_string()
-                } else {
-                    "nvidia".to_string()
-                }
+            if mode == "on-demand" {
+                "hybrid".to_string()
+            } else if mode == "off" {
+                "compute".to_string()
             } else {
-                "integrated".to_string()
-            };
+                "nvidia".to_string()
+            }
+        } else if self.backends.state.load::<String>(GRAPHICS_STATE).as_deref() == Some("vfio") {
+            // The dGPU is bound to vfio-pci rather than the NVIDIA driver.
+            "vfio".to_string()
+        } else {
+            "integrated".to_string()
+        };
 
         Ok(vendor)
     }
 
+    /// Switches the graphics mode of the discrete GPUs. See `get_vendor`.
+    #[tracing::instrument(skip(self))]
     pub fn set_vendor(&self, vendor: &str) -> Result<(), GraphicsDeviceError> {
-        self.switchable_or_fail()?;
+        let transaction = self.begin_switch(vendor)?;
+        Self::finish_switch(transaction)
+    }
+
+    /// The discrete GPUs which would be switched to a graphics mode, if they support it.
+    fn switchable_to(&self, vendor: &str) -> Result<Switchable, GraphicsDeviceError> {
+        if !VENDORS.contains(&vendor) {
+            return Err(GraphicsDeviceError::UnknownVendor(vendor.to_owned()));
+        }
+
+        let switchable = self.switchable().ok_or(GraphicsDeviceError::NotSwitchable)?;
+        if !switchable.vendors().contains(&vendor) {
+            return Err(GraphicsDeviceError::UnsupportedVendor(
+                vendor.to_owned(),
+                switchable.name(),
+            ));
+        }
+
+        let files = &*self.backends.files;
+        let iommu = files.list(Path::new(IOMMU_GROUPS)).map_or(false, |groups| !groups.is_empty());
+        if vendor == "vfio" && !iommu {
+            return Err(GraphicsDeviceError::IommuDisabled);
+        }
+
+        // Booting a kernel without the driver in a mode which requires it leaves a black screen.
+        if let Switchable::Nvidia(ref nvidia) = switchable {
+            if !nvidia.nouveau && ["compute", "hybrid", "nvidia"].contains(&vendor) {
+                let kernels = module::kernels_missing("nvidia", files);
+                if !kernels.is_empty() {
+                    let dkms = module::dkms_status("nvidia");
+                    return Err(GraphicsDeviceError::NvidiaModuleMissing { kernels, dkms });
+                }
+            }
+        }
 
+        Ok(switchable)
+    }
+
+    /// Writes the configuration of a graphics mode, which only takes effect once the initramfs
+    /// has been rebuilt by `finish_switch`.
+    ///
+    /// The files are saved beforehand, and restored if writing them fails. The switch is marked
+    /// as pending until it is finished, so that it can be completed before shutdown if it is
+    /// interrupted.
+    #[tracing::instrument(skip(self))]
+    pub fn begin_switch(&self, vendor: &str) -> Result<SwitchTransaction, GraphicsDeviceError> {
+        let switchable = self.switchable_to(vendor)?;
+        let transaction = SwitchTransaction::begin(self, vendor, &switchable)?;
+
+        self.backends
+            .state
+            .store(GRAPHICS_PENDING_STATE, &vendor)
+            .map_err(GraphicsDeviceError::StateWrite)?;
+
+        match Self::write_vendor_config(vendor, &switchable, &self.services, &self.backends) {
+            Ok(()) => Ok(transaction),
+            Err(why) => Err(transaction.rollback(why, false)),
+        }
+    }
+
+    /// Rebuilds the initramfs for a switch started by `begin_switch`, which may take a minute.
+    /// If it fails, the previous configuration is restored and the initramfs is rebuilt again.
+    pub fn finish_switch(transaction: SwitchTransaction) -> Result<(), GraphicsDeviceError> {
+        let commands = transaction.backends.commands.clone();
+        if let Err(why) = Self::update_initramfs(transaction.initramfs, &*commands) {
+            return Err(transaction.rollback(why, true));
+        }
+
+        transaction
+            .backends
+            .state
+            .remove(GRAPHICS_PENDING_STATE)
+            .map_err(GraphicsDeviceError::StateWrite)
+    }
+
+    /// The graphics mode of a switch which has not completed, if any.
+    pub fn pending_vendor(&self) -> Option<String> {
+        self.backends.state.load(GRAPHICS_PENDING_STATE)
+    }
+
+    /// Rewrites the configuration for a pending switch and rebuilds the initramfs, returning the
+    /// graphics mode which was switched to.
+    #[tracing::instrument]
+    pub fn complete_pending_switch(
+        switchable: &Switchable,
+        initramfs: Option<InitramfsTool>,
+        services: &GraphicsServices,
+        backends: &Backends,
+    ) -> Result<Option<String>, GraphicsDeviceError> {
+        let vendor = match backends.state.load::<String>(GRAPHICS_PENDING_STATE) {
+            Some(vendor) => vendor,
+            None => return Ok(None),
+        };
+
+        log::info!("Completing switch to {} graphics", vendor);
+        Self::write_vendor_config(&vendor, switchable, services, backends)?;
+        Self::update_initramfs(initramfs, &*backends.commands)?;
+
+        backends.state.remove(GRAPHICS_PENDING_STATE).map_err(GraphicsDeviceError::StateWrite)?;
+        Ok(Some(vendor))
+    }
+
+    /// The module configuration which is written for a graphics mode of AMD dGPUs.
+    fn amd_modprobe(vendor: &str, intel: bool) -> &'static [u8] {
+        match vendor {
+            "discrete" => MODPROBE_AMD_DISCRETE,
+            // With an AMD iGPU, the dGPU is instead removed from the bus by `auto_power`.
+            "integrated" if intel => MODPROBE_AMD_INTEGRATED,
+            _ => MODPROBE_AMD_HYBRID,
+        }
+    }
+
+    /// The Xorg configuration which makes the first AMD dGPU the primary GPU in discrete mode.
+    fn amd_xorg(vendor: &str, amd: &AmdSwitch) -> Option<String> {
+        if vendor != "discrete" {
+            return None;
+        }
+
+        let bus_id = amd.devices.first().and_then(|id| xorg_bus_id(id))?;
+        Some(format!(
+            "# Automatically generated by system76-power\nSection \"Device\"\n    Identifier \
+             \"system76-power-amdgpu\"\n    Driver \"amdgpu\"\n    BusID \"{}\"\nEndSection\n",
+            bus_id
+        ))
+    }
+
+    /// The PRIME mode and module configuration which are written for a graphics mode, on a
+    /// system which suspends to S0ix or S3.
+    fn vendor_files(
+        vendor: &str,
+        templates: &[(String, Vec<u8>)],
+        s0ix: bool,
+    ) -> (&'static str, Vec<u8>) {
         let mode = if vendor == "hybrid" {
             "on-demand\n"
         } else if vendor == "nvidia" {
             "on\n"
         } else {
-            // Integrated or Compute
+            // Integrated, Compute or VFIO
             "off\n"
         };
 
-        log::info!("Setting {} to {}", PRIME_DISCRETE_PATH, mode);
-        Self::set_prime_discrete(mode)?;
+        let text = if vendor == "hybrid" {
+            MODPROBE_HYBRID
+        } else if vendor == "compute" {
+            MODPROBE_COMPUTE
+        } else if vendor == "nvidia" {
+            MODPROBE_NVIDIA
+        } else if vendor == "vfio" {
+            MODPROBE_VFIO
+        } else {
+            MODPROBE_INTEGRATED
+        };
+        let mut text = template_or(vendor, text, templates);
 
-        {
-            log::info!("Creating {}", MODPROBE_PATH);
-
-            let mut file = fs::OpenOptions::new()
-                .create(true)
-                .truncate(true)
-                .write(true)
-                .open(MODPROBE_PATH)
-                .map_err(GraphicsDeviceError::ModprobeFileOpen)?;
-
-            let text = if vendor == "hybrid" {
-                MODPROBE_HYBRID
-            } else if vendor == "compute" {
-                MODPROBE_COMPUTE
-            } else if vendor == "nvidia" {
-                MODPROBE_NVIDIA
-            } else {
-                MODPROBE_INTEGRATED
-            };
+        // Power management must be configured depending on if the system
+        // uses S0ix or S3 for suspend.
+        if vendor != "integrated" && vendor != "vfio" {
+            let sleep = if s0ix { SYSTEM_SLEEP_S0IX } else { SYSTEM_SLEEP_S3 };
+
+            // We should also check if the GPU supports Video Memory Self
+            // Refresh, but that requires already being in hybrid or nvidia
+            // graphics mode. In compute mode, it just reports '?'.
 
-            file.write_all(text)
-                .and_then(|_| file.sync_all())
-                .map_err(GraphicsDeviceError::ModprobeFileWrite)?;
+            text.extend_from_slice(sleep);
+        }
 
-            // Power management must be configured depending on if the system
-            // uses S0ix or S3 for suspend.
-            if vendor != "integrated" {
-                // XXX: Better way to check?
-                let s0ix = fs::read_to_string("/sys/power/mem_sleep")
-                    .unwrap_or_default()
-                    .contains("[s2idle]");
+        (mode, text)
+    }
 
-                let sleep = if s0ix { SYSTEM_SLEEP_S0IX } else { SYSTEM_SLEEP_S3 };
+    /// The PRIME mode and module configuration which are written for a graphics mode, where
+    /// nouveau drives the dGPUs. The NVIDIA options for suspend do not apply to it.
+    fn nouveau_files(vendor: &str, templates: &[(String, Vec<u8>)]) -> (&'static str, Vec<u8>) {
+        let (mode, text) = match vendor {
+            "hybrid" => ("on-demand\n", MODPROBE_NOUVEAU_HYBRID),
+            "vfio" => ("off\n", MODPROBE_VFIO),
+            _ => ("off\n", MODPROBE_INTEGRATED),
+        };
 
-                // We should also check if the GPU supports Video Memory Self
-                // Refresh, but that requires already being in hybrid or nvidia
-                // graphics mode. In compute mode, it just reports '?'.
+        (mode, template_or(vendor, text, templates))
+    }
 
-                file.write_all(sleep)
-                    .and_then(|_| file.sync_all())
-                    .map_err(GraphicsDeviceError::ModprobeFileWrite)?;
+    /// The graphics mode which a PRIME mode and module configuration select, whichever tool
+    /// wrote them.
+    fn infer_vendor(prime: &str, modprobe: &[u8]) -> Option<&'static str> {
+        match prime.trim() {
+            "on-demand" => Some("hybrid"),
+            "on" => Some("nvidia"),
+            "off" => {
+                let modprobe = String::from_utf8_lossy(modprobe);
+                if modprobe.lines().any(|line| line.contains("vfio-pci")) {
+                    Some("vfio")
+                } else if modprobe.lines().any(|line| line.trim() == "blacklist nvidia") {
+                    Some("integrated")
+                } else {
+                    Some("compute")
+                }
             }
+            _ => None,
         }
+    }
 
-        const SYSTEMCTL_CMD: &str = "systemctl";
+    /// Checks whether another tool has rewritten the configuration files to select a different
+    /// graphics mode than the saved one. If so, returns the saved mode along with the mode which
+    /// the files now select, or `None` if they no longer select any.
+    ///
+    /// Nothing is reported while a switch is pending, or if the daemon never switched modes.
+    /// Only NVIDIA dGPUs are configured through files which other tools write.
+    pub fn external_change(&self) -> Option<(String, Option<String>)> {
+        let switchable = match self.switchable() {
+            Some(switchable @ Switchable::Nvidia(_)) if self.pending_vendor().is_none() => {
+                switchable
+            }
+            _ => return None,
+        };
+
+        let saved = self.backends.state.load::<String>(GRAPHICS_STATE)?;
+
+        // The files which were written for the saved mode select it, even where a template
+        // replaced the configuration which it is otherwise told apart by.
+        let files = &*self.backends.files;
+        let plan = Self::vendor_plan(&saved, &switchable, &self.services, files);
+        let written = plan.files.iter().all(|(path, contents)| {
+            files.read(Path::new(path)).map_or(false, |current| &current == contents)
+        });
+        if written {
+            return None;
+        }
+
+        let modprobe = files.read(Path::new(MODPROBE_PATH)).unwrap_or_default();
+        let configured = self
+            .get_prime_discrete()
+            .ok()
+            .and_then(|prime| Self::infer_vendor(&prime, &modprobe))
+            .map(str::to_owned);
 
-        let action = if vendor == "nvidia" {
-            log::info!("Enabling nvidia-fallback.service");
-            "enable"
+        if configured.as_deref() == Some(saved.as_str()) {
+            None
         } else {
-            log::info!("Disabling nvidia-fallback.service");
-            "disable"
+            Some((saved, configured))
+        }
+    }
+
+    /// Compares the mode which was last switched to with the configuration files which select
+    /// it, the NVIDIA modules which are loaded, and the initramfs which loads them at boot.
+    /// Where the configured mode disagrees with the loaded modules, such as after the NVIDIA
+    /// driver was uninstalled in hybrid mode, the mode which the modules match is returned too.
+    /// Nothing is compared while a switch is pending.
+    pub fn verify(&self) -> GraphicsVerification {
+        let mut verification = GraphicsVerification {
+            saved: self.backends.state.load(GRAPHICS_STATE),
+            pending: self.pending_vendor(),
+            ..GraphicsVerification::default()
         };
 
-        let status = process::Command::new(SYSTEMCTL_CMD)
-            .arg(action)
-            .arg("nvidia-fallback.service")
-            .status()
-            .map_err(|why| GraphicsDeviceError::Command { cmd: SYSTEMCTL_CMD, why })?;
-
-        if !status.success() {
-            // Error is ignored in case this service is removed
-            log::warn!(
-                "systemctl: failed with {} (not an error if service does not exist!)",
-                status
-            );
+        let switchable = match self.switchable() {
+            Some(switchable) if verification.pending.is_none() => switchable,
+            _ => return verification,
+        };
+
+        let modprobe = self.backends.files.read(Path::new(MODPROBE_PATH)).ok();
+        let supported = |vendor: &String| switchable.vendors().contains(&vendor.as_str());
+        if let Switchable::Nvidia(_) = switchable {
+            let modules = self
+                .backends
+                .files
+                .read(Path::new(module::PROC_MODULES))
+                .ok()
+                .and_then(|modules| Module::parse_all(&String::from_utf8_lossy(&modules)).ok())
+                .unwrap_or_default();
+            verification.modules = NVIDIA_MODULES
+                .iter()
+                .copied()
+                .filter(|&name| modules.iter().any(|module| module.name == name))
+                .collect();
+            verification.prime = self.get_prime_discrete().ok();
+            verification.check_nvidia(modprobe.as_deref());
+        } else if let Some(saved) = verification.saved.clone().filter(supported) {
+            // Other tools do not configure AMD and Intel dGPUs, so the file is what was written.
+            let plan =
+                Self::vendor_plan(&saved, &switchable, &self.services, &*self.backends.files);
+            let expected = plan.files.iter().find(|(path, _)| *path == MODPROBE_PATH);
+            if modprobe.is_none() {
+                verification.problems.push(format!("{} is missing", MODPROBE_PATH));
+            } else if expected.map(|(_, text)| text) != modprobe.as_ref() {
+                verification.problems.push(format!(
+                    "{} is not the configuration of {} graphics",
+                    MODPROBE_PATH, saved
+                ));
+            }
+        }
+
+        if let Some(image) = initramfs::image(&*self.backends.files) {
+            for &path in &[MODPROBE_PATH, MODPROBE_PM_PATH] {
+                if changed_after(&*self.backends.files, Path::new(path), &image) {
+                    verification.problems.push(format!(
+                        "{} was changed after {} was built, so it is not used at boot until the \
+                         initramfs is rebuilt",
+                        path,
+                        image.display()
+                    ));
+                }
+            }
         }
 
-        log::info!("Updating initramfs");
-        
+        verification
+    }
+
+    /// The graphics mode which the loaded NVIDIA modules match, if it is not the configured one.
+    fn loaded_vendor(configured: &str, nvidia: bool, nvidia_drm: bool) -> Option<&'static str> {
+        let loaded = match (nvidia, nvidia_drm) {
+            // vfio-pci binds the dGPU in place of the NVIDIA driver.
+            (false, _) if configured == "vfio" => "vfio",
+            (false, _) => "integrated",
+            (true, false) => "compute",
+            // Both NVIDIA and hybrid modes load nvidia-drm.
+            (true, true) if configured == "nvidia" => "nvidia",
+            (true, true) => "hybrid",
+        };
 
-        const COMMAND_CMD: &str = "command";
-        const UPDATE_DRACUT_CMD: &str = "dracut";
-        const UPDATE_INITRAMFS_CMD: &str = "update-initramfs";
+        if loaded == configured {
+            None
+        } else {
+            Some(loaded)
+        }
+    }
 
-        if process::Command::new(COMMAND_CMD).arg("-v").arg(UPDATE_DRACUT_CMD).stdout(process::Stdio::null()).status().map_err(|why| GraphicsDeviceError::Command { cmd: UPDATE_DRACUT_CMD, why })?.success() {
+    /// Records a graphics mode which another tool configured as the current mode, leaving its
+    /// files as they are.
+    pub fn adopt_vendor(&self, vendor: &str) -> Result<(), GraphicsDeviceError> {
+        self.backends.state.store(GRAPHICS_STATE, &vendor).map_err(GraphicsDeviceError::StateWrite)
+    }
 
-            let status = process::Command::new(UPDATE_DRACUT_CMD)
-                .arg("--force")
-                .status()
-                .map_err(|why| GraphicsDeviceError::Command { cmd: UPDATE_DRACUT_CMD, why })?;
-            if ! status.success() {
-                return Err(GraphicsDeviceError::UpdateInitramfs(status));
-            }
+    /// Describes every file which switching to a graphics mode writes or removes, every service
+    /// it enables or disables, and every command it runs, without changing anything.
+    pub fn preview_vendor(&self, vendor: &str) -> Result<SwitchPlan, GraphicsDeviceError> {
+        let switchable = self.switchable_to(vendor)?;
+        let mut plan =
+            Self::vendor_plan(vendor, &switchable, &self.services, &*self.backends.files);
 
-            
+        let (cmd, args) = initramfs::backend(self.initramfs).command();
+        plan.commands.push(if args.is_empty() {
+            cmd.to_owned()
         } else {
+            format!("{} {}", cmd, args.join(" "))
+        });
+        Ok(plan)
+    }
+
+    /// The configuration of a graphics mode, apart from the initramfs rebuild.
+    fn vendor_plan(
+        vendor: &str,
+        switchable: &Switchable,
+        services: &GraphicsServices,
+        files: &dyn FileBackend,
+    ) -> SwitchPlan {
+        let templates = modprobe_templates(files);
+        // XXX: Better way to check?
+        let s0ix = files
+            .read(Path::new(MEM_SLEEP))
+            .map_or(false, |modes| String::from_utf8_lossy(&modes).contains("[s2idle]"));
+        let mut plan = SwitchPlan::default();
+        match switchable {
+            Switchable::Nvidia(nvidia) => {
+                let (mode, mut text) = if nvidia.nouveau {
+                    Self::nouveau_files(vendor, &templates)
+                } else {
+                    Self::vendor_files(vendor, &templates, s0ix)
+                };
+                if vendor == "vfio" {
+                    text.extend_from_slice(
+                        format!("options vfio-pci ids={}\n", nvidia.vfio_ids.join(",")).as_bytes(),
+                    );
+                }
+                extend_templates(&mut text, &templates);
+                plan.files.push((PRIME_DISCRETE_PATH, mode.as_bytes().to_vec()));
+                plan.files.push((MODPROBE_PATH, text));
+
+                // The services of the NVIDIA driver are left alone while it is not installed.
+                if nvidia.nouveau {
+                    return plan;
+                }
 
-            let status = process::Command::new(UPDATE_INITRAMFS_CMD)
-                .arg("-u")
-                .status()
-                .map_err(|why| GraphicsDeviceError::Command { cmd: UPDATE_INITRAMFS_CMD, why })?;
-        
-            if ! status.success() {
-                return Err(GraphicsDeviceError::UpdateInitramfs(status));
+                if let Some(units) = services.mode(vendor) {
+                    for unit in &units.enable {
+                        plan.services.push((service::Action::Enable, unit.clone()));
+                    }
+                    for unit in &units.disable {
+                        plan.services.push((service::Action::Disable, unit.clone()));
+                    }
+                }
+
+                // Video memory is only preserved while the NVIDIA driver is loaded, so the
+                // services are needed in every mode but integrated and VFIO.
+                let sleep = if vendor == "integrated" || vendor == "vfio" {
+                    service::Action::Disable
+                } else {
+                    service::Action::Enable
+                };
+                plan.services
+                    .extend(NVIDIA_SLEEP_SERVICES.iter().map(|&unit| (sleep, unit.to_owned())));
+            }
+            Switchable::Amd(amd) => {
+                let mut text =
+                    template_or(vendor, Self::amd_modprobe(vendor, amd.intel), &templates);
+                extend_templates(&mut text, &templates);
+                plan.files.push((MODPROBE_PATH, text));
+                match Self::amd_xorg(vendor, amd) {
+                    Some(text) => plan.files.push((XORG_AMD_PATH, text.into_bytes())),
+                    None => plan.removed.push(XORG_AMD_PATH),
+                }
             }
+            Switchable::Intel(_) => {
+                let mut text = template_or(vendor, MODPROBE_INTEL, &templates);
+                extend_templates(&mut text, &templates);
+                plan.files.push((MODPROBE_PATH, text));
+            }
+        }
+
+        plan
+    }
+
+    fn write_vendor_config(
+        vendor: &str,
+        switchable: &Switchable,
+        services: &GraphicsServices,
+        backends: &Backends,
+    ) -> Result<(), GraphicsDeviceError> {
+        let plan = Self::vendor_plan(vendor, switchable, services, &*backends.files);
+
+        backends.state.store(GRAPHICS_STATE, &vendor).map_err(GraphicsDeviceError::StateWrite)?;
+
+        for (path, contents) in &plan.files {
+            log::info!("Creating {}", path);
+            let write_error = match *path {
+                PRIME_DISCRETE_PATH => GraphicsDeviceError::PrimeModeWrite,
+                XORG_AMD_PATH => GraphicsDeviceError::XorgFileWrite,
+                _ => GraphicsDeviceError::ModprobeFileWrite,
+            };
+
+            backends.files.write(Path::new(path), contents).map_err(write_error)?;
+        }
 
+        for path in &plan.removed {
+            backends.files.remove(Path::new(path)).map_err(GraphicsDeviceError::XorgFileWrite)?;
+        }
+
+        for (action, unit) in &plan.services {
+            log::info!("{} {}", action.as_str(), unit);
+            // Error is ignored in case this service is removed, or the init system is unsupported
+            if let Err(why) = service::run_with(&*backends.commands, *action, unit, true) {
+                log::warn!("{} (not an error if service does not exist!)", why);
+            }
         }
 
         Ok(())
     }
 
+    /// Rebuilds the initramfs with the configured tool, or else the tool which is installed.
+    fn update_initramfs(
+        tool: Option<InitramfsTool>,
+        commands: &dyn CommandBackend,
+    ) -> Result<(), GraphicsDeviceError> {
+        let _span = tracing::info_span!("update_initramfs").entered();
+        initramfs::backend(tool).rebuild(commands).map_err(GraphicsDeviceError::Initramfs)
+    }
+
+    /// Whether any switched discrete GPU is powered on. Other discrete GPUs are controlled
+    /// individually with `set_device_power`.
     pub fn get_power(&self) -> Result<bool, GraphicsDeviceError> {
         self.switchable_or_fail()?;
-        Ok(self.nvidia.iter().any(GraphicsDevice::exists))
+        Ok(self.switched_devices().iter().any(|dev| dev.exists()))
     }
 
-    pub fn set_power(&self, power: bool) -> Result<(), GraphicsDeviceError> {
+    /// Powers the switched discrete GPUs on or off. External displays keep them from being
+    /// powered off unless it is forced, as do processes which have them open, which are found by
+    /// `blocking_users` to be stopped first.
+    #[tracing::instrument(skip(self))]
+    pub fn set_power(&self, power: bool, force: bool) -> Result<(), GraphicsDeviceError> {
         self.switchable_or_fail()?;
 
         if power {
             log::info!("Enabling graphics power");
-            self.bus.rescan().map_err(GraphicsDeviceError::Rescan)?;
+            self.backends.pci.rescan(None).map_err(GraphicsDeviceError::Rescan)?;
         } else {
             log::info!("Disabling graphics power");
 
-            // TODO: Don't allow turning off power if nvidia_drm modeset is enabled
+            let devices = self.switched_devices();
+            self.check_power_off(&devices, force)?;
+            self.release(&devices)?;
 
             unsafe {
-                // Unbind NVIDIA graphics devices and their functions
-                let unbinds = self.nvidia.iter().map(|dev| dev.unbind());
+                // Unbind discrete graphics devices and their functions
+                let unbinds = devices.iter().map(|dev| dev.unbind());
 
-                // Remove NVIDIA graphics devices and their functions
-                let removes = self.nvidia.iter().map(|dev| dev.remove());
+                // Remove discrete graphics devices and their functions
+                let removes = devices.iter().map(|dev| dev.remove());
 
                 Result::from_iter(unbinds.chain(removes))?;
             }
@@ -521,9 +1944,161 @@ impl Graphics {
         Ok(())
     }
 
+    /// Checks that no display server is displaying from the devices, as removing them from
+    /// under it would take down the session, and that no external displays are connected to
+    /// them, unless forced.
+    fn check_power_off(
+        &self,
+        devices: &[&GraphicsDevice],
+        force: bool,
+    ) -> Result<(), GraphicsDeviceError> {
+        let nodes: Vec<_> = devices.iter().flat_map(|dev| drm::card_nodes(&dev.id)).collect();
+        if let Some(user) = drm::find_user(&nodes) {
+            return Err(GraphicsDeviceError::DisplayInUse(user));
+        }
+
+        let displays: Vec<_> =
+            devices.iter().flat_map(|dev| drm::external_displays(&dev.id)).collect();
+        if !force && !displays.is_empty() {
+            return Err(GraphicsDeviceError::ExternalDisplays(displays));
+        }
+
+        Ok(())
+    }
+
+    /// The processes which keep a discrete GPU, or the switched GPUs if `id` is empty, from being
+    /// forced off, which are to be stopped before it is. This fails where it cannot be forced off
+    /// anyway, so that nothing is stopped in vain. The display server is never stopped.
+    pub fn blocking_users(&self, id: &str) -> Result<Vec<drm::DrmUser>, GraphicsDeviceError> {
+        let devices = if id.is_empty() {
+            self.switchable_or_fail()?;
+            self.switched_devices()
+        } else {
+            vec![self.discrete_device(id)?]
+        };
+
+        self.check_power_off(&devices, true)?;
+        Ok(self.users(&devices))
+    }
+
+    /// Checks that no process has the devices open before they are removed, as unbinding the
+    /// driver would otherwise block until they close it.
+    fn release(&self, devices: &[&GraphicsDevice]) -> Result<(), GraphicsDeviceError> {
+        let users = self.users(devices);
+        if users.is_empty() {
+            Ok(())
+        } else {
+            Err(GraphicsDeviceError::DeviceOpen(users))
+        }
+    }
+
+    /// The processes which have the devices open.
+    fn users(&self, devices: &[&GraphicsDevice]) -> Vec<drm::DrmUser> {
+        let mut nodes: Vec<PathBuf> = devices
+            .iter()
+            .flat_map(|dev| drm::card_nodes(&dev.id).into_iter().chain(drm::render_nodes(&dev.id)))
+            .collect();
+
+        // The nodes which the NVIDIA driver shares between its GPUs are only counted when every
+        // NVIDIA GPU is removed, so that one of two cards may be powered off while the other is
+        // used.
+        let nvidia: Vec<_> = devices
+            .iter()
+            .filter(|dev| self.nvidia.iter().any(|nvidia| nvidia.id == dev.id))
+            .collect();
+        if !nvidia.is_empty()
+            && self.nvidia.iter().filter(|dev| dev.exists()).count() <= nvidia.len()
+        {
+            nodes.extend(drm::nvidia_nodes());
+        } else {
+            nodes.extend(nvidia.iter().filter_map(|dev| drm::nvidia_device_node(&dev.id)));
+        }
+
+        drm::find_users(&nodes)
+    }
+
+    /// The nodes through which applications use the NVIDIA GPUs.
+    pub fn nvidia_nodes(&self) -> Vec<PathBuf> {
+        let mut nodes = drm::nvidia_nodes();
+        for dev in &self.nvidia {
+            nodes.extend(drm::card_nodes(&dev.id));
+            nodes.extend(drm::render_nodes(&dev.id));
+        }
+
+        nodes
+    }
+
     pub fn auto_power(&self) -> Result<(), GraphicsDeviceError> {
         let vendor = self.get_vendor()?;
-        self.set_power(vendor != "integrated")
+        self.set_power(vendor != "integrated", false)
+    }
+
+    /// Lists the GPUs which applications may be launched on, starting with the default.
+    ///
+    /// The NVIDIA GPU is only listed when it is the only GPU in use, or it is available for PRIME
+    /// render offload in hybrid mode. Likewise, the AMD dGPU is the only GPU listed in discrete
+    /// mode. Intel dGPUs are listed whenever they are powered on.
+    pub fn gpus(&self) -> Vec<Gpu> {
+        let existing = |devices: &[GraphicsDevice]| devices.iter().any(GraphicsDevice::exists);
+        let dri_prime =
+            |dev: &GraphicsDevice| format!("pci-{}", dev.id.replace(&[':', '.'][..], "_"));
+        let vendor = self.get_vendor().unwrap_or_else(|_| "integrated".to_owned());
+
+        let mut gpus = Vec::new();
+        let amd_discrete = self.amd_discrete().find(|dev| dev.exists());
+        if vendor == "nvidia" && existing(&self.nvidia) {
+            gpus.push(Gpu::new("NVIDIA Graphics", &[]));
+        } else if vendor == "discrete" && amd_discrete.is_some() {
+            gpus.push(Gpu::new("AMD Graphics", &[]));
+        } else {
+            if self.intel.iter().any(|dev| dev.exists() && !dev.is_intel_discrete()) {
+                gpus.push(Gpu::new("Intel Graphics", &[]));
+            }
+
+            // AMD graphics are discrete when paired with Intel graphics, and are selected by
+            // their PCI address through Mesa.
+            for dev in self.amd.iter().filter(|dev| dev.exists()) {
+                if gpus.is_empty() {
+                    gpus.push(Gpu::new("AMD Graphics", &[]));
+                } else {
+                    gpus.push(Gpu::new("AMD Graphics", &["DRI_PRIME", &dri_prime(dev)]));
+                }
+            }
+
+            // As are Intel dGPUs.
+            for dev in self.intel_discrete().filter(|dev| dev.exists()) {
+                if gpus.is_empty() {
+                    gpus.push(Gpu::new("Intel Arc Graphics", &[]));
+                } else {
+                    gpus.push(Gpu::new("Intel Arc Graphics", &["DRI_PRIME", &dri_prime(dev)]));
+                }
+            }
+
+            if vendor == "hybrid" && self.nouveau {
+                // Mesa selects nouveau by PCI address, as it does amdgpu.
+                for dev in self.nvidia.iter().filter(|dev| dev.exists()) {
+                    gpus.push(Gpu::new("NVIDIA Graphics", &["DRI_PRIME", &dri_prime(dev)]));
+                }
+            } else if vendor == "hybrid" && existing(&self.nvidia) {
+                gpus.push(Gpu::new(
+                    "NVIDIA Graphics",
+                    &[
+                        "__NV_PRIME_RENDER_OFFLOAD",
+                        "1",
+                        "__GLX_VENDOR_LIBRARY_NAME",
+                        "nvidia",
+                        "__VK_LAYER_NV_optimus",
+                        "NVIDIA_only",
+                    ],
+                ));
+            }
+        }
+
+        if let Some(gpu) = gpus.first_mut() {
+            gpu.default = true;
+        }
+
+        gpus
     }
 
     fn switchable_or_fail(&self) -> Result<(), GraphicsDeviceError> {
@@ -534,3 +2109,475 @@ impl Graphics {
         }
     }
 }
+
+/// The module configuration templates, by file name, in order of name.
+fn modprobe_templates(files: &dyn FileBackend) -> Vec<(String, Vec<u8>)> {
+    let mut templates: Vec<(String, Vec<u8>)> = files
+        .list(Path::new(MODPROBE_TEMPLATES))
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|name| name.ends_with(".conf"))
+        .filter_map(|name| {
+            let path = Path::new(MODPROBE_TEMPLATES).join(&name);
+            match files.read(&path) {
+                Ok(contents) => Some((name, contents)),
+                Err(why) => {
+                    log::warn!("failed to read {}: {}", path.display(), why);
+                    None
+                }
+            }
+        })
+        .collect();
+    templates.sort_by(|a, b| a.0.cmp(&b.0));
+    templates
+}
+
+/// The template of a graphics mode, or the configuration generated for it if there is none.
+fn template_or(vendor: &str, generated: &[u8], templates: &[(String, Vec<u8>)]) -> Vec<u8> {
+    templates
+        .iter()
+        .find(|(name, _)| name.strip_suffix(".conf") == Some(vendor))
+        .map_or_else(|| generated.to_vec(), |(_, contents)| contents.clone())
+}
+
+/// Appends the templates which are not named after a graphics mode.
+fn extend_templates(text: &mut Vec<u8>, templates: &[(String, Vec<u8>)]) {
+    let extensions = templates.iter().filter(|(name, _)| {
+        name.strip_suffix(".conf").map_or(true, |mode| !VENDORS.contains(&mode))
+    });
+
+    for (name, contents) in extensions {
+        if !text.ends_with(b"\n") {
+            text.push(b'\n');
+        }
+        text.extend_from_slice(format!("# From {}/{}\n", MODPROBE_TEMPLATES, name).as_bytes());
+        text.extend_from_slice(contents);
+    }
+}
+
+/// The Xorg `BusID` of a PCI address, such as `PCI:3:0:0` for `0000:03:00.0`.
+fn xorg_bus_id(id: &str) -> Option<String> {
+    let mut parts = id.split(&[':', '.'][..]);
+    let mut next = || parts.next().and_then(|part| u32::from_str_radix(part, 16).ok());
+    let (domain, bus, device, function) = (next()?, next()?, next()?, next()?);
+
+    Some(if domain == 0 {
+        format!("PCI:{}:{}:{}", bus, device, function)
+    } else {
+        format!("PCI:{}@{}:{}:{}", bus, domain, device, function)
+    })
+}
+
+/// Whether a file was changed after an initramfs image was built, which then holds an older copy
+/// of it.
+fn changed_after(files: &dyn FileBackend, path: &Path, image: &Path) -> bool {
+    match (files.modified(path), files.modified(image)) {
+        (Ok(changed), Ok(built)) => changed > built,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        collections::BTreeMap, env, os::unix::process::ExitStatusExt, process::ExitStatus,
+        sync::Mutex, time::SystemTime,
+    };
+
+    fn devices(ids: &[&str]) -> Vec<GraphicsDevice> {
+        ids.iter().map(|&id| GraphicsDevice::new(id.to_owned(), Vec::new())).collect()
+    }
+
+    fn roles(graphics: &Graphics) -> Vec<(&'static str, GpuRole)> {
+        graphics.topology().into_iter().map(|dev| (dev.vendor, dev.role)).collect()
+    }
+
+    #[test]
+    fn topology() {
+        let intel_amd_nvidia = Graphics {
+            intel: devices(&["0000:00:02.0"]),
+            amd: devices(&["0000:03:00.0"]),
+            nvidia: devices(&["0000:01:00.0"]),
+            ..Graphics::default()
+        };
+        assert_eq!(
+            roles(&intel_amd_nvidia),
+            vec![
+                ("intel", GpuRole::Integrated),
+                ("amd", GpuRole::Discrete),
+                ("nvidia", GpuRole::Discrete),
+            ]
+        );
+
+        let apu_nvidia = Graphics {
+            amd: devices(&["0000:05:00.0"]),
+            nvidia: devices(&["0000:01:00.0"]),
+            ..Graphics::default()
+        };
+        assert_eq!(
+            roles(&apu_nvidia),
+            vec![("amd", GpuRole::Integrated), ("nvidia", GpuRole::Discrete),]
+        );
+
+        let err = intel_amd_nvidia.set_device_power("0000:00:02.0", false, false).unwrap_err();
+        assert!(matches!(err, GraphicsDeviceError::NotDiscrete(_)));
+        let err = intel_amd_nvidia.set_device_power("0000:09:00.0", false, false).unwrap_err();
+        assert!(matches!(err, GraphicsDeviceError::UnknownDevice(_)));
+        let err = intel_amd_nvidia.begin_switch("headless").unwrap_err();
+        assert!(matches!(err, GraphicsDeviceError::UnknownVendor(_)));
+    }
+
+    #[test]
+    fn amd_switching() {
+        let intel_amd = Graphics {
+            intel: devices(&["0000:00:02.0"]),
+            amd: devices(&["0000:03:00.0"]),
+            ..Graphics::default()
+        };
+        let amd = AmdSwitch { devices: vec!["0000:03:00.0".to_owned()], intel: true };
+        assert_eq!(intel_amd.switchable(), Some(Switchable::Amd(amd.clone())));
+        let err = intel_amd.begin_switch("nvidia").unwrap_err();
+        assert!(matches!(err, GraphicsDeviceError::UnsupportedVendor(_, "AMD")));
+
+        let apu_amd =
+            Graphics { amd: devices(&["0000:05:00.0", "0000:03:00.0"]), ..Graphics::default() };
+        assert!(apu_amd.can_switch());
+        let apu = Graphics { amd: devices(&["0000:05:00.0"]), ..Graphics::default() };
+        assert!(!apu.can_switch());
+
+        assert!(Graphics::amd_modprobe("integrated", true).starts_with(MODPROBE_AMD_INTEGRATED));
+        assert_eq!(Graphics::amd_modprobe("integrated", false), MODPROBE_AMD_HYBRID);
+        assert_eq!(Graphics::amd_xorg("hybrid", &amd), None);
+        assert!(Graphics::amd_xorg("discrete", &amd).unwrap().contains("BusID \"PCI:3:0:0\""));
+        assert_eq!(xorg_bus_id("0001:0a:1f.2").as_deref(), Some("PCI:10@1:31:2"));
+    }
+
+    #[test]
+    fn intel_switching() {
+        let arc = || {
+            let mut arc = devices(&["0000:03:00.0"]);
+            arc[0].pci_ids = vec!["8086:5693".to_owned()];
+            arc
+        };
+        let intel_arc = Graphics {
+            intel: devices(&["0000:00:02.0"]).into_iter().chain(arc()).collect(),
+            ..Graphics::default()
+        };
+        assert_eq!(
+            roles(&intel_arc),
+            vec![("intel", GpuRole::Integrated), ("intel", GpuRole::Discrete)]
+        );
+        let intel = IntelSwitch { devices: vec!["0000:03:00.0".to_owned()] };
+        assert_eq!(intel_arc.switchable(), Some(Switchable::Intel(intel.clone())));
+        let err = intel_arc.begin_switch("discrete").unwrap_err();
+        assert!(matches!(err, GraphicsDeviceError::UnsupportedVendor(_, "Intel")));
+
+        // An Arc dGPU which is listed first is still not taken for the iGPU.
+        let arc_first = Graphics {
+            intel: arc().into_iter().chain(devices(&["0000:00:02.0"])).collect(),
+            ..Graphics::default()
+        };
+        assert_eq!(arc_first.integrated().map(|dev| dev.id.as_str()), Some("0000:00:02.0"));
+
+        // Without an iGPU, the dGPU is the only GPU and is never switched.
+        let arc_only = Graphics { intel: arc(), ..Graphics::default() };
+        assert!(!arc_only.can_switch());
+        let apu_arc =
+            Graphics { intel: arc(), amd: devices(&["0000:05:00.0"]), ..Graphics::default() };
+        assert_eq!(roles(&apu_arc)[1], ("amd", GpuRole::Integrated));
+        assert!(apu_arc.can_switch());
+
+        let services = GraphicsServices::default();
+        let files = MemFiles::default();
+        let plan =
+            Graphics::vendor_plan("integrated", &Switchable::Intel(intel), &services, &files);
+        assert_eq!(plan.files, vec![(MODPROBE_PATH, MODPROBE_INTEL.to_vec())]);
+    }
+
+    #[test]
+    fn loaded_modules() {
+        assert_eq!(Graphics::loaded_vendor("hybrid", false, false), Some("integrated"));
+        assert_eq!(Graphics::loaded_vendor("integrated", true, true), Some("hybrid"));
+        assert_eq!(Graphics::loaded_vendor("hybrid", true, false), Some("compute"));
+        assert_eq!(Graphics::loaded_vendor("nvidia", true, true), None);
+        assert_eq!(Graphics::loaded_vendor("compute", true, false), None);
+        assert_eq!(Graphics::loaded_vendor("vfio", false, false), None);
+    }
+
+    #[test]
+    fn unplugged_egpu() {
+        let mut egpu = devices(&["0000:0c:00.0"]);
+        egpu[0].external = true;
+        let mut graphics =
+            Graphics { intel: devices(&["0000:00:02.0"]), nvidia: egpu, ..Graphics::default() };
+        assert!(graphics.can_switch());
+
+        graphics.forget_unplugged();
+        assert!(graphics.nvidia.is_empty());
+        assert_eq!(graphics.intel.len(), 1);
+    }
+
+    #[test]
+    fn switch_plans() {
+        let services = GraphicsServices::default();
+        let files = MemFiles::default();
+        let has = |plan: &SwitchPlan, action, unit: &str| {
+            plan.services.contains(&(action, unit.to_owned()))
+        };
+
+        let amd = AmdSwitch { devices: vec!["0000:03:00.0".to_owned()], intel: false };
+        let plan =
+            Graphics::vendor_plan("hybrid", &Switchable::Amd(amd.clone()), &services, &files);
+        assert_eq!(plan.files, vec![(MODPROBE_PATH, MODPROBE_AMD_HYBRID.to_vec())]);
+        assert_eq!(plan.removed, vec![XORG_AMD_PATH]);
+        let plan = Graphics::vendor_plan("discrete", &Switchable::Amd(amd), &services, &files);
+        assert_eq!(plan.files[1].0, XORG_AMD_PATH);
+
+        let vfio_ids = vec!["10de:1f95".to_owned(), "10de:10fa".to_owned()];
+        let nvidia =
+            Switchable::Nvidia(NvidiaSwitch { vfio_ids: vfio_ids.clone(), nouveau: false });
+        let plan = Graphics::vendor_plan("integrated", &nvidia, &services, &files);
+        assert_eq!(plan.files[0], (PRIME_DISCRETE_PATH, b"off\n".to_vec()));
+        assert!(has(&plan, service::Action::Disable, "nvidia-fallback.service"));
+        assert!(has(&plan, service::Action::Disable, "nvidia-suspend.service"));
+
+        let plan = Graphics::vendor_plan("vfio", &nvidia, &services, &files);
+        assert!(String::from_utf8_lossy(&plan.files[1].1)
+            .ends_with("options vfio-pci ids=10de:1f95,10de:10fa\n"));
+        assert!(has(&plan, service::Action::Disable, "nvidia-suspend.service"));
+
+        // Distributions without the fallback service configure their own units.
+        let custom: GraphicsServices =
+            toml::from_str("[nvidia]\nenable = [\"nvidia-persistenced.service\"]\n").unwrap();
+        let plan = Graphics::vendor_plan("nvidia", &nvidia, &custom, &files);
+        assert!(has(&plan, service::Action::Enable, "nvidia-persistenced.service"));
+        assert!(!has(&plan, service::Action::Enable, "nvidia-fallback.service"));
+        let plan = Graphics::vendor_plan("hybrid", &nvidia, &custom, &files);
+        assert!(!has(&plan, service::Action::Disable, "nvidia-fallback.service"));
+
+        let nouveau = Switchable::Nvidia(NvidiaSwitch { vfio_ids, nouveau: true });
+        assert!(!nouveau.vendors().contains(&"nvidia"));
+        let plan = Graphics::vendor_plan("hybrid", &nouveau, &services, &files);
+        assert_eq!(plan.files[0], (PRIME_DISCRETE_PATH, b"on-demand\n".to_vec()));
+        assert_eq!(plan.files[1], (MODPROBE_PATH, MODPROBE_NOUVEAU_HYBRID.to_vec()));
+        assert!(plan.services.is_empty());
+    }
+
+    #[test]
+    fn templates() {
+        let templates = vec![
+            (
+                "hybrid.conf".to_owned(),
+                b"options nvidia NVreg_DynamicPowerManagement=0x03\n".to_vec(),
+            ),
+            ("nvreg.conf".to_owned(), b"options nvidia NVreg_UsePageAttributeTable=1".to_vec()),
+        ];
+
+        let (_, text) = Graphics::vendor_files("compute", &templates, false);
+        assert!(text.starts_with(MODPROBE_COMPUTE));
+        let (_, mut text) = Graphics::vendor_files("hybrid", &templates, false);
+        assert!(text.starts_with(b"options nvidia NVreg_DynamicPowerManagement=0x03\n"));
+        assert!(!text.starts_with(MODPROBE_HYBRID));
+
+        extend_templates(&mut text, &templates);
+        let text = String::from_utf8(text).unwrap();
+        assert_eq!(text.matches("NVreg_DynamicPowerManagement").count(), 1);
+        assert!(text.ends_with(
+            "# From /etc/system76-power/modprobe.d/nvreg.conf\noptions nvidia \
+             NVreg_UsePageAttributeTable=1"
+        ));
+    }
+
+    #[test]
+    fn infer_vendor() {
+        for &vendor in NVIDIA_VENDORS {
+            let (prime, modprobe) = Graphics::vendor_files(vendor, &[], false);
+            assert_eq!(Graphics::infer_vendor(prime, &modprobe), Some(vendor));
+        }
+
+        assert_eq!(Graphics::infer_vendor("off", b""), Some("compute"));
+        assert_eq!(Graphics::infer_vendor("auto", b""), None);
+    }
+
+    #[test]
+    fn external_changes() {
+        let dir = env::temp_dir().join(format!("system76-power-external-{}", std::process::id()));
+        let files = MemFiles::default();
+        let backends = Backends {
+            files: Arc::new(files.clone()),
+            state: StateStore::new(&dir),
+            ..Backends::default()
+        };
+        let graphics = Graphics {
+            backends,
+            intel: devices(&["0000:00:02.0"]),
+            nvidia: devices(&["0000:01:00.0"]),
+            ..Graphics::default()
+        };
+        graphics.adopt_vendor("integrated").unwrap();
+
+        // A template need not blacklist nvidia to select integrated graphics.
+        let template = Path::new(MODPROBE_TEMPLATES).join("integrated.conf");
+        files.write(&template, b"alias nvidia off\n").unwrap();
+        let switchable = graphics.switchable().unwrap();
+        let plan = Graphics::vendor_plan("integrated", &switchable, &graphics.services, &files);
+        for (path, contents) in &plan.files {
+            files.write(Path::new(path), contents).unwrap();
+        }
+        assert_eq!(graphics.external_change(), None);
+
+        files.write(Path::new(PRIME_DISCRETE_PATH), b"on-demand\n").unwrap();
+        let change = Some(("integrated".to_owned(), Some("hybrid".to_owned())));
+        assert_eq!(graphics.external_change(), change);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn verification() {
+        let verify =
+            |saved: Option<&str>, prime: Option<&str>, modprobe: Option<&[u8]>, modules| {
+                let mut verification = GraphicsVerification {
+                    saved: saved.map(str::to_owned),
+                    prime: prime.map(str::to_owned),
+                    modules,
+                    ..GraphicsVerification::default()
+                };
+                verification.check_nvidia(modprobe);
+                verification
+            };
+
+        let hybrid = verify(
+            Some("hybrid"),
+            Some("on-demand"),
+            Some(MODPROBE_HYBRID),
+            vec!["nvidia", "nvidia_drm", "nvidia_modeset"],
+        );
+        assert_eq!(hybrid.configured, Some("hybrid"));
+        assert!(hybrid.problems.is_empty(), "{:?}", hybrid.problems);
+
+        let integrated =
+            verify(Some("integrated"), Some("off"), Some(MODPROBE_INTEGRATED), vec!["nvidia"]);
+        assert_eq!(integrated.loaded, Some("compute"));
+        assert_eq!(
+            integrated.problems,
+            vec!["integrated graphics are selected, but nvidia is loaded, as in compute graphics"]
+        );
+
+        let contradicted =
+            verify(Some("integrated"), Some("on"), Some(MODPROBE_INTEGRATED), vec![]);
+        assert_eq!(contradicted.configured, Some("nvidia"));
+        assert_eq!(contradicted.loaded, Some("integrated"));
+        assert_eq!(contradicted.problems.len(), 3, "{:?}", contradicted.problems);
+
+        // Nothing is missing until a mode was switched to.
+        assert!(verify(None, None, None, vec![]).problems.is_empty());
+        assert_eq!(verify(Some("hybrid"), None, None, vec![]).problems.len(), 2);
+    }
+
+    /// A bus whose devices are directories of sysfs attributes.
+    struct FakeBus(PathBuf);
+
+    impl PciBackend for FakeBus {
+        fn devices(&self) -> io::Result<Vec<PciDevice>> {
+            // `from_path` only accepts devices under /sys/bus/pci/devices.
+            fs::read_dir(&self.0)?
+                .map(|entry| Ok(unsafe { PciDevice::from_path_unchecked(entry?.path()) }))
+                .collect()
+        }
+
+        fn rescan(&self, _bridge: Option<&Path>) -> io::Result<()> { Ok(()) }
+    }
+
+    #[derive(Clone, Default)]
+    struct MemFiles(Arc<Mutex<BTreeMap<PathBuf, Vec<u8>>>>);
+
+    impl FileBackend for MemFiles {
+        fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+            let files = self.0.lock().unwrap();
+            files.get(path).cloned().ok_or_else(|| io::ErrorKind::NotFound.into())
+        }
+
+        fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+            self.0.lock().unwrap().insert(path.to_owned(), contents.to_vec());
+            Ok(())
+        }
+
+        fn remove(&self, path: &Path) -> io::Result<()> {
+            self.0.lock().unwrap().remove(path);
+            Ok(())
+        }
+
+        fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+            self.read(path).map(|_| SystemTime::UNIX_EPOCH)
+        }
+
+        fn list(&self, dir: &Path) -> io::Result<Vec<String>> {
+            let files = self.0.lock().unwrap();
+            let mut names: Vec<String> = files
+                .keys()
+                .filter_map(|path| path.strip_prefix(dir).ok()?.iter().next())
+                .map(|name| name.to_string_lossy().into_owned())
+                .collect();
+            names.dedup();
+            Ok(names)
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct Commands(Arc<Mutex<Vec<String>>>);
+
+    impl CommandBackend for Commands {
+        fn run(&self, program: &str, args: &[&str]) -> io::Result<ExitStatus> {
+            self.0.lock().unwrap().push(format!("{} {}", program, args.join(" ")));
+            Ok(ExitStatus::from_raw(0))
+        }
+    }
+
+    #[test]
+    fn builder() {
+        let dir = env::temp_dir().join(format!("system76-power-graphics-{}", std::process::id()));
+        let bus = dir.join("devices");
+        for &(id, boot_vga) in &[("0000:05:00.0", "1"), ("0000:03:00.0", "0")] {
+            let device = bus.join(id);
+            fs::create_dir_all(&device).unwrap();
+            fs::write(device.join("class"), "0x030000\n").unwrap();
+            fs::write(device.join("vendor"), "0x1002\n").unwrap();
+            fs::write(device.join("device"), "0x73bf\n").unwrap();
+            fs::write(device.join("boot_vga"), boot_vga).unwrap();
+        }
+
+        let files = MemFiles::default();
+        let commands = Commands::default();
+        let graphics = GraphicsBuilder::new()
+            .pci(FakeBus(bus))
+            .files(files.clone())
+            .commands(commands.clone())
+            .state(StateStore::new(dir.join("state")))
+            .initramfs(InitramfsTool::Dracut)
+            .build()
+            .unwrap();
+
+        // The APU is integrated, and the other AMD GPU is switched.
+        assert_eq!(graphics.amd.len(), 2);
+        assert!(matches!(graphics.switchable(), Some(Switchable::Amd(_))));
+
+        graphics.set_vendor("discrete").unwrap();
+        assert_eq!(graphics.get_vendor().unwrap(), "discrete");
+        assert_eq!(graphics.pending_vendor(), None);
+        assert_eq!(*commands.0.lock().unwrap(), vec!["dracut --force".to_owned()]);
+        {
+            let files = files.0.lock().unwrap();
+            assert_eq!(files[Path::new(MODPROBE_PATH)], MODPROBE_AMD_DISCRETE);
+            let xorg = String::from_utf8_lossy(&files[Path::new(XORG_AMD_PATH)]).into_owned();
+            assert!(xorg.contains("BusID \"PCI:3:0:0\""));
+        }
+
+        // Switching back removes the Xorg configuration.
+        graphics.set_vendor("hybrid").unwrap();
+        assert!(!files.0.lock().unwrap().contains_key(Path::new(XORG_AMD_PATH)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,372 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! A read-only endpoint which reports the state of the daemon as JSON, so that fleet management
+//! agents can monitor systems without talking to DBus.
+//!
+//! It answers `GET /` and `GET /health` with HTTP/1.0 on a unix socket, or on a TCP port bound to
+//! a loopback address, closing each connection after the response. The unix socket is only
+//! accessible to root.
+
+use crate::{
+    ec,
+    logging::{self, LoggedError},
+    power_source::PowerSource,
+    sensors::SensorCache,
+};
+use serde::Serialize;
+use std::{
+    fs, io,
+    net::SocketAddr,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use sysfs_class::{HwMon, SysClass};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, UnixListener},
+    time,
+};
+
+// Requests larger than this are rejected, as only the request line is needed.
+const MAX_REQUEST: usize = 8192;
+
+// Clients which do not finish sending their request in time are disconnected.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The status is degraded while an error has been logged within this period.
+const DEGRADED_PERIOD: Duration = Duration::from_secs(10 * 60);
+
+// Sensors of the CPU and integrated or AMD GPUs, which report their temperature as `temp1`.
+const TEMPERATURE_SENSORS: &[&str] = &["coretemp", "k10temp", "amdgpu"];
+
+#[derive(Debug, Error)]
+pub enum HealthError {
+    #[error("{} is neither a socket path nor an address and port", _0)]
+    InvalidAddress(String),
+    #[error("{} is not a loopback address", _0)]
+    NotLoopback(SocketAddr),
+    #[error("failed to listen on {}: {}", _0, _1)]
+    Listen(String, io::Error),
+}
+
+/// Where the endpoint listens, as given by the `listen` setting.
+#[derive(Debug, PartialEq)]
+pub enum Listen {
+    Unix(PathBuf),
+    Tcp(SocketAddr),
+}
+
+impl Listen {
+    pub fn parse(listen: &str) -> Result<Listen, HealthError> {
+        if listen.starts_with('/') {
+            return Ok(Listen::Unix(PathBuf::from(listen)));
+        }
+
+        let addr: SocketAddr =
+            listen.parse().map_err(|_| HealthError::InvalidAddress(listen.to_owned()))?;
+
+        // The state of the system is not meant to be exposed to the network.
+        if !addr.ip().is_loopback() {
+            return Err(HealthError::NotLoopback(addr));
+        }
+
+        Ok(Listen::Tcp(addr))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Temperature {
+    /// The name of the hwmon device, such as `coretemp`.
+    pub sensor:  String,
+    pub celsius: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Sensors {
+    pub temperatures: Vec<Temperature>,
+    /// Fan speeds reported by the embedded controller, in RPM.
+    pub fan_speeds:   Vec<u32>,
+}
+
+impl Sensors {
+    pub fn read(cache: &SensorCache) -> Sensors {
+        let temperatures = HwMon::all()
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|hwmon| {
+                let sensor = hwmon.name().ok()?;
+                if !TEMPERATURE_SENSORS.contains(&sensor.as_str()) {
+                    return None;
+                }
+
+                let millidegrees = cache.read_u32(&hwmon.path().join("temp1_input"))?;
+                Some(Temperature { sensor, celsius: f64::from(millidegrees) / 1000.0 })
+            })
+            .collect();
+
+        Sensors { temperatures, fan_speeds: ec::fan_speeds(cache) }
+    }
+}
+
+/// Statistics of a rotational drive, since the daemon started.
+#[derive(Debug, Serialize)]
+pub struct DiskStats {
+    /// The name of the block device, such as `sda`.
+    pub name:        String,
+    pub spinning:    Option<bool>,
+    /// The temperature reported by `drivetemp` while the drive was last spinning.
+    pub celsius:     Option<f64>,
+    pub spinups:     u32,
+    /// Starts from cold within the last hour.
+    pub cold_starts: usize,
+    /// Whether spindown is disabled because the drive was started cold too often.
+    pub backed_off:  bool,
+}
+
+/// Throttling of the CPU or GPUs, since the daemon started.
+#[derive(Debug, Serialize)]
+pub struct ThrottleStats {
+    /// Such as `cpu-thermal` or `gpu-power`.
+    pub source:    &'static str,
+    pub events:    u32,
+    /// The total time spent throttled, in seconds.
+    pub seconds:   u64,
+    pub throttled: bool,
+}
+
+/// The document returned by the endpoint.
+#[derive(Debug, Serialize)]
+pub struct Health {
+    /// `ok`, or `degraded` if an error was logged recently.
+    pub status:        &'static str,
+    pub version:       &'static str,
+    /// Seconds since the daemon started.
+    pub uptime:        u64,
+    pub profile:       String,
+    pub capabilities:  Vec<String>,
+    pub power_source:  Option<PowerSource>,
+    pub sensors:       Sensors,
+    pub disks:         Vec<DiskStats>,
+    pub throttling:    Vec<ThrottleStats>,
+    /// The most recent warnings and errors, oldest first.
+    pub recent_errors: Vec<LoggedError>,
+}
+
+impl Health {
+    pub fn new(
+        profile: String,
+        capabilities: Vec<String>,
+        power_source: Option<PowerSource>,
+        sensors: Sensors,
+        disks: Vec<DiskStats>,
+        throttling: Vec<ThrottleStats>,
+        uptime: Duration,
+    ) -> Health {
+        let recent_errors = logging::recent_errors();
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+        let degraded = recent_errors.iter().any(|error| {
+            error.level == "error" && now.saturating_sub(error.time) < DEGRADED_PERIOD.as_secs()
+        });
+
+        Health {
+            status: if degraded { "degraded" } else { "ok" },
+            version: env!("CARGO_PKG_VERSION"),
+            uptime: uptime.as_secs(),
+            profile,
+            capabilities,
+            power_source,
+            sensors,
+            disks,
+            throttling,
+            recent_errors,
+        }
+    }
+}
+
+/// Starts serving the endpoint in the background. The snapshot is taken for every request, and
+/// is `None` if the state of the daemon is unavailable.
+pub async fn serve<F>(listen: &str, snapshot: F) -> Result<(), HealthError>
+where
+    F: Fn() -> Option<Health> + Send + Sync + 'static,
+{
+    let snapshot = Arc::new(snapshot);
+    let listen_error = |why| HealthError::Listen(listen.to_owned(), why);
+
+    match Listen::parse(listen)? {
+        Listen::Unix(path) => {
+            let listener = bind_unix(&path).map_err(listen_error)?;
+            tokio::spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, _)) => {
+                            tokio::spawn(respond(stream, snapshot.clone()));
+                        }
+                        Err(why) => accept_failed(why).await,
+                    }
+                }
+            });
+        }
+        Listen::Tcp(addr) => {
+            let listener = TcpListener::bind(addr).await.map_err(listen_error)?;
+            tokio::spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, _)) => {
+                            tokio::spawn(respond(stream, snapshot.clone()));
+                        }
+                        Err(why) => accept_failed(why).await,
+                    }
+                }
+            });
+        }
+    }
+
+    Ok(())
+}
+
+fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // A socket left behind by a previous instance prevents binding to the path.
+    if fs::symlink_metadata(path).map_or(false, |metadata| metadata.file_type().is_socket()) {
+        fs::remove_file(path)?;
+    }
+
+    // The socket is created without access for others, rather than being accessible to them
+    // until its permissions are changed.
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(umask) };
+
+    let listener = listener?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+async fn accept_failed(why: io::Error) {
+    log::warn!("health endpoint failed to accept a connection: {}", why);
+
+    // Errors such as running out of file descriptors would otherwise repeat immediately.
+    time::sleep(Duration::from_secs(1)).await;
+}
+
+async fn respond<S, F>(mut stream: S, snapshot: Arc<F>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: Fn() -> Option<Health>,
+{
+    let request = match time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Ok(request)) => request,
+        _ => return,
+    };
+
+    let (status, body) = match route(&request) {
+        Route::Health => match snapshot() {
+            Some(health) => match serde_json::to_string(&health) {
+                Ok(body) => ("200 OK", body),
+                Err(why) => ("500 Internal Server Error", error_body(&why.to_string())),
+            },
+            None => ("503 Service Unavailable", error_body("daemon state is unavailable")),
+        },
+        Route::NotFound => ("404 Not Found", error_body("not found")),
+        Route::MethodNotAllowed => ("405 Method Not Allowed", error_body("method not allowed")),
+        Route::BadRequest => ("400 Bad Request", error_body("bad request")),
+    };
+
+    let response = format!(
+        "HTTP/1.0 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: \
+         close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+
+    if stream.write_all(response.as_bytes()).await.is_ok() {
+        let _ = stream.shutdown().await;
+    }
+}
+
+/// Reads the request line and headers, up to the blank line which ends them.
+async fn read_request<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<String> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+
+        request.extend_from_slice(&buf[..read]);
+        if request.len() > MAX_REQUEST {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "request is too large"));
+        }
+    }
+
+    Ok(String::from_utf8_lossy(&request).into_owned())
+}
+
+fn error_body(message: &str) -> String { serde_json::json!({ "error": message }).to_string() }
+
+#[derive(Debug, PartialEq)]
+enum Route {
+    Health,
+    NotFound,
+    MethodNotAllowed,
+    BadRequest,
+}
+
+fn route(request: &str) -> Route {
+    let mut words = request.lines().next().unwrap_or_default().split_whitespace();
+    let (method, target) = match (words.next(), words.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => return Route::BadRequest,
+    };
+
+    if method != "GET" {
+        return Route::MethodNotAllowed;
+    }
+
+    // Query strings are accepted, so that agents may add cache busting parameters.
+    match target.split('?').next() {
+        Some("/") | Some("/health") => Route::Health,
+        _ => Route::NotFound,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listen() {
+        assert_eq!(
+            Listen::parse("/run/system76-power/health.sock").unwrap(),
+            Listen::Unix(PathBuf::from("/run/system76-power/health.sock"))
+        );
+        assert_eq!(
+            Listen::parse("127.0.0.1:7676").unwrap(),
+            Listen::Tcp("127.0.0.1:7676".parse().unwrap())
+        );
+        assert!(Listen::parse("[::1]:7676").is_ok());
+        assert!(matches!(Listen::parse("0.0.0.0:7676"), Err(HealthError::NotLoopback(_))));
+        assert!(matches!(Listen::parse("health.sock"), Err(HealthError::InvalidAddress(_))));
+    }
+
+    #[test]
+    fn routes() {
+        assert_eq!(route("GET / HTTP/1.1\r\nHost: localhost\r\n\r\n"), Route::Health);
+        assert_eq!(route("GET /health?t=1 HTTP/1.0\r\n\r\n"), Route::Health);
+        assert_eq!(route("GET /metrics HTTP/1.1\r\n\r\n"), Route::NotFound);
+        assert_eq!(route("POST /health HTTP/1.1\r\n\r\n"), Route::MethodNotAllowed);
+        assert_eq!(route(""), Route::BadRequest);
+    }
+}
@@ -0,0 +1,175 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Checks whether the system is able to hibernate and resume, so that hibernation is not offered
+//! where the image could not be written, or would not be found again after the battery runs out.
+//!
+//! The kernel shrinks the image of memory to at most `/sys/power/image_size` bytes, which is 2/5
+//! of memory by default, and writes it to swap. Swap on zram does not survive power loss, so it
+//! is not counted. The device to resume from is given by `resume=` on the kernel command line, or
+//! found by systemd, and is then written to `/sys/power/resume`.
+
+use std::fs;
+
+const POWER_STATE: &str = "/sys/power/state";
+const IMAGE_SIZE: &str = "/sys/power/image_size";
+const RESUME: &str = "/sys/power/resume";
+const RESUME_OFFSET: &str = "/sys/power/resume_offset";
+const SWAPS: &str = "/proc/swaps";
+const CMDLINE: &str = "/proc/cmdline";
+const LOCKDOWN: &str = "/sys/kernel/security/lockdown";
+
+const GIB: f64 = 1024.0 * 1024.0 * 1024.0;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Hibernation {
+    /// Whether the kernel was built with hibernation, which lists `disk` as a sleep state.
+    pub supported:     bool,
+    /// The swap on disk, in bytes.
+    pub swap:          u64,
+    /// Whether every swap on disk is a file, which is only found through `resume_offset=`.
+    pub swap_files:    bool,
+    /// The largest size of the image, in bytes.
+    pub image_size:    u64,
+    /// The `resume=` parameter of the kernel command line, if any.
+    pub resume:        Option<String>,
+    /// Whether the kernel knows the device to resume from, which it otherwise holds as `0:0`.
+    pub resume_device: bool,
+    /// Whether the kernel knows where the image is within a swap file.
+    pub resume_offset: bool,
+    /// Whether the kernel is locked down, as with Secure Boot, which disables hibernation.
+    pub locked_down:   bool,
+}
+
+impl Hibernation {
+    pub fn read() -> Self {
+        let read = |path: &str| fs::read_to_string(path).unwrap_or_default();
+        let (swap, swap_files) = disk_swap(&read(SWAPS));
+        let cmdline = read(CMDLINE);
+
+        Hibernation {
+            supported: read(POWER_STATE).split_whitespace().any(|state| state == "disk"),
+            swap,
+            swap_files,
+            image_size: read(IMAGE_SIZE).trim().parse().unwrap_or(0),
+            resume: kernel_parameter(&cmdline, "resume").map(String::from),
+            resume_device: !matches!(read(RESUME).trim(), "" | "0:0"),
+            resume_offset: !matches!(read(RESUME_OFFSET).trim(), "" | "0"),
+            locked_down: is_locked_down(&read(LOCKDOWN)),
+        }
+    }
+
+    /// The reasons that hibernation would fail, which is empty if it is expected to succeed.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if !self.supported {
+            problems.push("the kernel does not support hibernation".to_owned());
+            return problems;
+        }
+
+        if self.locked_down {
+            problems.push(
+                "the kernel is locked down, as with Secure Boot, which disables hibernation"
+                    .to_owned(),
+            );
+        }
+
+        if self.swap == 0 {
+            problems.push("there is no swap on disk to write the hibernation image to".to_owned());
+        } else if self.swap < self.image_size {
+            problems.push(format!(
+                "swap of {:.1} GiB is smaller than the hibernation image of up to {:.1} GiB",
+                self.swap as f64 / GIB,
+                self.image_size as f64 / GIB
+            ));
+        }
+
+        if !self.resume_device {
+            problems.push(match self.resume {
+                Some(ref resume) => {
+                    format!("resume={} does not name a device which exists", resume)
+                }
+                None => "no device to resume from is set with resume= on the kernel command line"
+                    .to_owned(),
+            });
+        } else if self.swap_files && !self.resume_offset {
+            problems.push(
+                "swap files are only resumed from with resume_offset= on the kernel command line"
+                    .to_owned(),
+            );
+        }
+
+        problems
+    }
+}
+
+/// The size of the swap on disk in bytes from `/proc/swaps`, which lists sizes in KiB, and
+/// whether all of it is in files.
+fn disk_swap(swaps: &str) -> (u64, bool) {
+    let mut bytes = 0;
+    let mut files = true;
+    for line in swaps.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 || fields[0].starts_with("/dev/zram") {
+            continue;
+        }
+
+        if let Ok(size) = fields[2].parse::<u64>() {
+            bytes += size * 1024;
+            files &= fields[1] == "file";
+        }
+    }
+
+    (bytes, files && bytes != 0)
+}
+
+/// The value of a parameter of the kernel command line.
+fn kernel_parameter<'a>(cmdline: &'a str, name: &str) -> Option<&'a str> {
+    cmdline.split_whitespace().rev().find_map(|arg| arg.strip_prefix(name)?.strip_prefix('='))
+}
+
+/// Whether the mode of kernel lockdown, selected within brackets as in
+/// `none [integrity] confidentiality`, is other than `none`.
+fn is_locked_down(lockdown: &str) -> bool {
+    lockdown.split_whitespace().any(|mode| mode.starts_with('[') && mode != "[none]")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hibernation() {
+        let swaps = "Filename\t\t\t\tType\t\tSize\t\tUsed\t\tPriority\n/dev/zram0 \
+                     partition\t8388604\t\t0\t\t100\n/swapfile file\t\t4194300\t\t0\t\t-2\n";
+        assert_eq!(disk_swap(swaps), (4194300 * 1024, true));
+        assert_eq!(disk_swap("Filename Type Size Used Priority\n"), (0, false));
+
+        let cmdline = "BOOT_IMAGE=/vmlinuz root=UUID=1234 resume=UUID=5678 resume_offset=34816";
+        assert_eq!(kernel_parameter(cmdline, "resume"), Some("UUID=5678"));
+        assert_eq!(kernel_parameter(cmdline, "resume_offset"), Some("34816"));
+        assert_eq!(kernel_parameter("quiet splash", "resume"), None);
+
+        assert!(!is_locked_down("[none] integrity confidentiality\n"));
+        assert!(is_locked_down("none [integrity] confidentiality\n"));
+        assert!(!is_locked_down(""));
+
+        let ready = Hibernation {
+            supported:     true,
+            swap:          16 << 30,
+            swap_files:    false,
+            image_size:    6 << 30,
+            resume:        Some("UUID=5678".to_owned()),
+            resume_device: true,
+            resume_offset: false,
+            locked_down:   false,
+        };
+        assert!(ready.problems().is_empty());
+
+        let small = Hibernation { swap: 2 << 30, swap_files: true, ..ready.clone() };
+        assert_eq!(small.problems().len(), 2);
+        let unresumable = Hibernation { resume: None, resume_device: false, ..ready };
+        assert_eq!(unresumable.problems().len(), 1);
+    }
+}
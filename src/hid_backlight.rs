@@ -2,9 +2,9 @@
 //
 // SPDX-License-Identifier: GPL-3.0-only
 
+use crate::ec::KeyboardBacklight;
 use hidapi::{HidApi, HidDevice, HidResult};
 use inotify::{Inotify, WatchMask};
-use std::{fs, path::Path};
 
 fn keyboard(device: &HidDevice, brightness: u8, color: u32) -> HidResult<()> {
     // TODO: reset
@@ -54,6 +54,9 @@ fn lightguide(device: &HidDevice, brightness: u8, color: u32) -> HidResult<()> {
     Ok(())
 }
 
+/// Whether the keyboard backlight is provided by the `system76_acpi` driver.
+pub fn is_supported() -> bool { KeyboardBacklight::new().is_some() }
+
 // TODO: better error handling
 pub fn daemon() {
     let api = match HidApi::new() {
@@ -64,11 +67,15 @@ pub fn daemon() {
         }
     };
 
-    let dir = Path::new("/sys/class/leds/system76_acpi::kbd_backlight");
-    if !dir.is_dir() {
-        log::error!("hid_backlight: no system76_acpi::kbd_backlight led");
-        return;
-    }
+    let backlight = match KeyboardBacklight::new() {
+        Some(backlight) => backlight,
+        None => {
+            log::info!("hid_backlight: no keyboard backlight led");
+            return;
+        }
+    };
+
+    let dir = backlight.path();
 
     // TODO: check for existence of files
     let brightness_file = dir.join("brightness");
@@ -82,11 +89,8 @@ pub fn daemon() {
 
     let mut buffer = [0; 1024];
     loop {
-        let brightness_string = fs::read_to_string(&brightness_file).unwrap();
-        let brightness = brightness_string.trim().parse::<u8>().unwrap();
-
-        let color_string = fs::read_to_string(&color_file).unwrap();
-        let color = u32::from_str_radix(color_string.trim(), 16).unwrap();
+        let brightness = backlight.brightness().unwrap() as u8;
+        let color = backlight.color().unwrap();
 
         let mut devices = 0;
 
@@ -0,0 +1,266 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Notifies configured targets of events, such as profile changes, with a JSON payload, so that
+//! home automation and fleet management systems can react without polling.
+//!
+//! A target is either an `http://` URL, which the payload is posted to, or a program, which is
+//! run with the payload on its standard input. Targets are notified in order on a thread of their
+//! own, so that a slow target cannot hold up the daemon.
+
+use crate::{
+    config::HooksConfig,
+    program::{self, ProgramError},
+};
+use serde::Deserialize;
+use std::{
+    convert::TryFrom,
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::mpsc::{self, Sender},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+
+// How far, in millidegrees Celsius, the temperature must fall below the critical temperature
+// before the `thermal-critical` event may be sent again.
+const CRITICAL_HYSTERESIS: u32 = 5000;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum HookEvent {
+    ProfileChanged,
+    ThermalCritical,
+    GraphicsSwitched,
+}
+
+impl HookEvent {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HookEvent::ProfileChanged => "profile-changed",
+            HookEvent::ThermalCritical => "thermal-critical",
+            HookEvent::GraphicsSwitched => "graphics-switched",
+        }
+    }
+}
+
+/// Where an event is sent, written as an `http://` URL or the absolute path of a program.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub enum HookTarget {
+    Http { host: String, port: u16, path: String },
+    Exec(PathBuf),
+}
+
+impl TryFrom<String> for HookTarget {
+    type Error = String;
+
+    fn try_from(target: String) -> Result<Self, Self::Error> {
+        if target.starts_with('/') {
+            return Ok(HookTarget::Exec(PathBuf::from(target)));
+        }
+
+        if target.starts_with("https://") {
+            return Err(format!(
+                "`{}`: https is not supported, run a program such as curl instead",
+                target
+            ));
+        }
+
+        let url = target.strip_prefix("http://").ok_or_else(|| {
+            format!("invalid target `{}`, expected an http:// URL or an absolute path", target)
+        })?;
+
+        let (authority, path) = match url.find('/') {
+            Some(pos) => (&url[..pos], &url[pos..]),
+            None => (url, "/"),
+        };
+
+        let (host, port) = match authority.rfind(':') {
+            Some(pos) => {
+                let port = authority[pos + 1..]
+                    .parse()
+                    .map_err(|_| format!("invalid port in target `{}`", target))?;
+                (&authority[..pos], port)
+            }
+            None => (authority, 80),
+        };
+
+        if host.is_empty() {
+            return Err(format!("missing host in target `{}`", target));
+        }
+
+        Ok(HookTarget::Http { host: host.to_owned(), port, path: path.to_owned() })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum HookError {
+    #[error("failed to connect to {}: {}", _0, _1)]
+    Connect(String, io::Error),
+    #[error("failed to post to {}: {}", _0, _1)]
+    Post(String, io::Error),
+    #[error("{} responded with `{}`", _0, _1)]
+    Status(String, String),
+    #[error("{}", _0)]
+    Exec(ProgramError),
+}
+
+fn post(host: &str, port: u16, path: &str, body: &str, timeout: Duration) -> Result<(), HookError> {
+    let name = format!("{}:{}", host, port);
+    let connect_error = |why| HookError::Connect(name.clone(), why);
+
+    let addr = (host, port)
+        .to_socket_addrs()
+        .map_err(connect_error)?
+        .next()
+        .ok_or_else(|| connect_error(io::ErrorKind::NotFound.into()))?;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout).map_err(connect_error)?;
+
+    let post_error = |why| HookError::Post(name.clone(), why);
+    stream.set_read_timeout(Some(timeout)).map_err(post_error)?;
+    stream.set_write_timeout(Some(timeout)).map_err(post_error)?;
+
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: \
+         {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes()).map_err(post_error)?;
+
+    // Only the status line of the response matters.
+    let mut response = [0; 256];
+    let read = stream.read(&mut response).map_err(post_error)?;
+    let response = String::from_utf8_lossy(&response[..read]);
+    let status = response.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1).map_or(false, |code| code.starts_with('2')) {
+        Ok(())
+    } else {
+        Err(HookError::Status(name, status.to_owned()))
+    }
+}
+
+fn exec(program: &Path, event: HookEvent, body: &str, timeout: Duration) -> Result<(), HookError> {
+    let mut command = program::command(program);
+    command
+        .env("SYSTEM76_POWER_EVENT", event.as_str())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+
+    program::run(program, &mut command, body.as_bytes(), timeout).map_err(HookError::Exec)
+}
+
+#[derive(Default)]
+pub struct Hooks {
+    config:   HooksConfig,
+    /// Whether the temperature is critical, so that the event is only sent once it becomes so.
+    critical: bool,
+    sender:   Option<Sender<(HookEvent, String)>>,
+}
+
+impl Hooks {
+    pub fn new(config: HooksConfig) -> Self { Hooks { config, critical: false, sender: None } }
+
+    pub fn profile_changed(&mut self, profile: &str) {
+        self.notify(HookEvent::ProfileChanged, serde_json::json!({ "profile": profile }));
+    }
+
+    /// Records the highest temperature, in millidegrees Celsius, sending the `thermal-critical`
+    /// event once it reaches the critical temperature.
+    pub fn temperature(&mut self, temp: u32) {
+        let critical = u32::from(self.config.critical) * 1000;
+        if self.critical {
+            self.critical = temp + CRITICAL_HYSTERESIS >= critical;
+            return;
+        }
+
+        if temp >= critical {
+            self.critical = true;
+            let celsius = f64::from(temp) / 1000.0;
+            self.notify(HookEvent::ThermalCritical, serde_json::json!({ "celsius": celsius }));
+        }
+    }
+
+    pub fn graphics_switched(&mut self, vendor: &str) {
+        self.notify(HookEvent::GraphicsSwitched, serde_json::json!({ "vendor": vendor }));
+    }
+
+    fn notify(&mut self, event: HookEvent, mut payload: serde_json::Value) {
+        if !self.config.targets.iter().any(|target| target.wants(event)) {
+            return;
+        }
+
+        let timestamp =
+            SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+        payload["event"] = event.as_str().into();
+        payload["timestamp"] = timestamp.into();
+
+        let config = &self.config;
+        let sender = self.sender.get_or_insert_with(|| {
+            let targets = config.targets.clone();
+            let timeout = Duration::from_secs(config.timeout);
+            let (sender, receiver) = mpsc::channel::<(HookEvent, String)>();
+            thread::spawn(move || {
+                for (event, body) in receiver {
+                    for target in targets.iter().filter(|target| target.wants(event)) {
+                        let res = match target.target {
+                            HookTarget::Http { ref host, port, ref path } => {
+                                post(host, port, path, &body, timeout)
+                            }
+                            HookTarget::Exec(ref program) => exec(program, event, &body, timeout),
+                        };
+
+                        if let Err(why) = res {
+                            log::warn!("{} hook: {}", event.as_str(), why);
+                        }
+                    }
+                }
+            });
+            sender
+        });
+
+        let _ = sender.send((event, payload.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(target: &str) -> Result<HookTarget, String> {
+        HookTarget::try_from(target.to_owned())
+    }
+
+    #[test]
+    fn targets() {
+        assert_eq!(
+            target("http://192.168.1.10:8123/api/webhook/laptop"),
+            Ok(HookTarget::Http {
+                host: "192.168.1.10".into(),
+                port: 8123,
+                path: "/api/webhook/laptop".into(),
+            })
+        );
+        assert_eq!(
+            target("http://fleet.local"),
+            Ok(HookTarget::Http { host: "fleet.local".into(), port: 80, path: "/".into() })
+        );
+        assert_eq!(
+            target("/usr/local/bin/notify"),
+            Ok(HookTarget::Exec(PathBuf::from("/usr/local/bin/notify")))
+        );
+
+        assert!(target("https://fleet.local/hook").is_err());
+        assert!(target("http://:80/").is_err());
+        assert!(target("http://fleet.local:http/").is_err());
+        assert!(target("notify").is_err());
+    }
+}
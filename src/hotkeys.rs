@@ -0,0 +1,204 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Listens for the fan boost and performance hotkeys on evdev devices, so that they work on any
+//! desktop rather than only where a vendor extension handles them.
+//!
+//! Only devices which report one of the configured key codes are opened. The events are read
+//! without grabbing the devices, so the keys still reach the desktop as well.
+
+use crate::config::HotkeysConfig;
+use libc::{c_ulong, input_event, O_CLOEXEC, O_NONBLOCK, O_RDONLY};
+use std::{
+    ffi::CString,
+    fs, io, mem,
+    os::unix::{
+        ffi::OsStrExt,
+        io::{AsRawFd, RawFd},
+    },
+    path::{Path, PathBuf},
+};
+
+const INPUT_DIR: &str = "/dev/input";
+
+const EV_KEY: u16 = 0x01;
+const KEY_MAX: usize = 0x2ff;
+
+// Key event values.
+const KEY_PRESSED: i32 = 1;
+
+/// An action which is bound to a key.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hotkey {
+    /// Toggles running the fans at full speed.
+    FanBoost,
+    /// Toggles between the performance profile and the previous profile.
+    PerformanceToggle,
+}
+
+impl Hotkey {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Hotkey::FanBoost => "fan-boost",
+            Hotkey::PerformanceToggle => "performance-toggle",
+        }
+    }
+}
+
+/// Key codes, from `linux/input-event-codes.h`, which trigger each action.
+#[derive(Clone, Debug)]
+pub struct Bindings {
+    pub fan_boost:          Option<u16>,
+    pub performance_toggle: Option<u16>,
+}
+
+impl Bindings {
+    pub fn new(config: &HotkeysConfig) -> Bindings {
+        let bound = |code| if code == 0 { None } else { Some(code) };
+        Bindings {
+            fan_boost:          bound(config.fan_boost),
+            performance_toggle: bound(config.performance_toggle),
+        }
+    }
+
+    fn hotkey(&self, code: u16) -> Option<Hotkey> {
+        if self.fan_boost == Some(code) {
+            Some(Hotkey::FanBoost)
+        } else if self.performance_toggle == Some(code) {
+            Some(Hotkey::PerformanceToggle)
+        } else {
+            None
+        }
+    }
+
+    fn codes(&self) -> impl Iterator<Item = u16> {
+        self.fan_boost.into_iter().chain(self.performance_toggle)
+    }
+}
+
+/// `EVIOCGBIT(EV_KEY, len)`, which reads the bitmap of keys that a device reports.
+fn eviocgbit_key(len: usize) -> c_ulong {
+    const IOC_READ: c_ulong = 2;
+    (IOC_READ << 30)
+        | ((len as c_ulong) << 16)
+        | ((b'E' as c_ulong) << 8)
+        | (0x20 + EV_KEY as c_ulong)
+}
+
+pub struct HotkeyDevice {
+    fd:   RawFd,
+    path: PathBuf,
+}
+
+impl HotkeyDevice {
+    fn open(path: &Path) -> io::Result<HotkeyDevice> {
+        let cpath = CString::new(path.as_os_str().as_bytes())
+            .map_err(|why| io::Error::new(io::ErrorKind::InvalidInput, why))?;
+
+        let fd = unsafe { libc::open(cpath.as_ptr(), O_RDONLY | O_NONBLOCK | O_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(HotkeyDevice { fd, path: path.to_owned() })
+    }
+
+    pub fn path(&self) -> &Path { &self.path }
+
+    fn reports_any(&self, codes: impl Iterator<Item = u16>) -> io::Result<bool> {
+        let mut bits = [0u8; KEY_MAX / 8 + 1];
+        let res = unsafe { libc::ioctl(self.fd, eviocgbit_key(bits.len()), bits.as_mut_ptr()) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut codes = codes.map(usize::from).filter(|&code| code <= KEY_MAX);
+        Ok(codes.any(|code| bits[code / 8] & (1 << (code % 8)) != 0))
+    }
+
+    /// Reads all queued events, returning the hotkeys which were pressed.
+    pub fn hotkeys(&self, bindings: &Bindings) -> io::Result<Vec<Hotkey>> {
+        let mut hotkeys = Vec::new();
+        let mut events: [input_event; 16] = unsafe { mem::zeroed() };
+        let size = mem::size_of::<input_event>();
+
+        loop {
+            let read = unsafe {
+                libc::read(self.fd, events.as_mut_ptr() as *mut libc::c_void, size * events.len())
+            };
+
+            if read == 0 {
+                return Ok(hotkeys);
+            }
+
+            if read < 0 {
+                let why = io::Error::last_os_error();
+                if why.kind() == io::ErrorKind::WouldBlock {
+                    return Ok(hotkeys);
+                }
+
+                return Err(why);
+            }
+
+            for event in &events[..read as usize / size] {
+                if event.type_ == EV_KEY && event.value == KEY_PRESSED {
+                    hotkeys.extend(bindings.hotkey(event.code));
+                }
+            }
+        }
+    }
+}
+
+impl AsRawFd for HotkeyDevice {
+    fn as_raw_fd(&self) -> RawFd { self.fd }
+}
+
+impl Drop for HotkeyDevice {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Opens every event device which reports one of the bound keys.
+pub fn devices(bindings: &Bindings) -> Vec<HotkeyDevice> {
+    if bindings.codes().next().is_none() {
+        return Vec::new();
+    }
+
+    let entries = match fs::read_dir(INPUT_DIR) {
+        Ok(entries) => entries,
+        Err(why) => {
+            log::warn!("failed to read {}: {}", INPUT_DIR, why);
+            return Vec::new();
+        }
+    };
+
+    entries
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("event"))
+        .filter_map(|entry| {
+            let device = HotkeyDevice::open(&entry.path())
+                .map_err(|why| log::debug!("failed to open {}: {}", entry.path().display(), why))
+                .ok()?;
+
+            match device.reports_any(bindings.codes()) {
+                Ok(true) => Some(device),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ioctl_request() {
+        // EVIOCGBIT(EV_KEY, 96), as computed by the kernel's macros.
+        assert_eq!(eviocgbit_key(96), 0x8060_4521);
+    }
+}
@@ -0,0 +1,88 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Identifies the daemon and the hardware it runs on, so that bug reports and GUIs can show
+//! exactly what they are talking to.
+
+use crate::{fan::FanCurve, hotplug};
+use std::{collections::HashMap, fs};
+
+const DMI: &str = "/sys/class/dmi/id";
+
+// The DMI fields which identify the model and its firmware.
+const DMI_FIELDS: &[&str] = &[
+    "sys_vendor",
+    "product_name",
+    "product_version",
+    "board_vendor",
+    "board_name",
+    "bios_vendor",
+    "bios_version",
+    "bios_date",
+];
+
+fn read_dmi(field: &str) -> Option<String> {
+    let value = fs::read_to_string([DMI, field].join("/")).ok()?;
+    let value = value.trim();
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_owned())
+    }
+}
+
+pub fn version() -> &'static str { env!("CARGO_PKG_VERSION") }
+
+/// The cargo features which the daemon was built with.
+pub fn features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(feature = "profiling") {
+        features.push("profiling");
+    }
+
+    features
+}
+
+/// The DMI fields which the firmware provides.
+pub fn dmi() -> HashMap<String, String> {
+    DMI_FIELDS.iter().filter_map(|&field| Some((field.to_owned(), read_dmi(field)?))).collect()
+}
+
+/// The release of the embedded controller firmware, as reported in DMI.
+pub fn ec_version() -> Option<String> { read_dmi("ec_firmware_release") }
+
+/// The model-specific behaviors which apply to a model.
+pub fn quirks(model: &str, model_profiles: bool) -> Vec<String> {
+    let mut quirks = Vec::new();
+    if let Some((name, _)) = FanCurve::for_model(model) {
+        quirks.push(format!("fan-curve-{}", name));
+    }
+
+    if model_profiles {
+        quirks.push("model-power-limits".to_owned());
+    }
+
+    if hotplug::REQUIRES_NVIDIA.contains(&model) {
+        quirks.push("external-displays-require-dgpu".to_owned());
+    }
+
+    quirks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn model_quirks() {
+        assert_eq!(quirks("thelio-major-r1", false), vec!["fan-curve-threadripper2"]);
+        assert_eq!(
+            quirks("gaze15", true),
+            vec!["model-power-limits", "external-displays-require-dgpu"]
+        );
+        assert_eq!(quirks("thelio-r3", false), vec!["fan-curve-desktop"]);
+        assert!(quirks("lemp10", false).is_empty());
+        assert!(quirks("", false).is_empty());
+    }
+}
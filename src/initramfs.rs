@@ -0,0 +1,188 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Rebuilds the initramfs, which holds the module configuration of the graphics mode, with the
+//! tool of the distribution.
+//!
+//! The tool is detected by which is installed, preferring the tools which distributions install
+//! alongside another, unless the `initramfs` setting of the `graphics` section names one.
+
+use crate::backend::{CommandBackend, FileBackend};
+use serde::Deserialize;
+use std::{
+    env,
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+};
+use thiserror::Error;
+
+const OS_RELEASE: &str = "/proc/sys/kernel/osrelease";
+
+// Where Arch Linux records the package of each kernel, such as `linux-lts`, in `pkgbase`.
+const MODULES_DIR: &str = "/usr/lib/modules";
+
+#[derive(Debug, Error)]
+pub enum InitramfsError {
+    #[error("failed to run {}: {}", _0, _1)]
+    Command(&'static str, std::io::Error),
+    #[error("{} failed with {} status", _0, _1)]
+    Failed(&'static str, ExitStatus),
+}
+
+/// A tool which rebuilds the initramfs.
+pub trait InitramfsBackend: Sync {
+    /// The program and its arguments.
+    fn command(&self) -> (&'static str, &'static [&'static str]);
+
+    /// Whether the tool is installed, and so is presumably the tool of the distribution.
+    fn is_installed(&self) -> bool { is_installed(self.command().0, env::var_os("PATH")) }
+
+    /// Rebuilds the initramfs, which may take a minute.
+    fn rebuild(&self, commands: &dyn CommandBackend) -> Result<(), InitramfsError> {
+        let (cmd, args) = self.command();
+        log::info!("Updating initramfs with {}", cmd);
+        let status = commands.run(cmd, args).map_err(|why| InitramfsError::Command(cmd, why))?;
+        if !status.success() {
+            return Err(InitramfsError::Failed(cmd, status));
+        }
+
+        Ok(())
+    }
+}
+
+/// Fedora, and others which may also ship `update-initramfs` as a wrapper.
+pub struct Dracut;
+
+impl InitramfsBackend for Dracut {
+    fn command(&self) -> (&'static str, &'static [&'static str]) { ("dracut", &["--force"]) }
+}
+
+/// Debian, Ubuntu, and Pop!_OS.
+pub struct UpdateInitramfs;
+
+impl InitramfsBackend for UpdateInitramfs {
+    fn command(&self) -> (&'static str, &'static [&'static str]) { ("update-initramfs", &["-u"]) }
+}
+
+/// Arch Linux, which rebuilds the images of every preset.
+pub struct Mkinitcpio;
+
+impl InitramfsBackend for Mkinitcpio {
+    fn command(&self) -> (&'static str, &'static [&'static str]) { ("mkinitcpio", &["-P"]) }
+}
+
+/// Booster, which regenerates the image of each installed kernel through the script that its
+/// package hooks use.
+pub struct Booster;
+
+impl InitramfsBackend for Booster {
+    fn command(&self) -> (&'static str, &'static [&'static str]) {
+        ("/usr/lib/booster/regenerate_images", &[])
+    }
+}
+
+// In order of preference, where more than one is installed.
+const DETECTED: [&dyn InitramfsBackend; 3] = [&Dracut, &Mkinitcpio, &Booster];
+
+/// The tool which rebuilds the initramfs, as given by the `initramfs` setting.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum InitramfsTool {
+    Dracut,
+    UpdateInitramfs,
+    Mkinitcpio,
+    Booster,
+}
+
+impl InitramfsTool {
+    pub fn backend(self) -> &'static dyn InitramfsBackend {
+        match self {
+            InitramfsTool::Dracut => &Dracut,
+            InitramfsTool::UpdateInitramfs => &UpdateInitramfs,
+            InitramfsTool::Mkinitcpio => &Mkinitcpio,
+            InitramfsTool::Booster => &Booster,
+        }
+    }
+}
+
+/// The configured tool, or else the first installed tool, falling back to `update-initramfs` so
+/// that its absence is reported when it runs.
+pub fn backend(tool: Option<InitramfsTool>) -> &'static dyn InitramfsBackend {
+    match tool {
+        Some(tool) => tool.backend(),
+        None => DETECTED
+            .iter()
+            .copied()
+            .find(|backend| backend.is_installed())
+            .unwrap_or(&UpdateInitramfs),
+    }
+}
+
+/// The initramfs image of the running kernel, where it is found at the path which one of the
+/// tools writes it to.
+pub fn image(files: &dyn FileBackend) -> Option<PathBuf> {
+    let read = |path: &Path| {
+        let text = String::from_utf8(files.read(path).ok()?).ok()?;
+        Some(text.trim().to_owned())
+    };
+
+    let release = read(Path::new(OS_RELEASE))?;
+    let package = read(&Path::new(MODULES_DIR).join(&release).join("pkgbase"));
+    image_paths(&release, package.as_deref()).into_iter().find(|path| files.modified(path).is_ok())
+}
+
+fn image_paths(release: &str, package: Option<&str>) -> Vec<PathBuf> {
+    let mut paths = vec![
+        PathBuf::from(format!("/boot/initrd.img-{}", release)),
+        PathBuf::from(format!("/boot/initramfs-{}.img", release)),
+    ];
+
+    // mkinitcpio and booster name images after the package of the kernel, rather than the
+    // release, which is not known without it.
+    if let Some(package) = package {
+        paths.push(PathBuf::from(format!("/boot/initramfs-{}.img", package)));
+        paths.push(PathBuf::from(format!("/boot/booster-{}.img", package)));
+    }
+
+    paths
+}
+
+/// Whether a program is an absolute path which exists, or is found in one of the directories of
+/// `PATH`.
+fn is_installed<P: AsRef<OsStr>>(program: &str, path: Option<P>) -> bool {
+    if Path::new(program).is_absolute() {
+        return Path::new(program).exists();
+    }
+
+    path.map_or(false, |path| env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn installed() {
+        let dir = env::temp_dir().join(format!("system76-power-initramfs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("mkinitcpio"), "").unwrap();
+
+        let path = env::join_paths(vec![Path::new("/nonexistent"), &dir]).unwrap();
+        assert!(is_installed("mkinitcpio", Some(&path)));
+        assert!(!is_installed("dracut", Some(&path)));
+        assert!(!is_installed("mkinitcpio", None::<&OsStr>));
+        assert!(!is_installed("/nonexistent/regenerate_images", Some(&path)));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn images() {
+        let paths = image_paths("6.6.30-1-lts", Some("linux-lts"));
+        assert!(paths.contains(&PathBuf::from("/boot/initramfs-linux-lts.img")));
+        assert!(!paths.contains(&PathBuf::from("/boot/initramfs-linux.img")));
+        assert_eq!(image_paths("6.6.30-1-lts", None).len(), 2);
+    }
+}
@@ -7,6 +7,7 @@ pub use sysfs_class::RuntimePowerManagement;
 
 use std::{
     fs::{read_to_string, write},
+    io,
     path::{Path, PathBuf},
     str,
 };
@@ -34,6 +35,17 @@ pub trait KernelParameter {
         None
     }
 
+    /// Sets the value, returning the error rather than logging it.
+    fn try_set(&self, value: &[u8]) -> io::Result<()> {
+        let path = self.get_path();
+        log::debug!(
+            "Modifying kernel parameter at {:?} to {}",
+            path,
+            str::from_utf8(value).unwrap_or("[INVALID UTF8]")
+        );
+        write(path, value)
+    }
+
     fn set(&self, value: &[u8]) {
         let path = self.get_path();
         if path.exists() {
@@ -0,0 +1,187 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Attributes of sysfs and sysctl which are set with each profile, from the `[[knobs]]` tables
+//! of the configuration, so that hardware may be tuned before it is supported by the daemon.
+//!
+//! A profile which leaves a knob unset restores the value it held before the daemon first set
+//! it. Each value is read back to verify that the kernel accepted it.
+
+use crate::{
+    config::Profile,
+    verify::{self, VerifyError},
+};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    path::{Component, PathBuf},
+};
+
+// Knobs may only be attributes of the kernel, rather than arbitrary files.
+const ALLOWED_ROOTS: &[&str] = &["/sys/", "/proc/sys/"];
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KnobTable {
+    path:        String,
+    battery:     Option<String>,
+    balanced:    Option<String>,
+    performance: Option<String>,
+}
+
+/// An attribute, and the value it is set to with each profile.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "KnobTable")]
+pub struct Knob {
+    path:        PathBuf,
+    battery:     Option<String>,
+    balanced:    Option<String>,
+    performance: Option<String>,
+}
+
+impl TryFrom<KnobTable> for Knob {
+    type Error = String;
+
+    fn try_from(table: KnobTable) -> Result<Self, Self::Error> {
+        let path = PathBuf::from(&table.path);
+        let parent_dir = path.components().any(|component| component == Component::ParentDir);
+        if parent_dir || !ALLOWED_ROOTS.iter().any(|root| table.path.starts_with(root)) {
+            return Err(format!(
+                "invalid knob `{}`, expected a path in {}",
+                table.path,
+                ALLOWED_ROOTS.join(" or ")
+            ));
+        }
+
+        for value in [&table.battery, &table.balanced, &table.performance].iter().copied().flatten()
+        {
+            if value.is_empty() || value.contains('\n') {
+                return Err(format!("invalid value {:?} for knob `{}`", value, table.path));
+            }
+        }
+
+        Ok(Knob {
+            path,
+            battery: table.battery,
+            balanced: table.balanced,
+            performance: table.performance,
+        })
+    }
+}
+
+impl Knob {
+    fn value(&self, profile: Profile) -> Option<&str> {
+        match profile {
+            Profile::Battery => self.battery.as_deref(),
+            Profile::Balanced => self.balanced.as_deref(),
+            Profile::Performance => self.performance.as_deref(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Knobs {
+    knobs: Vec<Knob>,
+    /// The value of each knob before it was first set, which is restored once a profile leaves
+    /// it unset.
+    saved: HashMap<PathBuf, String>,
+}
+
+impl Knobs {
+    pub fn new(knobs: Vec<Knob>) -> Self { Knobs { knobs, saved: HashMap::new() } }
+
+    pub fn is_empty(&self) -> bool { self.knobs.is_empty() }
+
+    /// Sets or restores every knob for a profile. A knob which fails does not stop the others
+    /// from being set.
+    pub fn apply(&mut self, profile: Profile) -> Vec<VerifyError> {
+        let mut errors = Vec::new();
+        for knob in &self.knobs {
+            let result = match knob.value(profile) {
+                Some(value) => {
+                    if !self.saved.contains_key(&knob.path) {
+                        match verify::read(&knob.path) {
+                            Ok(original) => {
+                                self.saved.insert(knob.path.clone(), original);
+                            }
+                            Err(why) => {
+                                errors.push(why);
+                                continue;
+                            }
+                        }
+                    }
+
+                    log::debug!("setting {} to {}", knob.path.display(), value);
+                    verify::write(&knob.path, value)
+                }
+                None => match self.saved.remove(&knob.path) {
+                    Some(original) => {
+                        log::debug!("restoring {} to {}", knob.path.display(), original);
+                        verify::write(&knob.path, &original)
+                    }
+                    None => Ok(()),
+                },
+            };
+
+            if let Err(why) = result {
+                errors.push(why);
+            }
+        }
+
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, process};
+
+    fn table(path: &str, battery: Option<&str>) -> KnobTable {
+        KnobTable {
+            path:        path.to_owned(),
+            battery:     battery.map(str::to_owned),
+            balanced:    None,
+            performance: None,
+        }
+    }
+
+    #[test]
+    fn validation() {
+        let knob = Knob::try_from(table("/sys/devices/platform/foo/bar", Some("1"))).unwrap();
+        assert_eq!(knob.value(Profile::Battery), Some("1"));
+        assert_eq!(knob.value(Profile::Balanced), None);
+        assert!(Knob::try_from(table("/proc/sys/vm/laptop_mode", None)).is_ok());
+
+        assert!(Knob::try_from(table("/etc/shadow", Some("1"))).is_err());
+        assert!(Knob::try_from(table("/sys/../etc/shadow", Some("1"))).is_err());
+        assert!(Knob::try_from(table("sys/foo", Some("1"))).is_err());
+        assert!(Knob::try_from(table("/sys/foo", Some("1\n2"))).is_err());
+        assert!(Knob::try_from(table("/sys/foo", Some(""))).is_err());
+    }
+
+    #[test]
+    fn restore_on_switch() {
+        let path = std::env::temp_dir().join(format!("system76-power-knob-{}", process::id()));
+        fs::write(&path, "0\n").unwrap();
+
+        let knob = Knob {
+            path:        path.clone(),
+            battery:     Some("1".to_owned()),
+            balanced:    None,
+            performance: Some("2".to_owned()),
+        };
+        let mut knobs = Knobs::new(vec![knob]);
+
+        assert!(knobs.apply(Profile::Battery).is_empty());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1");
+        assert!(knobs.apply(Profile::Performance).is_empty());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "2");
+        assert!(knobs.apply(Profile::Balanced).is_empty());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "0");
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -6,26 +6,66 @@
 #![allow(clippy::missing_safety_doc)]
 
 pub mod acpi_platform;
+pub mod adapter;
+pub mod backend;
+pub mod capabilities;
 pub mod charge_thresholds;
+pub mod clamshell;
 pub mod client;
+pub mod config;
+pub mod consumers;
 pub mod daemon;
+pub mod devices;
 pub mod disks;
+pub mod drm;
+pub mod ec;
 pub mod errors;
+pub mod export;
 pub mod fan;
 pub mod graphics;
+pub mod health;
+pub mod hibernate;
 pub mod hid_backlight;
+pub mod hooks;
+pub mod hotkeys;
 pub mod hotplug;
+pub mod info;
+pub mod initramfs;
 pub mod kernel_parameters;
+pub mod knobs;
 pub mod logging;
+pub mod logind;
 pub mod modprobe;
 pub mod module;
 pub mod mux;
+pub mod nvml;
 pub mod pci;
 pub mod polkit;
+pub mod power_limit;
+pub mod power_profiles;
+pub mod power_source;
+pub mod privileges;
+#[cfg(feature = "profiling")]
+pub mod profiling;
+pub mod program;
+pub mod quiet_hours;
 pub mod radeon;
+pub mod s0ix;
+pub mod safe_mode;
+pub mod safety;
+pub mod sched;
+pub mod sensors;
+pub mod service;
+pub mod setup;
 pub mod sideband;
 pub mod snd;
+pub mod state;
+pub mod switcheroo;
+pub mod temperature;
+pub mod uevent;
+pub mod upower;
 pub mod util;
+pub mod verify;
 pub mod wifi;
 
 use charge_thresholds::ChargeProfile;
@@ -33,6 +73,8 @@ use charge_thresholds::ChargeProfile;
 pub static DBUS_NAME: &str = "com.system76.PowerDaemon";
 pub static DBUS_PATH: &str = "/com/system76/PowerDaemon";
 pub static DBUS_IFACE: &str = "com.system76.PowerDaemon";
+/// The current version of the interface, served alongside the original one.
+pub static DBUS_IFACE_V2: &str = "com.system76.PowerDaemon2";
 
 pub trait Power {
     fn performance(&mut self) -> Result<(), String>;
@@ -47,9 +89,11 @@ pub trait Power {
     fn get_graphics_power(&mut self) -> Result<bool, String>;
     fn set_graphics_power(&mut self, power: bool) -> Result<(), String>;
     fn auto_graphics_power(&mut self) -> Result<(), String>;
+    fn force_graphics_power_off(&mut self, device: &str) -> Result<(), String>;
     fn get_charge_thresholds(&mut self) -> Result<(u8, u8), String>;
     fn set_charge_thresholds(&mut self, thresholds: (u8, u8)) -> Result<(), String>;
     fn get_charge_profiles(&mut self) -> Result<Vec<ChargeProfile>, String>;
+    fn get_capabilities(&mut self) -> Result<Vec<String>, String>;
 }
 
 // Helper function for errors
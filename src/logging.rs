@@ -2,18 +2,79 @@
 //
 // SPDX-License-Identifier: GPL-3.0-only
 
-use fern::{Dispatch, InitError};
-use log::LevelFilter;
-use std::io;
+use fern::{Dispatch, InitError, Output};
+use log::{Level, LevelFilter, Record};
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    io, ptr,
+    sync::{
+        atomic::{AtomicPtr, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// How many of the most recent warnings and errors are kept for the health endpoint.
+const RECENT_ERRORS: usize = 20;
+
+/// A warning or error which was logged by the daemon.
+#[derive(Clone, Debug, Serialize)]
+pub struct LoggedError {
+    /// Seconds since the Unix epoch.
+    pub time:    u64,
+    pub level:   &'static str,
+    pub message: String,
+}
+
+type RecentErrors = Mutex<VecDeque<LoggedError>>;
+
+// Set once by `setup`, and never freed.
+static RECENT: AtomicPtr<RecentErrors> = AtomicPtr::new(ptr::null_mut());
 
 pub fn setup(filter: LevelFilter) -> Result<(), InitError> {
+    let recent: &'static RecentErrors = Box::leak(Box::new(Mutex::new(VecDeque::new())));
+    RECENT.store(recent as *const RecentErrors as *mut RecentErrors, Ordering::SeqCst);
+
     Dispatch::new()
         // Exclude logs for crates that we use
         .level(LevelFilter::Off)
         // Include only the logs for this binary
         .level_for("system76_power", filter)
-        .format(|out, message, record| out.finish(format_args!("[{}] {}", record.level(), message)))
-        .chain(io::stderr())
+        .chain(
+            Dispatch::new()
+                .format(|out, message, record| {
+                    out.finish(format_args!("[{}] {}", record.level(), message))
+                })
+                .chain(io::stderr()),
+        )
+        .chain(
+            Dispatch::new()
+                .level(LevelFilter::Warn)
+                .chain(Output::call(move |record| record_error(recent, record))),
+        )
         .apply()?;
     Ok(())
 }
+
+fn record_error(recent: &RecentErrors, record: &Record) {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    let level = if record.level() == Level::Error { "error" } else { "warning" };
+
+    let mut recent = recent.lock().unwrap();
+    if recent.len() == RECENT_ERRORS {
+        recent.pop_front();
+    }
+
+    recent.push_back(LoggedError { time, level, message: record.args().to_string() });
+}
+
+/// The most recent warnings and errors, oldest first.
+pub fn recent_errors() -> Vec<LoggedError> {
+    let recent = RECENT.load(Ordering::SeqCst);
+    if recent.is_null() {
+        return Vec::new();
+    }
+
+    unsafe { &*recent }.lock().unwrap().iter().cloned().collect()
+}
@@ -0,0 +1,174 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Delays shutdown through logind, so that work which must not be interrupted by a reboot can
+//! be finished first, and follows the idle and lock state of sessions.
+
+use dbus::{
+    arg::{OwnedFd, PropMap, RefArg},
+    message::MatchRule,
+    nonblock::{stdintf::org_freedesktop_dbus::Properties as _, MsgMatch, Proxy, SyncConnection},
+    Message,
+};
+use futures::channel::mpsc::UnboundedReceiver;
+use std::time::Duration;
+
+const LOGIN1_NAME: &str = "org.freedesktop.login1";
+const LOGIN1_PATH: &str = "/org/freedesktop/login1";
+const MANAGER_IFACE: &str = "org.freedesktop.login1.Manager";
+const SEAT_PATH: &str = "/org/freedesktop/login1/seat/seat0";
+const SEAT_IFACE: &str = "org.freedesktop.login1.Seat";
+const SESSION_IFACE: &str = "org.freedesktop.login1.Session";
+const PROPERTIES_IFACE: &str = "org.freedesktop.DBus.Properties";
+
+// The error of `GetSessionByPID` for processes outside of any session, such as services.
+const NO_SESSION_FOR_PID: &str = "org.freedesktop.login1.NoSessionForPID";
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Takes a delay inhibitor lock on shutdown, which is released when the descriptor is dropped.
+///
+/// logind only waits for delay locks for a limited time, configured by `InhibitDelayMaxSec`.
+pub async fn inhibit_shutdown(conn: &SyncConnection, why: &str) -> Result<OwnedFd, dbus::Error> {
+    inhibit(conn, "shutdown", why).await
+}
+
+/// Takes a delay inhibitor lock on sleep, which is released when the descriptor is dropped, so
+/// that the system is not suspended until it has been prepared for it.
+pub async fn inhibit_sleep(conn: &SyncConnection, why: &str) -> Result<OwnedFd, dbus::Error> {
+    inhibit(conn, "sleep", why).await
+}
+
+async fn inhibit(conn: &SyncConnection, what: &str, why: &str) -> Result<OwnedFd, dbus::Error> {
+    let proxy = Proxy::new(LOGIN1_NAME, LOGIN1_PATH, TIMEOUT, conn);
+    let (fd,): (OwnedFd,) =
+        proxy.method_call(MANAGER_IFACE, "Inhibit", (what, "system76-power", why, "delay")).await?;
+    Ok(fd)
+}
+
+/// Subscribes to changes of the properties of logind, which include `IdleHint`. Every session is
+/// idle once it is set, which desktops report after their idle timeout.
+pub async fn watch_idle(
+    conn: &SyncConnection,
+) -> Result<(MsgMatch, UnboundedReceiver<Message>), dbus::Error> {
+    let rule = MatchRule::new_signal(PROPERTIES_IFACE, "PropertiesChanged")
+        .with_sender(LOGIN1_NAME)
+        .with_path(LOGIN1_PATH);
+
+    Ok(conn.add_match(rule).await?.msg_stream())
+}
+
+/// Reads the idle hint from a `PropertiesChanged` signal, if it changed.
+pub fn idle_changed(message: &Message) -> Option<bool> {
+    let (interface, changed): (String, PropMap) = message.read2().ok()?;
+    if interface != MANAGER_IFACE {
+        return None;
+    }
+
+    changed.get("IdleHint")?.0.as_u64().map(|idle| idle != 0)
+}
+
+/// Subscribes to changes of the properties of sessions and seats, which include the `LockedHint`
+/// that desktops set while the screen is locked.
+pub async fn watch_locks(
+    conn: &SyncConnection,
+) -> Result<(MsgMatch, UnboundedReceiver<Message>), dbus::Error> {
+    let rule = MatchRule::new_signal(PROPERTIES_IFACE, "PropertiesChanged")
+        .with_sender(LOGIN1_NAME)
+        .with_namespaced_path(LOGIN1_PATH);
+
+    Ok(conn.add_match(rule).await?.msg_stream())
+}
+
+/// Whether a `PropertiesChanged` signal may have changed whether the active session is locked,
+/// as the `LockedHint` of a session changed, or another session became active.
+pub fn lock_may_have_changed(message: &Message) -> bool {
+    let (interface, changed): (String, PropMap) = match message.read2() {
+        Ok(args) => args,
+        Err(_) => return false,
+    };
+
+    match interface.as_str() {
+        SESSION_IFACE => changed.contains_key("LockedHint"),
+        SEAT_IFACE => changed.contains_key("ActiveSession"),
+        _ => false,
+    }
+}
+
+/// The object path of the active session of the first seat, if any.
+async fn active_session(conn: &SyncConnection) -> Result<Option<dbus::Path<'static>>, dbus::Error> {
+    let proxy = Proxy::new(LOGIN1_NAME, SEAT_PATH, TIMEOUT, conn);
+    let (id, session): (String, dbus::Path<'static>) =
+        proxy.get(SEAT_IFACE, "ActiveSession").await?;
+    Ok(if id.is_empty() { None } else { Some(session) })
+}
+
+/// Whether the active session of the first seat is locked, which it is not if no session is
+/// active.
+pub async fn active_session_locked(conn: &SyncConnection) -> Result<bool, dbus::Error> {
+    match active_session(conn).await? {
+        Some(session) => {
+            Proxy::new(LOGIN1_NAME, session, TIMEOUT, conn).get(SESSION_IFACE, "LockedHint").await
+        }
+        None => Ok(false),
+    }
+}
+
+/// Whether a process belongs to the active session of the first seat.
+pub async fn in_active_session(conn: &SyncConnection, pid: u32) -> Result<bool, dbus::Error> {
+    let active = match active_session(conn).await? {
+        Some(active) => active,
+        None => return Ok(false),
+    };
+
+    let proxy = Proxy::new(LOGIN1_NAME, LOGIN1_PATH, TIMEOUT, conn);
+    let res: Result<(dbus::Path<'static>,), _> =
+        proxy.method_call(MANAGER_IFACE, "GetSessionByPID", (pid,)).await;
+    match res {
+        Ok((session,)) => Ok(session == active),
+        Err(why) if why.name() == Some(NO_SESSION_FOR_PID) => Ok(false),
+        Err(why) => Err(why),
+    }
+}
+
+/// Subscribes to the `PrepareForSleep` signal, whose argument is `true` before suspend, and
+/// `false` on resume.
+pub async fn watch_sleep(
+    conn: &SyncConnection,
+) -> Result<(MsgMatch, UnboundedReceiver<Message>), dbus::Error> {
+    let rule = MatchRule::new_signal(MANAGER_IFACE, "PrepareForSleep")
+        .with_sender(LOGIN1_NAME)
+        .with_path(LOGIN1_PATH);
+
+    Ok(conn.add_match(rule).await?.msg_stream())
+}
+
+/// Subscribes to the `PrepareForShutdown` signal, whose argument is `true` before shutdown, and
+/// `false` if shutdown was cancelled.
+pub async fn watch_shutdown(
+    conn: &SyncConnection,
+) -> Result<(MsgMatch, UnboundedReceiver<Message>), dbus::Error> {
+    let rule = MatchRule::new_signal(MANAGER_IFACE, "PrepareForShutdown")
+        .with_sender(LOGIN1_NAME)
+        .with_path(LOGIN1_PATH);
+
+    Ok(conn.add_match(rule).await?.msg_stream())
+}
+
+/// Sets the brightness of a device on behalf of the active session of the first seat, so that the
+/// session sees the change as its own. This fails when no session is active, or logind predates
+/// `SetBrightness`.
+pub async fn set_brightness(
+    conn: &SyncConnection,
+    subsystem: &str,
+    name: &str,
+    value: u32,
+) -> Result<(), dbus::Error> {
+    let session =
+        active_session(conn).await?.ok_or_else(|| dbus::Error::new_failed("no active session"))?;
+
+    Proxy::new(LOGIN1_NAME, session, TIMEOUT, conn)
+        .method_call(SESSION_IFACE, "SetBrightness", (subsystem, name, value))
+        .await
+}
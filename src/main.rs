@@ -4,10 +4,10 @@
 
 #![deny(clippy::all)]
 
-use clap::{App, AppSettings, Arg, ArgGroup, SubCommand};
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand};
 use log::LevelFilter;
 use std::process;
-use system76_power::{charge_thresholds::get_charge_profiles, client, daemon, logging};
+use system76_power::{charge_thresholds::get_charge_profiles, client, config, daemon, logging};
 
 fn main() {
     let matches = App::new("system76-power")
@@ -39,6 +39,25 @@ fn main() {
                         .help("Set the verbosity of daemon logs to 'debug' [default is 'info']")
                         .global(true)
                         .group("verbosity"),
+                )
+                .arg(
+                    Arg::with_name("trace-chrome")
+                        .long("trace-chrome")
+                        .help("Record tracing spans to a Chrome trace file (profiling builds only)")
+                        .takes_value(true)
+                        .value_name("file")
+                        .group("trace"),
+                )
+                .arg(
+                    Arg::with_name("trace-flame")
+                        .long("trace-flame")
+                        .help(
+                            "Record tracing spans as folded stacks for flamegraphs (profiling \
+                             builds only)",
+                        )
+                        .takes_value(true)
+                        .value_name("file")
+                        .group("trace"),
                 ),
         )
         .subcommand(
@@ -64,10 +83,19 @@ fn main() {
                      graphics profile will be queried\n - Otherwise, that profile will be set, if \
                      it is a valid profile\n\nA reboot is required after switching modes.",
                 )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Show what switching modes would change, without switching"),
+                )
                 .subcommand(
                     SubCommand::with_name("compute")
                         .about("Like integrated, but the dGPU is available for compute"),
                 )
+                .subcommand(
+                    SubCommand::with_name("discrete")
+                        .about("Set the graphics mode to the AMD dGPU exclusively"),
+                )
                 .subcommand(
                     SubCommand::with_name("hybrid")
                         .about("Set the graphics mode to Hybrid (PRIME)"),
@@ -76,13 +104,45 @@ fn main() {
                     SubCommand::with_name("integrated")
                         .about("Set the graphics mode to integrated"),
                 )
+                .subcommand(
+                    SubCommand::with_name("info")
+                        .about("List the IDs, video memory, driver and boot VGA flag of each GPU"),
+                )
+                .subcommand(
+                    SubCommand::with_name("link")
+                        .about("List the PCIe link speed and width of each discrete GPU"),
+                )
                 .subcommand(
                     SubCommand::with_name("nvidia").about("Set the graphics mode to NVIDIA"),
                 )
+                .subcommand(
+                    SubCommand::with_name("power-states").about(
+                        "List the runtime power state and driver of each function of each GPU",
+                    ),
+                )
+                .subcommand(SubCommand::with_name("reconcile").about(
+                    "Rewrite the graphics configuration to match the modules which are loaded",
+                ))
                 .subcommand(
                     SubCommand::with_name("switchable")
                         .about("Determines if the system has switchable graphics"),
                 )
+                .subcommand(
+                    SubCommand::with_name("verify")
+                        .about("Check that the graphics configuration, modules and initramfs agree")
+                        .long_about(
+                            "Compare the graphics mode which was last switched to with \
+                             /etc/prime-discrete, /etc/modprobe.d/system76-power.conf, the NVIDIA \
+                             modules which are loaded, and the initramfs. With --repair, the \
+                             configuration of the mode is rewritten and the initramfs rebuilt. To \
+                             adopt the loaded modules instead, use reconcile.",
+                        )
+                        .arg(
+                            Arg::with_name("repair")
+                                .long("repair")
+                                .help("Rewrite the configuration if problems are found"),
+                        ),
+                )
                 .subcommand(
                     SubCommand::with_name("power")
                         .about("Query or set the discrete graphics power state")
@@ -90,7 +150,29 @@ fn main() {
                             Arg::with_name("state")
                                 .help("Set whether discrete graphics should be on or off")
                                 .possible_values(&["auto", "off", "on"]),
-                        ),
+                        )
+                        .arg(
+                            Arg::with_name("device")
+                                .long("device")
+                                .takes_value(true)
+                                .requires("state")
+                                .help(
+                                    "PCI address of a single discrete GPU, from `graphics \
+                                     topology`",
+                                ),
+                        )
+                        .arg(Arg::with_name("force").long("force").requires("state").help(
+                            "Stop the processes which have the GPU open when turning it off, \
+                             other than the display server, even with external displays connected",
+                        )),
+                )
+                .subcommand(
+                    SubCommand::with_name("topology")
+                        .about("List graphics devices and whether each is integrated or discrete"),
+                )
+                .subcommand(
+                    SubCommand::with_name("vfio")
+                        .about("Bind the NVIDIA dGPU to vfio-pci for passthrough to a VM"),
                 ),
         )
         .subcommand(
@@ -142,6 +224,188 @@ fn main() {
                         .required(false),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("config")
+                .about("Manage the system configuration")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("validate")
+                        .about("Check the configuration file for errors")
+                        .arg(
+                            Arg::with_name("path")
+                                .help(
+                                    "Path to the configuration file [default is \
+                                     /etc/system76-power/config.toml]",
+                                )
+                                .required(false),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Export the current settings, to reproduce them on a new install")
+                .long_about(
+                    "Export the current profile, charge thresholds, graphics mode and \
+                     configuration file, which holds any custom fan curve.\n\nBy default, a shell \
+                     script is written which applies the settings when run as root.",
+                )
+                .group(ArgGroup::with_name("format").arg("shell").arg("toml"))
+                .arg(Arg::with_name("shell").long("shell").help("Export a shell script [default]"))
+                .arg(Arg::with_name("toml").long("toml").help("Export a TOML document")),
+        )
+        .subcommand(
+            SubCommand::with_name("setup")
+                .about("Propose and apply settings suited to this machine")
+                .long_about(
+                    "Detect the hardware, ask how the machine is used, and propose a default \
+                     profile, fan curve, charge thresholds and graphics mode. Once accepted, the \
+                     configuration file is written with comments explaining each setting, keeping \
+                     any previous one as a backup, and the charge thresholds and graphics mode \
+                     are applied.",
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .help(
+                            "Path to write the configuration to [default is \
+                             /etc/system76-power/config.toml]",
+                        )
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("fan")
+                .about("Show temperatures and the fan curve")
+                .arg(
+                    Arg::with_name("unit")
+                        .long("unit")
+                        .help(
+                            "The unit to show temperatures in [default is from the configuration]",
+                        )
+                        .takes_value(true)
+                        .possible_values(&["celsius", "fahrenheit", "c", "f"])
+                        .case_insensitive(true),
+                )
+                .arg(
+                    Arg::with_name("history")
+                        .long("history")
+                        .help("Show the temperatures and fan duty cycles of the last few minutes"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("capabilities")
+                .about("List the features supported by this hardware"),
+        )
+        .subcommand(
+            SubCommand::with_name("info")
+                .about("Show the daemon version and the hardware model, for bug reports"),
+        )
+        .subcommand(
+            SubCommand::with_name("brightness")
+                .about("Step the screen or keyboard brightness up or down")
+                .long_about(
+                    "Step the screen or keyboard brightness up or down, by the steps from the \
+                     configuration, and show the new brightness. Window managers may bind \
+                     brightness keys to this command.",
+                )
+                .arg(
+                    Arg::with_name("device")
+                        .possible_values(&["screen", "keyboard"])
+                        .required(true),
+                )
+                .arg(Arg::with_name("direction").possible_values(&["up", "down"]).required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("keyboard")
+                .about("Query or set the colors of the keyboard zones")
+                .long_about(
+                    "Query or set the colors of the zones of an RGB keyboard, such as `left` or \
+                     `right`, or of every zone with `all`. Keyboards which are lit in a single \
+                     color have one zone, named `main`. The brightness is shared by every zone, \
+                     and is kept unless given.",
+                )
+                .arg(Arg::with_name("zone").help("The zone to set, or `all`"))
+                .arg(
+                    Arg::with_name("color")
+                        .help("The color to set, such as `#FF8000`")
+                        .required(false)
+                        .requires("zone"),
+                )
+                .arg(
+                    Arg::with_name("brightness")
+                        .help("The brightness to set, as a percentage")
+                        .required(false)
+                        .requires("color"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("doctor")
+                .about("Check whether power management features are set up to work")
+                .long_about(
+                    "Check whether power management features are set up to work, such as \
+                     hibernation, which needs swap on disk at least as large as the hibernation \
+                     image, and the device to resume from on the kernel command line. The report \
+                     of the last s2idle suspend is included, as from `suspend-report`.",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("suspend-report")
+                .about("Show how much of the last s2idle suspend was spent in low power states")
+                .long_about(
+                    "Show how much of the last s2idle suspend was spent in SLP_S0 and the deepest \
+                     package C-state. Where SLP_S0 was never reached, the IP blocks which were \
+                     still powered are listed, as any of them may have kept the system awake. \
+                     Listing them requires debugfs and the intel_pmc_core driver.",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("consumers")
+                .about("Show the processes which use the most energy")
+                .long_about(
+                    "Show the processes which used the most CPU time over the last sampling \
+                     interval, with the power of each estimated from its share of the energy of \
+                     the CPU package, and the pressure stall information of the system. Sampling \
+                     must be enabled with `enabled` in the `consumers` section of the \
+                     configuration.",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("throttling")
+                .about("Show how often the CPU and GPUs were throttled")
+                .long_about(
+                    "Show how often the CPU and NVIDIA GPUs were throttled for temperature or \
+                     power since the daemon started, and for how long in total. The CPU is only \
+                     covered where the kernel counts throttling, as on Intel CPUs.",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("runtime-pm")
+                .about("Show how long devices were runtime suspended")
+                .long_about(
+                    "Show whether runtime power management is allowed for each GPU, NVMe drive, \
+                     wireless card and USB 3 controller, its current status, and how long it was \
+                     active and suspended since it was probed, to tell whether runtime power \
+                     management is engaging under the current profile.",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("bench-mode")
+                .about("Query or set benchmark mode")
+                .long_about(
+                    "Query or set benchmark mode, which locks the CPU to its base frequency \
+                     without turbo and holds the fans at a fixed duty cycle, so that benchmarks \
+                     are reproducible.\n\nBenchmark mode turns itself off after the duration, or \
+                     the maximum from the configuration.",
+                )
+                .arg(Arg::with_name("state").possible_values(&["on", "off"]))
+                .arg(
+                    Arg::with_name("duration")
+                        .long("duration")
+                        .help("Minutes to stay in benchmark mode")
+                        .takes_value(true)
+                        .requires("state"),
+                ),
+        )
         .get_matches();
 
     let res = match matches.subcommand() {
@@ -157,12 +421,24 @@ fn main() {
                 process::exit(1);
             }
 
+            let _trace = match setup_tracing(matches) {
+                Ok(guard) => guard,
+                Err(why) => {
+                    eprintln!("failed to set up tracing: {}", why);
+                    process::exit(1);
+                }
+            };
+
             if unsafe { libc::geteuid() } == 0 {
                 daemon::daemon()
             } else {
                 Err("must be run as root".to_string())
             }
         }
+        ("config", Some(matches)) => match matches.subcommand() {
+            ("validate", Some(matches)) => config::validate(matches.value_of("path")),
+            _ => unreachable!(),
+        },
         (subcommand, Some(matches)) => client::client(subcommand, matches),
         _ => unreachable!(),
     };
@@ -175,3 +451,30 @@ fn main() {
         }
     }
 }
+
+#[cfg(feature = "profiling")]
+fn setup_tracing(
+    matches: &ArgMatches,
+) -> Result<Option<system76_power::profiling::TraceGuard>, String> {
+    use std::path::Path;
+    use system76_power::profiling::{self, TraceFormat};
+
+    let (format, path) = if let Some(path) = matches.value_of("trace-chrome") {
+        (TraceFormat::Chrome, path)
+    } else if let Some(path) = matches.value_of("trace-flame") {
+        (TraceFormat::Flame, path)
+    } else {
+        return Ok(None);
+    };
+
+    profiling::setup(format, Path::new(path)).map(Some)
+}
+
+#[cfg(not(feature = "profiling"))]
+fn setup_tracing(matches: &ArgMatches) -> Result<Option<()>, String> {
+    if matches.is_present("trace") {
+        Err("system76-power was built without the `profiling` feature".into())
+    } else {
+        Ok(None)
+    }
+}
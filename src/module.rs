@@ -2,15 +2,30 @@
 //
 // SPDX-License-Identifier: GPL-3.0-only
 
-use std::{fs::read_to_string, io};
+use crate::backend::FileBackend;
+use std::{
+    fs::read_to_string,
+    io,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+/// The modules which are loaded, one per line.
+pub const PROC_MODULES: &str = "/proc/modules";
+
+const MODULES_DIR: &str = "/lib/modules";
+const OS_RELEASE: &str = "/proc/sys/kernel/osrelease";
 
 pub struct Module {
     pub name: String,
 }
 
 impl Module {
-    pub fn all() -> io::Result<Vec<Module>> {
-        read_to_string("/proc/modules")?.lines().map(parse).collect()
+    pub fn all() -> io::Result<Vec<Module>> { Self::parse_all(&read_to_string(PROC_MODULES)?) }
+
+    /// The modules which are listed in the format of `/proc/modules`.
+    pub fn parse_all(modules: &str) -> io::Result<Vec<Module>> {
+        modules.lines().map(parse).collect()
     }
 }
 
@@ -23,3 +38,90 @@ fn parse(line: &str) -> io::Result<Module> {
 
     Ok(Module { name })
 }
+
+/// Whether `modules.dep` lists a module, whose file may be compressed, as in
+/// `updates/dkms/nvidia.ko.zst: ...`.
+fn lists_module(modules_dep: &str, name: &str) -> bool {
+    modules_dep.lines().filter_map(|line| line.split(':').next()).any(|path| {
+        let file = path.rsplit('/').next().unwrap_or(path);
+        file.find(".ko").map_or(false, |end| &file[..end] == name)
+    })
+}
+
+/// Whether a module is built for the running kernel.
+pub fn available(name: &str) -> bool {
+    read_to_string(OS_RELEASE)
+        .and_then(|release| {
+            read_to_string(Path::new(MODULES_DIR).join(release.trim()).join("modules.dep"))
+        })
+        .map_or(false, |dep| lists_module(&dep, name))
+}
+
+/// The releases of the kernels which are installed, with the running kernel first. Directories
+/// of modules which were left behind by removed kernels are skipped.
+pub fn installed_kernels(files: &dyn FileBackend) -> Vec<String> {
+    let running = files
+        .read(Path::new(OS_RELEASE))
+        .map(|release| String::from_utf8_lossy(&release).trim().to_owned())
+        .ok();
+    let exists = |path: &Path| files.modified(path).is_ok();
+
+    let mut kernels: Vec<String> = files
+        .list(Path::new(MODULES_DIR))
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|release| Some(release) != running.as_ref())
+        .filter(|release| {
+            let dir = Path::new(MODULES_DIR).join(release);
+            exists(&dir.join("modules.dep"))
+                && (exists(&dir.join("vmlinuz"))
+                    || exists(&Path::new("/boot").join(format!("vmlinuz-{}", release))))
+        })
+        .collect();
+
+    kernels.sort();
+    kernels.splice(0..0, running);
+    kernels
+}
+
+/// The installed kernels for which a module is not available.
+pub fn kernels_missing(name: &str, files: &dyn FileBackend) -> Vec<String> {
+    installed_kernels(files)
+        .into_iter()
+        .filter(|release| {
+            let modules_dep = Path::new(MODULES_DIR).join(release).join("modules.dep");
+            !files
+                .read(&modules_dep)
+                .map_or(false, |dep| lists_module(&String::from_utf8_lossy(&dep), name))
+        })
+        .collect()
+}
+
+/// The lines of `dkms status` about a module, if DKMS is installed.
+pub fn dkms_status(name: &str) -> Option<String> {
+    let output = Command::new("dkms").arg("status").stderr(Stdio::null()).output().ok()?;
+    let status = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = status.lines().filter(|line| line.starts_with(name)).collect();
+    if lines.is_empty() {
+        None
+    } else {
+        Some(lines.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modules_dep() {
+        let dep = "kernel/drivers/gpu/drm/i915/i915.ko.zst: \
+                   kernel/drivers/gpu/drm/drm.ko.zst\nupdates/dkms/nvidia-drm.ko: \
+                   updates/dkms/nvidia-modeset.ko\nupdates/dkms/nvidia.ko:\n";
+        assert!(lists_module(dep, "nvidia"));
+        assert!(lists_module(dep, "nvidia-drm"));
+        assert!(lists_module(dep, "i915"));
+        assert!(!lists_module(dep, "nvidia-uvm"));
+        assert!(!lists_module("kernel/drivers/video/nvidiafb.ko:\n", "nvidia"));
+    }
+}
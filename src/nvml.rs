@@ -0,0 +1,353 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Management of NVIDIA GPUs through NVML, the library behind `nvidia-smi`.
+//!
+//! The library is loaded at runtime, as it is only installed alongside the proprietary driver.
+//! Every function returns an error when it is absent, which callers should treat as the GPU not
+//! being manageable.
+
+use libc::{c_char, c_int, c_uint, c_void};
+use std::{
+    ffi::{CStr, CString},
+    mem, ptr,
+    sync::{Arc, Mutex},
+};
+use thiserror::Error;
+
+const LIBRARY: &[u8] = b"libnvidia-ml.so.1\0";
+
+type Return = c_int;
+type RawDevice = *mut c_void;
+
+const SUCCESS: Return = 0;
+const ERROR_NOT_SUPPORTED: Return = 3;
+
+// The sensor of the GPU die, from `nvmlTemperatureSensors_t`.
+const TEMPERATURE_GPU: c_uint = 0;
+
+// Reasons that the clocks of a GPU are held down, from `nvmlClocksThrottleReasons`.
+pub const THROTTLE_SW_POWER_CAP: u64 = 0x4;
+pub const THROTTLE_HW_SLOWDOWN: u64 = 0x8;
+pub const THROTTLE_SW_THERMAL: u64 = 0x20;
+pub const THROTTLE_HW_THERMAL: u64 = 0x40;
+pub const THROTTLE_HW_POWER_BRAKE: u64 = 0x80;
+
+#[repr(C)]
+#[derive(Default)]
+struct RawUtilization {
+    gpu:    c_uint,
+    memory: c_uint,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct RawMemory {
+    total: u64,
+    free:  u64,
+    used:  u64,
+}
+
+#[derive(Debug, Error)]
+pub enum NvmlError {
+    #[error("failed to load NVML: {}", _0)]
+    Load(String),
+    #[error("NVML does not provide {}", _0)]
+    Symbol(&'static str),
+    #[error("{} failed: {}", function, message)]
+    Call { function: &'static str, code: c_int, message: String },
+}
+
+impl NvmlError {
+    /// Whether the GPU or driver does not support the operation, rather than it failing.
+    pub fn is_not_supported(&self) -> bool {
+        matches!(self, NvmlError::Call { code: ERROR_NOT_SUPPORTED, .. })
+    }
+}
+
+/// GPU and memory utilization over the last sample period, in percent.
+#[derive(Clone, Copy, Debug)]
+pub struct Utilization {
+    pub gpu:    u32,
+    pub memory: u32,
+}
+
+/// Video memory, in bytes.
+#[derive(Clone, Copy, Debug)]
+pub struct Memory {
+    pub total: u64,
+    pub used:  u64,
+}
+
+struct Functions {
+    init:                    unsafe extern "C" fn() -> Return,
+    shutdown:                unsafe extern "C" fn() -> Return,
+    error_string:            unsafe extern "C" fn(Return) -> *const c_char,
+    device_count:            unsafe extern "C" fn(*mut c_uint) -> Return,
+    device_by_index:         unsafe extern "C" fn(c_uint, *mut RawDevice) -> Return,
+    device_by_pci_bus_id:    unsafe extern "C" fn(*const c_char, *mut RawDevice) -> Return,
+    utilization:             unsafe extern "C" fn(RawDevice, *mut RawUtilization) -> Return,
+    memory:                  unsafe extern "C" fn(RawDevice, *mut RawMemory) -> Return,
+    power_usage:             unsafe extern "C" fn(RawDevice, *mut c_uint) -> Return,
+    temperature:             unsafe extern "C" fn(RawDevice, c_uint, *mut c_uint) -> Return,
+    persistence_mode:        unsafe extern "C" fn(RawDevice, *mut c_int) -> Return,
+    set_persistence_mode:    unsafe extern "C" fn(RawDevice, c_int) -> Return,
+    power_limit:             unsafe extern "C" fn(RawDevice, *mut c_uint) -> Return,
+    power_limit_constraints: unsafe extern "C" fn(RawDevice, *mut c_uint, *mut c_uint) -> Return,
+    set_power_limit:         unsafe extern "C" fn(RawDevice, c_uint) -> Return,
+    set_locked_clocks:       unsafe extern "C" fn(RawDevice, c_uint, c_uint) -> Return,
+    reset_locked_clocks:     unsafe extern "C" fn(RawDevice) -> Return,
+    throttle_reasons:        unsafe extern "C" fn(RawDevice, *mut u64) -> Return,
+}
+
+/// An initialized instance of NVML, which is shut down when dropped.
+pub struct Nvml {
+    library:   *mut c_void,
+    functions: Functions,
+}
+
+// NVML is thread safe, and device handles remain valid until it is shut down.
+unsafe impl Send for Nvml {}
+unsafe impl Sync for Nvml {}
+
+unsafe fn symbol<T: Copy>(library: *mut c_void, name: &'static str) -> Result<T, NvmlError> {
+    let cname = CString::new(name).unwrap();
+    let symbol = libc::dlsym(library, cname.as_ptr());
+    if symbol.is_null() {
+        return Err(NvmlError::Symbol(name));
+    }
+
+    Ok(mem::transmute_copy(&symbol))
+}
+
+impl Nvml {
+    /// Loads and initializes NVML.
+    pub fn new() -> Result<Nvml, NvmlError> {
+        let library = unsafe {
+            libc::dlopen(LIBRARY.as_ptr() as *const c_char, libc::RTLD_NOW | libc::RTLD_LOCAL)
+        };
+
+        if library.is_null() {
+            let why = unsafe { libc::dlerror() };
+            let why = if why.is_null() {
+                String::from("unknown error")
+            } else {
+                unsafe { CStr::from_ptr(why) }.to_string_lossy().into_owned()
+            };
+            return Err(NvmlError::Load(why));
+        }
+
+        let functions = unsafe { Nvml::load(library) };
+        let functions = match functions {
+            Ok(functions) => functions,
+            Err(why) => {
+                unsafe { libc::dlclose(library) };
+                return Err(why);
+            }
+        };
+
+        // If initialization fails, dropping the instance still closes the library. Shutting down
+        // NVML without initializing it only returns an error.
+        let nvml = Nvml { library, functions };
+        let code = unsafe { (nvml.functions.init)() };
+        nvml.check("nvmlInit", code)?;
+        Ok(nvml)
+    }
+
+    unsafe fn load(library: *mut c_void) -> Result<Functions, NvmlError> {
+        Ok(Functions {
+            init:                    symbol(library, "nvmlInit_v2")?,
+            shutdown:                symbol(library, "nvmlShutdown")?,
+            error_string:            symbol(library, "nvmlErrorString")?,
+            device_count:            symbol(library, "nvmlDeviceGetCount_v2")?,
+            device_by_index:         symbol(library, "nvmlDeviceGetHandleByIndex_v2")?,
+            device_by_pci_bus_id:    symbol(library, "nvmlDeviceGetHandleByPciBusId_v2")?,
+            utilization:             symbol(library, "nvmlDeviceGetUtilizationRates")?,
+            memory:                  symbol(library, "nvmlDeviceGetMemoryInfo")?,
+            power_usage:             symbol(library, "nvmlDeviceGetPowerUsage")?,
+            temperature:             symbol(library, "nvmlDeviceGetTemperature")?,
+            persistence_mode:        symbol(library, "nvmlDeviceGetPersistenceMode")?,
+            set_persistence_mode:    symbol(library, "nvmlDeviceSetPersistenceMode")?,
+            power_limit:             symbol(library, "nvmlDeviceGetPowerManagementLimit")?,
+            power_limit_constraints: symbol(
+                library,
+                "nvmlDeviceGetPowerManagementLimitConstraints",
+            )?,
+            set_power_limit:         symbol(library, "nvmlDeviceSetPowerManagementLimit")?,
+            set_locked_clocks:       symbol(library, "nvmlDeviceSetGpuLockedClocks")?,
+            reset_locked_clocks:     symbol(library, "nvmlDeviceResetGpuLockedClocks")?,
+            throttle_reasons:        symbol(library, "nvmlDeviceGetCurrentClocksThrottleReasons")?,
+        })
+    }
+
+    fn check(&self, function: &'static str, code: Return) -> Result<(), NvmlError> {
+        if code == SUCCESS {
+            return Ok(());
+        }
+
+        let message = unsafe { (self.functions.error_string)(code) };
+        let message = if message.is_null() {
+            format!("error {}", code)
+        } else {
+            unsafe { CStr::from_ptr(message) }.to_string_lossy().into_owned()
+        };
+
+        Err(NvmlError::Call { function, code, message })
+    }
+
+    pub fn devices(&self) -> Result<Vec<Device<'_>>, NvmlError> {
+        let mut count = 0;
+        self.check("nvmlDeviceGetCount", unsafe { (self.functions.device_count)(&mut count) })?;
+
+        (0..count)
+            .map(|index| {
+                let mut handle = ptr::null_mut();
+                let code = unsafe { (self.functions.device_by_index)(index, &mut handle) };
+                self.check("nvmlDeviceGetHandleByIndex", code)?;
+                Ok(Device { nvml: self, handle })
+            })
+            .collect()
+    }
+
+    /// Finds a device by its PCI address, such as `0000:01:00.0`.
+    pub fn device_by_pci_id(&self, pci_id: &str) -> Result<Device<'_>, NvmlError> {
+        let pci_id = CString::new(pci_id).unwrap_or_default();
+        let mut handle = ptr::null_mut();
+        let code = unsafe { (self.functions.device_by_pci_bus_id)(pci_id.as_ptr(), &mut handle) };
+        self.check("nvmlDeviceGetHandleByPciBusId", code)?;
+        Ok(Device { nvml: self, handle })
+    }
+}
+
+/// An instance of NVML which is loaded on first use and kept, as loading and initializing it is
+/// slow. It is shared by everything which manages NVIDIA GPUs, and must be released before the
+/// GPUs are powered off, as it holds the driver open.
+#[derive(Default)]
+pub struct SharedNvml {
+    nvml: Mutex<Option<Arc<Nvml>>>,
+}
+
+impl SharedNvml {
+    /// The loaded instance, which is loaded again if it was released or failed to load.
+    pub fn get(&self) -> Result<Arc<Nvml>, NvmlError> {
+        let mut shared = self.nvml.lock().unwrap();
+        if let Some(ref nvml) = *shared {
+            return Ok(nvml.clone());
+        }
+
+        let nvml = Arc::new(Nvml::new()?);
+        *shared = Some(nvml.clone());
+        Ok(nvml)
+    }
+
+    /// Releases the instance, which is shut down once its last user drops it.
+    pub fn release(&self) { self.nvml.lock().unwrap().take(); }
+}
+
+impl Drop for Nvml {
+    fn drop(&mut self) {
+        unsafe {
+            (self.functions.shutdown)();
+            libc::dlclose(self.library);
+        }
+    }
+}
+
+pub struct Device<'a> {
+    nvml:   &'a Nvml,
+    handle: RawDevice,
+}
+
+impl<'a> Device<'a> {
+    fn functions(&self) -> &Functions { &self.nvml.functions }
+
+    pub fn utilization(&self) -> Result<Utilization, NvmlError> {
+        let mut raw = RawUtilization::default();
+        let code = unsafe { (self.functions().utilization)(self.handle, &mut raw) };
+        self.nvml.check("nvmlDeviceGetUtilizationRates", code)?;
+        Ok(Utilization { gpu: raw.gpu, memory: raw.memory })
+    }
+
+    pub fn memory(&self) -> Result<Memory, NvmlError> {
+        let mut raw = RawMemory::default();
+        let code = unsafe { (self.functions().memory)(self.handle, &mut raw) };
+        self.nvml.check("nvmlDeviceGetMemoryInfo", code)?;
+        Ok(Memory { total: raw.total, used: raw.used })
+    }
+
+    /// Power draw of the whole board, in milliwatts.
+    pub fn power_usage(&self) -> Result<u32, NvmlError> {
+        let mut milliwatts = 0;
+        let code = unsafe { (self.functions().power_usage)(self.handle, &mut milliwatts) };
+        self.nvml.check("nvmlDeviceGetPowerUsage", code)?;
+        Ok(milliwatts)
+    }
+
+    /// The temperature of the GPU die, in degrees Celsius.
+    pub fn temperature(&self) -> Result<u32, NvmlError> {
+        let mut celsius = 0;
+        let code =
+            unsafe { (self.functions().temperature)(self.handle, TEMPERATURE_GPU, &mut celsius) };
+        self.nvml.check("nvmlDeviceGetTemperature", code)?;
+        Ok(celsius)
+    }
+
+    pub fn persistence_mode(&self) -> Result<bool, NvmlError> {
+        let mut enabled = 0;
+        let code = unsafe { (self.functions().persistence_mode)(self.handle, &mut enabled) };
+        self.nvml.check("nvmlDeviceGetPersistenceMode", code)?;
+        Ok(enabled != 0)
+    }
+
+    pub fn set_persistence_mode(&self, enabled: bool) -> Result<(), NvmlError> {
+        let code =
+            unsafe { (self.functions().set_persistence_mode)(self.handle, enabled as c_int) };
+        self.nvml.check("nvmlDeviceSetPersistenceMode", code)
+    }
+
+    /// The current power limit, in milliwatts.
+    pub fn power_limit(&self) -> Result<u32, NvmlError> {
+        let mut milliwatts = 0;
+        let code = unsafe { (self.functions().power_limit)(self.handle, &mut milliwatts) };
+        self.nvml.check("nvmlDeviceGetPowerManagementLimit", code)?;
+        Ok(milliwatts)
+    }
+
+    /// The minimum and maximum power limits which may be set, in milliwatts.
+    pub fn power_limit_constraints(&self) -> Result<(u32, u32), NvmlError> {
+        let (mut min, mut max) = (0, 0);
+        let code =
+            unsafe { (self.functions().power_limit_constraints)(self.handle, &mut min, &mut max) };
+        self.nvml.check("nvmlDeviceGetPowerManagementLimitConstraints", code)?;
+        Ok((min, max))
+    }
+
+    /// Sets the power limit, in milliwatts, clamped to the limits which the board supports.
+    pub fn set_power_limit(&self, milliwatts: u32) -> Result<(), NvmlError> {
+        let (min, max) = self.power_limit_constraints()?;
+        let milliwatts = milliwatts.max(min).min(max);
+        let code = unsafe { (self.functions().set_power_limit)(self.handle, milliwatts) };
+        self.nvml.check("nvmlDeviceSetPowerManagementLimit", code)
+    }
+
+    /// Locks the graphics clock within a range, in MHz.
+    pub fn set_locked_clocks(&self, min: u32, max: u32) -> Result<(), NvmlError> {
+        let code = unsafe { (self.functions().set_locked_clocks)(self.handle, min, max) };
+        self.nvml.check("nvmlDeviceSetGpuLockedClocks", code)
+    }
+
+    pub fn reset_locked_clocks(&self) -> Result<(), NvmlError> {
+        let code = unsafe { (self.functions().reset_locked_clocks)(self.handle) };
+        self.nvml.check("nvmlDeviceResetGpuLockedClocks", code)
+    }
+
+    /// The reasons that the clocks are currently held down, as a mask of the `THROTTLE_`
+    /// constants, among others.
+    pub fn throttle_reasons(&self) -> Result<u64, NvmlError> {
+        let mut reasons = 0;
+        let code = unsafe { (self.functions().throttle_reasons)(self.handle, &mut reasons) };
+        self.nvml.check("nvmlDeviceGetCurrentClocksThrottleReasons", code)?;
+        Ok(reasons)
+    }
+}
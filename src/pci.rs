@@ -2,7 +2,29 @@
 //
 // SPDX-License-Identifier: GPL-3.0-only
 
-use std::{fs::write, io, path::PathBuf};
+use std::{
+    fs::{self, write, File, OpenOptions},
+    io::{self, Read},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+};
+
+const PCI_DEVICES: &str = "/sys/bus/pci/devices";
+
+// The offset of the pointer to the first capability in the configuration space, and the ID of
+// the PCI Express capability.
+const CAPABILITIES_POINTER: usize = 0x34;
+const CAP_EXP: u8 = 0x10;
+
+// Registers of the PCI Express capability, by their offset within it.
+const LINK_CAPABILITIES: u64 = 0x0C;
+const LINK_CONTROL: u64 = 0x10;
+const LINK_CONTROL_2: u64 = 0x30;
+
+// Set in the link control register of a downstream port to train the link again.
+const RETRAIN_LINK: u16 = 1 << 5;
+// The bits of the link capabilities and link control 2 registers which hold a speed.
+const SPEED_MASK: u16 = 0xF;
 
 pub struct PciBus {
     path: PathBuf,
@@ -20,3 +42,283 @@ impl PciBus {
 
     pub fn rescan(&self) -> io::Result<()> { write(self.path.join("rescan"), "1") }
 }
+
+/// The PCI Express link of a device, with speeds as generations, such as 3 for 8 GT/s.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PcieLink {
+    pub speed:        Option<u8>,
+    pub width:        Option<u8>,
+    pub max_speed:    Option<u8>,
+    pub max_width:    Option<u8>,
+    /// The speed which the port above the device trains the link to, where it may be read.
+    pub target_speed: Option<u8>,
+}
+
+impl PcieLink {
+    pub fn read(device: &str) -> Self {
+        let path = Path::new(PCI_DEVICES).join(device);
+        let read = |attr: &str| fs::read_to_string(path.join(attr)).ok();
+
+        PcieLink {
+            speed:        read("current_link_speed").as_deref().and_then(generation),
+            width:        read("current_link_width").as_deref().and_then(width),
+            max_speed:    read("max_link_speed").as_deref().and_then(generation),
+            max_width:    read("max_link_width").as_deref().and_then(width),
+            target_speed: Port::above(device)
+                .and_then(|port| port.read(LINK_CONTROL_2))
+                .ok()
+                .map(|control| (control & SPEED_MASK) as u8),
+        }
+    }
+}
+
+/// A kind of device whose runtime power management is worth watching.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeviceKind {
+    Gpu,
+    Nvme,
+    Wifi,
+    Xhci,
+}
+
+impl DeviceKind {
+    /// The kind of a device, from its class code, such as `0x010802` for an NVMe controller.
+    fn from_class(class: u32) -> Option<Self> {
+        let kind = match class >> 8 {
+            0x0108 => DeviceKind::Nvme,
+            0x0280 => DeviceKind::Wifi,
+            0x0c03 if class & 0xFF == 0x30 => DeviceKind::Xhci,
+            _ if class >> 16 == 0x03 => DeviceKind::Gpu,
+            _ => return None,
+        };
+        Some(kind)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DeviceKind::Gpu => "gpu",
+            DeviceKind::Nvme => "nvme",
+            DeviceKind::Wifi => "wifi",
+            DeviceKind::Xhci => "xhci",
+        }
+    }
+}
+
+/// The runtime power management of a device, with times in milliseconds since it was probed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuntimePm {
+    pub id:             String,
+    pub kind:           DeviceKind,
+    /// Whether the kernel may suspend the device, as `auto`, or keeps it on, as `on`.
+    pub control:        String,
+    pub status:         String,
+    pub active_time:    u64,
+    pub suspended_time: u64,
+}
+
+impl RuntimePm {
+    /// The runtime power management of GPUs, NVMe drives, wireless cards and USB controllers.
+    pub fn read_all() -> Vec<Self> {
+        let entries = match fs::read_dir(PCI_DEVICES) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+
+        let mut devices: Vec<Self> = entries
+            .filter_map(Result::ok)
+            .filter_map(|entry| Self::read(&entry.file_name().to_string_lossy()))
+            .collect();
+        devices.sort_by(|a, b| a.id.cmp(&b.id));
+        devices
+    }
+
+    fn read(device: &str) -> Option<Self> {
+        let path = Path::new(PCI_DEVICES).join(device);
+        let read = |attr: &str| fs::read_to_string(path.join(attr)).ok();
+        let class = read("class")?;
+        let class = u32::from_str_radix(class.trim().trim_start_matches("0x"), 16).ok()?;
+        let kind = DeviceKind::from_class(class)?;
+        let time = |attr: &str| read(attr).and_then(|time| time.trim().parse().ok()).unwrap_or(0);
+
+        Some(RuntimePm {
+            id: device.to_owned(),
+            kind,
+            control: read("power/control").map(|s| s.trim().to_owned()).unwrap_or_default(),
+            status: read("power/runtime_status").map(|s| s.trim().to_owned()).unwrap_or_default(),
+            active_time: time("power/runtime_active_time"),
+            suspended_time: time("power/runtime_suspended_time"),
+        })
+    }
+}
+
+/// Whether a device is powered up, rather than suspended by runtime power management. Reading
+/// its status does not wake it, unlike querying it through its driver.
+pub fn is_active(device: &str) -> bool {
+    fs::read_to_string(Path::new(PCI_DEVICES).join(device).join("power/runtime_status"))
+        .map_or(false, |status| status.trim() == "active")
+}
+
+/// Caps the speed of the link of a device to a generation, or lifts the cap with `None`. The
+/// device is attached to the bridge at `parent`, which is still there once it has been removed,
+/// or is looked up from the device where that is unknown.
+///
+/// The width of a link cannot be limited this way, as it is only negotiated by the hardware. The
+/// target link speed of the port above the device is set, as `setpci` would with
+/// `CAP_EXP+30.w`, and the link is retrained if the device is present, so that the speed takes
+/// effect without waiting for the device to be powered on again.
+pub fn set_link_speed(
+    device: &str,
+    parent: Option<&Path>,
+    generation: Option<u8>,
+) -> io::Result<()> {
+    let port = match parent {
+        Some(parent) => Port::new(parent)?,
+        None => Port::above(device)?,
+    };
+    let max = port.read(LINK_CAPABILITIES)? & SPEED_MASK;
+    let target = generation.map_or(max, |generation| u16::from(generation).clamp(1, max));
+
+    let control = port.read(LINK_CONTROL_2)?;
+    if control & SPEED_MASK == target {
+        return Ok(());
+    }
+
+    port.write(LINK_CONTROL_2, (control & !SPEED_MASK) | target)?;
+    if Path::new(PCI_DEVICES).join(device).exists() {
+        let control = port.read(LINK_CONTROL)?;
+        port.write(LINK_CONTROL, control | RETRAIN_LINK)?;
+    }
+
+    Ok(())
+}
+
+/// The downstream port of a link, through its configuration space.
+struct Port {
+    config: PathBuf,
+    /// The offset of the PCI Express capability.
+    cap:    u64,
+}
+
+impl Port {
+    fn above(device: &str) -> io::Result<Self> {
+        let path = fs::canonicalize(Path::new(PCI_DEVICES).join(device))?;
+        Port::new(path.parent().ok_or_else(no_bridge)?)
+    }
+
+    /// The port of a bridge, from its path in sysfs.
+    fn new(bridge: &Path) -> io::Result<Self> {
+        let config = bridge.join("config");
+        if !config.exists() {
+            return Err(no_bridge());
+        }
+
+        let mut data = Vec::new();
+        File::open(&config)?.read_to_end(&mut data)?;
+        let cap = find_capability(&data, CAP_EXP).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "port is not a PCI Express port")
+        })?;
+
+        Ok(Port { config, cap: cap as u64 })
+    }
+
+    fn read(&self, register: u64) -> io::Result<u16> {
+        let mut bytes = [0; 2];
+        File::open(&self.config)?.read_exact_at(&mut bytes, self.cap + register)?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    fn write(&self, register: u64, value: u16) -> io::Result<()> {
+        OpenOptions::new()
+            .write(true)
+            .open(&self.config)?
+            .write_all_at(&value.to_le_bytes(), self.cap + register)
+    }
+}
+
+fn no_bridge() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "device is not behind a PCI bridge")
+}
+
+/// The offset of a capability in a configuration space, by following the list of capabilities.
+fn find_capability(config: &[u8], id: u8) -> Option<usize> {
+    let mut offset = usize::from(*config.get(CAPABILITIES_POINTER)?) & !3;
+    // Each capability takes at least four bytes, which bounds the length of a looping list.
+    for _ in 0..64 {
+        if offset == 0 {
+            return None;
+        }
+
+        if *config.get(offset)? == id {
+            return Some(offset);
+        }
+
+        offset = usize::from(*config.get(offset + 1)?) & !3;
+    }
+
+    None
+}
+
+/// The generation of a link speed, such as `8.0 GT/s PCIe`.
+fn generation(speed: &str) -> Option<u8> {
+    let transfers: f32 = speed.split_whitespace().next()?.parse().ok()?;
+    let generation = match transfers as u32 {
+        2 => 1,
+        5 => 2,
+        8 => 3,
+        16 => 4,
+        32 => 5,
+        64 => 6,
+        _ => return None,
+    };
+    Some(generation)
+}
+
+/// The number of lanes of a link, which some kernels write as `x16`.
+fn width(width: &str) -> Option<u8> {
+    match width.trim().trim_start_matches('x').parse() {
+        Ok(0) | Err(_) => None,
+        Ok(lanes) => Some(lanes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn links() {
+        assert_eq!(generation("2.5 GT/s PCIe\n"), Some(1));
+        assert_eq!(generation("8.0 GT/s"), Some(3));
+        assert_eq!(generation("16.0 GT/s PCIe"), Some(4));
+        assert_eq!(generation("Unknown"), None);
+        assert_eq!(width("16\n"), Some(16));
+        assert_eq!(width("x8"), Some(8));
+        assert_eq!(width("0"), None);
+
+        // The power management capability at 0x40 links to the PCI Express capability at 0x60.
+        let mut config = vec![0; 256];
+        config[CAPABILITIES_POINTER] = 0x40;
+        config[0x40] = 0x01;
+        config[0x41] = 0x60;
+        config[0x60] = CAP_EXP;
+        assert_eq!(find_capability(&config, CAP_EXP), Some(0x60));
+        assert_eq!(find_capability(&config, 0x05), None);
+
+        // A list which loops must not be followed forever.
+        config[0x61] = 0x40;
+        assert_eq!(find_capability(&config, 0x05), None);
+        assert_eq!(find_capability(&config[..0x40], CAP_EXP), None);
+    }
+
+    #[test]
+    fn device_kinds() {
+        assert_eq!(DeviceKind::from_class(0x030000), Some(DeviceKind::Gpu));
+        assert_eq!(DeviceKind::from_class(0x030200), Some(DeviceKind::Gpu));
+        assert_eq!(DeviceKind::from_class(0x010802), Some(DeviceKind::Nvme));
+        assert_eq!(DeviceKind::from_class(0x028000), Some(DeviceKind::Wifi));
+        assert_eq!(DeviceKind::from_class(0x0c0330), Some(DeviceKind::Xhci));
+        // EHCI controllers, and audio, are not covered.
+        assert_eq!(DeviceKind::from_class(0x0c0320), None);
+        assert_eq!(DeviceKind::from_class(0x040300), None);
+    }
+}
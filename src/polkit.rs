@@ -3,7 +3,7 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
 use dbus::{
-    arg::{RefArg, Variant},
+    arg::{prop_cast, PropMap, RefArg, Variant},
     nonblock::{Proxy, SyncConnection},
     strings::BusName,
 };
@@ -28,6 +28,36 @@ pub(crate) async fn get_connection_unix_process_id(
     Ok(pid)
 }
 
+/// The user, groups, and process of a connection, as reported by the bus.
+pub(crate) struct Credentials {
+    pub uid:    Option<u32>,
+    pub groups: Vec<u32>,
+    pub pid:    Option<u32>,
+}
+
+pub(crate) async fn get_connection_credentials(
+    c: &SyncConnection,
+    sender: BusName<'_>,
+) -> Result<Credentials, dbus::Error> {
+    let proxy =
+        Proxy::new("org.freedesktop.DBus", "/org/freedesktop/DBus", Duration::new(25, 0), c);
+    let (credentials,): (PropMap,) = proxy
+        .method_call("org.freedesktop.DBus", "GetConnectionCredentials", (sender.to_string(),))
+        .await?;
+
+    let groups = credentials
+        .get("UnixGroupIDs")
+        .and_then(|groups| groups.0.as_iter())
+        .map(|groups| groups.filter_map(|gid| gid.as_u64()).map(|gid| gid as u32).collect())
+        .unwrap_or_default();
+
+    Ok(Credentials {
+        uid: prop_cast::<u32>(&credentials, "UnixUserID").copied(),
+        groups,
+        pid: prop_cast::<u32>(&credentials, "ProcessID").copied(),
+    })
+}
+
 pub(crate) async fn check_authorization(
     c: &SyncConnection,
     pid: u32,
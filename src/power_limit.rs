@@ -0,0 +1,106 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! The long term (PL1) power limit of the CPU package, which quiet hours and underpowered AC
+//! adapters lower below the limit of the active profile.
+//!
+//! Both lower the same limit, so it is owned here: the lowest of their limits is written, and
+//! the limit of the profile is restored once neither lowers it any more.
+
+use std::{fs, io};
+
+// The long term (PL1) power limit of the CPU package, in microwatts.
+const PL1: &str = "/sys/class/powercap/intel-rapl:0/constraint_0_power_limit_uw";
+
+/// What lowers the power limit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reason {
+    Adapter,
+    QuietHours,
+}
+
+fn read_pl1() -> io::Result<u64> {
+    fs::read_to_string(PL1)?
+        .trim()
+        .parse()
+        .map_err(|why| io::Error::new(io::ErrorKind::InvalidData, why))
+}
+
+fn write_pl1(microwatts: u64) -> io::Result<()> { fs::write(PL1, microwatts.to_string()) }
+
+#[derive(Default)]
+pub struct PowerLimit {
+    /// The limits requested for each reason, in microwatts.
+    adapter:     Option<u64>,
+    quiet_hours: Option<u64>,
+    /// The power limit of the active profile, which is restored once nothing lowers it.
+    saved:       Option<u64>,
+    /// The limit which was last written, to tell whether a profile has changed it since.
+    written:     Option<u64>,
+}
+
+impl PowerLimit {
+    /// Lowers the power limit to `limit` microwatts for a reason, or stops lowering it for that
+    /// reason with `None`.
+    pub fn set(&mut self, reason: Reason, limit: Option<u64>) {
+        match reason {
+            Reason::Adapter => self.adapter = limit,
+            Reason::QuietHours => self.quiet_hours = limit,
+        }
+
+        self.apply();
+    }
+
+    /// Lowers the power limit again, after a profile which may have changed it is applied.
+    pub fn profile_applied(&mut self) {
+        if self.saved.is_some() {
+            self.apply();
+        }
+    }
+
+    /// Restores the power limit of the profile, such as when the daemon exits.
+    pub fn restore(&mut self) {
+        self.written = None;
+        if let Some(saved) = self.saved.take() {
+            if let Err(why) = write_pl1(saved) {
+                log::warn!("failed to restore power limit: {}", why);
+            }
+        }
+    }
+
+    fn lowest(&self) -> Option<u64> {
+        match (self.adapter, self.quiet_hours) {
+            (Some(adapter), Some(quiet)) => Some(adapter.min(quiet)),
+            (adapter, quiet) => adapter.or(quiet),
+        }
+    }
+
+    fn apply(&mut self) {
+        let lowest = match self.lowest() {
+            Some(lowest) => lowest,
+            None => return self.restore(),
+        };
+
+        let current = match read_pl1() {
+            Ok(current) => current,
+            Err(why) => {
+                log::warn!("failed to read power limit from {}: {}", PL1, why);
+                return;
+            }
+        };
+
+        // If the profile left the limit alone, it is still the one written here.
+        if self.saved.is_none() || self.written != Some(current) {
+            self.saved = Some(current);
+        }
+
+        let limit = self.saved.map_or(lowest, |saved| saved.min(lowest));
+        self.written = Some(limit);
+        if limit != current {
+            if let Err(why) = write_pl1(limit) {
+                log::warn!("failed to lower power limit: {}", why);
+            }
+        }
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Coordination with power-profiles-daemon, which also manages CPU settings when installed.
+//!
+//! If both daemons apply their own profiles, each overwrites the settings of the other. This
+//! daemon either follows the profile chosen through power-profiles-daemon, or replaces it by
+//! claiming its bus name and serving the parts of its interface which desktops use.
+
+use crate::config::Profile;
+use dbus::{
+    arg::{PropMap, RefArg, Variant},
+    blocking::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged,
+    message::{MatchRule, SignalArgs},
+    nonblock::{
+        stdintf::org_freedesktop_dbus::{Properties, RequestNameReply},
+        MsgMatch, Proxy, SyncConnection,
+    },
+    Message,
+};
+use futures::channel::mpsc::UnboundedReceiver;
+use std::time::Duration;
+
+pub const PPD_NAME: &str = "net.hadess.PowerProfiles";
+pub const PPD_PATH: &str = "/net/hadess/PowerProfiles";
+pub const PPD_IFACE: &str = "net.hadess.PowerProfiles";
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn from_ppd(name: &str) -> Option<Profile> {
+    match name {
+        "power-saver" => Some(Profile::Battery),
+        "balanced" => Some(Profile::Balanced),
+        "performance" => Some(Profile::Performance),
+        _ => None,
+    }
+}
+
+/// Converts a profile name, as returned by `GetProfile`, to its power-profiles-daemon name.
+pub fn to_ppd(name: &str) -> &'static str {
+    match name {
+        "Battery" => "power-saver",
+        "Performance" => "performance",
+        _ => "balanced",
+    }
+}
+
+/// The value of the `Profiles` property, listing every profile which may be selected.
+pub fn profiles() -> Vec<PropMap> {
+    ["power-saver", "balanced", "performance"]
+        .iter()
+        .map(|&profile| {
+            let mut map = PropMap::new();
+            map.insert("Profile".into(), Variant(Box::new(profile.to_owned()) as Box<dyn RefArg>));
+            map.insert("Driver".into(), Variant(Box::new("system76-power".to_owned())));
+            map
+        })
+        .collect()
+}
+
+/// Whether power-profiles-daemon is currently running. Its bus name is checked directly, as
+/// calling it would start it through bus activation.
+pub async fn is_running(conn: &SyncConnection) -> Result<bool, dbus::Error> {
+    let proxy = Proxy::new("org.freedesktop.DBus", "/org/freedesktop/DBus", TIMEOUT, conn);
+    let (has_owner,): (bool,) =
+        proxy.method_call("org.freedesktop.DBus", "NameHasOwner", (PPD_NAME,)).await?;
+    Ok(has_owner)
+}
+
+pub async fn active_profile(conn: &SyncConnection) -> Result<Option<Profile>, dbus::Error> {
+    let proxy = Proxy::new(PPD_NAME, PPD_PATH, TIMEOUT, conn);
+    let profile: String = proxy.get(PPD_IFACE, "ActiveProfile").await?;
+    Ok(from_ppd(&profile))
+}
+
+/// Subscribes to property changes of power-profiles-daemon, including those of instances which
+/// start later. The match must be kept alive for as long as messages are received.
+pub async fn watch(
+    conn: &SyncConnection,
+) -> Result<(MsgMatch, UnboundedReceiver<Message>), dbus::Error> {
+    let rule = MatchRule::new_signal("org.freedesktop.DBus.Properties", "PropertiesChanged")
+        .with_sender(PPD_NAME)
+        .with_path(PPD_PATH);
+
+    Ok(conn.add_match(rule).await?.msg_stream())
+}
+
+/// The new profile announced by a `PropertiesChanged` signal, if it changed.
+pub fn changed_profile(message: &Message) -> Option<Profile> {
+    let changed = PropertiesPropertiesChanged::from_message(message)?;
+    if changed.interface_name != PPD_IFACE {
+        return None;
+    }
+
+    changed.changed_properties.get("ActiveProfile")?.0.as_str().and_then(from_ppd)
+}
+
+/// Claims the bus name of power-profiles-daemon, which exits when its name is taken.
+pub async fn replace(conn: &SyncConnection) -> Result<bool, dbus::Error> {
+    let reply = conn.request_name(PPD_NAME, false, true, true).await?;
+    Ok(reply == RequestNameReply::PrimaryOwner || reply == RequestNameReply::AlreadyOwner)
+}
+
+/// Announces a change of the active profile while the bus name is owned.
+pub fn active_profile_changed(name: &str) -> Message {
+    let mut changed = PropertiesPropertiesChanged {
+        interface_name:         PPD_IFACE.into(),
+        changed_properties:     PropMap::new(),
+        invalidated_properties: Vec::new(),
+    };
+
+    changed
+        .changed_properties
+        .insert("ActiveProfile".into(), Variant(Box::new(to_ppd(name).to_owned())));
+    changed.to_emit_message(&PPD_PATH.into())
+}
@@ -0,0 +1,71 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Whether the system is running on battery, and how much charge remains.
+//!
+//! UPower is preferred as the source of this information, so that the daemon agrees with what
+//! the desktop shows. The `power_supply` class in sysfs is read when UPower is not running.
+
+use serde::Serialize;
+use std::{fs, path::Path};
+
+const POWER_SUPPLY: &str = "/sys/class/power_supply";
+
+// Below this percentage, the battery is considered to be low when read from sysfs.
+const LOW_PERCENTAGE: f64 = 10.0;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct PowerSource {
+    pub on_battery: bool,
+    pub percentage: Option<f64>,
+    /// The battery has reached a level which should be warned about.
+    pub low:        bool,
+}
+
+impl PowerSource {
+    /// Reads the state of the AC adapters and system batteries in sysfs.
+    pub fn from_sysfs() -> Self {
+        let mut ac_online = false;
+        let mut discharging = false;
+        let mut percentage = None;
+
+        let entries = match fs::read_dir(POWER_SUPPLY) {
+            Ok(entries) => entries,
+            Err(why) => {
+                log::warn!("failed to read {}: {}", POWER_SUPPLY, why);
+                return PowerSource::default();
+            }
+        };
+
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            match read_trimmed(&path, "type").as_deref() {
+                Some("Mains") | Some("USB") => {
+                    ac_online |= read_trimmed(&path, "online").as_deref() == Some("1");
+                }
+                // Batteries of peripherals such as mice have a scope of `Device`.
+                Some("Battery") if read_trimmed(&path, "scope").as_deref() != Some("Device") => {
+                    discharging |= read_trimmed(&path, "status").as_deref() == Some("Discharging");
+                    if let Some(capacity) =
+                        read_trimmed(&path, "capacity").and_then(|c| c.parse::<f64>().ok())
+                    {
+                        percentage = Some(percentage.map_or(capacity, |p: f64| p.min(capacity)));
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        let on_battery = discharging && !ac_online;
+        PowerSource {
+            on_battery,
+            percentage,
+            low: on_battery && percentage.map_or(false, |p| p <= LOW_PERCENTAGE),
+        }
+    }
+}
+
+fn read_trimmed(path: &Path, attribute: &str) -> Option<String> {
+    fs::read_to_string(path.join(attribute)).ok().map(|value| value.trim().to_owned())
+}
@@ -0,0 +1,111 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Reduces the capabilities of the daemon once its devices have been opened.
+//!
+//! The daemon must remain root, as the sysfs attributes, `/etc` files, and initramfs it manages
+//! are all owned by root. However, it does not need most of the capabilities which root has,
+//! and those that are only needed to open devices such as `/dev/mem` may be dropped once the
+//! devices are open. Capabilities are dropped from the bounding set as well, so that they cannot
+//! be regained by the helper programs which the daemon runs.
+
+use libc::{c_int, c_long};
+use std::io;
+
+// Capability numbers from `linux/capability.h`.
+const CAP_CHOWN: u32 = 0;
+const CAP_DAC_OVERRIDE: u32 = 1;
+const CAP_DAC_READ_SEARCH: u32 = 2;
+const CAP_FOWNER: u32 = 3;
+const CAP_KILL: u32 = 5;
+const CAP_NET_ADMIN: u32 = 12;
+const CAP_SYS_MODULE: u32 = 16;
+const CAP_SYS_RAWIO: u32 = 17;
+const CAP_SYS_PTRACE: u32 = 19;
+const CAP_SYS_ADMIN: u32 = 21;
+const CAP_MKNOD: u32 = 27;
+
+/// Capabilities which are needed for the lifetime of the daemon.
+///
+/// - File capabilities allow initramfs tools to assemble their images.
+/// - `CAP_SYS_MODULE` allows modules to be loaded and reloaded.
+/// - `CAP_SYS_ADMIN` is needed for device management, such as reading PCI configuration space.
+/// - `CAP_SYS_PTRACE` allows the open files and environment of the processes of every user to be
+///   read, to find those which use a GPU before it is switched or powered off.
+/// - `CAP_KILL` allows those processes to be terminated before a GPU is unbound.
+/// - `CAP_NET_ADMIN` allows `iw` to set the power saving of wireless interfaces.
+const RETAINED: &[u32] = &[
+    CAP_CHOWN,
+    CAP_DAC_OVERRIDE,
+    CAP_DAC_READ_SEARCH,
+    CAP_FOWNER,
+    CAP_KILL,
+    CAP_MKNOD,
+    CAP_NET_ADMIN,
+    CAP_SYS_ADMIN,
+    CAP_SYS_MODULE,
+    CAP_SYS_PTRACE,
+];
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid:     c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective:   u32,
+    permitted:   u32,
+    inheritable: u32,
+}
+
+/// Drops every capability which is not needed after initialization.
+///
+/// `CAP_SYS_RAWIO` may be retained for models which write to MSRs when switching profiles, or
+/// where drives are sent commands by `hdparm`.
+pub fn reduce(retain_rawio: bool) -> io::Result<()> {
+    let mut retained = RETAINED.to_vec();
+    if retain_rawio {
+        retained.push(CAP_SYS_RAWIO);
+    }
+
+    let last_cap = std::fs::read_to_string("/proc/sys/kernel/cap_last_cap")
+        .ok()
+        .and_then(|last| last.trim().parse::<u32>().ok())
+        .unwrap_or(40);
+
+    // The bounding set must be reduced first, as it requires `CAP_SETPCAP`.
+    for cap in (0..=last_cap).filter(|cap| !retained.contains(cap)) {
+        if unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap as c_long, 0, 0, 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    if unsafe { libc::prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_CLEAR_ALL, 0, 0, 0) } != 0 {
+        // Kernels older than 4.3 do not support ambient capabilities.
+        let why = io::Error::last_os_error();
+        if why.raw_os_error() != Some(libc::EINVAL) {
+            return Err(why);
+        }
+    }
+
+    let mut data = [CapData::default(); 2];
+    for &cap in &retained {
+        let set = &mut data[(cap / 32) as usize];
+        set.effective |= 1 << (cap % 32);
+        set.permitted |= 1 << (cap % 32);
+    }
+
+    let header = CapHeader { version: LINUX_CAPABILITY_VERSION_3, pid: 0 };
+    if unsafe { libc::syscall(libc::SYS_capset, &header, data.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    log::info!("reduced capabilities to {} of {}", retained.len(), last_cap + 1);
+    Ok(())
+}
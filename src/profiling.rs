@@ -0,0 +1,47 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Records the daemon's tracing spans to a file, so that latency issues such as slow PCI rescans
+//! can be profiled on the affected hardware. Only built with the `profiling` feature.
+
+use std::{any::Any, path::Path};
+use tracing::subscriber::set_global_default;
+use tracing_chrome::ChromeLayerBuilder;
+use tracing_flame::FlameLayer;
+use tracing_subscriber::layer::SubscriberExt;
+
+pub enum TraceFormat {
+    /// JSON which can be loaded into `chrome://tracing` or Perfetto.
+    Chrome,
+    /// Folded stacks for `inferno-flamegraph`.
+    Flame,
+}
+
+/// Flushes the trace file when dropped.
+pub struct TraceGuard {
+    _guard: Box<dyn Any>,
+}
+
+pub fn setup(format: TraceFormat, path: &Path) -> Result<TraceGuard, String> {
+    // The daemon's logger is left in place, as events are also forwarded to it.
+    let registry = tracing_subscriber::registry();
+
+    let guard: Box<dyn Any> = match format {
+        TraceFormat::Chrome => {
+            let (layer, guard) = ChromeLayerBuilder::new()
+                .file(path.to_string_lossy().into_owned())
+                .include_args(true)
+                .build();
+            set_global_default(registry.with(layer)).map_err(|why| why.to_string())?;
+            Box::new(guard)
+        }
+        TraceFormat::Flame => {
+            let (layer, guard) = FlameLayer::with_file(path).map_err(|why| why.to_string())?;
+            set_global_default(registry.with(layer)).map_err(|why| why.to_string())?;
+            Box::new(guard)
+        }
+    };
+
+    Ok(TraceGuard { _guard: guard })
+}
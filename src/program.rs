@@ -0,0 +1,120 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Runs the programs which users configure, such as profile scripts and hook programs.
+//!
+//! Each is given an empty environment, and a process group of its own, so that the processes it
+//! starts are killed along with it if it runs past its timeout. Whatever it writes to a piped
+//! output is logged.
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus},
+    thread,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+
+// Lines of output logged from each stream of a program, so that a chatty program cannot flood
+// the journal.
+const MAX_OUTPUT_LINES: usize = 100;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Error)]
+pub enum ProgramError {
+    #[error("failed to run {}: {}", _0.display(), _1)]
+    Spawn(PathBuf, io::Error),
+    #[error("failed to wait for {}: {}", _0.display(), _1)]
+    Wait(PathBuf, io::Error),
+    #[error("{} was killed after {:?}", _0.display(), _1)]
+    Timeout(PathBuf, Duration),
+    #[error("{} exited with {}", _0.display(), _1)]
+    Failed(PathBuf, ExitStatus),
+}
+
+/// A command for a program, with an empty environment apart from `PATH`, run from `/`.
+pub fn command(program: &Path) -> Command {
+    let mut command = Command::new(program);
+    command
+        .env_clear()
+        .env("PATH", "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin")
+        .current_dir("/");
+    command
+}
+
+fn log_output<R: Read + Send + 'static>(stream: Option<R>, program: &Path, stderr: bool) {
+    let stream = match stream {
+        Some(stream) => stream,
+        None => return,
+    };
+
+    let program = program.display().to_string();
+    thread::spawn(move || {
+        for line in BufReader::new(stream).lines().take(MAX_OUTPUT_LINES) {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+
+            if stderr {
+                log::warn!("{}: {}", program, line);
+            } else {
+                log::info!("{}: {}", program, line);
+            }
+        }
+    });
+}
+
+/// Runs a command in a process group of its own, writing `input` to its standard input where
+/// that is piped, and waits until it exits or the timeout elapses. The whole process group is
+/// killed on a timeout.
+pub fn run(
+    program: &Path,
+    command: &mut Command,
+    input: &[u8],
+    timeout: Duration,
+) -> Result<(), ProgramError> {
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setpgid(0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+
+    let mut child = command.spawn().map_err(|why| ProgramError::Spawn(program.to_owned(), why))?;
+    log_output(child.stdout.take(), program, false);
+    log_output(child.stderr.take(), program, true);
+
+    // A program which does not read its input is not a failure.
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(input);
+    }
+
+    let started = Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if started.elapsed() >= timeout => {
+                unsafe {
+                    libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+                }
+                let _ = child.wait();
+                return Err(ProgramError::Timeout(program.to_owned(), timeout));
+            }
+            Ok(None) => thread::sleep(POLL_INTERVAL),
+            Err(why) => return Err(ProgramError::Wait(program.to_owned(), why)),
+        }
+    };
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(ProgramError::Failed(program.to_owned(), status))
+    }
+}
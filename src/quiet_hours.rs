@@ -0,0 +1,159 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Time windows, such as overnight, during which the fans are quieter and the CPU power limit is
+//! lowered. These are layered onto whichever profile is active, and reverted outside the
+//! windows. The power limit itself is lowered through [`crate::power_limit`].
+
+use crate::config::QuietHoursConfig;
+use serde::Deserialize;
+use std::{
+    convert::TryFrom,
+    fmt::{self, Display, Formatter},
+    mem,
+};
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+/// A daily window in local time, written as `22:00-07:00`. It may extend past midnight.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Window {
+    /// Minutes since midnight.
+    start: u16,
+    end:   u16,
+}
+
+impl Window {
+    pub fn contains(self, minute: u16) -> bool {
+        if self.start <= self.end {
+            self.start <= minute && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+fn parse_time(time: &str) -> Option<u16> {
+    let mut parts = time.trim().splitn(2, ':');
+    let hours: u16 = parts.next()?.parse().ok()?;
+    let minutes: u16 = parts.next()?.parse().ok()?;
+    if hours > 24 || minutes > 59 || (hours == 24 && minutes != 0) {
+        return None;
+    }
+
+    Some((hours * 60 + minutes) % MINUTES_PER_DAY)
+}
+
+impl TryFrom<String> for Window {
+    type Error = String;
+
+    fn try_from(window: String) -> Result<Self, Self::Error> {
+        let invalid =
+            || format!("invalid window `{}`, expected a range like `22:00-07:00`", window);
+
+        let mut times = window.splitn(2, '-');
+        let start = times.next().and_then(parse_time).ok_or_else(invalid)?;
+        let end = times.next().and_then(parse_time).ok_or_else(invalid)?;
+        Ok(Window { start, end })
+    }
+}
+
+impl Display for Window {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+/// Minutes since midnight, in local time.
+fn local_minute() -> u16 {
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        (tm.tm_hour * 60 + tm.tm_min) as u16
+    }
+}
+
+pub struct QuietHours {
+    config: QuietHoursConfig,
+    active: bool,
+}
+
+impl QuietHours {
+    pub fn new(config: QuietHoursConfig) -> Self { QuietHours { config, active: false } }
+
+    pub fn is_enabled(&self) -> bool { self.config.enabled && !self.config.windows.is_empty() }
+
+    /// The maximum fan duty cycle while active, in hundredths of a percent.
+    pub fn fan_max_duty(&self) -> Option<u16> {
+        if self.active {
+            Some(u16::from(self.config.fan_max_duty.min(100)) * 100)
+        } else {
+            None
+        }
+    }
+
+    /// Checks the clock, entering or leaving quiet hours. Returns whether this changed.
+    pub fn update(&mut self) -> bool {
+        let minute = local_minute();
+        let active =
+            self.is_enabled() && self.config.windows.iter().any(|window| window.contains(minute));
+        if active == self.active {
+            return false;
+        }
+
+        self.active = active;
+        if active {
+            log::info!("entering quiet hours");
+        } else {
+            log::info!("leaving quiet hours");
+        }
+
+        true
+    }
+
+    /// The power limit of the CPU while active, in microwatts.
+    pub fn power_limit(&self) -> Option<u64> {
+        match self.config.power_limit {
+            0 => None,
+            _ if !self.active => None,
+            watts => Some(u64::from(watts) * 1_000_000),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(window: &str) -> Window { Window::try_from(window.to_owned()).unwrap() }
+
+    #[test]
+    fn windows() {
+        let overnight = window("22:00-07:00");
+        assert_eq!(overnight.to_string(), "22:00-07:00");
+        assert!(overnight.contains(23 * 60));
+        assert!(overnight.contains(0));
+        assert!(overnight.contains(6 * 60 + 59));
+        assert!(!overnight.contains(7 * 60));
+        assert!(!overnight.contains(12 * 60));
+
+        let lunch = window(" 12:00 - 13:30 ");
+        assert!(lunch.contains(13 * 60));
+        assert!(!lunch.contains(13 * 60 + 30));
+        assert_eq!(window("18:00-24:00").end, 0);
+
+        assert!(Window::try_from(String::from("22:00")).is_err());
+        assert!(Window::try_from(String::from("25:00-07:00")).is_err());
+        assert!(Window::try_from(String::from("22:60-07:00")).is_err());
+    }
+}
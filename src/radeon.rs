@@ -2,7 +2,8 @@
 //
 // SPDX-License-Identifier: GPL-3.0-only
 
-use crate::kernel_parameters::*;
+use crate::{errors::RadeonError, kernel_parameters::*};
+use std::io;
 
 pub struct RadeonDevice {
     card:                      u8,
@@ -37,7 +38,12 @@ impl RadeonDevice {
         }
     }
 
-    pub fn set_profiles(&self, power_profile: &str, dpm_state: &str, dpm_perf: &str) {
+    pub fn set_profiles(
+        &self,
+        power_profile: &str,
+        dpm_state: &str,
+        dpm_perf: &str,
+    ) -> Result<(), RadeonError> {
         log::debug!(
             "Setting radeon{} to power profile {}; DPM state {}; DPM perf {}",
             self.card,
@@ -45,10 +51,16 @@ impl RadeonDevice {
             dpm_state,
             dpm_perf
         );
-        self.dpm_state.set(dpm_state.as_bytes());
-        self.dpm_force_performance.set(dpm_perf.as_bytes());
-        self.power_method.set(b"profile");
-        self.power_profile.set(power_profile.as_bytes());
+        let card = self.card;
+        let set =
+            |name, result: io::Result<()>| result.map_err(|why| RadeonError::Set(name, card, why));
+        set(RadeonDpmState::NAME, self.dpm_state.try_set(dpm_state.as_bytes()))?;
+        set(
+            RadeonDpmForcePerformance::NAME,
+            self.dpm_force_performance.try_set(dpm_perf.as_bytes()),
+        )?;
+        set(RadeonPowerMethod::NAME, self.power_method.try_set(b"profile"))?;
+        set(RadeonPowerProfile::NAME, self.power_profile.try_set(power_profile.as_bytes()))
     }
 }
 
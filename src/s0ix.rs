@@ -0,0 +1,147 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Diagnostics of s2idle, reporting whether the package reached its low power states while
+//! suspended, and which IP blocks may have kept it from doing so.
+//!
+//! The residency counters are read from cpuidle, which does not require debugfs. The power gating
+//! status of the PCH is only available through the debugfs interface of `intel_pmc_core`.
+
+use std::{fs, path::Path, time::Duration};
+
+// Time spent in SLP_S0, the lowest power state of the platform, in microseconds.
+const SYSTEM_RESIDENCY: &str = "/sys/devices/system/cpu/cpuidle/low_power_idle_system_residency_us";
+// Time spent in the deepest package C-state, in microseconds.
+const CPU_RESIDENCY: &str = "/sys/devices/system/cpu/cpuidle/low_power_idle_cpu_residency_us";
+const POWER_GATING_STATUS: &str = "/sys/kernel/debug/pmc_core/pch_ip_power_gating_status";
+const MEM_SLEEP: &str = "/sys/power/mem_sleep";
+
+fn read_counter(path: &str) -> Option<u64> { fs::read_to_string(path).ok()?.trim().parse().ok() }
+
+/// Whether suspend uses s2idle, rather than S3.
+pub fn uses_s2idle() -> bool {
+    fs::read_to_string(MEM_SLEEP).map_or(false, |modes| modes.contains("[s2idle]"))
+}
+
+/// Whether the platform reports low power idle residency.
+pub fn is_supported() -> bool {
+    Path::new(SYSTEM_RESIDENCY).exists() || Path::new(CPU_RESIDENCY).exists()
+}
+
+/// The low power idle residency counters, in microseconds.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Residency {
+    pub system: Option<u64>,
+    pub cpu:    Option<u64>,
+}
+
+impl Residency {
+    pub fn read() -> Self {
+        Residency { system: read_counter(SYSTEM_RESIDENCY), cpu: read_counter(CPU_RESIDENCY) }
+    }
+}
+
+/// The names of the PCH IP blocks which are not power gated, from `pch_ip_power_gating_status`,
+/// whose lines are such as `PCH IP: 3  - XHCI    State: On`.
+fn powered_ips(status: &str) -> Vec<String> {
+    status
+        .lines()
+        .filter_map(|line| {
+            let state_pos = line.find("State:")?;
+            if line[state_pos + "State:".len()..].trim() != "On" {
+                return None;
+            }
+
+            let name_pos = line.find(" - ")?;
+            Some(line[name_pos + 3..state_pos].trim().to_owned())
+        })
+        .collect()
+}
+
+/// The IP blocks which are powered, of which any may keep the package out of SLP_S0. This is
+/// empty where debugfs is unavailable.
+pub fn powered_blocks() -> Vec<String> {
+    fs::read_to_string(POWER_GATING_STATUS).map(|status| powered_ips(&status)).unwrap_or_default()
+}
+
+/// How much of a suspend was spent in low power states.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SuspendReport {
+    pub duration: Duration,
+    /// Time spent in SLP_S0.
+    pub system:   Option<Duration>,
+    /// Time spent in the deepest package C-state.
+    pub cpu:      Option<Duration>,
+    /// The IP blocks which were powered as the system went to sleep, if SLP_S0 was not reached.
+    pub blockers: Vec<String>,
+}
+
+impl SuspendReport {
+    /// A report from the residency before and after a suspend, and the IP blocks which were
+    /// powered before it, as read by `powered_blocks`. Everything is powered again on resume.
+    pub fn new(
+        before: Residency,
+        after: Residency,
+        duration: Duration,
+        blockers: Vec<String>,
+    ) -> Self {
+        let delta = |before: Option<u64>, after: Option<u64>| {
+            Some(Duration::from_micros(after?.checked_sub(before?)?))
+        };
+
+        let system = delta(before.system, after.system);
+        let blockers = if system == Some(Duration::from_secs(0)) { blockers } else { Vec::new() };
+
+        SuspendReport { duration, system, cpu: delta(before.cpu, after.cpu), blockers }
+    }
+
+    /// The share of the suspend spent in SLP_S0, in percent.
+    pub fn system_percent(&self) -> Option<f64> { self.percent(self.system) }
+
+    /// The share of the suspend spent in the deepest package C-state, in percent.
+    pub fn cpu_percent(&self) -> Option<f64> { self.percent(self.cpu) }
+
+    fn percent(&self, residency: Option<Duration>) -> Option<f64> {
+        if self.duration == Duration::from_secs(0) {
+            return None;
+        }
+
+        residency.map(|residency| {
+            (residency.as_secs_f64() / self.duration.as_secs_f64() * 100.0).min(100.0)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn powered() {
+        let status = "PCH IP: 0  - PMC                                \tState: On\nPCH IP: 1  - \
+                      SATA                               \tState: Off\nPCH IP: 3  - XHCI         \
+                      \tState: On\n";
+        assert_eq!(powered_ips(status), vec!["PMC".to_owned(), "XHCI".to_owned()]);
+    }
+
+    #[test]
+    fn residency() {
+        let before = Residency { system: Some(1_000_000), cpu: Some(5_000_000) };
+        let after = Residency { system: Some(16_000_000), cpu: Some(65_000_000) };
+        let blockers = vec!["XHCI".to_owned()];
+        let report = SuspendReport::new(before, after, Duration::from_secs(60), blockers.clone());
+
+        assert_eq!(report.system, Some(Duration::from_secs(15)));
+        assert_eq!(report.system_percent(), Some(25.0));
+        assert_eq!(report.cpu_percent(), Some(100.0));
+        assert!(report.blockers.is_empty());
+
+        let report = SuspendReport::new(before, before, Duration::from_secs(60), blockers.clone());
+        assert_eq!(report.blockers, blockers);
+
+        let report =
+            SuspendReport::new(Residency::default(), after, Duration::from_secs(60), Vec::new());
+        assert_eq!(report.system_percent(), None);
+    }
+}
@@ -0,0 +1,43 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Safe mode, an escape hatch for when a bad configuration makes the machine unusable.
+//!
+//! Booting with `system76_power.safe` on the kernel command line makes the daemon ignore its
+//! configuration, applying the default profile and fan curve, and revert switchable graphics to
+//! integrated.
+
+use std::fs;
+
+/// The kernel command line parameter which enables safe mode.
+pub const PARAMETER: &str = "system76_power.safe";
+
+/// Whether the parameter is set on the command line, either alone or with a true value.
+fn cmdline_has(cmdline: &str, parameter: &str) -> bool {
+    cmdline.split_whitespace().any(|arg| {
+        let mut parts = arg.splitn(2, '=');
+        parts.next() == Some(parameter)
+            && parts.next().map_or(true, |value| matches!(value, "1" | "y" | "Y" | "true"))
+    })
+}
+
+/// Whether the system was booted in safe mode.
+pub fn is_enabled() -> bool {
+    fs::read_to_string("/proc/cmdline").map_or(false, |cmdline| cmdline_has(&cmdline, PARAMETER))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parameter() {
+        let cmdline = |cmdline: &str| cmdline_has(cmdline, PARAMETER);
+        assert!(cmdline("root=UUID=1234 ro quiet splash system76_power.safe\n"));
+        assert!(cmdline("system76_power.safe=1 quiet"));
+        assert!(!cmdline("quiet system76_power.safe=0"));
+        assert!(!cmdline("quiet system76_power.safer"));
+        assert!(!cmdline("quiet splash"));
+    }
+}
@@ -0,0 +1,112 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Restores hardware to a safe state if the daemon crashes.
+//!
+//! Fans under manual control would otherwise be left at whatever duty was last set, which may
+//! be far too low once the system heats up. Fatal signals are handled as well as panics, but as
+//! their handlers may only use async-signal-safe functions, they only restore the fans.
+
+use crate::state::StateStore;
+use std::{
+    ffi::CString,
+    os::unix::ffi::OsStrExt,
+    panic,
+    path::PathBuf,
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+use sysfs_class::{Backlight, Brightness, SysClass};
+
+const FATAL_SIGNALS: [libc::c_int; 5] =
+    [libc::SIGABRT, libc::SIGBUS, libc::SIGFPE, libc::SIGILL, libc::SIGSEGV];
+
+struct SafeState {
+    fan_controls: Vec<CString>,
+}
+
+static SAFE_STATE: AtomicPtr<SafeState> = AtomicPtr::new(ptr::null_mut());
+
+/// Registers the `pwm1_enable` files of the fans which the daemon controls.
+pub fn set_fan_controls<I: IntoIterator<Item = PathBuf>>(paths: I) {
+    let fan_controls = paths
+        .into_iter()
+        .filter_map(|path| CString::new(path.as_os_str().as_bytes()).ok())
+        .collect();
+
+    // The previous state is leaked, as a signal handler may still be reading it. This only
+    // happens when hwmon devices are added or removed.
+    let state = Box::into_raw(Box::new(SafeState { fan_controls }));
+    SAFE_STATE.swap(state, Ordering::SeqCst);
+}
+
+/// Returns control of the fans to the firmware, using only async-signal-safe functions.
+fn restore_fans() {
+    let state = SAFE_STATE.load(Ordering::SeqCst);
+    if state.is_null() {
+        return;
+    }
+
+    for path in unsafe { &(*state).fan_controls } {
+        unsafe {
+            let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+            if fd >= 0 {
+                libc::write(fd, b"2".as_ptr() as *const libc::c_void, 1);
+                libc::close(fd);
+            }
+        }
+    }
+}
+
+/// Ensures that no screen is left completely dark.
+fn restore_backlights() {
+    for backlight in Backlight::iter().filter_map(Result::ok) {
+        if backlight.brightness().ok() == Some(0) {
+            let max_brightness = backlight.max_brightness().unwrap_or(0);
+            let _ = backlight.set_brightness((max_brightness / 2).max(1));
+        }
+    }
+}
+
+extern "C" fn fatal_signal(signal: libc::c_int) {
+    restore_fans();
+
+    // The handler was reset to the default by `SA_RESETHAND`, so this terminates the process.
+    unsafe {
+        libc::raise(signal);
+    }
+}
+
+/// Installs a panic hook and fatal signal handlers which restore a safe hardware state.
+pub fn install() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        restore_fans();
+        restore_backlights();
+
+        if let Err(why) = StateStore::default().sync() {
+            log::error!("failed to sync state: {}", why);
+        }
+
+        log::error!("restored automatic fan control after panic");
+        default_hook(info);
+    }));
+
+    for &signal in &FATAL_SIGNALS {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = fatal_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESETHAND;
+            libc::sigemptyset(&mut action.sa_mask);
+
+            if libc::sigaction(signal, &action, ptr::null_mut()) != 0 {
+                log::warn!(
+                    "failed to install handler for signal {}: {}",
+                    signal,
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+    }
+}
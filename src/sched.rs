@@ -0,0 +1,91 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Options of the scheduler which trade performance for energy, chiefly on systems whose CPUs
+//! differ in capacity, such as those with performance and efficiency cores.
+//!
+//! Energy aware scheduling places each task on the CPU which runs it at the least energy, rather
+//! than spreading tasks for throughput. `sched_energy_aware` only permits it: the kernel also
+//! requires asymmetric CPU capacities, an energy model, and the schedutil governor. Utilization
+//! clamps bound the frequencies which the scheduler requests for tasks, and exist where the
+//! kernel was built with `CONFIG_UCLAMP_TASK`.
+
+use crate::{
+    config::SchedulerProfileConfig,
+    verify::{self, VerifyError},
+};
+use std::{fs, path::Path};
+
+const CPUS: &str = "/sys/devices/system/cpu";
+const ENERGY_AWARE: &str = "/proc/sys/kernel/sched_energy_aware";
+const UTIL_CLAMP_MIN: &str = "/proc/sys/kernel/sched_util_clamp_min";
+const UTIL_CLAMP_MAX: &str = "/proc/sys/kernel/sched_util_clamp_max";
+const UTIL_CLAMP_MIN_RT: &str = "/proc/sys/kernel/sched_util_clamp_min_rt_default";
+
+fn read_value(path: &str) -> Option<u16> { fs::read_to_string(path).ok()?.trim().parse().ok() }
+
+/// Whether the capacities of the CPUs differ.
+fn asymmetric(capacities: &[u32]) -> bool { capacities.windows(2).any(|pair| pair[0] != pair[1]) }
+
+/// Whether the CPUs differ in capacity, from the `cpu_capacity` of each.
+pub fn is_asymmetric() -> bool {
+    let capacities: Vec<u32> = fs::read_dir(CPUS)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            fs::read_to_string(entry.path().join("cpu_capacity")).ok()?.trim().parse().ok()
+        })
+        .collect();
+    asymmetric(&capacities)
+}
+
+/// Whether energy aware scheduling may be enabled or disabled.
+pub fn energy_aware_supported() -> bool { Path::new(ENERGY_AWARE).exists() && is_asymmetric() }
+
+/// Applies the options of a profile which the kernel supports, returning whether there were any.
+/// Each is read back to verify that the kernel accepted it.
+pub fn apply(config: &SchedulerProfileConfig) -> Result<bool, VerifyError> {
+    let mut settings = Vec::new();
+    if let Some(enabled) = config.energy_aware {
+        if energy_aware_supported() {
+            settings.push((ENERGY_AWARE, if enabled { 1 } else { 0 }));
+        }
+    }
+
+    // The minimum may not exceed the maximum, so whichever keeps them in order is set first.
+    let min = (UTIL_CLAMP_MIN, config.util_clamp_min);
+    let max = (UTIL_CLAMP_MAX, config.util_clamp_max);
+    let raising = match (config.util_clamp_min, read_value(UTIL_CLAMP_MAX)) {
+        (Some(min), Some(max)) => min > max,
+        _ => false,
+    };
+    let clamps = if raising { [max, min] } else { [min, max] };
+    let min_rt = (UTIL_CLAMP_MIN_RT, config.util_clamp_min_rt);
+    for &(path, value) in clamps.iter().chain(Some(&min_rt)) {
+        if let Some(value) = value {
+            if Path::new(path).exists() {
+                settings.push((path, value));
+            }
+        }
+    }
+
+    for &(path, value) in &settings {
+        verify::write(Path::new(path), &value.to_string())?;
+    }
+
+    Ok(!settings.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capacities() {
+        assert!(asymmetric(&[1024, 1024, 512, 512]));
+        assert!(!asymmetric(&[1024, 1024, 1024]));
+        assert!(!asymmetric(&[]));
+    }
+}
@@ -0,0 +1,121 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! A cache of hwmon readings, shared by the fan daemon, the health endpoint, and DBus queries, so
+//! that each attribute is read at most once per tick of the fan daemon.
+//!
+//! Readings are dropped at the start of every tick, and otherwise expire after a tick's length,
+//! so that they stay fresh while the fan daemon is not running.
+//!
+//! Temperatures are also read from here directly, for the features which follow them on systems
+//! whose fans are not controlled by the daemon.
+
+use std::{
+    cmp,
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+const HWMON: &str = "/sys/class/hwmon";
+
+pub struct SensorCache {
+    max_age:  Duration,
+    /// The trimmed contents of each attribute, or `None` if it could not be read.
+    readings: Mutex<HashMap<PathBuf, (Instant, Option<String>)>>,
+}
+
+impl SensorCache {
+    pub fn new(max_age: Duration) -> Self {
+        SensorCache { max_age, readings: Mutex::new(HashMap::new()) }
+    }
+
+    /// Drops every reading, so that the next tick reads each attribute again.
+    pub fn next_tick(&self) { self.readings.lock().unwrap().clear(); }
+
+    /// The contents of an attribute, which is only read if it was not read within this tick.
+    /// Failed reads are cached as well, so that missing attributes are not probed repeatedly.
+    pub fn read(&self, path: &Path) -> Option<String> {
+        self.read_with(path, Instant::now(), |path| {
+            fs::read_to_string(path).ok().map(|value| value.trim().to_owned())
+        })
+    }
+
+    /// An attribute holding an integer, such as `temp1_input` or `fan1_input`.
+    pub fn read_u32(&self, path: &Path) -> Option<u32> { self.read(path)?.parse().ok() }
+
+    /// The highest temperature of the hwmon devices with one of the given names, in thousandths
+    /// of a Celsius.
+    fn hwmon_temp(&self, names: &[&str]) -> Option<u32> {
+        fs::read_dir(HWMON)
+            .ok()?
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| {
+                self.read(&path.join("name")).map_or(false, |name| names.contains(&name.as_str()))
+            })
+            .filter_map(|path| self.read_u32(&path.join("temp1_input")))
+            .max()
+    }
+
+    /// The highest CPU temperature, in thousandths of a Celsius.
+    pub fn cpu_temp(&self) -> Option<u32> { self.hwmon_temp(&["coretemp", "k10temp"]) }
+
+    /// The highest temperature of the GPUs with hwmon devices, in thousandths of a Celsius. The
+    /// NVIDIA driver has none.
+    pub fn gpu_temp(&self) -> Option<u32> { self.hwmon_temp(&["amdgpu"]) }
+
+    /// The highest of the CPU and GPU temperatures, in thousandths of a Celsius.
+    pub fn temp(&self) -> Option<u32> { cmp::max(self.cpu_temp(), self.gpu_temp()) }
+
+    fn read_with<F>(&self, path: &Path, now: Instant, read: F) -> Option<String>
+    where
+        F: FnOnce(&Path) -> Option<String>,
+    {
+        let mut readings = self.readings.lock().unwrap();
+        if let Some((time, value)) = readings.get(path) {
+            if now.saturating_duration_since(*time) < self.max_age {
+                return value.clone();
+            }
+        }
+
+        // Readings of devices which were removed would otherwise be kept forever.
+        let max_age = self.max_age;
+        readings.retain(|_, (time, _)| now.saturating_duration_since(*time) < max_age);
+
+        let value = read(path);
+        readings.insert(path.to_owned(), (now, value.clone()));
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn cached_per_tick() {
+        let cache = SensorCache::new(Duration::from_secs(1));
+        let reads = Cell::new(0);
+        let read = |_: &Path| {
+            reads.set(reads.get() + 1);
+            Some(reads.get().to_string())
+        };
+
+        let path = Path::new("/sys/class/hwmon/hwmon0/temp1_input");
+        let start = Instant::now();
+        assert_eq!(cache.read_with(path, start, read).as_deref(), Some("1"));
+        let later = start + Duration::from_millis(500);
+        assert_eq!(cache.read_with(path, later, read).as_deref(), Some("1"));
+        let expired = start + Duration::from_secs(1);
+        assert_eq!(cache.read_with(path, expired, read).as_deref(), Some("2"));
+
+        cache.next_tick();
+        assert_eq!(cache.read_with(path, expired, read).as_deref(), Some("3"));
+        assert_eq!(reads.get(), 3);
+    }
+}
@@ -0,0 +1,200 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Control of system services through the init system of the distribution.
+//!
+//! Services are named as systemd units, such as `nvidia-fallback.service`. The `.service` suffix
+//! is dropped for OpenRC and runit, whose services are otherwise expected to share the name.
+//! Without a supported init system, every action fails with `ServiceError::Unsupported`, which
+//! callers should report rather than treat as fatal.
+
+use crate::backend::{CommandBackend, System};
+use std::{fs, io, os::unix::fs::symlink, path::Path, process::ExitStatus};
+use thiserror::Error;
+
+// The directories which runit supervises services from, on Void and Artix respectively.
+const RUNIT_SERVICE_DIRS: &[&str] = &["/var/service", "/run/runit/service"];
+const RUNIT_SV_DIRS: &[&str] = &["/etc/sv", "/etc/runit/sv"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InitSystem {
+    Systemd,
+    OpenRc,
+    Runit,
+}
+
+impl InitSystem {
+    /// The init system which is running, if it is supported.
+    pub fn detect() -> Option<Self> {
+        if Path::new("/run/systemd/system").is_dir() {
+            Some(InitSystem::Systemd)
+        } else if Path::new("/run/openrc").is_dir() {
+            Some(InitSystem::OpenRc)
+        } else if Path::new("/run/runit").is_dir() || Path::new("/etc/runit").is_dir() {
+            Some(InitSystem::Runit)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    /// Starts the service at boot.
+    Enable,
+    /// Stops starting the service at boot.
+    Disable,
+    Start,
+    Stop,
+}
+
+impl Action {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Action::Enable => "enable",
+            Action::Disable => "disable",
+            Action::Start => "start",
+            Action::Stop => "stop",
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ServiceError {
+    #[error("failed to execute {}: {}", _0, _1)]
+    Command(&'static str, io::Error),
+    #[error("{} failed with {}", _0, _1)]
+    Failed(&'static str, ExitStatus),
+    #[error("failed to {} {}: {}", _0.as_str(), _1, _2)]
+    Link(Action, String, io::Error),
+    #[error("no supported init system to {} {}", _0.as_str(), _1)]
+    Unsupported(Action, String),
+}
+
+/// The command which performs an action on a service, except for enabling and disabling with
+/// runit, which is done by linking the service into the supervised directory.
+fn command(
+    init: InitSystem,
+    action: Action,
+    unit: &str,
+    wait: bool,
+) -> Option<(&'static str, Vec<String>)> {
+    let name = unit.strip_suffix(".service").unwrap_or(unit).to_owned();
+    let command = match (init, action) {
+        (InitSystem::Systemd, Action::Start) | (InitSystem::Systemd, Action::Stop) if !wait => (
+            "systemctl",
+            vec![action.as_str().to_owned(), "--no-block".to_owned(), unit.to_owned()],
+        ),
+        (InitSystem::Systemd, _) => {
+            ("systemctl", vec![action.as_str().to_owned(), unit.to_owned()])
+        }
+        (InitSystem::OpenRc, Action::Enable) => {
+            ("rc-update", vec!["add".to_owned(), name, "default".to_owned()])
+        }
+        (InitSystem::OpenRc, Action::Disable) => {
+            ("rc-update", vec!["del".to_owned(), name, "default".to_owned()])
+        }
+        (InitSystem::OpenRc, _) => ("rc-service", vec![name, action.as_str().to_owned()]),
+        (InitSystem::Runit, Action::Start) => ("sv", vec!["up".to_owned(), name]),
+        (InitSystem::Runit, Action::Stop) => ("sv", vec!["down".to_owned(), name]),
+        (InitSystem::Runit, _) => return None,
+    };
+
+    Some(command)
+}
+
+/// Enables or disables a runit service, by linking it into the supervised directory.
+fn runit_link(action: Action, name: &str) -> Result<(), ServiceError> {
+    let link_error = |why| ServiceError::Link(action, name.to_owned(), why);
+    let service_dir = RUNIT_SERVICE_DIRS
+        .iter()
+        .map(Path::new)
+        .find(|dir| dir.is_dir())
+        .ok_or_else(|| link_error(io::ErrorKind::NotFound.into()))?;
+    let link = service_dir.join(name);
+
+    if action == Action::Disable {
+        return match fs::remove_file(&link) {
+            Err(why) if why.kind() != io::ErrorKind::NotFound => Err(link_error(why)),
+            _ => Ok(()),
+        };
+    }
+
+    if link.exists() {
+        return Ok(());
+    }
+
+    let service = RUNIT_SV_DIRS
+        .iter()
+        .map(|dir| Path::new(dir).join(name))
+        .find(|service| service.is_dir())
+        .ok_or_else(|| link_error(io::ErrorKind::NotFound.into()))?;
+    symlink(service, link).map_err(link_error)
+}
+
+/// Performs an action on a service. With `wait` unset, systemd returns without waiting for the
+/// service to start or stop.
+pub fn run(action: Action, unit: &str, wait: bool) -> Result<(), ServiceError> {
+    run_with(&System, action, unit, wait)
+}
+
+/// Performs an action on a service, running its command through a backend.
+pub fn run_with(
+    commands: &dyn CommandBackend,
+    action: Action,
+    unit: &str,
+    wait: bool,
+) -> Result<(), ServiceError> {
+    let init =
+        InitSystem::detect().ok_or_else(|| ServiceError::Unsupported(action, unit.to_owned()))?;
+
+    let (cmd, args) = match command(init, action, unit, wait) {
+        Some(command) => command,
+        None => return runit_link(action, unit.strip_suffix(".service").unwrap_or(unit)),
+    };
+
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let status = commands.run(cmd, &args).map_err(|why| ServiceError::Command(cmd, why))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(ServiceError::Failed(cmd, status))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command_line(init: InitSystem, action: Action, wait: bool) -> Option<String> {
+        command(init, action, "nvidia-fallback.service", wait)
+            .map(|(cmd, args)| format!("{} {}", cmd, args.join(" ")))
+    }
+
+    #[test]
+    fn commands() {
+        let line = |init, action| command_line(init, action, true);
+        assert_eq!(
+            line(InitSystem::Systemd, Action::Enable).as_deref(),
+            Some("systemctl enable nvidia-fallback.service")
+        );
+        assert_eq!(
+            command_line(InitSystem::Systemd, Action::Stop, false).as_deref(),
+            Some("systemctl stop --no-block nvidia-fallback.service")
+        );
+        assert_eq!(
+            line(InitSystem::OpenRc, Action::Disable).as_deref(),
+            Some("rc-update del nvidia-fallback default")
+        );
+        assert_eq!(
+            line(InitSystem::OpenRc, Action::Start).as_deref(),
+            Some("rc-service nvidia-fallback start")
+        );
+        assert_eq!(
+            line(InitSystem::Runit, Action::Stop).as_deref(),
+            Some("sv down nvidia-fallback")
+        );
+        assert_eq!(line(InitSystem::Runit, Action::Enable), None);
+    }
+}
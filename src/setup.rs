@@ -0,0 +1,198 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! The settings which `system76-power setup` proposes for a machine, from what was detected of
+//! its hardware and how it is used, and the commented configuration file which holds them.
+//!
+//! Charge thresholds and the graphics mode are not part of the configuration, so they are applied
+//! through the daemon, which keeps them.
+
+use crate::{config::Profile, fan::FanCurve};
+use std::{fmt::Write, fs};
+
+const POWER_SUPPLY: &str = "/sys/class/power_supply";
+
+/// How the machine is mostly used.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Usage {
+    /// Always plugged in.
+    Desktop,
+    /// Often on battery.
+    Portable,
+}
+
+/// How the fans respond to temperature.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FanPreset {
+    /// The curve of the model.
+    Standard,
+    /// The curve of the model, with its duty limited until the last point.
+    Quiet,
+    /// The curve of the model, reaching each duty 5 degrees sooner.
+    Cool,
+}
+
+/// What was detected of the machine.
+#[derive(Debug, Default)]
+pub struct Hardware {
+    /// The model, as in DMI `product_version`.
+    pub model:             String,
+    pub battery:           bool,
+    pub charge_thresholds: bool,
+    pub fan_control:       bool,
+    /// The default graphics mode, and the mode which renders everything on the dGPU, where
+    /// graphics are switchable.
+    pub graphics:          Option<(String, &'static str)>,
+}
+
+/// Whether the system has a battery of its own, rather than only peripherals with batteries.
+pub fn has_battery() -> bool {
+    fs::read_dir(POWER_SUPPLY).map_or(false, |entries| {
+        entries.filter_map(Result::ok).any(|entry| {
+            let path = entry.path();
+            let read = |attr: &str| fs::read_to_string(path.join(attr)).unwrap_or_default();
+            read("type").trim() == "Battery" && read("scope").trim() != "Device"
+        })
+    })
+}
+
+#[derive(Debug)]
+pub struct Proposal {
+    pub usage:             Usage,
+    pub profile:           Profile,
+    /// Whether the profile follows the power source.
+    pub auto_profile:      bool,
+    pub charge_thresholds: Option<(u8, u8)>,
+    pub fan:               FanPreset,
+    /// The curve which replaces the curve of the model, if any.
+    pub fan_curve:         Option<FanCurve>,
+    pub graphics:          Option<String>,
+}
+
+impl Proposal {
+    pub fn new(hardware: &Hardware, usage: Usage, fan: FanPreset) -> Self {
+        let portable = usage == Usage::Portable;
+
+        // A battery which is always plugged in lasts longest when kept from charging fully.
+        let charge_thresholds = if !hardware.charge_thresholds {
+            None
+        } else if portable {
+            Some((86, 90))
+        } else {
+            Some((50, 60))
+        };
+
+        let model_curve = || {
+            FanCurve::for_model(&hardware.model).map_or_else(FanCurve::standard, |(_, curve)| curve)
+        };
+        let fan_curve = match fan {
+            _ if !hardware.fan_control => None,
+            FanPreset::Standard => None,
+            FanPreset::Quiet => Some(model_curve().quiet(60_00)),
+            FanPreset::Cool => Some(model_curve().lowered(5_00)),
+        };
+
+        let graphics = hardware.graphics.as_ref().map(|(default, discrete)| {
+            if portable {
+                default.clone()
+            } else {
+                (*discrete).to_owned()
+            }
+        });
+
+        Proposal {
+            usage,
+            profile: if portable { Profile::Balanced } else { Profile::Performance },
+            auto_profile: portable && hardware.battery,
+            charge_thresholds,
+            fan,
+            fan_curve,
+            graphics,
+        }
+    }
+
+    /// The configuration file, with a comment explaining each choice.
+    pub fn to_config(&self, hardware: &Hardware) -> String {
+        let profile = match self.profile {
+            Profile::Battery => "battery",
+            Profile::Balanced => "balanced",
+            Profile::Performance => "performance",
+        };
+        let usage = match self.usage {
+            Usage::Desktop => "always plugged in",
+            Usage::Portable => "often on battery",
+        };
+
+        let model = if hardware.model.is_empty() { "this machine" } else { &hardware.model };
+        let mut config = format!(
+            "# Written by `system76-power setup` for {}, which is {}.\n# See `system76-power \
+             config validate` after making changes.\n\n[daemon]\n# The profile applied when the \
+             daemon starts.\ndefault_profile = \"{}\"\n",
+            model, usage, profile
+        );
+
+        if self.auto_profile {
+            config.push_str(
+                "\n[auto_profile]\n# Switches to the battery profile when unplugged, and back \
+                 when plugged in.\nenabled = true\nac = \"balanced\"\nbattery = \"battery\"\n",
+            );
+        }
+
+        if let Some(ref curve) = self.fan_curve {
+            let comment = match self.fan {
+                FanPreset::Quiet => "Holds the fans at 60% until the last point of the curve.",
+                _ => "Reaches each duty cycle 5 degrees sooner than the curve of the model.",
+            };
+            let _ = write!(config, "\n[fan]\n# {}\nunit = \"celsius\"\ncurve = [\n", comment);
+            for (millicelsius, duty) in curve.points() {
+                let _ = writeln!(
+                    config,
+                    "    {{ temp = {:.2}, duty = {} }},",
+                    f64::from(millicelsius) / 1000.0,
+                    duty / 100
+                );
+            }
+            config.push_str("]\n");
+        }
+
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::path::Path;
+
+    #[test]
+    fn proposals() {
+        let hardware = Hardware {
+            model:             "oryp6".into(),
+            battery:           true,
+            charge_thresholds: true,
+            fan_control:       true,
+            graphics:          Some(("hybrid".into(), "nvidia")),
+        };
+
+        let portable = Proposal::new(&hardware, Usage::Portable, FanPreset::Standard);
+        assert_eq!(portable.profile, Profile::Balanced);
+        assert_eq!(portable.charge_thresholds, Some((86, 90)));
+        assert_eq!(portable.graphics.as_deref(), Some("hybrid"));
+        assert!(portable.fan_curve.is_none());
+
+        let desktop = Proposal::new(&hardware, Usage::Desktop, FanPreset::Quiet);
+        assert_eq!(desktop.profile, Profile::Performance);
+        assert_eq!(desktop.charge_thresholds, Some((50, 60)));
+        assert_eq!(desktop.graphics.as_deref(), Some("nvidia"));
+
+        for proposal in &[portable, desktop] {
+            let config = Config::parse(Path::new("config.toml"), &proposal.to_config(&hardware));
+            let config = config.unwrap();
+            assert_eq!(config.daemon.default_profile, proposal.profile);
+            assert_eq!(config.auto_profile.enabled, proposal.auto_profile);
+            assert_eq!(config.fan.curve.is_empty(), proposal.fan_curve.is_none());
+        }
+    }
+}
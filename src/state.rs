@@ -0,0 +1,132 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Persistent daemon state, kept as one JSON file per key in `/var/lib/system76-power`.
+//!
+//! Files are replaced atomically, so that a crash or power loss while writing leaves either the
+//! previous or the new contents on disk. Files which still fail to parse are moved aside, and
+//! treated as if they did not exist.
+
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+pub const STATE_DIR: &str = "/var/lib/system76-power";
+
+#[derive(Clone, Debug)]
+pub struct StateStore {
+    dir: PathBuf,
+}
+
+impl Default for StateStore {
+    fn default() -> Self { StateStore::new(STATE_DIR) }
+}
+
+impl StateStore {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self { StateStore { dir: dir.into() } }
+
+    fn path(&self, key: &str) -> PathBuf { self.dir.join(key).with_extension("json") }
+
+    /// Loads the value stored for a key, if it exists and is valid.
+    pub fn load<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let path = self.path(key);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(ref why) if why.kind() == io::ErrorKind::NotFound => return None,
+            Err(why) => {
+                log::warn!("failed to read state from {}: {}", path.display(), why);
+                return None;
+            }
+        };
+
+        match serde_json::from_slice(&data) {
+            Ok(value) => Some(value),
+            Err(why) => {
+                log::warn!("discarding corrupt state in {}: {}", path.display(), why);
+                let corrupt = path.with_extension("json.corrupt");
+                if let Err(why) = fs::rename(&path, &corrupt) {
+                    log::warn!("failed to move aside {}: {}", path.display(), why);
+                }
+                None
+            }
+        }
+    }
+
+    /// Atomically replaces the value stored for a key.
+    pub fn store<T: Serialize>(&self, key: &str, value: &T) -> io::Result<()> {
+        let data = serde_json::to_vec_pretty(value)
+            .map_err(|why| io::Error::new(io::ErrorKind::InvalidData, why))?;
+        fs::create_dir_all(&self.dir)?;
+        write_atomic(&self.path(key), &data)
+    }
+
+    /// Ensures that all completed writes have reached the disk.
+    pub fn sync(&self) -> io::Result<()> {
+        match File::open(&self.dir) {
+            Ok(dir) => dir.sync_all(),
+            Err(ref why) if why.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(why) => Err(why),
+        }
+    }
+
+    pub fn remove(&self, key: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(key)) {
+            Err(ref why) if why.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        }
+    }
+}
+
+/// Replaces the contents of a file by writing to a temporary file in the same directory, which
+/// is then synced and renamed over the original.
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(path.file_name().unwrap_or_default());
+    tmp_name.push(format!(".{}.tmp", process::id()));
+    let tmp = path.with_file_name(tmp_name);
+
+    let res = File::create(&tmp)
+        .and_then(|mut file| file.write_all(data).and_then(|_| file.sync_all()))
+        .and_then(|_| fs::rename(&tmp, path));
+
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+        return res;
+    }
+
+    // Sync the directory so that the rename itself survives a power loss.
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn store_and_recover() {
+        let dir = std::env::temp_dir().join(format!("system76-power-state-{}", process::id()));
+        let store = StateStore::new(&dir);
+
+        assert_eq!(store.load::<String>("graphics"), None);
+        store.store("graphics", &"hybrid").unwrap();
+        assert_eq!(store.load::<String>("graphics").as_deref(), Some("hybrid"));
+
+        // Corrupt files are moved aside and treated as missing.
+        fs::write(dir.join("graphics.json"), b"{").unwrap();
+        assert_eq!(store.load::<String>("graphics"), None);
+        assert!(dir.join("graphics.json.corrupt").exists());
+        assert!(!dir.join("graphics.json").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
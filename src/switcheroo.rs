@@ -0,0 +1,80 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Provides the interface of switcheroo-control, which desktops use to launch applications on
+//! the discrete GPU.
+//!
+//! If switcheroo-control is already running, it is left to provide the interface itself.
+//!
+//! Alongside the properties of switcheroo-control, `OffloadApplications` maps the applications
+//! which the configuration always launches on the discrete GPU to the environment which selects
+//! it, so that launchers need not be told per launch.
+
+use crate::graphics::Gpu;
+use dbus::{
+    arg::{PropMap, RefArg, Variant},
+    nonblock::{stdintf::org_freedesktop_dbus::RequestNameReply, SyncConnection},
+};
+use std::collections::HashMap;
+
+pub const SWITCHEROO_NAME: &str = "net.hadess.SwitcherooControl";
+pub const SWITCHEROO_PATH: &str = "/net/hadess/SwitcherooControl";
+pub const SWITCHEROO_IFACE: &str = "net.hadess.SwitcherooControl";
+
+/// Claims the bus name of switcheroo-control, if it is not already owned.
+pub async fn claim(conn: &SyncConnection) -> Result<bool, dbus::Error> {
+    let reply = conn.request_name(SWITCHEROO_NAME, false, false, true).await?;
+    Ok(reply == RequestNameReply::PrimaryOwner || reply == RequestNameReply::AlreadyOwner)
+}
+
+/// The value of the `GPUs` property.
+pub fn gpu_properties(gpus: &[Gpu]) -> Vec<PropMap> {
+    gpus.iter()
+        .map(|gpu| {
+            let mut map = PropMap::new();
+            map.insert("Name".into(), Variant(Box::new(gpu.name.clone()) as Box<dyn RefArg>));
+            map.insert("Environment".into(), Variant(Box::new(gpu.environment.clone())));
+            map.insert("Default".into(), Variant(Box::new(gpu.default)));
+            map
+        })
+        .collect()
+}
+
+/// The value of the `OffloadApplications` property: the environment of the first GPU which is
+/// not the default, for each application ID, such as `org.blender.Blender`. Empty where no GPU
+/// is offloaded to, such as in integrated or NVIDIA mode.
+pub fn offload_applications(applications: &[String], gpus: &[Gpu]) -> HashMap<String, Vec<String>> {
+    let environment = match gpus.iter().find(|gpu| !gpu.default && !gpu.environment.is_empty()) {
+        Some(gpu) => &gpu.environment,
+        None => return HashMap::new(),
+    };
+
+    applications
+        .iter()
+        .map(|app| (app.trim_end_matches(".desktop").to_owned(), environment.clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gpu(environment: &[&str], default: bool) -> Gpu {
+        let environment = environment.iter().map(|&var| var.to_owned()).collect();
+        Gpu { name: String::from("GPU"), environment, default }
+    }
+
+    #[test]
+    fn offload() {
+        let applications = vec![String::from("org.blender.Blender.desktop"), String::from("steam")];
+        let gpus = [gpu(&[], true), gpu(&["DRI_PRIME", "1"], false)];
+
+        let offloaded = offload_applications(&applications, &gpus);
+        assert_eq!(offloaded.len(), 2);
+        assert_eq!(offloaded["org.blender.Blender"], vec!["DRI_PRIME", "1"]);
+        assert_eq!(offloaded["steam"], vec!["DRI_PRIME", "1"]);
+
+        assert!(offload_applications(&applications, &gpus[..1]).is_empty());
+    }
+}
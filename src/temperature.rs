@@ -0,0 +1,87 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Conversion between millidegrees Celsius, which hwmon and the daemon use throughout, and the
+//! unit that temperatures are configured and displayed in.
+
+use serde::Deserialize;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TemperatureUnit {
+    Celsius,
+    Fahrenheit,
+}
+
+impl Default for TemperatureUnit {
+    fn default() -> Self { TemperatureUnit::Celsius }
+}
+
+impl TemperatureUnit {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            TemperatureUnit::Celsius => "°C",
+            TemperatureUnit::Fahrenheit => "°F",
+        }
+    }
+
+    /// Converts millidegrees Celsius to degrees in this unit.
+    pub fn degrees(self, millicelsius: i32) -> f64 {
+        let celsius = f64::from(millicelsius) / 1000.0;
+        match self {
+            TemperatureUnit::Celsius => celsius,
+            TemperatureUnit::Fahrenheit => celsius * 9.0 / 5.0 + 32.0,
+        }
+    }
+
+    /// Converts degrees in this unit to millidegrees Celsius.
+    pub fn to_millicelsius(self, degrees: f64) -> i32 {
+        let celsius = match self {
+            TemperatureUnit::Celsius => degrees,
+            TemperatureUnit::Fahrenheit => (degrees - 32.0) * 5.0 / 9.0,
+        };
+
+        (celsius * 1000.0).round() as i32
+    }
+
+    /// Formats a temperature in millidegrees Celsius, such as `72.5 °F`.
+    pub fn format(self, millicelsius: i32) -> String {
+        format!("{:.1} {}", self.degrees(millicelsius), self.symbol())
+    }
+}
+
+impl FromStr for TemperatureUnit {
+    type Err = String;
+
+    fn from_str(unit: &str) -> Result<Self, Self::Err> {
+        match unit.to_ascii_lowercase().as_str() {
+            "c" | "celsius" => Ok(TemperatureUnit::Celsius),
+            "f" | "fahrenheit" => Ok(TemperatureUnit::Fahrenheit),
+            _ => Err(format!("unknown temperature unit `{}`", unit)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conversions() {
+        let fahrenheit = TemperatureUnit::Fahrenheit;
+        assert!((fahrenheit.degrees(100_000) - 212.0).abs() < f64::EPSILON);
+        assert!((fahrenheit.degrees(-40_000) + 40.0).abs() < f64::EPSILON);
+        assert_eq!(fahrenheit.to_millicelsius(113.0), 45_000);
+        assert_eq!(fahrenheit.format(22_500), "72.5 °F");
+
+        let celsius = TemperatureUnit::Celsius;
+        assert_eq!(celsius.to_millicelsius(44.99), 44_990);
+        assert_eq!(celsius.format(44_990), "45.0 °C");
+
+        assert_eq!("F".parse(), Ok(TemperatureUnit::Fahrenheit));
+        assert_eq!("celsius".parse(), Ok(TemperatureUnit::Celsius));
+        assert!("kelvin".parse::<TemperatureUnit>().is_err());
+    }
+}
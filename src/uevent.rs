@@ -0,0 +1,136 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Kernel uevents are broadcast over netlink whenever a device is added, removed, or changed.
+//! Listening to them allows cached device enumerations to be updated only when something
+//! changes, rather than rescanning sysfs on every request.
+
+use libc::{
+    c_void, sockaddr, sockaddr_nl, socklen_t, AF_NETLINK, NETLINK_KOBJECT_UEVENT, SOCK_CLOEXEC,
+    SOCK_DGRAM, SOCK_NONBLOCK,
+};
+use std::{
+    io, mem,
+    os::unix::io::{AsRawFd, RawFd},
+};
+
+/// Multicast group for events sent by the kernel, rather than those re-broadcast by udev.
+const KERNEL_GROUP: u32 = 1;
+
+#[derive(Debug, Default)]
+pub struct Uevent {
+    pub action:    String,
+    pub devpath:   String,
+    pub subsystem: String,
+}
+
+impl Uevent {
+    /// Parses a message in the form of `ACTION@DEVPATH\0KEY=VALUE\0KEY=VALUE...`.
+    fn parse(data: &[u8]) -> Option<Uevent> {
+        let mut fields = data.split(|&b| b == 0).map(String::from_utf8_lossy);
+
+        // The header must contain an `@` separator, or this is not a kernel uevent.
+        if !fields.next()?.contains('@') {
+            return None;
+        }
+
+        let mut event = Uevent::default();
+        for field in fields {
+            let mut kv = field.splitn(2, '=');
+            let (key, value) = match (kv.next(), kv.next()) {
+                (Some(key), Some(value)) => (key, value),
+                _ => continue,
+            };
+
+            match key {
+                "ACTION" => event.action = value.to_owned(),
+                "DEVPATH" => event.devpath = value.to_owned(),
+                "SUBSYSTEM" => event.subsystem = value.to_owned(),
+                _ => (),
+            }
+        }
+
+        Some(event)
+    }
+}
+
+pub struct UeventSocket {
+    fd: RawFd,
+}
+
+impl UeventSocket {
+    pub fn new() -> io::Result<UeventSocket> {
+        let fd = unsafe {
+            libc::socket(
+                AF_NETLINK,
+                SOCK_DGRAM | SOCK_CLOEXEC | SOCK_NONBLOCK,
+                NETLINK_KOBJECT_UEVENT,
+            )
+        };
+
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let socket = UeventSocket { fd };
+
+        let mut addr: sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = AF_NETLINK as u16;
+        addr.nl_groups = KERNEL_GROUP;
+
+        let res = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const sockaddr_nl as *const sockaddr,
+                mem::size_of::<sockaddr_nl>() as socklen_t,
+            )
+        };
+
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(socket)
+    }
+
+    /// Reads all uevents which are currently queued, without blocking.
+    ///
+    /// If the kernel had to drop events because they were not read quickly enough, an error of
+    /// `ENOBUFS` is returned, and callers should assume that anything may have changed.
+    pub fn events(&self) -> io::Result<Vec<Uevent>> {
+        let mut events = Vec::new();
+        let mut buffer = [0u8; 8192];
+
+        loop {
+            let read =
+                unsafe { libc::recv(self.fd, buffer.as_mut_ptr() as *mut c_void, buffer.len(), 0) };
+
+            if read < 0 {
+                let why = io::Error::last_os_error();
+                return match why.kind() {
+                    io::ErrorKind::WouldBlock => Ok(events),
+                    io::ErrorKind::Interrupted => continue,
+                    _ => Err(why),
+                };
+            }
+
+            if let Some(event) = Uevent::parse(&buffer[..read as usize]) {
+                log::trace!("uevent: {:?}", event);
+                events.push(event);
+            }
+        }
+    }
+}
+
+impl AsRawFd for UeventSocket {
+    fn as_raw_fd(&self) -> RawFd { self.fd }
+}
+
+impl Drop for UeventSocket {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
@@ -0,0 +1,58 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Reads the power source from UPower, and watches for changes to it.
+
+use crate::power_source::PowerSource;
+use dbus::{
+    message::MatchRule,
+    nonblock::{stdintf::org_freedesktop_dbus::Properties, MsgMatch, Proxy, SyncConnection},
+    Message,
+};
+use futures::channel::mpsc::UnboundedReceiver;
+use std::time::Duration;
+
+const UPOWER_NAME: &str = "org.freedesktop.UPower";
+const UPOWER_PATH: &str = "/org/freedesktop/UPower";
+const UPOWER_IFACE: &str = "org.freedesktop.UPower";
+const DISPLAY_DEVICE_PATH: &str = "/org/freedesktop/UPower/devices/DisplayDevice";
+const DEVICE_IFACE: &str = "org.freedesktop.UPower.Device";
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// Values of the `WarningLevel` property of a UPower device.
+const WARNING_LEVEL_LOW: u32 = 3;
+
+pub async fn power_source(conn: &SyncConnection) -> Result<PowerSource, dbus::Error> {
+    let upower = Proxy::new(UPOWER_NAME, UPOWER_PATH, TIMEOUT, conn);
+    let on_battery: bool = upower.get(UPOWER_IFACE, "OnBattery").await?;
+
+    // The display device combines all of the system's batteries.
+    let display = Proxy::new(UPOWER_NAME, DISPLAY_DEVICE_PATH, TIMEOUT, conn);
+    let present: bool = display.get(DEVICE_IFACE, "IsPresent").await?;
+    if !present {
+        return Ok(PowerSource { on_battery, percentage: None, low: false });
+    }
+
+    let percentage: f64 = display.get(DEVICE_IFACE, "Percentage").await?;
+    let warning_level: u32 = display.get(DEVICE_IFACE, "WarningLevel").await?;
+
+    Ok(PowerSource {
+        on_battery,
+        percentage: Some(percentage),
+        low: warning_level >= WARNING_LEVEL_LOW,
+    })
+}
+
+/// Subscribes to property changes of UPower and its devices. The match must be kept alive for
+/// as long as messages are received from the stream.
+pub async fn watch(
+    conn: &SyncConnection,
+) -> Result<(MsgMatch, UnboundedReceiver<Message>), dbus::Error> {
+    let rule = MatchRule::new_signal("org.freedesktop.DBus.Properties", "PropertiesChanged")
+        .with_sender(UPOWER_NAME)
+        .with_namespaced_path(UPOWER_PATH);
+
+    Ok(conn.add_match(rule).await?.msg_stream())
+}
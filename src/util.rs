@@ -2,7 +2,11 @@
 //
 // SPDX-License-Identifier: GPL-3.0-only
 
-use std::{fs::DirEntry, io, path::Path};
+use std::{
+    fs::{self, DirEntry},
+    io,
+    path::Path,
+};
 
 pub fn entries<T, F: FnMut(DirEntry) -> T>(path: &Path, mut func: F) -> io::Result<Vec<T>> {
     let mut ret = Vec::new();
@@ -12,3 +16,9 @@ pub fn entries<T, F: FnMut(DirEntry) -> T>(path: &Path, mut func: F) -> io::Resu
 
     Ok(ret)
 }
+
+/// The identifier of the current boot, which tells state saved during this boot apart from
+/// state left over from a previous one.
+pub fn boot_id() -> Option<String> {
+    fs::read_to_string("/proc/sys/kernel/random/boot_id").ok().map(|id| id.trim().to_owned())
+}
@@ -0,0 +1,96 @@
+// Copyright 2018-2021 System76 <info@system76.com>
+//
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Writes to sysfs which are read back to verify that the kernel accepted them.
+//!
+//! A write to an attribute can succeed while the driver clamps the value or ignores it, such as
+//! `max_perf_pct` while the platform limits performance, or `power/control` of a device whose
+//! driver forbids runtime PM. Such writes are retried a few times, in case the driver was busy,
+//! before the value which the kernel kept is reported.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+use thiserror::Error;
+
+// How many times a value is written before it is reported as rejected.
+const ATTEMPTS: u32 = 3;
+// How long to wait before writing a rejected value again.
+const RETRY_DELAY: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Error)]
+pub enum VerifyError {
+    #[error("failed to write {} to {}: {}", _1, _0.display(), _2)]
+    Write(PathBuf, String, io::Error),
+    #[error("failed to read back {}: {}", _0.display(), _1)]
+    Read(PathBuf, io::Error),
+    #[error("{} holds {} after writing {}", _0.display(), _2, _1)]
+    Rejected(PathBuf, String, String),
+}
+
+/// Writes a value to an attribute, which must then read back as the same value.
+pub fn write(path: &Path, value: &str) -> Result<(), VerifyError> {
+    write_accepting(path, value, |actual| actual == value)
+}
+
+/// Writes a value to an attribute, which must then read back as a value that `accepts` allows,
+/// for attributes which the driver is expected to adjust.
+pub fn write_accepting<F: Fn(&str) -> bool>(
+    path: &Path,
+    value: &str,
+    accepts: F,
+) -> Result<(), VerifyError> {
+    let mut actual = String::new();
+    for attempt in 1..=ATTEMPTS {
+        if attempt > 1 {
+            log::debug!("{} holds {} after writing {}, retrying", path.display(), actual, value);
+            thread::sleep(RETRY_DELAY);
+        }
+
+        fs::write(path, value)
+            .map_err(|why| VerifyError::Write(path.to_owned(), value.to_owned(), why))?;
+        actual = read(path)?;
+        if accepts(&actual) {
+            return Ok(());
+        }
+    }
+
+    Err(VerifyError::Rejected(path.to_owned(), value.to_owned(), actual))
+}
+
+/// The value of an attribute. Attributes which list their choices, such as `[auto] on`, are read
+/// as the selected choice.
+pub fn read(path: &Path) -> Result<String, VerifyError> {
+    let value = fs::read_to_string(path).map_err(|why| VerifyError::Read(path.to_owned(), why))?;
+    Ok(selected(value.trim()).to_owned())
+}
+
+fn selected(value: &str) -> &str {
+    value
+        .split_whitespace()
+        .find(|choice| choice.starts_with('[') && choice.ends_with(']'))
+        .map_or(value, |choice| &choice[1..choice.len() - 1])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn verified_writes() {
+        let path = std::env::temp_dir().join(format!("system76-power-verify-{}", process::id()));
+
+        write(&path, "auto").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "auto");
+        assert!(write_accepting(&path, "10", |actual| actual == "20").is_err());
+        assert_eq!(selected("performance [powersave]"), "powersave");
+        assert_eq!(selected("auto"), "auto");
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -3,7 +3,73 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
 use crate::{kernel_parameters::*, modprobe};
-use std::path::Path;
+use std::{
+    fs, io,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+const WIRELESS: &str = "/proc/net/wireless";
+
+/// The quality of the link of a wireless interface, as reported by the kernel.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LinkStatus {
+    pub interface:      String,
+    /// Signal level, in dBm.
+    pub signal:         i32,
+    /// Packets which were retransmitted too often and dropped.
+    pub retries:        u64,
+    /// Beacons of the access point which were missed.
+    pub missed_beacons: u64,
+}
+
+/// The link status of every associated wireless interface.
+pub fn link_status() -> Vec<LinkStatus> {
+    fs::read_to_string(WIRELESS).map(|source| parse_wireless(&source)).unwrap_or_default()
+}
+
+fn parse_wireless(source: &str) -> Vec<LinkStatus> {
+    // The first two lines are headers.
+    source
+        .lines()
+        .skip(2)
+        .filter_map(|line| {
+            let (interface, fields) = line.split_at(line.find(':')?);
+            let fields: Vec<&str> = fields[1..].split_whitespace().collect();
+            let number = |index: usize| fields.get(index).map(|field| field.trim_end_matches('.'));
+            Some(LinkStatus {
+                interface:      interface.trim().to_owned(),
+                signal:         number(2)?.parse().ok()?,
+                retries:        number(7)?.parse().ok()?,
+                missed_beacons: number(9)?.parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+/// Sets the power saving of a wireless interface, which takes effect without reloading its
+/// driver.
+pub fn set_power_save(interface: &str, enabled: bool) -> io::Result<()> {
+    log::debug!("Setting power saving of {} to {}", interface, enabled);
+    let output = Command::new("iw")
+        .args(&["dev", interface, "set", "power_save", if enabled { "on" } else { "off" }])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stderr = stderr.trim();
+    if output.status.success() {
+        if !stderr.is_empty() {
+            log::warn!("iw: {}", stderr);
+        }
+        Ok(())
+    } else {
+        let message = format!("iw exited with {}: {}", output.status, stderr);
+        Err(io::Error::new(io::ErrorKind::Other, message))
+    }
+}
 
 pub struct WifiDevice {
     device:      &'static str,
@@ -57,3 +123,26 @@ impl DeviceList<WifiDevice> for WifiDevice {
         Box::new(Self::SUPPORTED.iter().flat_map(|dev| WifiDevice::new(dev)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wireless() {
+        let source = concat!(
+            "Inter-| sta-|   Quality        |   Discarded packets               | Missed | WE\n",
+            " face | tus | link level noise |  nwid  crypt   frag  retry   misc | beacon | 22\n",
+            "wlp0s20f3: 0000   54.  -56.  -256        0      0      0     17      0        3  0\n",
+        );
+        assert_eq!(
+            parse_wireless(source),
+            vec![LinkStatus {
+                interface:      String::from("wlp0s20f3"),
+                signal:         -56,
+                retries:        17,
+                missed_beacons: 3,
+            }]
+        );
+    }
+}